@@ -1,7 +1,7 @@
 #![feature(box_syntax,plugin)]
 #![plugin(regex_macros)]
 
-use irc::event_stream::{Action, HandlerAction, Response};
+use irc::event_stream::{Action, HandlerAction, HandlerOptions, Response};
 use irc::protocol;
 
 extern crate env_logger;
@@ -20,13 +20,14 @@ fn main() {
 
     let echo_handler = box move |line: &str| {
         if let Some(pm) = protocol::Privmsg::parse(line).and_then(|pm| pm.targeted_msg(nick)) {
-            if regex!(r"^[Hh]i$").is_match(pm.msg) {
+            let msg = pm.plain_msg();
+            if regex!(r"^[Hh]i$").is_match(&msg) {
                 if let Some(reply_to) = pm.reply_target(nick) {
                     if let Some(protocol::Source::User(ref user_info)) = pm.src {
-                        return Response::respond(protocol::Privmsg::new(reply_to, &format!("Hi, {}!", user_info.nick)).format());
+                        return Response::respond(protocol::Privmsg::new(reply_to, &format!("Hi, {}!", user_info.nick)).command());
                     }
                 }
-            } else if regex!(r"^go away$").is_match(pm.msg) {
+            } else if regex!(r"^go away$").is_match(&msg) {
                 return Response(None, HandlerAction::Keep, Action::Stop);
             }
         }
@@ -35,10 +36,14 @@ fn main() {
 
     let (mut client, join_handle) = irc::Client::connect(
         &addr, nick, &channels, "rustbot", "rust irc robot")
-        .ok().expect(&format!("Error connecting to {:?}.", addr));
-
-    client.add_handler(echo_handler);
-    join_handle.join().unwrap_or_else(|_| {
-        error!("Unknown error!");
-    });
+        .unwrap_or_else(|e| panic!("Error connecting to {:?}: {}", addr, e));
+
+    if let Err(e) = client.add_handler_with(HandlerOptions::new("echo"), echo_handler) {
+        error!("Error adding handler: {}", e);
+    }
+    match join_handle.join() {
+        Ok(Ok(())) => (),
+        Ok(Err(e)) => { error!("Disconnected: {}", e); },
+        Err(_) => { error!("Unknown error!"); },
+    }
 }
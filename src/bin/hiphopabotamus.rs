@@ -1,206 +1,104 @@
 #![feature(box_syntax)]
-#![feature(plugin)]
-#![plugin(postgres_macros,regex_macros)]
 
-use irc::event_stream::{Action, HandlerAction, Response};
-use irc::protocol;
-use postgres::{Connection, SslMode};
-use regex::Regex;
-use rand::{thread_rng, Rng};
-use std::ascii::AsciiExt;
+use irc::ClientBuilder;
+use irc::command::Command;
+use irc::config::Config;
+use irc::encoding::Encoding;
+use irc::plugin::{Plugin, Registry};
+use irc::plugins;
+use irc::plugins::admin;
+use irc::plugins::admin::{Admin, Envelope};
+use irc::storage;
 use std::env;
-use std::str::FromStr;
-use std::sync::{Arc, Mutex};
+use std::sync::mpsc::channel;
+use std::thread;
 
 extern crate env_logger;
 extern crate getopts;
 extern crate irc;
-extern crate postgres;
-extern crate rand;
-extern crate regex;
 
 #[macro_use]
 extern crate log;
 
-fn init_pg_tables() -> Connection {
-    let pg = Connection::connect("postgres://leif@localhost", &SslMode::None).unwrap();
-    pg.execute("CREATE TABLE IF NOT EXISTS knowledge (
-                  key VARCHAR,
-                  val VARCHAR,
-                  PRIMARY KEY(key, val)
-                )", &[]).unwrap();
-    pg.execute("CREATE TABLE IF NOT EXISTS karma (
-                  nick VARCHAR PRIMARY KEY,
-                  karma INTEGER NOT NULL DEFAULT 0
-                )", &[]).unwrap();
-    pg
+enum Event {
+    Admin(Envelope),
+    Finished(thread::Result<irc::Result<()>>),
 }
 
 fn main() {
     env_logger::init().unwrap();
 
     let mut opts = getopts::Options::new();
-    opts.reqopt("", "host", "irc server hostname", "HOSTNAME");
-    opts.optopt("", "port", "irc server port", "PORT");
-    opts.reqopt("n", "nick", "nickname", "NICK");
-    opts.optmulti("c", "chan", "channels to join", "CHAN");
+    opts.reqopt("c", "config", "configuration file", "FILE");
+    opts.optopt("", "encoding", "encoding for incoming text (utf-8, utf-8+latin-1, latin-1, cp1252)", "ENCODING");
 
     let args: Vec<String> = env::args().collect();
-    let matches = opts.parse(&args[1..]).unwrap();
-
-    let host = matches.opt_str("host").expect("must provide --host");
-    let mut port = 6667;
-    if matches.opt_present("port") {
-        port = u16::from_str(&matches.opt_str("port").expect("must provide argument to --port")).unwrap();
+    let matches = opts.parse(&args[1..]).unwrap_or_else(|e| panic!("{}", e));
+    let config_path = matches.opt_str("config").expect("must provide --config");
+    let mut config = Config::load(&config_path)
+        .unwrap_or_else(|e| panic!("Error loading {}: {}", config_path, e));
+    if let Some(name) = matches.opt_str("encoding") {
+        config.encodings.inbound = Encoding::from_name(&name).expect("unknown --encoding");
     }
 
-    let addr = (host.as_ref(), port);
-    let nick = matches.opt_str("nick").expect("must provide --nick");
-    let channels = matches.opt_strs("chan");
-
-    let choice_nick = nick.clone();
-    let choice_handler = box move |line: &str| {
-        if let Some(pm) = protocol::Privmsg::parse(line).and_then(|pm| pm.targeted_msg(&choice_nick)) {
-            let choices: Vec<_> = regex!(r"\s+or\s+")
-                .split(pm.msg)
-                .map(|s| s.trim())
-                .filter(|s| !s.is_empty())
-                .collect();
-            if choices.len() > 1 {
-                let mut rng = thread_rng();
-                if let Some(reply_to) = pm.reply_target(&choice_nick) {
-                    return Response::respond(protocol::Privmsg::new(reply_to, rng.choose(&choices).unwrap()).format());
-                }
-            }
-        }
-        Response::nothing()
-    };
-
-    let pg = Arc::new(Mutex::new(init_pg_tables()));
-
-    // {
-    //     // TODO: Seed our knowledge
-    //     let mut knowledge = knowledge_mutex.lock().unwrap();
-    //     knowledge.insert(format!("{}: info", nick), vec!["I'm a robot running https://github.com/leifwalsh/irc, feel free to contribute!".to_string()]);
-    //     knowledge.insert(format!("{}, info", nick), vec!["I'm a robot running https://github.com/leifwalsh/irc, feel free to contribute!".to_string()]);
-    // }
-
-    let learning_pg = pg.clone();
-    let learning_nick = nick.clone();
-    let learning_handler = box move |line: &str| {
-        if let Some(pm) = protocol::Privmsg::parse(line).and_then(|pm| pm.targeted_msg(&learning_nick)) {
-            let assignment: Vec<_> = regex!(r"\s+is\s+")
-                .splitn(pm.msg, 2)
-                .map(|s| s.trim())
-                .filter(|s| !s.is_empty())
-                .collect();
-            if assignment.len() == 2 {
-                let conn = learning_pg.lock().unwrap();
-                conn.execute(sql!("INSERT INTO knowledge (key, val) VALUES ($1, $2)"),
-                             &[&assignment[0], &assignment[1]]).unwrap();
-            }
+    let storage_url = config.storage_url.clone().unwrap_or_else(|| {
+        warn!("No [storage] url configured, so nothing will be kept after exiting.");
+        "memory:".to_string()
+    });
+    let storage = storage::open(&storage_url, config.storage_pool_size.map(|n| n as usize).unwrap_or(storage::DEFAULT_POOL_SIZE))
+        .unwrap_or_else(|e| panic!("Error opening {}: {}", storage_url, e));
+
+    let (mut client, join_handle) = ClientBuilder::from_config(&config).connect()
+        .unwrap_or_else(|e| panic!("Error connecting: {}", e));
+
+    let mut registry = Registry::new(config.plugins.clone(), Some(storage));
+    for plugin in plugins::builtins().into_iter() {
+        let name = plugin.name().to_string();
+        if let Err(e) = registry.load(&mut client, plugin) {
+            error!("Error loading {} plugin: {}", name, e);
         }
-        Response::nothing()
-    };
-
-    let info_pg = pg.clone();
-    let info_nick = nick.clone();
-    let info_handler = box move |line: &str| {
-        if let Some(pm) = protocol::Privmsg::parse(line) {
-            if let Some(reply_to) = pm.reply_target(&info_nick) {
-                let mut rng = thread_rng();
-                let conn = info_pg.lock().unwrap();
-                let info_stmt = conn.prepare(sql!("SELECT val FROM knowledge WHERE key = $1")).unwrap();
-                if let Some(choice) = rand::sample(&mut rng, info_stmt.query(&[&pm.msg]).unwrap().into_iter().map(|r| { r.get::<_, String>(0) }), 1).get(0) {
-                    if let Some(c) = regex!(r"^<action>\s+(.*)$").captures(choice) {
-                        return Response::respond(protocol::Privmsg::new(reply_to, &protocol::ctcp_action(c.at(1).expect("Bad regex match"))).format());
-                    } else {
-                        return Response::respond(protocol::Privmsg::new(reply_to, choice).format());
-                    }
-                }
-            }
-        }
-        Response::nothing()
-    };
+    }
 
-    let karma_pg = pg.clone();
-    let karma_nick = nick.clone();
-    let karma_handler = box move |line: &str| {
-        if let Some(pm) = protocol::Privmsg::parse(line) {
-            if let Some(c) = regex!(r"^([^-+\s]+)(\+\+|--)$").captures(pm.msg) {
-                let n = c.at(1).expect("Bad match group");
-                let inc = c.at(2).expect("Bad match group") == "++";
-                let conn = karma_pg.lock().unwrap();
-                let stmt = conn.prepare(sql!("SELECT count(nick) FROM karma WHERE nick = $1")).unwrap();
-                let count: i64 = stmt.query(&[&n]).unwrap().into_iter().next().unwrap().get(0);
-                let change = if inc { 1 } else { -1 };
-                if count == 0 {
-                    conn.execute(sql!("INSERT INTO karma (nick, karma) VALUES ($1, $2)"), &[&n, &change]).unwrap();
-                } else {
-                    conn.execute(sql!("UPDATE karma SET karma = karma + $2 WHERE nick = $1"), &[&n, &change]).unwrap();
-                }
-            } else if let Some(c) = regex!(r"^karma\s+([^\s]+)$").captures(pm.msg) {
-                if let Some(reply_to) = pm.reply_target(&karma_nick) {
-                    let n = c.at(1).expect("Bad match group");
-                    let conn = karma_pg.lock().unwrap();
-                    let stmt = conn.prepare(sql!("SELECT karma FROM karma WHERE nick = $1")).unwrap();
-                    let k: i32 = stmt.query(&[&n]).unwrap()
-                        .into_iter().next().and_then(|r| r.get(0)).or(Some(0)).unwrap();
-                    return Response::respond(protocol::Privmsg::new(reply_to, &format!("{}: {}", n, k)).format())
-                }
+    // Plugin requests from IRC, and the event loop finishing, both come
+    // here, since the registry lives on this thread.
+    let (events_tx, events_rx) = channel();
+    let (admin_tx, admin_rx) = channel();
+    if let Err(e) = registry.load(&mut client, box Admin::new(admin_tx)) {
+        error!("Error loading admin plugin: {}", e);
+    }
+    let admin_events = events_tx.clone();
+    thread::spawn(move || {
+        for envelope in admin_rx.iter() {
+            if admin_events.send(Event::Admin(envelope)).is_err() {
+                return;
             }
         }
-        Response::nothing()
-    };
-
-    let join_nick = nick.clone();
-    let join_handler = box move |line: &str| {
-        if let Some(pm) = protocol::Privmsg::parse(line).and_then(|pm| pm.targeted_msg(&join_nick)) {
-            if let Some(c) = regex!(r"^(join|part) (#[^\s]+)").captures(pm.msg) {
-                let action = c.at(1).expect("Bad match group");
-                let chan = c.at(2).expect("Bad match group");
-                let success_msg = format!("{} channel {}!", if action == "join" { "Joined" } else { "Left" }, chan);
-                if let Ok(joined_regex) = Regex::new(&format!(r"{}\s+:?{}", action.to_ascii_uppercase(), chan)) {
-                    let joined_handler = box move |joined_line: &str| {
-                        if joined_regex.is_match(joined_line) {
-                            info!("{}", success_msg);
-                            return Response(None, HandlerAction::Remove, Action::Skip);
-                        }
-                        Response::nothing()
-                    };
-                    info!("{} channel {}...", if action == "join" { "Joining" } else { "Leaving" }, chan);
-                    return Response(Some(format!("{} {}", action.to_ascii_uppercase(), chan)), HandlerAction::Add(joined_handler), Action::Skip);
+    });
+    thread::spawn(move || {
+        let _ = events_tx.send(Event::Finished(join_handle.join()));
+    });
+    for event in events_rx.iter() {
+        match event {
+            Event::Admin(envelope) => {
+                let reply = admin::handle(&mut registry, &mut client, envelope.request);
+                if let Err(e) = client.send(&Command::privmsg(&envelope.reply_to, &reply)) {
+                    error!("Error replying to plugin request: {}", e);
                 }
-            }
-        }
-        Response::nothing()
-    };
-
-    let echo_nick = nick.clone();
-    let echo_handler = box move |line: &str| {
-        if let Some(pm) = protocol::Privmsg::parse(line).and_then(|pm| pm.targeted_msg(&echo_nick)) {
-            if regex!(r"^[Hh]i$").is_match(pm.msg) {
-                if let Some(reply_to) = pm.reply_target(&echo_nick) {
-                    if let Some(protocol::Source::User(ref user_info)) = pm.src {
-                        return Response::respond(protocol::Privmsg::new(reply_to, &format!("Hi, {}!", user_info.nick)).format());
-                    }
+            },
+            Event::Finished(result) => {
+                match result {
+                    Ok(Ok(())) => (),
+                    Ok(Err(e)) => { error!("Disconnected: {}", e); },
+                    Err(_) => { error!("Unknown error!"); },
                 }
-            } else if regex!(r"^go away$").is_match(pm.msg) {
-                return Response(None, HandlerAction::Keep, Action::Stop);
-            }
+                break;
+            },
         }
-        Response::nothing()
-    };
-
-    let (mut client, join_handle) = irc::Client::connect(
-        &addr, &nick, &channels.iter().map(|s| s.as_ref()).collect::<Vec<&str>>(), "leifw_rustbot", "leifw's rust robot")
-        .ok().expect(&format!("Error connecting to {:?}.", addr));
-
-    client.add_handler(choice_handler);
-    client.add_handler(learning_handler);
-    client.add_handler(karma_handler);
-    client.add_handler(info_handler);
-    client.add_handler(join_handler);
-    client.add_handler(echo_handler);
-    join_handle.join().unwrap_or_else(|_| { error!("Unknown error!"); });
+    }
+    if let Err(e) = registry.unload_all(&mut client) {
+        error!("Error unloading plugins: {}", e);
+    }
+    if let Err(e) = client.quit("adios", 10 * 1000) {
+        error!("Error quitting: {}", e);
+    }
 }
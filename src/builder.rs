@@ -0,0 +1,299 @@
+use openssl::nid::Nid;
+use openssl::ssl::{SslContext, SslMethod, SslStream, SSL_VERIFY_PEER};
+use std::net;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use super::Client;
+use super::config::{Config, ServerConfig};
+use super::encoding::Encodings;
+use super::error::{Error, Result};
+use super::event_stream::{EventStream, Handler, HandlerOptions, RateLimit, StreamOptions, INTERNAL_PRIORITY};
+use super::join::JoinPolicy;
+use super::membership::Membership;
+use super::modes::UserModes;
+use super::protocol;
+use super::protocol::Registration;
+use super::state;
+use super::state::ServerState;
+
+/// Collects everything needed to connect a `Client`.
+///
+/// # Example:
+/// ```{.ignore .rust}
+/// let (mut client, join_handle) = irc::ClientBuilder::new("rustbot")
+///     .tls_server("irc.freenode.net", 6697)
+///     .alt_nick("rustbot_")
+///     .sasl("rustbot", "hunter2")
+///     .channel("#rustbot_test")
+///     .channel_with_key("#secret", "swordfish")
+///     .connect().ok().unwrap();
+/// ```
+#[derive(Clone, Debug)]
+pub struct ClientBuilder {
+    servers: Vec<ServerConfig>,
+    registration: Registration,
+    channels: Vec<(String, Option<String>)>,
+    join_policy: JoinPolicy,
+    options: StreamOptions,
+}
+
+impl ClientBuilder {
+
+    /// Starts building a client that goes by `nick`, and uses it for
+    /// its user name and real name too unless told otherwise.  Asks
+    /// to be invisible (`+i`) by default.
+    pub fn new(nick: &str) -> ClientBuilder {
+        let mut registration = Registration::new(nick);
+        registration.modes = UserModes::new().invisible(true);
+        ClientBuilder{
+            servers: Vec::new(),
+            registration: registration,
+            channels: Vec::new(),
+            join_policy: JoinPolicy::new(),
+            options: StreamOptions::new(),
+        }
+    }
+
+    /// Starts from a loaded configuration file.
+    pub fn from_config(config: &Config) -> ClientBuilder {
+        let mut builder = ClientBuilder::new(&config.nicks[0])
+            .user(&config.username)
+            .realname(&config.realname)
+            .user_modes(config.user_modes.clone())
+            .encodings(config.encodings.clone());
+        builder.servers = config.servers.clone();
+        for nick in config.nicks[1..].iter() {
+            builder = builder.alt_nick(nick);
+        }
+        for cap in config.capabilities.iter() {
+            builder = builder.capability(cap);
+        }
+        if let Some(ref sasl) = config.sasl {
+            builder = builder.sasl(&sasl.account, &sasl.password);
+        }
+        for chan in config.channels.iter() {
+            builder.channels.push((chan.name.clone(), chan.key.clone()));
+        }
+        builder.join_policy = config.join_policy.clone();
+        if let Some(limit) = config.rate_limit {
+            builder = builder.rate_limit(limit);
+        }
+        builder
+    }
+
+    /// Adds a server to try, in plain text.  Servers are tried in the
+    /// order they're added.
+    pub fn server(mut self, host: &str, port: u16) -> ClientBuilder {
+        self.servers.push(ServerConfig{ host: host.to_string(), port: port, tls: false, tls_verify: true });
+        self
+    }
+
+    /// Adds a server to try over TLS.  Its certificate must be signed
+    /// by one of the system's CAs and be for `host`.
+    pub fn tls_server(mut self, host: &str, port: u16) -> ClientBuilder {
+        self.servers.push(ServerConfig{ host: host.to_string(), port: port, tls: true, tls_verify: true });
+        self
+    }
+
+    /// Adds a server to try over TLS without checking its certificate,
+    /// for servers with self-signed ones.  Anyone in between can read
+    /// everything, including the SASL password.
+    pub fn unverified_tls_server(mut self, host: &str, port: u16) -> ClientBuilder {
+        self.servers.push(ServerConfig{ host: host.to_string(), port: port, tls: true, tls_verify: false });
+        self
+    }
+
+    /// Adds a nick to fall back to if the ones before it are taken.
+    pub fn alt_nick(mut self, nick: &str) -> ClientBuilder {
+        self.registration.nicks.push(nick.to_string());
+        self
+    }
+
+    pub fn user(mut self, user: &str) -> ClientBuilder {
+        self.registration.user = user.to_string();
+        self
+    }
+
+    pub fn realname(mut self, realname: &str) -> ClientBuilder {
+        self.registration.realname = realname.to_string();
+        self
+    }
+
+    pub fn user_modes(mut self, modes: UserModes) -> ClientBuilder {
+        self.registration.modes = modes;
+        self
+    }
+
+    /// Requests an IRCv3 capability, if the server offers it.
+    pub fn capability(mut self, cap: &str) -> ClientBuilder {
+        if !self.registration.capabilities.iter().any(|c| c == cap) {
+            self.registration.capabilities.push(cap.to_string());
+        }
+        self
+    }
+
+    /// Authenticates with SASL PLAIN before registering.
+    pub fn sasl(mut self, account: &str, password: &str) -> ClientBuilder {
+        self.registration.sasl = Some((account.to_string(), password.to_string()));
+        self
+    }
+
+    /// Joins `chan` once connected.
+    pub fn channel(mut self, chan: &str) -> ClientBuilder {
+        self.channels.push((chan.to_string(), None));
+        self
+    }
+
+    /// Joins `chan` with `key` once connected.
+    pub fn channel_with_key(mut self, chan: &str, key: &str) -> ClientBuilder {
+        self.channels.push((chan.to_string(), Some(key.to_string())));
+        self
+    }
+
+    /// What to do about channels we can't join.
+    pub fn join_policy(mut self, policy: JoinPolicy) -> ClientBuilder {
+        self.join_policy = policy;
+        self
+    }
+
+    pub fn encodings(mut self, encodings: Encodings) -> ClientBuilder {
+        self.options.encodings = encodings;
+        self
+    }
+
+    pub fn rate_limit(mut self, limit: RateLimit) -> ClientBuilder {
+        self.options.rate_limit = Some(limit);
+        self
+    }
+
+    /// How many threads run blocking handlers.  Defaults to 4, and
+    /// there's always at least one.
+    pub fn workers(mut self, workers: usize) -> ClientBuilder {
+        self.options.workers = workers;
+        self
+    }
+
+    /// Connects to the first server that will have us, registers, and
+    /// joins channels.  Failing to join a channel doesn't fail the
+    /// connection; see `Client::join_outcomes`.
+    ///
+    /// Returns the `Client` and a `thread::JoinHandle` which will join
+    /// when the thread handling IRC events finishes, yielding the
+    /// reason it stopped.  If every server fails, returns the error
+    /// from the last one.
+    pub fn connect(&self) -> Result<(Client, thread::JoinHandle<Result<()>>)> {
+        let mut last_error = Error::Registration("no servers to connect to".to_string());
+        for server in self.servers.iter() {
+            match self.connect_to(server) {
+                Ok(connected) => return Ok(connected),
+                Err(e) => {
+                    warn!("Couldn't connect to {}:{}: {}", server.host, server.port, e);
+                    last_error = e;
+                },
+            }
+        }
+        Err(last_error)
+    }
+
+    fn connect_to(&self, server: &ServerConfig) -> Result<(Client, thread::JoinHandle<Result<()>>)> {
+        let state = Arc::new(Mutex::new(ServerState::new(&self.registration.nicks[0])));
+        let internal = |name: &str| HandlerOptions::new(name).priority(INTERNAL_PRIORITY);
+        let default_handlers: Vec<(HandlerOptions, Handler)> = vec![
+            (internal("state"), state::tracker(state.clone())),
+            (internal("pong"), box protocol::pong_handler),
+            (internal("timeout"), box protocol::timeout_handler),
+            ];
+
+        debug!("Connecting to {}:{}...", server.host, server.port);
+        let conn = try!(net::TcpStream::connect((&server.host[..], server.port)));
+        // Kept so we can close the connection out from under the
+        // reader thread when quitting.
+        let socket = try!(conn.try_clone());
+        let (mut stream, threads, join_handle) = if server.tls {
+            let tls = try!(tls_connect(server, conn));
+            let tls_copy = try!(tls.try_clone());
+            try!(EventStream::with_threads(tls, tls_copy, default_handlers, self.options.clone()))
+        } else {
+            let conn_copy = try!(conn.try_clone());
+            try!(EventStream::with_threads(conn, conn_copy, default_handlers, self.options.clone()))
+        };
+        info!("Connected to {}:{}!", server.host, server.port);
+
+        let server_name = try!(protocol::login(&mut stream, &self.registration));
+        info!("Logged in at \"{}\"!", server_name);
+
+        let registered = state::snapshot(&state);
+        try!(protocol::request_user_modes(&mut stream, &registered.nick, &self.registration.modes, &registered.isupport));
+
+        let mut membership = Membership::new(stream.clone(), state.clone(), self.join_policy.clone());
+        try!(stream.add_handler_with(internal("rejoin"), membership.rejoin_handler()));
+        let joins = try!(membership.join(&self.channels));
+
+        let client = Client{
+            stream: stream,
+            state: state,
+            membership: membership,
+            joins: joins,
+            socket: Some(socket),
+            threads: Some(threads),
+        };
+        Ok((client, join_handle))
+    }
+
+}
+
+/// Starts TLS on `conn`, checking the server's certificate against the
+/// system's CAs and `server.host` unless told not to.
+fn tls_connect(server: &ServerConfig, conn: net::TcpStream) -> Result<SslStream<net::TcpStream>> {
+    let mut ctx = try!(SslContext::new(SslMethod::Sslv23).map_err(|e| Error::Tls(e.to_string())));
+    if server.tls_verify {
+        ctx.set_verify(SSL_VERIFY_PEER, None);
+        try!(ctx.set_default_verify_paths().map_err(|e| Error::Tls(e.to_string())));
+    } else {
+        warn!("Not verifying the certificate for {}.", server.host);
+    }
+    let tls = try!(SslStream::new(&ctx, conn).map_err(|e| Error::Tls(e.to_string())));
+    if server.tls_verify {
+        let name = tls.ssl().peer_certificate().and_then(|cert| {
+            cert.subject_name().text_by_nid(Nid::CN).map(|cn| cn.to_string())
+        });
+        match name {
+            Some(ref name) if hostname_matches(name, &server.host) => (),
+            Some(name) => return Err(Error::Tls(format!("certificate is for {}, not {}", name, server.host))),
+            None => return Err(Error::Tls("certificate has no common name".to_string())),
+        }
+    }
+    Ok(tls)
+}
+
+/// Whether a certificate for `name`, which may start with a `*.`
+/// wildcard for one label, covers `host`.
+fn hostname_matches(name: &str, host: &str) -> bool {
+    let name = name.to_lowercase();
+    let host = host.trim_right_matches('.').to_lowercase();
+    if name.starts_with("*.") {
+        let mut labels = host.splitn(2, '.');
+        let first = labels.next().unwrap_or("");
+        !first.is_empty() && labels.next() == Some(&name[2..])
+    } else {
+        name == host
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::hostname_matches;
+
+    #[test]
+    fn certificate_names() {
+        assert!(hostname_matches("irc.example.net", "irc.example.net"));
+        assert!(hostname_matches("IRC.example.net", "irc.Example.net."));
+        assert!(hostname_matches("*.example.net", "irc.example.net"));
+        assert!(!hostname_matches("*.example.net", "example.net"));
+        assert!(!hostname_matches("*.example.net", "a.irc.example.net"));
+        assert!(!hostname_matches("irc.example.net", "irc.example.net.evil.com"));
+        assert!(!hostname_matches("other.example.net", "irc.example.net"));
+    }
+
+}
@@ -1,28 +1,44 @@
 use std::io;
 use std::io::prelude::*;
+use std::sync::{Arc, RwLock};
 use std::sync::mpsc;
 use std::sync::mpsc::{channel, Receiver, Sender};
 use std::thread;
+use time;
+use super::encoding::Encodings;
+use super::event_stream::{RateLimit, SentHooks};
 
-pub enum ChanError<T> {
-    RecvError(mpsc::RecvError),
-    SendError(mpsc::SendError<T>),
-}
-
-pub fn send_to_chan_error<T>(e: mpsc::SendError<T>) -> ChanError<T> {
-    ChanError::SendError(e)
-}
+/// Encodings shared between the I/O threads and whoever wants to
+/// change them while we're connected.
+pub type SharedEncodings = Arc<RwLock<Encodings>>;
 
-pub fn recv_to_chan_error<T>(e: mpsc::RecvError) -> ChanError<T> {
-    ChanError::RecvError(e)
+fn current_encodings(encodings: &SharedEncodings) -> Encodings {
+    // Nothing can panic while holding this lock, but don't let that
+    // stop us if it somehow does.
+    match encodings.read() {
+        Ok(e) => e.clone(),
+        Err(poisoned) => poisoned.into_inner().clone(),
+    }
 }
 
-fn reader_loop<R: Read>(r: R, tx: Sender<String>) -> io::Result<()> {
+fn reader_loop<R: Read>(r: R, encodings: SharedEncodings, tx: Sender<String>) -> io::Result<()> {
     let mut reader = io::BufReader::new(r);
     loop {
-        let mut line = String::new();
-        try!(reader.read_line(&mut line));
-        line = line.trim().to_string();
+        let mut raw = Vec::new();
+        if try!(reader.read_until(b'\n', &mut raw)) == 0 {
+            debug!("Connection closed.");
+            return Ok(());
+        }
+        while raw.last().map_or(false, |&b| b == b'\n' || b == b'\r') {
+            raw.pop();
+        }
+        let line = match current_encodings(&encodings).decode_line(&raw) {
+            Ok(line) => line,
+            Err(lossy) => {
+                warn!("Couldn't decode {:?}, using \"{}\".", raw, lossy);
+                lossy
+            },
+        }.trim().to_string();
         debug!("Read \"{}\".", line);
         if let Some(mpsc::SendError(l)) = tx.send(line).err() {
             debug!("Send of \"{}\" failed, channel is disconnected.", l);
@@ -31,42 +47,136 @@ fn reader_loop<R: Read>(r: R, tx: Sender<String>) -> io::Result<()> {
     }
 }
 
-/// Reads lines from the provided `Read` and sends them into a
-/// channel.  Returns the `Receiver` of that channel.
-pub fn reader<R: Read + Send + 'static>(r: R) -> Receiver<String> {
+/// Reads lines from the provided `Read`, decodes them according to
+/// `encodings`, and sends them into a channel.  Returns the
+/// `Receiver` of that channel, and the reading thread.
+pub fn reader<R: Read + Send + 'static>(r: R, encodings: SharedEncodings) -> (Receiver<String>, thread::JoinHandle<()>) {
     let (tx, rx) = channel();
-    thread::spawn(move || {
-        reader_loop(r, tx).err().and_then(|e| -> Option<()> {
+    let join_handle = thread::spawn(move || {
+        reader_loop(r, encodings, tx).err().and_then(|e| -> Option<()> {
             error!("Fatal I/O error \"{:?}\".", e);
             None
         });
     });
-    rx
+    (rx, join_handle)
+}
+
+/// Tracks how many lines we may send right now, as milliseconds of
+/// credit.  Each line costs `interval_ms`, and credit accrues in real
+/// time up to `burst` lines' worth.
+struct Throttle {
+    limit: RateLimit,
+    credit_ms: u64,
+    last_ms: u64,
+}
+
+fn now_ms() -> u64 {
+    time::precise_time_ns() / 1000000
+}
+
+impl Throttle {
+
+    fn new(limit: RateLimit) -> Throttle {
+        Throttle{
+            limit: limit,
+            credit_ms: limit.burst as u64 * limit.interval_ms as u64,
+            last_ms: now_ms(),
+        }
+    }
+
+    /// Sleeps until we're allowed to send another line.
+    fn wait(&mut self) {
+        let interval = self.limit.interval_ms as u64;
+        let max_credit = self.limit.burst as u64 * interval;
+        let now = now_ms();
+        self.credit_ms = ::std::cmp::min(self.credit_ms + (now - self.last_ms), max_credit);
+        self.last_ms = now;
+        if self.credit_ms < interval {
+            let delay = interval - self.credit_ms;
+            debug!("Rate limited, waiting {}ms...", delay);
+            thread::sleep_ms(delay as u32);
+            self.credit_ms = interval;
+            self.last_ms = now_ms();
+        }
+        self.credit_ms -= interval;
+    }
+
+}
+
+/// What the writer thread is asked to do.
+pub enum Outgoing {
+    /// Write some text, which may be part of a line.
+    Text(String),
+    /// Stop once everything before this has been written.
+    Close,
 }
 
-fn writer_loop<W: Write>(w: W, rx: Receiver<String>) -> io::Result<()> {
+fn writer_loop<W: Write>(w: W, encodings: SharedEncodings, rate_limit: Option<RateLimit>, sent_hooks: SentHooks, rx: Receiver<Outgoing>) -> io::Result<()> {
     let mut writer = io::LineWriter::new(w);
+    let mut throttle = rate_limit.map(Throttle::new);
     let mut buf = String::new();
-    for line in rx.iter() {
-        buf.push_str(&line);
-        if buf.ends_with('\n') {
+    for outgoing in rx.iter() {
+        let chunk = match outgoing {
+            Outgoing::Text(chunk) => chunk,
+            Outgoing::Close => {
+                debug!("Writer closed.");
+                break;
+            },
+        };
+        buf.push_str(&chunk);
+        // Lines may arrive in pieces, but we need whole ones to pick
+        // an encoding.
+        while let Some(end) = buf.find('\n') {
+            let rest = buf[end + 1..].to_string();
+            buf.truncate(end + 1);
+            if let Some(ref mut t) = throttle {
+                t.wait();
+            }
             debug!("Sending \"{}\"...", buf.trim());
-            buf = String::new();
+            try!(writer.write_all(&current_encodings(&encodings).encode_line(&buf)));
+            sent_hooks.run(buf.trim_right_matches(|c| c == '\r' || c == '\n'));
+            buf = rest;
         }
-        try!(writer.write(line.as_bytes()));
     }
-    Ok(())
+    writer.flush()
 }
 
-/// Creates a channel that will write lines it receives to the
-/// provided `Write`.  Returns the `Sender` half of the channel.
-pub fn writer<W: Write + Send + 'static>(w: W) -> Sender<String> {
+/// Creates a channel that will encode lines it receives according to
+/// `encodings` and write them to the provided `Write`, no faster than
+/// `rate_limit` allows, showing each to `sent_hooks` once it's written.
+/// Returns the `Sender` half of the channel, and the writing thread.
+pub fn writer<W: Write + Send + 'static>(w: W, encodings: SharedEncodings, rate_limit: Option<RateLimit>, sent_hooks: SentHooks) -> (Sender<Outgoing>, thread::JoinHandle<()>) {
     let (tx, rx) = channel();
-    thread::spawn(move || {
-        writer_loop(w, rx).err().and_then(|e| -> Option<()> {
+    let join_handle = thread::spawn(move || {
+        writer_loop(w, encodings, rate_limit, sent_hooks, rx).err().and_then(|e| -> Option<()> {
             error!("Fatal I/O error \"{:?}\".", e);
             None
         });
     });
-    tx
+    (tx, join_handle)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::io::Cursor;
+    use std::sync::{Arc, RwLock};
+    use std::sync::mpsc::channel;
+    use super::reader_loop;
+    use super::super::encoding::{Encoding, Encodings};
+
+    #[test]
+    fn reader_decodes_per_channel() {
+        let mut encodings = Encodings::with(Encoding::Utf8Latin1Fallback, Encoding::Utf8);
+        encodings.set_channel("#old", Encoding::Cp1252);
+        let raw = b":a!b@c PRIVMSG #old :\x93hi\x94\r\n:a!b@c PRIVMSG #new :caf\xe9\r\n:a!b@c PRIVMSG #new :caf\xc3\xa9\n";
+        let (tx, rx) = channel();
+        reader_loop(Cursor::new(raw.to_vec()), Arc::new(RwLock::new(encodings)), tx).unwrap();
+        assert_eq!(rx.iter().collect::<Vec<_>>(), vec![
+            ":a!b@c PRIVMSG #old :\u{201c}hi\u{201d}",
+            ":a!b@c PRIVMSG #new :caf\u{e9}",
+            ":a!b@c PRIVMSG #new :caf\u{e9}",
+            ]);
+    }
+
 }
@@ -0,0 +1,111 @@
+//! Commands a client sends to the server.
+
+use std::fmt;
+
+/// An outgoing command.  Formatting one produces a line a client may
+/// send, without a prefix or the line ending, which the writer adds.
+///
+/// Line breaks and NULs in arguments are dropped, so text from users
+/// can't smuggle in extra commands.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Command {
+    /// `PRIVMSG <target> :<text>`
+    Privmsg(String, String),
+    /// `NOTICE <target> :<text>`
+    Notice(String, String),
+    /// `JOIN <channels> [<keys>]`.  Keys go with the first channels.
+    Join(Vec<String>, Vec<String>),
+    /// `PART <channels> [:<reason>]`
+    Part(Vec<String>, Option<String>),
+    /// `KICK <channel> <nick> [:<reason>]`
+    Kick(String, String, Option<String>),
+    /// `INVITE <nick> <channel>`
+    Invite(String, String),
+    /// `TOPIC <channel> [:<topic>]`
+    Topic(String, Option<String>),
+    /// `MODE <target> [<modes and parameters>...]`
+    Mode(String, Vec<String>),
+    /// `NICK <nick>`
+    Nick(String),
+    /// `PONG :<token>`
+    Pong(String),
+    /// `QUIT [:<reason>]`
+    Quit(Option<String>),
+    /// Anything else, sent as given.
+    Raw(String),
+}
+
+impl Command {
+
+    pub fn privmsg(target: &str, text: &str) -> Command {
+        Command::Privmsg(target.to_string(), text.to_string())
+    }
+
+    pub fn notice(target: &str, text: &str) -> Command {
+        Command::Notice(target.to_string(), text.to_string())
+    }
+
+    /// Joins a single channel, with an optional key.
+    pub fn join(chan: &str, key: Option<&str>) -> Command {
+        Command::Join(vec![chan.to_string()], key.into_iter().map(|k| k.to_string()).collect())
+    }
+
+}
+
+/// Drops characters that would end the line early.
+fn clean(s: &str) -> String {
+    s.chars().filter(|&c| c != '\r' && c != '\n' && c != '\0').collect()
+}
+
+fn write_trailing(f: &mut fmt::Formatter, trailing: &Option<String>) -> fmt::Result {
+    match *trailing {
+        Some(ref t) => write!(f, " :{}", clean(t)),
+        None => Ok(()),
+    }
+}
+
+impl fmt::Display for Command {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Command::Privmsg(ref target, ref text) => write!(f, "PRIVMSG {} :{}", clean(target), clean(text)),
+            Command::Notice(ref target, ref text) => write!(f, "NOTICE {} :{}", clean(target), clean(text)),
+            Command::Join(ref chans, ref keys) => {
+                try!(write!(f, "JOIN {}", clean(&chans.join(","))));
+                if keys.is_empty() {
+                    Ok(())
+                } else {
+                    write!(f, " {}", clean(&keys.join(",")))
+                }
+            },
+            Command::Part(ref chans, ref reason) => {
+                try!(write!(f, "PART {}", clean(&chans.join(","))));
+                write_trailing(f, reason)
+            },
+            Command::Kick(ref chan, ref nick, ref reason) => {
+                try!(write!(f, "KICK {} {}", clean(chan), clean(nick)));
+                write_trailing(f, reason)
+            },
+            Command::Invite(ref nick, ref chan) => write!(f, "INVITE {} {}", clean(nick), clean(chan)),
+            Command::Topic(ref chan, ref topic) => {
+                try!(write!(f, "TOPIC {}", clean(chan)));
+                write_trailing(f, topic)
+            },
+            Command::Mode(ref target, ref args) => {
+                try!(write!(f, "MODE {}", clean(target)));
+                for arg in args.iter() {
+                    try!(write!(f, " {}", clean(arg)));
+                }
+                Ok(())
+            },
+            Command::Nick(ref nick) => write!(f, "NICK {}", clean(nick)),
+            Command::Pong(ref token) => write!(f, "PONG :{}", clean(token)),
+            Command::Quit(ref reason) => {
+                try!(write!(f, "QUIT"));
+                write_trailing(f, reason)
+            },
+            Command::Raw(ref line) => write!(f, "{}", clean(line)),
+        }
+    }
+
+}
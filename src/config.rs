@@ -0,0 +1,520 @@
+//! Loading client configuration from TOML.
+//!
+//! # Example:
+//! ```{.ignore .toml}
+//! nicks = ["rustbot", "rustbot_"]
+//! username = "rustbot"
+//! realname = "rust irc robot"
+//! user_modes = "iB"
+//!
+//! [[servers]]
+//! host = "irc.freenode.net"
+//! port = 6697
+//! tls = true
+//! # Only for servers with self-signed certificates.  Defaults to true.
+//! tls_verify = true
+//!
+//! [sasl]
+//! account = "rustbot"
+//! password = "hunter2"
+//!
+//! [[channels]]
+//! name = "#rustbot_test"
+//!
+//! [[channels]]
+//! name = "#secret"
+//! key = "swordfish"
+//!
+//! [join]
+//! retries = 3
+//! retry_delay_ms = 60000
+//! wait_for_invite = true
+//! rejoin_delay_ms = 5000
+//!
+//! [rate_limit]
+//! burst = 5
+//! interval_ms = 2000
+//!
+//! [encoding]
+//! inbound = "utf-8+latin-1"
+//!
+//! [encoding.channels]
+//! "#oldtimers" = "cp1252"
+//!
+//! [storage]
+//! url = "sqlite:hiphopabotamus.db"
+//! pool_size = 4
+//!
+//! [plugins.karma]
+//! enabled = true
+//! channels = ["#rustbot_test"]
+//! ```
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+use std::result;
+use toml;
+use super::encoding::{Encoding, Encodings};
+use super::error::Result;
+use super::event_stream::RateLimit;
+use super::join::JoinPolicy;
+use super::modes::UserModes;
+
+const DEFAULT_PORT: u16 = 6667;
+const DEFAULT_TLS_PORT: u16 = 6697;
+
+/// A problem with a configuration file, and the key it's about.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConfigError {
+    /// Where the problem is, e.g. `"servers[1].port"`, or a line and
+    /// column for syntax errors.
+    pub key: String,
+    pub message: String,
+}
+
+impl ConfigError {
+
+    fn new(key: &str, message: &str) -> ConfigError {
+        ConfigError{
+            key: key.to_string(),
+            message: message.to_string(),
+        }
+    }
+
+}
+
+impl fmt::Display for ConfigError {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.key.is_empty() {
+            write!(f, "{}", self.message)
+        } else {
+            write!(f, "{}: {}", self.key, self.message)
+        }
+    }
+
+}
+
+/// An IRC server to connect to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ServerConfig {
+    pub host: String,
+    pub port: u16,
+    pub tls: bool,
+    /// Whether to check the server's certificate.  Only turned off for
+    /// servers with self-signed certificates.
+    pub tls_verify: bool,
+}
+
+/// A channel to join, and its key if it has one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChannelConfig {
+    pub name: String,
+    pub key: Option<String>,
+}
+
+/// Credentials for SASL PLAIN authentication.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SaslConfig {
+    pub account: String,
+    pub password: String,
+}
+
+/// Everything needed to connect a `Client`, plus settings for
+/// whatever the client runs.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Servers to try, in order.
+    pub servers: Vec<ServerConfig>,
+    /// Nicks to try, in order.
+    pub nicks: Vec<String>,
+    pub username: String,
+    pub realname: String,
+    pub user_modes: UserModes,
+    /// IRCv3 capabilities to request.
+    pub capabilities: Vec<String>,
+    pub sasl: Option<SaslConfig>,
+    pub channels: Vec<ChannelConfig>,
+    pub join_policy: JoinPolicy,
+    pub rate_limit: Option<RateLimit>,
+    pub encodings: Encodings,
+    /// Where the bot keeps its data, from `[storage] url`.  See
+    /// `storage::open`.
+    pub storage_url: Option<String>,
+    /// How many connections to the database to keep open.
+    pub storage_pool_size: Option<u32>,
+    /// The `[plugins.<name>]` tables, by plugin name.
+    pub plugins: BTreeMap<String, toml::Table>,
+}
+
+impl Config {
+
+    /// Reads and validates a configuration file.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Config> {
+        let mut text = String::new();
+        try!(try!(File::open(path)).read_to_string(&mut text));
+        Ok(try!(Config::parse(&text)))
+    }
+
+    /// Parses and validates configuration text.
+    pub fn parse(text: &str) -> result::Result<Config, ConfigError> {
+        let mut parser = toml::Parser::new(text);
+        let root = match parser.parse() {
+            Some(root) => root,
+            None => {
+                let (key, message) = match parser.errors.first() {
+                    Some(e) => {
+                        let (line, col) = parser.to_linecol(e.lo);
+                        (format!("line {}, column {}", line + 1, col + 1), e.desc.clone())
+                    },
+                    None => (String::new(), "couldn't parse TOML".to_string()),
+                };
+                return Err(ConfigError::new(&key, &message));
+            },
+        };
+        let root = Section{ table: &root, path: String::new() };
+        try!(root.allow_keys(&["servers", "nicks", "username", "realname", "user_modes",
+                               "capabilities", "sasl", "channels", "join", "rate_limit", "encoding",
+                               "storage", "plugins"]));
+
+        let servers = try!(root.sections("servers")).into_iter().map(|server| {
+            try!(server.allow_keys(&["host", "port", "tls", "tls_verify"]));
+            let tls = try!(server.bool("tls")).unwrap_or(false);
+            let port = match try!(server.integer("port")) {
+                Some(port) if port > 0 && port <= 65535 => port as u16,
+                Some(_) => return Err(server.error("port", "must be between 1 and 65535")),
+                None => if tls { DEFAULT_TLS_PORT } else { DEFAULT_PORT },
+            };
+            Ok(ServerConfig{
+                host: try!(server.required_string("host")),
+                port: port,
+                tls: tls,
+                tls_verify: try!(server.bool("tls_verify")).unwrap_or(true),
+            })
+        }).collect::<result::Result<Vec<_>, _>>();
+        let servers = try!(servers);
+        if servers.is_empty() {
+            return Err(root.error("servers", "at least one server is required"));
+        }
+
+        let nicks = try!(root.strings("nicks"));
+        if nicks.is_empty() {
+            return Err(root.error("nicks", "at least one nick is required"));
+        }
+        let username = try!(root.string("username")).unwrap_or(nicks[0].clone());
+        let realname = try!(root.string("realname")).unwrap_or(username.clone());
+
+        let user_modes = match try!(root.string("user_modes")) {
+            Some(chars) => try!(parse_user_modes(&chars).map_err(|m| root.error("user_modes", &m))),
+            None => UserModes::new().invisible(true),
+        };
+
+        let sasl = match try!(root.section("sasl")) {
+            Some(sasl) => {
+                try!(sasl.allow_keys(&["account", "password"]));
+                Some(SaslConfig{
+                    account: try!(sasl.required_string("account")),
+                    password: try!(sasl.required_string("password")),
+                })
+            },
+            None => None,
+        };
+
+        let channels = try!(root.channels("channels"));
+
+        let mut join_policy = JoinPolicy::new();
+        if let Some(join) = try!(root.section("join")) {
+            try!(join.allow_keys(&["retries", "retry_delay_ms", "wait_for_invite", "rejoin_delay_ms"]));
+            join_policy.retries = match try!(join.integer("retries")) {
+                Some(n) if n >= 0 && n <= ::std::u32::MAX as i64 => n as u32,
+                Some(_) => return Err(join.error("retries", "must not be negative")),
+                None => join_policy.retries,
+            };
+            join_policy.retry_delay_ms = try!(join.positive("retry_delay_ms")).unwrap_or(join_policy.retry_delay_ms);
+            join_policy.wait_for_invite = try!(join.bool("wait_for_invite")).unwrap_or(false);
+            join_policy.rejoin_delay_ms = try!(join.positive("rejoin_delay_ms"));
+        }
+
+        let rate_limit = match try!(root.section("rate_limit")) {
+            Some(limit) => {
+                try!(limit.allow_keys(&["burst", "interval_ms"]));
+                Some(RateLimit{
+                    burst: try!(limit.positive("burst")).unwrap_or(5),
+                    interval_ms: try!(limit.positive("interval_ms")).unwrap_or(2000),
+                })
+            },
+            None => None,
+        };
+
+        let encodings = match try!(root.section("encoding")) {
+            Some(encoding) => {
+                try!(encoding.allow_keys(&["inbound", "outbound", "channels"]));
+                let mut encodings = Encodings::with(
+                    try!(encoding.encoding("inbound")).unwrap_or(Encoding::Utf8),
+                    try!(encoding.encoding("outbound")).unwrap_or(Encoding::Utf8));
+                if let Some(channels) = try!(encoding.section("channels")) {
+                    for chan in channels.table.keys() {
+                        let enc = try!(channels.encoding(chan)).expect("Key went missing");
+                        encodings.set_channel(chan, enc);
+                    }
+                }
+                encodings
+            },
+            None => Encodings::new(),
+        };
+
+        let (storage_url, storage_pool_size) = match try!(root.section("storage")) {
+            Some(storage) => {
+                try!(storage.allow_keys(&["url", "pool_size"]));
+                (try!(storage.string("url")), try!(storage.positive("pool_size")))
+            },
+            None => (None, None),
+        };
+
+        let mut plugins = BTreeMap::new();
+        if let Some(section) = try!(root.section("plugins")) {
+            for name in section.table.keys() {
+                let plugin = try!(section.section(name)).expect("Key went missing");
+                plugins.insert(name.clone(), plugin.table.clone());
+            }
+        }
+
+        Ok(Config{
+            servers: servers,
+            nicks: nicks,
+            username: username,
+            realname: realname,
+            user_modes: user_modes,
+            capabilities: try!(root.strings("capabilities")),
+            sasl: sasl,
+            channels: channels,
+            join_policy: join_policy,
+            rate_limit: rate_limit,
+            encodings: encodings,
+            storage_url: storage_url,
+            storage_pool_size: storage_pool_size,
+            plugins: plugins,
+        })
+    }
+
+}
+
+/// Turns user mode characters like `"iB"` into `UserModes`.
+fn parse_user_modes(chars: &str) -> result::Result<UserModes, String> {
+    let mut modes = UserModes::new();
+    for c in chars.trim_left_matches('+').chars() {
+        modes = match c {
+            'i' => modes.invisible(true),
+            'w' => modes.wallops(true),
+            'x' => modes.cloak(true),
+            'B' => modes.bot(true),
+            'R' => modes.registered_only(true),
+            _ => return Err(format!("unsupported user mode {}", c)),
+        };
+    }
+    Ok(modes)
+}
+
+/// A TOML table and the path leading to it, for error messages.
+/// Plugins can use this to validate their own settings.
+pub struct Section<'a> {
+    pub table: &'a toml::Table,
+    pub path: String,
+}
+
+impl<'a> Section<'a> {
+
+    /// The full path of `key` in this section.
+    pub fn key_path(&self, key: &str) -> String {
+        if self.path.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}.{}", self.path, key)
+        }
+    }
+
+    /// An error about `key` in this section.
+    pub fn error(&self, key: &str, message: &str) -> ConfigError {
+        ConfigError::new(&self.key_path(key), message)
+    }
+
+    /// Fails on any key not in `allowed`, to catch typos.
+    pub fn allow_keys(&self, allowed: &[&str]) -> result::Result<(), ConfigError> {
+        match self.table.keys().find(|k| !allowed.contains(&&k[..])) {
+            Some(k) => Err(self.error(k, "unknown key")),
+            None => Ok(()),
+        }
+    }
+
+    pub fn string(&self, key: &str) -> result::Result<Option<String>, ConfigError> {
+        match self.table.get(key) {
+            Some(&toml::Value::String(ref s)) => Ok(Some(s.clone())),
+            Some(_) => Err(self.error(key, "expected a string")),
+            None => Ok(None),
+        }
+    }
+
+    pub fn required_string(&self, key: &str) -> result::Result<String, ConfigError> {
+        try!(self.string(key)).ok_or(self.error(key, "is required"))
+    }
+
+    pub fn integer(&self, key: &str) -> result::Result<Option<i64>, ConfigError> {
+        match self.table.get(key) {
+            Some(&toml::Value::Integer(i)) => Ok(Some(i)),
+            Some(_) => Err(self.error(key, "expected an integer")),
+            None => Ok(None),
+        }
+    }
+
+    /// An integer that must be at least 1 and fit in a `u32`.
+    pub fn positive(&self, key: &str) -> result::Result<Option<u32>, ConfigError> {
+        match try!(self.integer(key)) {
+            Some(i) if i >= 1 && i <= ::std::u32::MAX as i64 => Ok(Some(i as u32)),
+            Some(_) => Err(self.error(key, "must be a positive integer")),
+            None => Ok(None),
+        }
+    }
+
+    pub fn bool(&self, key: &str) -> result::Result<Option<bool>, ConfigError> {
+        match self.table.get(key) {
+            Some(&toml::Value::Boolean(b)) => Ok(Some(b)),
+            Some(_) => Err(self.error(key, "expected true or false")),
+            None => Ok(None),
+        }
+    }
+
+    /// An array of strings, empty if missing.
+    pub fn strings(&self, key: &str) -> result::Result<Vec<String>, ConfigError> {
+        match self.table.get(key) {
+            Some(&toml::Value::Array(ref values)) => {
+                values.iter().enumerate().map(|(i, value)| {
+                    match *value {
+                        toml::Value::String(ref s) => Ok(s.clone()),
+                        _ => Err(ConfigError::new(&format!("{}[{}]", self.key_path(key), i), "expected a string")),
+                    }
+                }).collect()
+            },
+            Some(_) => Err(self.error(key, "expected an array of strings")),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    pub fn encoding(&self, key: &str) -> result::Result<Option<Encoding>, ConfigError> {
+        match try!(self.string(key)) {
+            Some(name) => Encoding::from_name(&name).map(Some)
+                .ok_or(self.error(key, "unknown encoding, expected utf-8, utf-8+latin-1, latin-1 or cp1252")),
+            None => Ok(None),
+        }
+    }
+
+    pub fn section(&self, key: &str) -> result::Result<Option<Section>, ConfigError> {
+        match self.table.get(key) {
+            Some(&toml::Value::Table(ref table)) => Ok(Some(Section{
+                table: table,
+                path: self.key_path(key),
+            })),
+            Some(_) => Err(self.error(key, "expected a table")),
+            None => Ok(None),
+        }
+    }
+
+    /// An array of tables, empty if missing.
+    pub fn sections(&self, key: &str) -> result::Result<Vec<Section>, ConfigError> {
+        match self.table.get(key) {
+            Some(&toml::Value::Array(ref values)) => {
+                values.iter().enumerate().map(|(i, value)| {
+                    let path = format!("{}[{}]", self.key_path(key), i);
+                    match *value {
+                        toml::Value::Table(ref table) => Ok(Section{ table: table, path: path }),
+                        _ => Err(ConfigError::new(&path, "expected a table")),
+                    }
+                }).collect()
+            },
+            Some(_) => Err(self.error(key, "expected an array of tables")),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Channels, as either an array of names or an array of tables
+    /// with `name` and `key`.
+    pub fn channels(&self, key: &str) -> result::Result<Vec<ChannelConfig>, ConfigError> {
+        if let Ok(names) = self.strings(key) {
+            return Ok(names.into_iter().map(|name| ChannelConfig{ name: name, key: None }).collect());
+        }
+        try!(self.sections(key)).into_iter().map(|chan| {
+            try!(chan.allow_keys(&["name", "key"]));
+            Ok(ChannelConfig{
+                name: try!(chan.required_string("name")),
+                key: try!(chan.string("key")),
+            })
+        }).collect()
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{Config, Section};
+
+    const MINIMAL: &'static str = "nicks = [\"rustbot\"]\n[[servers]]\nhost = \"irc.example.net\"\n";
+
+    /// The key of the error from parsing `MINIMAL` plus `extra`.
+    fn error_key(extra: &str) -> String {
+        match Config::parse(&format!("{}{}", MINIMAL, extra)) {
+            Ok(_) => panic!("Expected an error for {:?}", extra),
+            Err(e) => e.key,
+        }
+    }
+
+    #[test]
+    fn minimal() {
+        let config = Config::parse(MINIMAL).unwrap();
+        assert_eq!(config.servers[0].port, 6667);
+        assert!(!config.servers[0].tls);
+        assert!(config.servers[0].tls_verify);
+        assert_eq!(config.username, "rustbot");
+    }
+
+    #[test]
+    fn unknown_keys() {
+        assert_eq!(error_key("hots = \"irc.example.net\"\n"), "servers[0].hots");
+        assert_eq!(error_key("[join]\nretry = 3\n"), "join.retry");
+        assert_eq!(error_key("[storage]\nurl = \"memory:\"\npool = 4\n"), "storage.pool");
+    }
+
+    #[test]
+    fn wrong_types() {
+        assert_eq!(error_key("port = \"6697\"\n"), "servers[0].port");
+        assert_eq!(error_key("tls = \"yes\"\n"), "servers[0].tls");
+        assert_eq!(error_key("[rate_limit]\nburst = 0\n"), "rate_limit.burst");
+        assert_eq!(error_key("[encoding]\ninbound = 8\n"), "encoding.inbound");
+    }
+
+    #[test]
+    fn bad_channel_tables() {
+        assert_eq!(error_key("[[channels]]\nnmae = \"#rust\"\n"), "channels[0].nmae");
+        assert_eq!(error_key("[[channels]]\nname = \"#rust\"\n[[channels]]\nkey = \"swordfish\"\n"),
+                   "channels[1].name");
+        assert_eq!(error_key("[[channels]]\nname = 5\n"), "channels[0].name");
+    }
+
+    #[test]
+    fn sasl_needs_a_password() {
+        assert_eq!(error_key("[sasl]\naccount = \"rustbot\"\n"), "sasl.password");
+    }
+
+    #[test]
+    fn plugin_settings_paths() {
+        let config = Config::parse(&format!("{}[plugins.karma.limits]\nper_hour = -1\n", MINIMAL)).unwrap();
+        let table = config.plugins.get("karma").unwrap();
+        let section = Section{ table: table, path: "plugins.karma".to_string() };
+        let limits = section.section("limits").unwrap().unwrap();
+        assert_eq!(limits.positive("per_hour").unwrap_err().key, "plugins.karma.limits.per_hour");
+        assert_eq!(section.allow_keys(&["enabled"]).unwrap_err().key, "plugins.karma.limits");
+    }
+
+}
@@ -0,0 +1,242 @@
+//! Character encodings for text on the wire.
+//!
+//! IRC is a byte protocol, and plenty of channels still speak latin-1
+//! or cp1252 rather than UTF-8.  `Encodings` decides how each line
+//! read from the server is decoded and how each line we send is
+//! encoded, optionally per channel.
+
+use std::ascii::AsciiExt;
+use std::collections::HashMap;
+use std::char;
+
+/// A character encoding.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    /// Strict UTF-8.  Invalid bytes are reported and replaced with
+    /// U+FFFD.
+    Utf8,
+    /// UTF-8, but lines which aren't valid UTF-8 are decoded as
+    /// latin-1 instead.  Encodes as UTF-8.
+    Utf8Latin1Fallback,
+    /// ISO-8859-1.
+    Latin1,
+    /// Windows-1252, a superset of the printable parts of latin-1.
+    Cp1252,
+}
+
+/// Windows-1252 code points for bytes 0x80-0x9f.  The five bytes
+/// cp1252 leaves undefined map to the C1 control of the same value.
+static CP1252_HIGH: [u32; 32] = [
+    0x20ac, 0x0081, 0x201a, 0x0192, 0x201e, 0x2026, 0x2020, 0x2021,
+    0x02c6, 0x2030, 0x0160, 0x2039, 0x0152, 0x008d, 0x017d, 0x008f,
+    0x0090, 0x2018, 0x2019, 0x201c, 0x201d, 0x2022, 0x2013, 0x2014,
+    0x02dc, 0x2122, 0x0161, 0x203a, 0x0153, 0x009d, 0x017e, 0x0178,
+    ];
+
+impl Encoding {
+
+    /// Parses an encoding name as it would appear in a config file.
+    pub fn from_name(name: &str) -> Option<Encoding> {
+        match &name.to_ascii_lowercase()[..] {
+            "utf-8" | "utf8" => Some(Encoding::Utf8),
+            "utf-8+latin-1" | "utf8+latin1" => Some(Encoding::Utf8Latin1Fallback),
+            "latin-1" | "latin1" | "iso-8859-1" => Some(Encoding::Latin1),
+            "cp1252" | "windows-1252" => Some(Encoding::Cp1252),
+            _ => None,
+        }
+    }
+
+    /// Decodes `bytes`.  If they aren't valid in this encoding,
+    /// returns `Err` containing a lossy decoding instead.
+    pub fn decode(&self, bytes: &[u8]) -> Result<String, String> {
+        match *self {
+            Encoding::Utf8 => {
+                String::from_utf8(bytes.to_vec())
+                    .map_err(|_| String::from_utf8_lossy(bytes).into_owned())
+            },
+            Encoding::Utf8Latin1Fallback => {
+                String::from_utf8(bytes.to_vec())
+                    .or_else(|_| Encoding::Latin1.decode(bytes))
+            },
+            Encoding::Latin1 => {
+                Ok(bytes.iter().map(|&b| b as char).collect())
+            },
+            Encoding::Cp1252 => {
+                Ok(bytes.iter().map(|&b| {
+                    if b >= 0x80 && b < 0xa0 {
+                        char::from_u32(CP1252_HIGH[(b - 0x80) as usize]).unwrap_or('\u{fffd}')
+                    } else {
+                        b as char
+                    }
+                }).collect())
+            },
+        }
+    }
+
+    /// Encodes `s`.  Characters this encoding can't represent become
+    /// `?`.
+    pub fn encode(&self, s: &str) -> Vec<u8> {
+        match *self {
+            Encoding::Utf8 | Encoding::Utf8Latin1Fallback => s.as_bytes().to_vec(),
+            Encoding::Latin1 => {
+                s.chars().map(|c| if (c as u32) < 0x100 { c as u8 } else { b'?' }).collect()
+            },
+            Encoding::Cp1252 => {
+                s.chars().map(|c| {
+                    let cp = c as u32;
+                    if cp < 0x80 || (cp >= 0xa0 && cp < 0x100) {
+                        cp as u8
+                    } else {
+                        CP1252_HIGH.iter().position(|&high| high == cp)
+                            .map(|i| 0x80 + i as u8)
+                            .unwrap_or(b'?')
+                    }
+                }).collect()
+            },
+        }
+    }
+
+}
+
+/// Which encodings to use for which lines.
+#[derive(Clone, Debug)]
+pub struct Encodings {
+    /// Used to decode lines that aren't for a channel with its own
+    /// encoding.
+    pub inbound: Encoding,
+    /// Used to encode lines that aren't for a channel with its own
+    /// encoding.
+    pub outbound: Encoding,
+    channels: HashMap<String, Encoding>,
+}
+
+impl Encodings {
+
+    /// Strict UTF-8 in both directions.
+    pub fn new() -> Encodings {
+        Encodings::with(Encoding::Utf8, Encoding::Utf8)
+    }
+
+    /// Uses `inbound` and `outbound` for everything, until told
+    /// otherwise with `set_channel`.
+    pub fn with(inbound: Encoding, outbound: Encoding) -> Encodings {
+        Encodings{
+            inbound: inbound,
+            outbound: outbound,
+            channels: HashMap::new(),
+        }
+    }
+
+    /// Uses `encoding` in both directions for lines to and from `chan`.
+    pub fn set_channel(&mut self, chan: &str, encoding: Encoding) {
+        self.channels.insert(chan.to_ascii_lowercase(), encoding);
+    }
+
+    /// Goes back to the default encodings for `chan`.
+    pub fn clear_channel(&mut self, chan: &str) {
+        self.channels.remove(&chan.to_ascii_lowercase());
+    }
+
+    /// The encoding set for `chan`, if any.
+    pub fn channel(&self, chan: &str) -> Option<Encoding> {
+        self.channels.get(&chan.to_ascii_lowercase()).cloned()
+    }
+
+    /// Decodes a raw line read from the server, without its line
+    /// ending.  If the line isn't valid in the chosen encoding,
+    /// returns `Err` containing a lossy decoding.
+    pub fn decode_line(&self, raw: &[u8]) -> Result<String, String> {
+        self.for_line(raw).unwrap_or(self.inbound).decode(raw)
+    }
+
+    /// Encodes a line we're about to send.
+    pub fn encode_line(&self, line: &str) -> Vec<u8> {
+        self.for_line(line.as_bytes()).unwrap_or(self.outbound).encode(line)
+    }
+
+    fn for_line(&self, raw: &[u8]) -> Option<Encoding> {
+        if self.channels.is_empty() {
+            return None;
+        }
+        target_channel(raw).and_then(|chan| {
+            self.channel(&String::from_utf8_lossy(chan))
+        })
+    }
+
+}
+
+/// Finds the channel a raw line is addressed to, if any, without
+/// decoding it: the first parameter after the command, when it looks
+/// like a channel name.
+fn target_channel(raw: &[u8]) -> Option<&[u8]> {
+    let mut words = raw.split(|&b| b == b' ').filter(|w| !w.is_empty()).peekable();
+    // Skip message tags and the prefix.
+    if words.peek().map_or(false, |w| w[0] == b'@') {
+        words.next();
+    }
+    if words.peek().map_or(false, |w| w[0] == b':') {
+        words.next();
+    }
+    // Skip the command.
+    words.next();
+    words.next().map(|param| {
+        if param[0] == b':' { &param[1..] } else { param }
+    }).and_then(|param| {
+        match param.first() {
+            Some(&b'#') | Some(&b'&') | Some(&b'+') | Some(&b'!') => {
+                // JOIN can name several channels; use the first one.
+                Some(param.split(|&b| b == b',').next().unwrap_or(param))
+            },
+            _ => None,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{target_channel, Encoding, Encodings};
+
+    #[test]
+    fn utf8_with_latin1_fallback() {
+        let fallback = Encoding::Utf8Latin1Fallback;
+        assert_eq!(fallback.decode(b"caf\xc3\xa9"), Ok("caf\u{e9}".to_string()));
+        assert_eq!(fallback.decode(b"caf\xe9"), Ok("caf\u{e9}".to_string()));
+        assert_eq!(fallback.encode("caf\u{e9}"), b"caf\xc3\xa9".to_vec());
+        assert_eq!(Encoding::Utf8.decode(b"caf\xe9"), Err("caf\u{fffd}".to_string()));
+    }
+
+    #[test]
+    fn cp1252() {
+        let cp1252 = Encoding::Cp1252;
+        assert_eq!(cp1252.decode(b"\x80 \x93hi\x94 \x81 \xe9"), Ok("\u{20ac} \u{201c}hi\u{201d} \u{81} \u{e9}".to_string()));
+        assert_eq!(cp1252.encode("\u{20ac} \u{201c}hi\u{201d} \u{e9} \u{2603}"), b"\x80 \x93hi\x94 \xe9 ?".to_vec());
+        assert_eq!(Encoding::Latin1.decode(b"\x93"), Ok("\u{93}".to_string()));
+        assert_eq!(Encoding::Latin1.encode("\u{e9}\u{20ac}"), b"\xe9?".to_vec());
+    }
+
+    #[test]
+    fn target_channels() {
+        assert_eq!(target_channel(b"PRIVMSG #Old :hi"), Some(&b"#Old"[..]));
+        assert_eq!(target_channel(b"@time=2015-06-01T12:00:00.000Z :a!b@c PRIVMSG #old :hi"), Some(&b"#old"[..]));
+        assert_eq!(target_channel(b":a!b@c JOIN :#old,#new"), Some(&b"#old"[..]));
+        assert_eq!(target_channel(b":a!b@c PRIVMSG bot :hi"), None);
+        assert_eq!(target_channel(b":irc.example.net 001 bot :Welcome"), None);
+        assert_eq!(target_channel(b"QUIT"), None);
+        assert_eq!(target_channel(b""), None);
+    }
+
+    #[test]
+    fn per_channel() {
+        let mut encodings = Encodings::with(Encoding::Utf8Latin1Fallback, Encoding::Utf8);
+        encodings.set_channel("#Old", Encoding::Cp1252);
+        assert_eq!(encodings.decode_line(b":a!b@c PRIVMSG #old :\x93hi\x94"), Ok(":a!b@c PRIVMSG #old :\u{201c}hi\u{201d}".to_string()));
+        assert_eq!(encodings.decode_line(b":a!b@c PRIVMSG #new :\x93hi\x94"), Ok(":a!b@c PRIVMSG #new :\u{93}hi\u{94}".to_string()));
+        assert_eq!(encodings.encode_line("PRIVMSG #OLD :\u{201c}hi\u{201d}"), b"PRIVMSG #OLD :\x93hi\x94".to_vec());
+        assert_eq!(encodings.encode_line("PRIVMSG #new :\u{e9}"), b"PRIVMSG #new :\xc3\xa9".to_vec());
+        encodings.clear_channel("#OLD");
+        assert_eq!(encodings.channel("#old"), None);
+        assert_eq!(encodings.encode_line("PRIVMSG #old :\u{e9}"), b"PRIVMSG #old :\xc3\xa9".to_vec());
+    }
+
+}
@@ -0,0 +1,143 @@
+use postgres;
+use rusqlite::SqliteError;
+use std::error;
+use std::fmt;
+use std::io;
+use std::result;
+use std::sync::mpsc;
+use super::config::ConfigError;
+
+/// Everything that can go wrong while talking to an IRC server.
+#[derive(Debug)]
+pub enum Error {
+    /// The underlying socket failed.
+    Io(io::Error),
+    /// The server sent something we couldn't make sense of.
+    Protocol(String),
+    /// The server refused to register us (nick in use, banned, etc.).
+    /// Contains the server's reply.
+    Registration(String),
+    /// We gave up waiting for something.  Contains what we were
+    /// waiting for.
+    Timeout(String),
+    /// The event loop or one of the I/O threads has gone away.
+    Disconnected,
+    /// Setting up TLS failed.
+    Tls(String),
+    /// The configuration is invalid.
+    Config(ConfigError),
+    /// A task's schedule is invalid.
+    Schedule(String),
+    /// A plugin couldn't be loaded.
+    Plugin(String),
+    /// The database failed.
+    Storage(String),
+    /// An HTTP request failed or was refused.
+    Http(String),
+}
+
+/// Shorthand for results returned by this crate.
+pub type Result<T> = result::Result<T, Error>;
+
+impl fmt::Display for Error {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref e) => write!(f, "I/O error: {}", e),
+            Error::Protocol(ref s) => write!(f, "protocol error: {}", s),
+            Error::Registration(ref s) => write!(f, "registration failed: {}", s),
+            Error::Timeout(ref s) => write!(f, "timed out waiting for {}", s),
+            Error::Disconnected => write!(f, "disconnected"),
+            Error::Tls(ref s) => write!(f, "TLS error: {}", s),
+            Error::Config(ref e) => write!(f, "invalid configuration: {}", e),
+            Error::Schedule(ref s) => write!(f, "invalid schedule: {}", s),
+            Error::Plugin(ref s) => write!(f, "plugin error: {}", s),
+            Error::Storage(ref s) => write!(f, "storage error: {}", s),
+            Error::Http(ref s) => write!(f, "HTTP error: {}", s),
+        }
+    }
+
+}
+
+impl error::Error for Error {
+
+    fn description(&self) -> &str {
+        match *self {
+            Error::Io(ref e) => e.description(),
+            Error::Protocol(_) => "protocol error",
+            Error::Registration(_) => "registration failed",
+            Error::Timeout(_) => "timed out",
+            Error::Disconnected => "disconnected",
+            Error::Tls(_) => "TLS error",
+            Error::Config(_) => "invalid configuration",
+            Error::Schedule(_) => "invalid schedule",
+            Error::Plugin(_) => "plugin error",
+            Error::Storage(_) => "storage error",
+            Error::Http(_) => "HTTP error",
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            Error::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+
+}
+
+impl From<io::Error> for Error {
+
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+
+}
+
+impl From<ConfigError> for Error {
+
+    fn from(e: ConfigError) -> Error {
+        Error::Config(e)
+    }
+
+}
+
+impl<T> From<mpsc::SendError<T>> for Error {
+
+    fn from(_: mpsc::SendError<T>) -> Error {
+        Error::Disconnected
+    }
+
+}
+
+impl From<mpsc::RecvError> for Error {
+
+    fn from(_: mpsc::RecvError) -> Error {
+        Error::Disconnected
+    }
+
+}
+
+impl From<postgres::Error> for Error {
+
+    fn from(e: postgres::Error) -> Error {
+        Error::Storage(e.to_string())
+    }
+
+}
+
+impl From<postgres::ConnectError> for Error {
+
+    fn from(e: postgres::ConnectError) -> Error {
+        Error::Storage(e.to_string())
+    }
+
+}
+
+impl From<SqliteError> for Error {
+
+    fn from(e: SqliteError) -> Error {
+        Error::Storage(e.message)
+    }
+
+}
@@ -1,14 +1,27 @@
 use regex::Regex;
+use std::collections::VecDeque;
 use std::io;
 use std::io::prelude::*;
 use std::str::from_utf8;
-use std::sync::{Arc, Mutex, Future};
-use std::sync::mpsc;
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::{channel, Sender, Receiver};
 use std::thread;
 use super::channels;
+use super::channels::Outgoing;
+use super::command::Command;
+use super::encoding::{Encoding, Encodings};
+use super::error::{Error, Result};
+use super::pool::Pool;
+use super::protocol::Message;
+use super::scheduler;
+use super::scheduler::{Schedule, Scheduler, Task, TaskHandle, TaskId, TaskInfo};
 
 /// What should we do next?
+///
+/// Whatever the action, the handler's response is sent and its
+/// `HandlerAction` applied first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Action {
     /// Don't run any more handlers on this line.
     Skip,
@@ -18,20 +31,61 @@ pub enum Action {
     Stop,
 }
 
+/// What to do with the handler that just ran.
+///
+/// Changes take effect from the next line: only the handlers that were
+/// installed when a line arrived see it, so a handler added or swapped
+/// in never sees the line that caused it to be added.
 pub enum HandlerAction {
+    /// Install another handler, with the default priority, after any
+    /// others with that priority.
     Add(Handler),
+    /// Replace this handler with another, keeping its id, name,
+    /// priority and place.
     Swap(Handler),
     Keep,
+    /// Uninstall this handler.  The rest keep their order.
     Remove,
 }
 
-/// Optionally respond with a message, then take the given next `Action`.
-pub struct Response(pub Option<String>, pub HandlerAction, pub Action);
+/// Limits how fast lines are sent, so the server doesn't disconnect
+/// us for flooding.  Up to `burst` lines go out at once, after which
+/// we send one line every `interval_ms` milliseconds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimit {
+    pub burst: u32,
+    pub interval_ms: u32,
+}
+
+/// How an `EventStream` talks to the wire.
+#[derive(Clone, Debug)]
+pub struct StreamOptions {
+    pub encodings: Encodings,
+    pub rate_limit: Option<RateLimit>,
+    /// How many threads run blocking handlers, at least one.
+    pub workers: usize,
+}
+
+impl StreamOptions {
+
+    /// Strict UTF-8, no rate limit, four workers.
+    pub fn new() -> StreamOptions {
+        StreamOptions{
+            encodings: Encodings::new(),
+            rate_limit: None,
+            workers: 4,
+        }
+    }
+
+}
+
+/// Optionally respond with a command, then take the given next `Action`.
+pub struct Response(pub Option<Command>, pub HandlerAction, pub Action);
 
 impl Response {
 
-    pub fn respond(s: String) -> Response {
-        Response(Some(s), HandlerAction::Keep, Action::Skip)
+    pub fn respond(command: Command) -> Response {
+        Response(Some(command), HandlerAction::Keep, Action::Skip)
     }
 
     pub fn nothing() -> Response {
@@ -45,15 +99,508 @@ impl Response {
 /// to take after processing the line.
 pub type Handler = Box<FnMut(&str) -> Response + Send>;
 
+/// The priority handlers get unless told otherwise.
+pub const DEFAULT_PRIORITY: i32 = 0;
+/// The priority of handlers waiting for replies on behalf of
+/// `EventStream::watch` and friends, so other handlers skipping a line
+/// can't hide it from them.
+pub const WAITER_PRIORITY: i32 = 50;
+/// The priority of the client's own bookkeeping, which needs to see
+/// every line first.
+pub const INTERNAL_PRIORITY: i32 = 100;
+
+/// Identifies an installed handler, so it can be removed or replaced
+/// later.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct HandlerId(u64);
+
+/// Sees each line we send, without its line ending, once it's been
+/// written.  It runs on the writer thread, so it should be quick.  A
+/// hook that panics is logged and carries on with the next line.
+pub type SentHook = Box<Fn(&str) + Send + Sync>;
+
+/// Identifies a `SentHook`, so it can be removed later.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SentHookId(u64);
+
+struct SentHookList {
+    /// Shared so they can run outside the lock, and be moved into
+    /// `thread::catch_panic`.
+    hooks: Vec<(SentHookId, Arc<SentHook>)>,
+    next_id: u64,
+}
+
+/// The hooks that see what we send.  Clones share the same hooks.
+#[derive(Clone)]
+pub struct SentHooks {
+    list: Arc<RwLock<SentHookList>>,
+}
+
+impl SentHooks {
+
+    pub fn new() -> SentHooks {
+        SentHooks{
+            list: Arc::new(RwLock::new(SentHookList{
+                hooks: Vec::new(),
+                next_id: 0,
+            })),
+        }
+    }
+
+    pub fn add(&self, hook: SentHook) -> SentHookId {
+        let mut list = match self.list.write() {
+            Ok(list) => list,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let id = SentHookId(list.next_id);
+        list.next_id += 1;
+        list.hooks.push((id, Arc::new(hook)));
+        id
+    }
+
+    /// Returns whether the hook was there to remove.
+    pub fn remove(&self, id: SentHookId) -> bool {
+        let mut list = match self.list.write() {
+            Ok(list) => list,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        match list.hooks.iter().position(|&(hook_id, _)| hook_id == id) {
+            Some(pos) => {
+                list.hooks.remove(pos);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Shows `line` to every hook, in the order they were added,
+    /// catching any panics.
+    pub fn run(&self, line: &str) {
+        let hooks: Vec<Arc<SentHook>> = match self.list.read() {
+            Ok(list) => list.hooks.iter().map(|&(_, ref hook)| hook.clone()).collect(),
+            Err(poisoned) => poisoned.into_inner().hooks.iter().map(|&(_, ref hook)| hook.clone()).collect(),
+        };
+        for hook in hooks.into_iter() {
+            let sent = line.to_string();
+            if thread::catch_panic(move || (**hook)(&sent)).is_err() {
+                error!("Sent hook panicked on \"{}\".", line);
+            }
+        }
+    }
+
+}
+
+/// How to install a handler.
+///
+/// # Example:
+/// ```{.ignore .rust}
+/// let id = stream.add_handler_with(HandlerOptions::new("karma").priority(10), handler);
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HandlerOptions {
+    pub name: String,
+    /// Handlers with higher priorities see each line first.  Handlers
+    /// with the same priority run in the order they were added.
+    pub priority: i32,
+    /// Stop running the handler after it has panicked this many times.
+    /// If `None`, it keeps running however often it panics.
+    pub max_failures: Option<u32>,
+    /// Run the handler on a worker thread, so it can take its time
+    /// without holding up other handlers.  See
+    /// `HandlerOptions::blocking`.
+    pub blocking: bool,
+}
+
+impl HandlerOptions {
+
+    pub fn new(name: &str) -> HandlerOptions {
+        HandlerOptions{
+            name: name.to_string(),
+            priority: DEFAULT_PRIORITY,
+            max_failures: None,
+            blocking: false,
+        }
+    }
+
+    pub fn priority(mut self, priority: i32) -> HandlerOptions {
+        self.priority = priority;
+        self
+    }
+
+    pub fn max_failures(mut self, max_failures: u32) -> HandlerOptions {
+        self.max_failures = Some(max_failures);
+        self
+    }
+
+    /// Runs the handler on a worker thread, for handlers that talk to
+    /// databases or the network.
+    ///
+    /// A blocking handler sees lines in order, one at a time, but
+    /// after the other handlers have moved on, so its `Action::Skip`
+    /// has no effect on them.  Its response is sent as soon as it's
+    /// ready, and its `HandlerAction` and `Action::Stop` are applied
+    /// when the event loop gets to them.
+    pub fn blocking(mut self) -> HandlerOptions {
+        self.blocking = true;
+        self
+    }
+
+}
+
+/// An installed handler, as listed by `EventStream::handlers`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HandlerInfo {
+    pub id: HandlerId,
+    pub name: String,
+    pub priority: i32,
+    /// How many times it has panicked.
+    pub failures: u32,
+    /// Whether it panicked too often and no longer runs.
+    pub disabled: bool,
+    /// Whether it runs on a worker thread.
+    pub blocking: bool,
+}
+
+/// Lines waiting for a blocking handler, and whether a worker is
+/// already working through them.
+struct Backlog {
+    lines: VecDeque<String>,
+    running: bool,
+    /// The handler as it is now.  Workers take it from here for each
+    /// line, so lines queued before a swap go to the new handler.
+    handler: Arc<Mutex<Handler>>,
+}
+
+fn lock_backlog(backlog: &Arc<Mutex<Backlog>>) -> MutexGuard<Backlog> {
+    match backlog.lock() {
+        Ok(backlog) => backlog,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+struct Entry {
+    id: HandlerId,
+    name: String,
+    priority: i32,
+    max_failures: Option<u32>,
+    failures: u32,
+    disabled: bool,
+    /// Behind its own lock so a panic can be caught without taking the
+    /// rest of the handlers down with it, and so blocking handlers can
+    /// run on workers.
+    handler: Arc<Mutex<Handler>>,
+    /// For blocking handlers.
+    backlog: Option<Arc<Mutex<Backlog>>>,
+}
+
+/// Runs `handler` on `line`, catching any panic.
+fn run_handler(handler: &Arc<Mutex<Handler>>, line: &str) -> Option<Response> {
+    let handler = handler.clone();
+    let line = line.to_string();
+    thread::catch_panic(move || {
+        // A previous panic poisoned the lock, but the handler is
+        // still there.
+        let mut guard = match handler.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let h = &mut *guard;
+        h(&line)
+    }).ok()
+}
+
+impl Entry {
+
+    /// Replaces the handler, including for lines a worker hasn't got
+    /// to yet.
+    fn set_handler(&mut self, handler: Handler) {
+        self.handler = Arc::new(Mutex::new(handler));
+        if let Some(ref backlog) = self.backlog {
+            lock_backlog(backlog).handler = self.handler.clone();
+        }
+    }
+
+    /// Notes that the handler panicked on `line`, and disables it if
+    /// that's too many times.
+    fn failed(&mut self, line: &str) {
+        self.failures += 1;
+        error!("Handler \"{}\" panicked on \"{}\" ({} failures).", self.name, line, self.failures);
+        if self.max_failures.map_or(false, |max| self.failures >= max) {
+            error!("Disabling handler \"{}\".", self.name);
+            self.disabled = true;
+        }
+    }
+
+}
+
+/// The installed handlers, in the order they run.
+struct Handlers {
+    entries: Vec<Entry>,
+    next_id: u64,
+}
+
+impl Handlers {
+
+    fn new() -> Handlers {
+        Handlers{
+            entries: Vec::new(),
+            next_id: 0,
+        }
+    }
+
+    /// Inserts `handler` after every handler with the same or higher
+    /// priority.
+    fn insert(&mut self, options: HandlerOptions, handler: Handler) -> HandlerId {
+        let id = HandlerId(self.next_id);
+        self.next_id += 1;
+        let pos = self.entries.iter().position(|e| e.priority < options.priority).unwrap_or(self.entries.len());
+        let handler = Arc::new(Mutex::new(handler));
+        let backlog = if options.blocking {
+            Some(Arc::new(Mutex::new(Backlog{ lines: VecDeque::new(), running: false, handler: handler.clone() })))
+        } else {
+            None
+        };
+        self.entries.insert(pos, Entry{
+            id: id,
+            name: options.name,
+            priority: options.priority,
+            max_failures: options.max_failures,
+            failures: 0,
+            disabled: false,
+            handler: handler,
+            backlog: backlog,
+        });
+        id
+    }
+
+    fn position(&self, id: HandlerId) -> Option<usize> {
+        self.entries.iter().position(|e| e.id == id)
+    }
+
+    fn remove(&mut self, id: HandlerId) -> bool {
+        match self.position(id) {
+            Some(i) => {
+                self.entries.remove(i);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Replaces a handler, forgetting its failures.
+    fn replace(&mut self, id: HandlerId, handler: Handler) -> bool {
+        match self.position(id) {
+            Some(i) => {
+                let entry = &mut self.entries[i];
+                entry.set_handler(handler);
+                entry.failures = 0;
+                entry.disabled = false;
+                true
+            },
+            None => false,
+        }
+    }
+
+    fn info(&self) -> Vec<HandlerInfo> {
+        self.entries.iter().map(|e| HandlerInfo{
+            id: e.id,
+            name: e.name.clone(),
+            priority: e.priority,
+            failures: e.failures,
+            disabled: e.disabled,
+            blocking: e.backlog.is_some(),
+        }).collect()
+    }
+
+}
+
 /// `EventStream` wraps a stream and will loop, reading lines and
 /// processing them with `Handler`s, which are allowed to write back
 /// to the stream and install additional `Handler`s.
 ///
 /// It implements `Write` so you can still write manually, and you can
-/// install additional `Handler`s when you want.
+/// install additional `Handler`s when you want.  Clones share the
+/// same connection and handlers.
+#[derive(Clone)]
 pub struct EventStream {
-    writer: Sender<String>,
-    handlers: Arc<Mutex<Vec<Handler>>>,
+    writer: Sender<Outgoing>,
+    handlers: Arc<Mutex<Handlers>>,
+    encodings: channels::SharedEncodings,
+    scheduler: Scheduler,
+    sent_hooks: SentHooks,
+}
+
+/// A line the event loop has been asked to look out for.  Returned by
+/// `EventStream::await_lines` and `EventStream::await_any`.
+pub struct Awaited {
+    tx: Sender<Option<String>>,
+    rx: Receiver<Option<String>>,
+    cancelled: Arc<AtomicBool>,
+    description: String,
+}
+
+impl Awaited {
+
+    /// Blocks until a matching line arrives, or until `timeout_ms`
+    /// milliseconds have passed, in which case the handler watching
+    /// for the line is removed and `Error::Timeout` is returned.
+    pub fn wait(self, timeout_ms: u32) -> Result<String> {
+        let timer_tx = self.tx;
+        thread::spawn(move || {
+            thread::sleep_ms(timeout_ms);
+            // The receiver is gone if the line already arrived.
+            let _ = timer_tx.send(None);
+        });
+        match try!(self.rx.recv()) {
+            Some(line) => Ok(line),
+            None => {
+                self.cancelled.store(true, Ordering::SeqCst);
+                Err(Error::Timeout(self.description))
+            },
+        }
+    }
+
+}
+
+enum Watched {
+    Line(String),
+    /// The timer for the `next` call with this sequence number went
+    /// off.
+    Timeout(usize),
+}
+
+/// A stream of lines the event loop has been asked to look out for.
+/// Returned by `EventStream::watch`.  Lines are queued until read,
+/// and the watching handler is removed when this is dropped.
+pub struct Watch {
+    tx: Sender<Watched>,
+    rx: Receiver<Watched>,
+    cancelled: Arc<AtomicBool>,
+    calls: usize,
+    description: String,
+}
+
+impl Watch {
+
+    /// Blocks until the next matching line arrives, or until
+    /// `timeout_ms` milliseconds have passed.
+    pub fn next(&mut self, timeout_ms: u32) -> Result<String> {
+        self.calls += 1;
+        let call = self.calls;
+        let timer_tx = self.tx.clone();
+        thread::spawn(move || {
+            thread::sleep_ms(timeout_ms);
+            let _ = timer_tx.send(Watched::Timeout(call));
+        });
+        loop {
+            match try!(self.rx.recv()) {
+                Watched::Line(line) => return Ok(line),
+                Watched::Timeout(c) if c == call => {
+                    return Err(Error::Timeout(self.description.clone()));
+                },
+                // A timer from an earlier call that already got its line.
+                Watched::Timeout(_) => (),
+            }
+        }
+    }
+
+}
+
+impl Drop for Watch {
+
+    fn drop(&mut self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+}
+
+/// The threads behind an `EventStream`, for whoever wants to shut it
+/// down cleanly.  Dropping this leaves them running.
+pub struct IoThreads {
+    reader: Option<thread::JoinHandle<()>>,
+    writer: Option<thread::JoinHandle<()>>,
+    /// The event loop sends `None` here when it finishes, and the
+    /// timer for each `wait_for_event_loop` call sends its sequence
+    /// number when it goes off.
+    done_tx: Sender<Option<usize>>,
+    done_rx: Receiver<Option<usize>>,
+    calls: usize,
+    finished: bool,
+}
+
+impl IoThreads {
+
+    /// Waits until the writer thread has finished, which happens once
+    /// `EventStream::close` has been called and everything queued
+    /// before it has been written.
+    pub fn join_writer(&mut self) {
+        if let Some(writer) = self.writer.take() {
+            if writer.join().is_err() {
+                error!("Writer thread panicked.");
+            }
+        }
+    }
+
+    /// Like `join_writer`, but gives up after `timeout_ms`
+    /// milliseconds, for when the writer is stuck on a socket that
+    /// isn't taking any more.  Returns whether the writer finished.
+    pub fn join_writer_timeout(&mut self, timeout_ms: u32) -> bool {
+        let writer = match self.writer.take() {
+            Some(writer) => writer,
+            None => return true,
+        };
+        let (joined_tx, joined_rx) = channel();
+        let timer_tx = joined_tx.clone();
+        thread::spawn(move || {
+            if writer.join().is_err() {
+                error!("Writer thread panicked.");
+            }
+            let _ = joined_tx.send(true);
+        });
+        thread::spawn(move || {
+            thread::sleep_ms(timeout_ms);
+            let _ = timer_tx.send(false);
+        });
+        joined_rx.recv().unwrap_or(false)
+    }
+
+    /// Waits until the reader thread has finished, which happens when
+    /// the connection is closed.
+    pub fn join_reader(&mut self) {
+        if let Some(reader) = self.reader.take() {
+            if reader.join().is_err() {
+                error!("Reader thread panicked.");
+            }
+        }
+    }
+
+    /// Waits up to `timeout_ms` milliseconds for the event loop to
+    /// finish.  Returns whether it has.
+    pub fn wait_for_event_loop(&mut self, timeout_ms: u32) -> bool {
+        if self.finished {
+            return true;
+        }
+        self.calls += 1;
+        let call = self.calls;
+        let timer_tx = self.done_tx.clone();
+        thread::spawn(move || {
+            thread::sleep_ms(timeout_ms);
+            let _ = timer_tx.send(Some(call));
+        });
+        loop {
+            match self.done_rx.recv() {
+                Ok(None) => {
+                    self.finished = true;
+                    return true;
+                },
+                Ok(Some(c)) if c == call => return false,
+                // A timer from an earlier call.
+                Ok(Some(_)) => (),
+                Err(_) => return false,
+            }
+        }
+    }
+
 }
 
 impl EventStream {
@@ -61,57 +608,294 @@ impl EventStream {
     /// Creates a new event stream with initial handlers.  You should
     /// pass the `Read` and `Write` parts separately, for example using
     /// `TcpStream::try_clone`.
-    pub fn new<R: Read + Send + 'static, W: Write + Send + 'static>(inner_reader: R, inner_writer: W, init_handlers: Vec<Handler>) -> io::Result<(EventStream, thread::JoinHandle<()>)> {
-        let reader = channels::reader(inner_reader);
-        let writer = channels::writer(inner_writer);
-        let handlers = Arc::new(Mutex::new(init_handlers));
-        let thread_writer = writer.clone();
-        let thread_handlers = handlers.clone();
-        let join_handle = thread::spawn(move || {
-            event_loop(reader, thread_writer, thread_handlers)
-                .err().and_then(|e| -> Option<()> {
-                    if let channels::ChanError::SendError(mpsc::SendError(l)) = e {
-                        error!("Send of \"{}\" failed, channel is disconnected.", l);
-                    } else {
-                        error!("Receive on channel failed, channel is disconnected.");
-                    }
-                    None
-                });
+    ///
+    /// The returned `thread::JoinHandle` yields the reason the event
+    /// loop stopped.
+    pub fn new<R: Read + Send + 'static, W: Write + Send + 'static>(inner_reader: R, inner_writer: W, init_handlers: Vec<Handler>) -> Result<(EventStream, thread::JoinHandle<Result<()>>)> {
+        let named = init_handlers.into_iter().map(|h| (HandlerOptions::new("initial"), h)).collect();
+        EventStream::with_options(inner_reader, inner_writer, named, StreamOptions::new())
+    }
+
+    /// Like `EventStream::new`, but with control over how the initial
+    /// handlers are installed, encodings and rate limiting.
+    pub fn with_options<R: Read + Send + 'static, W: Write + Send + 'static>(inner_reader: R, inner_writer: W, init_handlers: Vec<(HandlerOptions, Handler)>, options: StreamOptions) -> Result<(EventStream, thread::JoinHandle<Result<()>>)> {
+        let (stream, _, join_handle) = try!(EventStream::with_threads(inner_reader, inner_writer, init_handlers, options));
+        Ok((stream, join_handle))
+    }
+
+    /// Like `EventStream::with_options`, but also returns the
+    /// `IoThreads` so the caller can shut down cleanly.
+    pub fn with_threads<R: Read + Send + 'static, W: Write + Send + 'static>(inner_reader: R, inner_writer: W, init_handlers: Vec<(HandlerOptions, Handler)>, options: StreamOptions) -> Result<(EventStream, IoThreads, thread::JoinHandle<Result<()>>)> {
+        let encodings = Arc::new(RwLock::new(options.encodings));
+        let (reader, reader_handle) = channels::reader(inner_reader, encodings.clone());
+        let sent_hooks = SentHooks::new();
+        let (writer, writer_handle) = channels::writer(inner_writer, encodings.clone(), options.rate_limit, sent_hooks.clone());
+        let mut installed = Handlers::new();
+        for (options, handler) in init_handlers.into_iter() {
+            installed.insert(options, handler);
+        }
+        let handlers = Arc::new(Mutex::new(installed));
+
+        // Lines from the server and results from blocking handlers
+        // come in on the same channel.
+        let (events_tx, events_rx) = channel();
+        let reader_events = events_tx.clone();
+        thread::spawn(move || {
+            for line in reader.iter() {
+                if reader_events.send(Event::Line(line)).is_err() {
+                    return;
+                }
+            }
+            let _ = reader_events.send(Event::Closed);
+        });
+        let ticker_events = events_tx.clone();
+        thread::spawn(move || {
+            loop {
+                thread::sleep_ms(scheduler::TICK_MS);
+                if ticker_events.send(Event::Tick).is_err() {
+                    return;
+                }
+            }
         });
+        let dispatcher = Dispatcher{
+            writer: writer.clone(),
+            events: events_tx,
+            pool: Pool::new(options.workers),
+        };
         let stream = EventStream{
             writer: writer,
             handlers: handlers,
+            encodings: encodings,
+            scheduler: Scheduler::new(),
+            sent_hooks: sent_hooks,
         };
-        Ok((stream, join_handle))
+
+        let (done_tx, done_rx) = channel();
+        let thread_done = done_tx.clone();
+        let thread_stream = stream.clone();
+        let join_handle = thread::spawn(move || {
+            let result = event_loop(events_rx, dispatcher, thread_stream);
+            if let Err(ref e) = result {
+                error!("Event loop failed: {}", e);
+            }
+            let _ = thread_done.send(None);
+            result
+        });
+        let threads = IoThreads{
+            reader: Some(reader_handle),
+            writer: Some(writer_handle),
+            done_tx: done_tx,
+            done_rx: done_rx,
+            calls: 0,
+            finished: false,
+        };
+        Ok((stream, threads, join_handle))
     }
 
-    pub fn add_handler(&mut self, handler: Handler) {
-        self.handlers.lock().unwrap().push(handler);
+    /// Sends `command`.
+    pub fn send(&mut self, command: &Command) -> Result<()> {
+        try!(self.writer.send(Outgoing::Text(format!("{}\r\n", command))));
+        Ok(())
     }
 
-    pub fn await_lines(&mut self, expectations: Vec<Regex>) -> Vec<Future<String>> {
-        expectations.into_iter().map(|expectation| {
-            let (tx, rx) = channel();
-            self.add_handler(box move |line| {
-                if expectation.is_match(line) {
-                    tx.send(line.to_string())
-                        .ok().expect("Couldn't send awaited line.");;
-                    Response(None, HandlerAction::Remove, Action::Skip)
-                } else {
-                    Response::nothing()
+    /// Stops the writer thread once everything written so far has been
+    /// sent.  Writing afterwards fails, as do handlers' responses.
+    pub fn close(&mut self) -> Result<()> {
+        try!(self.writer.send(Outgoing::Close));
+        Ok(())
+    }
+
+    /// The encodings currently used on the wire.
+    pub fn encodings(&self) -> Encodings {
+        match self.encodings.read() {
+            Ok(e) => e.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+
+    /// Replaces the encodings used on the wire.  Takes effect from the
+    /// next line read or written.
+    pub fn set_encodings(&mut self, encodings: Encodings) {
+        match self.encodings.write() {
+            Ok(mut e) => { *e = encodings; },
+            Err(poisoned) => { *poisoned.into_inner() = encodings; },
+        }
+    }
+
+    /// Uses `encoding` for lines to and from `chan`.
+    pub fn set_channel_encoding(&mut self, chan: &str, encoding: Encoding) {
+        // One lock, so a concurrent change to another channel isn't
+        // lost.
+        match self.encodings.write() {
+            Ok(mut e) => e.set_channel(chan, encoding),
+            Err(poisoned) => poisoned.into_inner().set_channel(chan, encoding),
+        }
+    }
+
+    /// Adds a handler with the default priority, after any others
+    /// with that priority.
+    ///
+    /// Fails with `Error::Disconnected` if the event loop has died.
+    pub fn add_handler(&mut self, handler: Handler) -> Result<HandlerId> {
+        self.add_handler_with(HandlerOptions::new("handler"), handler)
+    }
+
+    /// Adds a handler with a name and priority.
+    pub fn add_handler_with(&mut self, options: HandlerOptions, handler: Handler) -> Result<HandlerId> {
+        Ok(try!(lock_handlers(&self.handlers)).insert(options, handler))
+    }
+
+    /// Removes the handler `id`.  Returns whether it was still
+    /// installed.
+    pub fn remove_handler(&mut self, id: HandlerId) -> Result<bool> {
+        Ok(try!(lock_handlers(&self.handlers)).remove(id))
+    }
+
+    /// Replaces the handler `id` with `handler`, keeping its name,
+    /// priority and place, and re-enabling it if it was disabled for
+    /// panicking.  Returns whether it was still installed.
+    pub fn replace_handler(&mut self, id: HandlerId, handler: Handler) -> Result<bool> {
+        Ok(try!(lock_handlers(&self.handlers)).replace(id, handler))
+    }
+
+    /// Removes the handlers `remove` and adds `add` in one go, so no
+    /// line is seen by both or neither.  Returns the new handlers' ids.
+    pub fn swap_handlers(&mut self, remove: &[HandlerId], add: Vec<(HandlerOptions, Handler)>) -> Result<Vec<HandlerId>> {
+        let mut handlers = try!(lock_handlers(&self.handlers));
+        for id in remove.iter() {
+            handlers.remove(*id);
+        }
+        Ok(add.into_iter().map(|(options, handler)| handlers.insert(options, handler)).collect())
+    }
+
+    /// The installed handlers, in the order they see each line.
+    pub fn handlers(&self) -> Result<Vec<HandlerInfo>> {
+        Ok(try!(lock_handlers(&self.handlers)).info())
+    }
+
+    /// Runs `task` on the event loop according to `schedule`.  The
+    /// returned `TaskHandle` cancels it.
+    ///
+    /// For example, to give up on a handler after a minute:
+    ///
+    /// ```ignore
+    /// let id = try!(stream.add_handler(handler));
+    /// stream.schedule("forget handler", Schedule::After(60 * 1000), box move |stream: &mut EventStream| {
+    ///     let _ = stream.remove_handler(id);
+    /// });
+    /// ```
+    pub fn schedule(&mut self, name: &str, schedule: Schedule, task: Task) -> TaskHandle {
+        self.scheduler.schedule(name, schedule, task)
+    }
+
+    /// Cancels the task `id`.  Returns whether it was still scheduled.
+    pub fn cancel_task(&mut self, id: TaskId) -> bool {
+        self.scheduler.cancel(id)
+    }
+
+    /// The scheduled tasks, soonest first.
+    pub fn tasks(&self) -> Vec<TaskInfo> {
+        self.scheduler.tasks()
+    }
+
+    /// A handle for scheduling tasks from handlers, which don't get the
+    /// stream.
+    pub fn scheduler(&self) -> Scheduler {
+        self.scheduler.clone()
+    }
+
+    /// A handle for seeing the lines we send, including handlers'
+    /// responses and anything written directly.
+    pub fn sent_hooks(&self) -> SentHooks {
+        self.sent_hooks.clone()
+    }
+
+    /// Installs a handler that passes every line matching any of
+    /// `expectations` to the returned `Watch`, without stopping other
+    /// handlers from seeing it.
+    pub fn watch(&mut self, expectations: Vec<Regex>) -> Result<Watch> {
+        let description = expectations.iter().map(|re| re.as_str()).collect::<Vec<_>>().join(" or ");
+        self.watch_lines(description, box move |line| expectations.iter().any(|re| re.is_match(line)))
+    }
+
+    /// Like `watch`, but for lines whose command is one of `commands`,
+    /// whatever tags or prefix come before it.
+    pub fn watch_commands(&mut self, commands: &[&str]) -> Result<Watch> {
+        let commands: Vec<String> = commands.iter().map(|c| c.to_string()).collect();
+        let description = commands.join(" or ");
+        self.watch_lines(description, box move |line| has_command(line, &commands))
+    }
+
+    fn watch_lines(&mut self, description: String, matches: Box<Fn(&str) -> bool + Send>) -> Result<Watch> {
+        let (tx, rx) = channel();
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let handler_tx = tx.clone();
+        let handler_cancelled = cancelled.clone();
+        let options = HandlerOptions::new(&format!("watch {}", description)).priority(WAITER_PRIORITY);
+        try!(self.add_handler_with(options, box move |line| {
+            if handler_cancelled.load(Ordering::SeqCst) {
+                Response(None, HandlerAction::Remove, Action::Continue)
+            } else {
+                if matches(line) {
+                    let _ = handler_tx.send(Watched::Line(line.to_string()));
                 }
-            });
-            Future::from_receiver(rx)
+                Response::nothing()
+            }
+        }));
+        Ok(Watch{
+            tx: tx,
+            rx: rx,
+            cancelled: cancelled,
+            calls: 0,
+            description: description,
+        })
+    }
+
+    /// Installs one handler per regex, each of which waits for a
+    /// single line matching its regex.
+    pub fn await_lines(&mut self, expectations: Vec<Regex>) -> Result<Vec<Awaited>> {
+        expectations.into_iter().map(|expectation| {
+            self.await_any(vec![expectation])
         }).collect()
     }
 
+    /// Installs a handler that waits for the first line matching any
+    /// of `expectations`.  Other handlers still see the line.
+    pub fn await_any(&mut self, expectations: Vec<Regex>) -> Result<Awaited> {
+        let (tx, rx) = channel();
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let description = expectations.iter().map(|re| re.as_str()).collect::<Vec<_>>().join(" or ");
+        let handler_tx = tx.clone();
+        let handler_cancelled = cancelled.clone();
+        let options = HandlerOptions::new(&format!("await {}", description)).priority(WAITER_PRIORITY);
+        try!(self.add_handler_with(options, box move |line| {
+            if handler_cancelled.load(Ordering::SeqCst) {
+                Response(None, HandlerAction::Remove, Action::Continue)
+            } else if expectations.iter().any(|re| re.is_match(line)) {
+                // Nobody is waiting any more if this fails, which is fine.
+                let _ = handler_tx.send(Some(line.to_string()));
+                Response(None, HandlerAction::Remove, Action::Continue)
+            } else {
+                Response::nothing()
+            }
+        }));
+        Ok(Awaited{
+            tx: tx,
+            rx: rx,
+            cancelled: cancelled,
+            description: description,
+        })
+    }
+
 }
 
 impl Write for EventStream {
 
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        let line = from_utf8(buf).unwrap().to_string();
-        self.writer.send(line)
+        let line = try!(from_utf8(buf).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidInput, "Outgoing line is not valid UTF-8.")
+        })).to_string();
+        self.writer.send(Outgoing::Text(line))
             .and(Ok(buf.len()))
             .or(Err(io::Error::new(io::ErrorKind::NotConnected, "Send failed, channel is disconnected.")))
     }
@@ -122,35 +906,137 @@ impl Write for EventStream {
 
 }
 
-fn process_one_event(line: &str, writer: &Sender<String>, handlers: &mut Vec<Handler>) -> Result<Action, mpsc::SendError<String>> {
-    let mut i: usize = 0;
-    while i < handlers.len() {
-        let Response(msg, handler_action, action) = {
-            let h = &mut handlers[i];
-            h(line)
-        };
-        // Send a message, if any.
-        if let Some(m) = msg {
-            try!(writer.send(m));
-        }
-        // Increment i if we didn't remove the handler here.
-        match handler_action {
-            HandlerAction::Remove => (),
-            _ => { i = i + 1; },
+/// Whether `line`'s command is one of `commands`.
+fn has_command(line: &str, commands: &[String]) -> bool {
+    Message::parse(line).map_or(false, |msg| commands.iter().any(|c| *c == msg.command))
+}
+
+fn lock_handlers(handlers: &Arc<Mutex<Handlers>>) -> Result<MutexGuard<Handlers>> {
+    // A poisoned lock means a handler panicked and took the event
+    // loop down with it.
+    handlers.lock().map_err(|_| Error::Disconnected)
+}
+
+/// What the event loop waits for.
+enum Event {
+    /// A line from the server.
+    Line(String),
+    /// The server closed the connection.
+    Closed,
+    /// Time to run any scheduled tasks that are due.
+    Tick,
+    /// A blocking handler finished with a line, with whatever's left of
+    /// its response once its command was sent, or `None` if it
+    /// panicked.
+    Finished(HandlerId, String, Option<Response>),
+}
+
+/// Where handlers' work goes.
+struct Dispatcher {
+    writer: Sender<Outgoing>,
+    events: Sender<Event>,
+    pool: Pool,
+}
+
+impl Dispatcher {
+
+    fn send(&self, command: Command) -> Result<()> {
+        try!(self.writer.send(Outgoing::Text(format!("{}\r\n", command))));
+        Ok(())
+    }
+
+    /// Queues `line` for the blocking handler `id`, starting a worker
+    /// on its backlog if one isn't already running.
+    fn enqueue(&self, id: HandlerId, backlog: &Arc<Mutex<Backlog>>, line: &str) -> Result<()> {
+        {
+            let mut queued = lock_backlog(backlog);
+            queued.lines.push_back(line.to_string());
+            if queued.running {
+                return Ok(());
+            }
+            queued.running = true;
+        }
+        let backlog = backlog.clone();
+        let writer = self.writer.clone();
+        let events = self.events.clone();
+        self.pool.submit(box move || {
+            loop {
+                let (line, handler) = {
+                    let mut queued = lock_backlog(&backlog);
+                    match queued.lines.pop_front() {
+                        Some(line) => (line, queued.handler.clone()),
+                        None => {
+                            queued.running = false;
+                            return;
+                        },
+                    }
+                };
+                let response = run_handler(&handler, &line).map(|Response(msg, handler_action, action)| {
+                    if let Some(command) = msg {
+                        let _ = writer.send(Outgoing::Text(format!("{}\r\n", command)));
+                    }
+                    Response(None, handler_action, action)
+                });
+                if events.send(Event::Finished(id, line, response)).is_err() {
+                    // The event loop is gone.
+                    return;
+                }
+            }
+        })
+    }
+
+}
+
+/// Changes the handler at `i` as it asked.
+fn apply(handlers: &mut Handlers, i: usize, handler_action: HandlerAction) {
+    match handler_action {
+        HandlerAction::Add(h) => {
+            handlers.insert(HandlerOptions::new("added"), h);
+        },
+        HandlerAction::Swap(h) => {
+            handlers.entries[i].set_handler(h);
+        },
+        HandlerAction::Remove => {
+            handlers.entries.remove(i);
+        },
+        HandlerAction::Keep => (),
+    };
+}
+
+/// Runs `line` through `handlers`, in order, applying each handler's
+/// `HandlerAction` before moving on.  Only the handlers installed when
+/// the line arrived see it.  A handler that panics is treated as
+/// having done nothing.  Blocking handlers are handed the line and
+/// skipped over.
+fn process_one_event(line: &str, dispatcher: &Dispatcher, handlers: &mut Handlers) -> Result<Action> {
+    let ids: Vec<HandlerId> = handlers.entries.iter().map(|e| e.id).collect();
+    for id in ids.into_iter() {
+        let i = match handlers.position(id) {
+            Some(i) => i,
+            None => continue,
         };
-        // Modify handlers, if needed.
-        match handler_action {
-            HandlerAction::Add(h) => {
-                handlers.push(h);
-            },
-            HandlerAction::Swap(h) => {
-                handlers[i] = h;
-            },
-            HandlerAction::Remove => {
-                handlers.swap_remove(i);
+        if handlers.entries[i].disabled {
+            continue;
+        }
+        if let Some(ref backlog) = handlers.entries[i].backlog {
+            try!(dispatcher.enqueue(id, backlog, line));
+            continue;
+        }
+        let response = run_handler(&handlers.entries[i].handler, line);
+        let Response(msg, handler_action, action) = match response {
+            Some(response) => response,
+            None => {
+                handlers.entries[i].failed(line);
+                continue;
             },
-            HandlerAction::Keep => (),
         };
+        // Send a command, if any.
+        if let Some(command) = msg {
+            try!(dispatcher.send(command));
+        }
+        // Modify handlers, if needed.  Nothing else has touched them
+        // since we looked up `i`.
+        apply(handlers, i, handler_action);
         // Exit early, if requested.
         match action {
             Action::Continue => (),
@@ -162,10 +1048,43 @@ fn process_one_event(line: &str, writer: &Sender<String>, handlers: &mut Vec<Han
     Ok(Action::Continue)
 }
 
-fn event_loop(reader: Receiver<String>, writer: Sender<String>, handlers: Arc<Mutex<Vec<Handler>>>) -> Result<(), channels::ChanError<String>> {
+/// Applies what a blocking handler asked for once it was done with
+/// `line`.  Only `Action::Stop` matters by now.
+fn process_finished(id: HandlerId, line: &str, response: Option<Response>, handlers: &mut Handlers) -> Action {
+    let i = match handlers.position(id) {
+        Some(i) => i,
+        // Removed while it was working.
+        None => return Action::Continue,
+    };
+    match response {
+        Some(Response(_, handler_action, action)) => {
+            apply(handlers, i, handler_action);
+            action
+        },
+        None => {
+            handlers.entries[i].failed(line);
+            Action::Continue
+        },
+    }
+}
+
+fn event_loop(events: Receiver<Event>, dispatcher: Dispatcher, mut stream: EventStream) -> Result<()> {
     loop {
-        let line = try!(reader.recv().map_err(channels::recv_to_chan_error));
-        match try!(process_one_event(&line, &writer, &mut *handlers.lock().unwrap()).map_err(channels::send_to_chan_error)) {
+        let action = match try!(events.recv()) {
+            Event::Line(line) => try!(process_one_event(&line, &dispatcher, &mut *try!(lock_handlers(&stream.handlers)))),
+            Event::Finished(id, line, response) => {
+                process_finished(id, &line, response, &mut *try!(lock_handlers(&stream.handlers)))
+            },
+            Event::Tick => {
+                // Tasks may add and remove handlers, so the handlers
+                // aren't locked while they run.
+                let scheduler = stream.scheduler.clone();
+                scheduler.run_due(&mut stream);
+                Action::Continue
+            },
+            Event::Closed => return Err(Error::Disconnected),
+        };
+        match action {
             Action::Stop => {
                 info!("Exiting event loop...");
                 break;
@@ -175,3 +1094,406 @@ fn event_loop(reader: Receiver<String>, writer: Sender<String>, handlers: Arc<Mu
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::sync::mpsc::{channel, Receiver};
+    use super::{has_command, process_finished, process_one_event, Action, Dispatcher, Event, Handler, HandlerAction, HandlerOptions, Handlers, Response, SentHooks};
+    use super::super::channels::Outgoing;
+    use super::super::command::Command;
+    use super::super::pool::Pool;
+
+    type Log = Arc<Mutex<Vec<String>>>;
+
+    /// A handler that records `name` and the line in `log` each time
+    /// it runs, then responds with `respond(line)`.
+    fn logged<F>(log: &Log, name: &str, mut respond: F) -> Handler
+        where F: FnMut(&str) -> Response + Send + 'static {
+        let log = log.clone();
+        let name = name.to_string();
+        box move |line: &str| {
+            log.lock().unwrap().push(format!("{} {}", name, line));
+            respond(line)
+        }
+    }
+
+    fn nothing(log: &Log, name: &str) -> Handler {
+        logged(log, name, |_| Response::nothing())
+    }
+
+    fn new_log() -> Log {
+        Arc::new(Mutex::new(Vec::new()))
+    }
+
+    fn entries(log: &Log) -> Vec<String> {
+        log.lock().unwrap().clone()
+    }
+
+    fn dispatcher() -> (Dispatcher, Receiver<Outgoing>, Receiver<Event>) {
+        let (writer, written) = channel();
+        let (events, received) = channel();
+        let dispatcher = Dispatcher{
+            writer: writer,
+            events: events,
+            pool: Pool::new(1),
+        };
+        (dispatcher, written, received)
+    }
+
+    fn dispatch(line: &str, handlers: &mut Handlers) -> (Action, Vec<String>) {
+        let (dispatcher, written, _) = dispatcher();
+        let action = process_one_event(line, &dispatcher, handlers).unwrap();
+        (action, sent(&written))
+    }
+
+    fn sent(rx: &Receiver<Outgoing>) -> Vec<String> {
+        let mut lines = Vec::new();
+        while let Ok(outgoing) = rx.try_recv() {
+            match outgoing {
+                Outgoing::Text(text) => lines.push(text),
+                Outgoing::Close => panic!("Handlers shouldn't close the writer"),
+            }
+        }
+        lines
+    }
+
+    fn names(handlers: &Handlers) -> Vec<String> {
+        handlers.info().into_iter().map(|h| h.name).collect()
+    }
+
+    #[test]
+    fn runs_handlers_by_priority_then_insertion_order() {
+        let log = new_log();
+        let mut handlers = Handlers::new();
+        handlers.insert(HandlerOptions::new("a"), nothing(&log, "a"));
+        handlers.insert(HandlerOptions::new("b").priority(10), nothing(&log, "b"));
+        handlers.insert(HandlerOptions::new("c"), nothing(&log, "c"));
+        handlers.insert(HandlerOptions::new("d").priority(-5), nothing(&log, "d"));
+        handlers.insert(HandlerOptions::new("e").priority(10), nothing(&log, "e"));
+
+        assert_eq!(names(&handlers), vec!["b", "e", "a", "c", "d"]);
+        assert_eq!(dispatch("x", &mut handlers).0, Action::Continue);
+        assert_eq!(entries(&log), vec!["b x", "e x", "a x", "c x", "d x"]);
+    }
+
+    #[test]
+    fn sends_responses_with_line_endings() {
+        let log = new_log();
+        let mut handlers = Handlers::new();
+        handlers.insert(HandlerOptions::new("a"), logged(&log, "a", |_| {
+            Response(Some(Command::privmsg("#chan", "hi")), HandlerAction::Keep, Action::Continue)
+        }));
+        handlers.insert(HandlerOptions::new("b"), logged(&log, "b", |_| {
+            Response::respond(Command::Pong("token".to_string()))
+        }));
+
+        let (action, sent) = dispatch("x", &mut handlers);
+        assert_eq!(action, Action::Skip);
+        assert_eq!(sent, vec!["PRIVMSG #chan :hi\r\n", "PONG :token\r\n"]);
+    }
+
+    #[test]
+    fn skip_stops_the_line_but_not_the_loop() {
+        let log = new_log();
+        let mut handlers = Handlers::new();
+        handlers.insert(HandlerOptions::new("a"), logged(&log, "a", |line| {
+            if line == "skip" {
+                Response(None, HandlerAction::Keep, Action::Skip)
+            } else {
+                Response::nothing()
+            }
+        }));
+        handlers.insert(HandlerOptions::new("b"), nothing(&log, "b"));
+
+        assert_eq!(dispatch("skip", &mut handlers).0, Action::Skip);
+        assert_eq!(dispatch("x", &mut handlers).0, Action::Continue);
+        assert_eq!(entries(&log), vec!["a skip", "a x", "b x"]);
+    }
+
+    #[test]
+    fn stop_sends_its_response_and_stops() {
+        let log = new_log();
+        let mut handlers = Handlers::new();
+        handlers.insert(HandlerOptions::new("a"), logged(&log, "a", |_| {
+            Response(Some(Command::Quit(None)), HandlerAction::Keep, Action::Stop)
+        }));
+        handlers.insert(HandlerOptions::new("b"), nothing(&log, "b"));
+
+        let (action, sent) = dispatch("x", &mut handlers);
+        assert_eq!(action, Action::Stop);
+        assert_eq!(sent, vec!["QUIT\r\n"]);
+        assert_eq!(entries(&log), vec!["a x"]);
+    }
+
+    #[test]
+    fn remove_keeps_the_others_in_order() {
+        let log = new_log();
+        let mut handlers = Handlers::new();
+        handlers.insert(HandlerOptions::new("a"), nothing(&log, "a"));
+        handlers.insert(HandlerOptions::new("b"), logged(&log, "b", |_| {
+            Response(None, HandlerAction::Remove, Action::Continue)
+        }));
+        handlers.insert(HandlerOptions::new("c"), nothing(&log, "c"));
+        handlers.insert(HandlerOptions::new("d"), nothing(&log, "d"));
+
+        dispatch("1", &mut handlers);
+        assert_eq!(names(&handlers), vec!["a", "c", "d"]);
+        dispatch("2", &mut handlers);
+        assert_eq!(entries(&log), vec!["a 1", "b 1", "c 1", "d 1", "a 2", "c 2", "d 2"]);
+    }
+
+    #[test]
+    fn removing_consecutive_handlers_skips_nobody() {
+        let log = new_log();
+        let mut handlers = Handlers::new();
+        for name in ["a", "b", "c"].iter() {
+            handlers.insert(HandlerOptions::new(name), logged(&log, name, |_| {
+                Response(None, HandlerAction::Remove, Action::Continue)
+            }));
+        }
+
+        dispatch("1", &mut handlers);
+        assert_eq!(entries(&log), vec!["a 1", "b 1", "c 1"]);
+        assert!(handlers.info().is_empty());
+    }
+
+    #[test]
+    fn remove_with_skip() {
+        let log = new_log();
+        let mut handlers = Handlers::new();
+        handlers.insert(HandlerOptions::new("a"), logged(&log, "a", |_| {
+            Response(None, HandlerAction::Remove, Action::Skip)
+        }));
+        handlers.insert(HandlerOptions::new("b"), nothing(&log, "b"));
+
+        assert_eq!(dispatch("1", &mut handlers).0, Action::Skip);
+        dispatch("2", &mut handlers);
+        assert_eq!(entries(&log), vec!["a 1", "b 2"]);
+    }
+
+    #[test]
+    fn swap_replaces_the_current_handler() {
+        let log = new_log();
+        let mut handlers = Handlers::new();
+        handlers.insert(HandlerOptions::new("a"), nothing(&log, "a"));
+        let swapped_log = log.clone();
+        let b = handlers.insert(HandlerOptions::new("b"), logged(&log, "b", move |_| {
+            Response(None, HandlerAction::Swap(nothing(&swapped_log, "b2")), Action::Continue)
+        }));
+        handlers.insert(HandlerOptions::new("c"), nothing(&log, "c"));
+
+        dispatch("1", &mut handlers);
+        dispatch("2", &mut handlers);
+        assert_eq!(entries(&log), vec!["a 1", "b 1", "c 1", "a 2", "b2 2", "c 2"]);
+        assert_eq!(names(&handlers), vec!["a", "b", "c"]);
+        assert_eq!(handlers.info()[1].id, b);
+    }
+
+    #[test]
+    fn swap_on_the_last_handler() {
+        let log = new_log();
+        let mut handlers = Handlers::new();
+        handlers.insert(HandlerOptions::new("a"), nothing(&log, "a"));
+        let swapped_log = log.clone();
+        handlers.insert(HandlerOptions::new("b"), logged(&log, "b", move |_| {
+            Response(None, HandlerAction::Swap(nothing(&swapped_log, "b2")), Action::Continue)
+        }));
+
+        dispatch("1", &mut handlers);
+        dispatch("2", &mut handlers);
+        assert_eq!(entries(&log), vec!["a 1", "b 1", "a 2", "b2 2"]);
+    }
+
+    #[test]
+    fn added_handlers_start_with_the_next_line() {
+        let log = new_log();
+        let mut handlers = Handlers::new();
+        let added_log = log.clone();
+        let mut added = false;
+        handlers.insert(HandlerOptions::new("a"), logged(&log, "a", move |_| {
+            if added {
+                Response::nothing()
+            } else {
+                added = true;
+                Response(None, HandlerAction::Add(nothing(&added_log, "new")), Action::Continue)
+            }
+        }));
+        handlers.insert(HandlerOptions::new("b"), nothing(&log, "b"));
+
+        dispatch("1", &mut handlers);
+        assert_eq!(names(&handlers), vec!["a", "b", "added"]);
+        dispatch("2", &mut handlers);
+        assert_eq!(entries(&log), vec!["a 1", "b 1", "a 2", "b 2", "new 2"]);
+    }
+
+    #[test]
+    fn added_handlers_go_after_equal_priorities() {
+        let log = new_log();
+        let mut handlers = Handlers::new();
+        handlers.insert(HandlerOptions::new("high").priority(10), nothing(&log, "high"));
+        let added_log = log.clone();
+        let mut added = false;
+        handlers.insert(HandlerOptions::new("a"), logged(&log, "a", move |_| {
+            if added {
+                Response::nothing()
+            } else {
+                added = true;
+                Response(None, HandlerAction::Add(nothing(&added_log, "new")), Action::Skip)
+            }
+        }));
+        handlers.insert(HandlerOptions::new("b"), nothing(&log, "b"));
+        handlers.insert(HandlerOptions::new("low").priority(-10), nothing(&log, "low"));
+
+        assert_eq!(dispatch("1", &mut handlers).0, Action::Skip);
+        assert_eq!(names(&handlers), vec!["high", "a", "b", "added", "low"]);
+    }
+
+    #[test]
+    fn remove_and_replace_by_id() {
+        let log = new_log();
+        let mut handlers = Handlers::new();
+        let a = handlers.insert(HandlerOptions::new("a"), nothing(&log, "a"));
+        let b = handlers.insert(HandlerOptions::new("b"), nothing(&log, "b"));
+        handlers.insert(HandlerOptions::new("c"), nothing(&log, "c"));
+
+        assert!(handlers.replace(b, nothing(&log, "b2")));
+        assert!(handlers.remove(a));
+        assert!(!handlers.remove(a));
+        assert!(!handlers.replace(a, nothing(&log, "a2")));
+        dispatch("1", &mut handlers);
+        assert_eq!(entries(&log), vec!["b2 1", "c 1"]);
+        assert_eq!(names(&handlers), vec!["b", "c"]);
+    }
+
+    #[test]
+    fn panicking_handlers_dont_stop_the_rest() {
+        let log = new_log();
+        let mut handlers = Handlers::new();
+        handlers.insert(HandlerOptions::new("a"), logged(&log, "a", |line| {
+            if line == "boom" {
+                panic!("boom");
+            }
+            Response::nothing()
+        }));
+        handlers.insert(HandlerOptions::new("b"), nothing(&log, "b"));
+
+        assert_eq!(dispatch("boom", &mut handlers).0, Action::Continue);
+        dispatch("x", &mut handlers);
+        assert_eq!(entries(&log), vec!["a boom", "b boom", "a x", "b x"]);
+        let info = handlers.info();
+        assert_eq!(info[0].failures, 1);
+        assert!(!info[0].disabled);
+    }
+
+    #[test]
+    fn handlers_are_disabled_after_too_many_panics() {
+        let log = new_log();
+        let mut handlers = Handlers::new();
+        let a = handlers.insert(HandlerOptions::new("a").max_failures(2), logged(&log, "a", |_| -> Response {
+            panic!("boom")
+        }));
+        handlers.insert(HandlerOptions::new("b"), nothing(&log, "b"));
+
+        dispatch("1", &mut handlers);
+        dispatch("2", &mut handlers);
+        dispatch("3", &mut handlers);
+        assert_eq!(entries(&log), vec!["a 1", "b 1", "a 2", "b 2", "b 3"]);
+        assert!(handlers.info()[0].disabled);
+
+        assert!(handlers.replace(a, nothing(&log, "a2")));
+        dispatch("4", &mut handlers);
+        assert_eq!(entries(&log)[5..].to_vec(), vec!["a2 4", "b 4"]);
+        assert_eq!(handlers.info()[0].failures, 0);
+    }
+
+    #[test]
+    fn blocking_handlers_run_in_order_off_the_loop() {
+        let log = new_log();
+        let mut handlers = Handlers::new();
+        let a = handlers.insert(HandlerOptions::new("a").blocking(), logged(&log, "a", |line| {
+            if line == "2" {
+                Response(Some(Command::privmsg("#c", "two")), HandlerAction::Remove, Action::Skip)
+            } else {
+                Response::nothing()
+            }
+        }));
+        handlers.insert(HandlerOptions::new("b").priority(-1), nothing(&log, "b"));
+        assert!(handlers.info()[0].blocking);
+
+        let (dispatcher, written, events) = dispatcher();
+        for line in ["1", "2"].iter() {
+            assert_eq!(process_one_event(line, &dispatcher, &mut handlers).unwrap(), Action::Continue);
+        }
+        for expected in ["1", "2"].iter() {
+            match events.recv().unwrap() {
+                Event::Finished(id, line, response) => {
+                    assert_eq!(id, a);
+                    assert_eq!(line, *expected);
+                    process_finished(id, &line, response, &mut handlers);
+                },
+                _ => panic!("Expected a finished handler"),
+            }
+        }
+        let mut log = entries(&log);
+        log.sort();
+        assert_eq!(log, vec!["a 1", "a 2", "b 1", "b 2"]);
+        assert_eq!(sent(&written), vec!["PRIVMSG #c :two\r\n"]);
+        assert_eq!(names(&handlers), vec!["b"]);
+    }
+
+    #[test]
+    fn panicking_sent_hooks_dont_stop_the_rest() {
+        let log = new_log();
+        let hooks = SentHooks::new();
+        hooks.add(box |line: &str| {
+            if line == "boom" {
+                panic!("boom");
+            }
+        });
+        let hook_log = log.clone();
+        hooks.add(box move |line: &str| hook_log.lock().unwrap().push(line.to_string()));
+        hooks.run("boom");
+        hooks.run("x");
+        assert_eq!(entries(&log), vec!["boom", "x"]);
+    }
+
+    #[test]
+    fn queued_lines_go_to_a_replaced_blocking_handler() {
+        let log = new_log();
+        let mut handlers = Handlers::new();
+        let (started_tx, started) = channel();
+        let (release, release_rx) = channel::<()>();
+        let a = handlers.insert(HandlerOptions::new("a").blocking(), logged(&log, "a", move |_| {
+            let _ = started_tx.send(());
+            let _ = release_rx.recv();
+            Response::nothing()
+        }));
+
+        let (dispatcher, _written, events) = dispatcher();
+        process_one_event("1", &dispatcher, &mut handlers).unwrap();
+        started.recv().unwrap();
+        process_one_event("2", &dispatcher, &mut handlers).unwrap();
+        assert!(handlers.replace(a, nothing(&log, "a2")));
+        release.send(()).unwrap();
+        for _ in 0..2 {
+            match events.recv().unwrap() {
+                Event::Finished(id, line, response) => {
+                    process_finished(id, &line, response, &mut handlers);
+                },
+                _ => panic!("Expected a finished handler"),
+            }
+        }
+        assert_eq!(entries(&log), vec!["a 1", "a2 2"]);
+    }
+
+    #[test]
+    fn commands_match_after_tags_and_prefix() {
+        let commands = vec!["001".to_string(), "JOIN".to_string()];
+        assert!(has_command(":irc.example.net 001 bot :Welcome", &commands));
+        assert!(has_command("@time=2015-06-01T12:00:00.000Z :bot!b@c JOIN #x", &commands));
+        assert!(has_command("@account=alice JOIN #x", &commands));
+        assert!(!has_command("@time=2015-06-01T12:00:00.000Z :a!b@c PRIVMSG #x :JOIN 001", &commands));
+        assert!(!has_command("", &commands));
+    }
+}
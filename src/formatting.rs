@@ -0,0 +1,411 @@
+//! mIRC-style text formatting.
+//!
+//! Clients embed control codes in messages for bold, colors, and so
+//! on.  `parse` splits text into styled spans, `strip` removes the
+//! codes so the text can be matched against, and `Formatted` builds
+//! formatted text to send.
+//!
+//! # Example:
+//! ```{.ignore .rust}
+//! use irc::formatting::{self, Color, Formatted};
+//!
+//! assert_eq!(formatting::strip("\x02karma\x02 foo"), "karma foo");
+//! let reply = Formatted::new()
+//!     .text("foo: ")
+//!     .color(Color::from_name("green").unwrap(), None, "42")
+//!     .build();
+//! ```
+
+use std::ascii::AsciiExt;
+use std::iter::Peekable;
+
+const BOLD: char = '\x02';
+const COLOR: char = '\x03';
+const HEX_COLOR: char = '\x04';
+const RESET: char = '\x0f';
+const MONOSPACE: char = '\x11';
+const REVERSE: char = '\x16';
+const ITALIC: char = '\x1d';
+const STRIKETHROUGH: char = '\x1e';
+const UNDERLINE: char = '\x1f';
+
+static PALETTE_NAMES: [&'static str; 16] = [
+    "white", "black", "blue", "green", "red", "brown", "magenta", "orange",
+    "yellow", "light green", "cyan", "light cyan", "light blue", "pink", "grey", "light grey",
+    ];
+
+/// A text color.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Color {
+    /// One of the numbered mIRC colors, 0-15 for the classic palette
+    /// and up to 98 for the extended one.
+    Palette(u8),
+    /// An arbitrary RGB color, sent with the `\x04` hex color code.
+    Rgb(u8, u8, u8),
+}
+
+impl Color {
+
+    /// Looks up a classic palette color by name, e.g. `"light blue"`.
+    pub fn from_name(name: &str) -> Option<Color> {
+        let name = name.to_ascii_lowercase();
+        PALETTE_NAMES.iter().position(|&n| n == name).map(|i| Color::Palette(i as u8))
+    }
+
+    fn format(&self) -> String {
+        match *self {
+            Color::Palette(n) => format!("{:02}", n),
+            Color::Rgb(r, g, b) => format!("{:02X}{:02X}{:02X}", r, g, b),
+        }
+    }
+
+}
+
+/// The formatting in effect for a piece of text.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Style {
+    pub bold: bool,
+    pub italic: bool,
+    pub underline: bool,
+    pub strikethrough: bool,
+    pub reverse: bool,
+    pub monospace: bool,
+    pub fg: Option<Color>,
+    pub bg: Option<Color>,
+}
+
+impl Style {
+
+    /// Whether this is just plain text.
+    pub fn is_plain(&self) -> bool {
+        *self == Style::default()
+    }
+
+}
+
+/// A run of text sharing one `Style`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Span {
+    pub style: Style,
+    pub text: String,
+}
+
+/// Splits formatted text into spans of consistently styled text.
+/// Adjacent spans always have different styles, and no span is
+/// empty.
+pub fn parse(s: &str) -> Vec<Span> {
+    let mut spans = Vec::new();
+    let mut style = Style::default();
+    let mut text = String::new();
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        let mut next_style = style.clone();
+        match c {
+            BOLD => { next_style.bold = !style.bold; },
+            ITALIC => { next_style.italic = !style.italic; },
+            UNDERLINE => { next_style.underline = !style.underline; },
+            STRIKETHROUGH => { next_style.strikethrough = !style.strikethrough; },
+            REVERSE => { next_style.reverse = !style.reverse; },
+            MONOSPACE => { next_style.monospace = !style.monospace; },
+            RESET => { next_style = Style::default(); },
+            COLOR => {
+                let (fg, bg) = take_colors(&mut chars, 1, 2, 10, |digits| {
+                    digits.parse().ok().map(Color::Palette)
+                });
+                apply_colors(&mut next_style, fg, bg);
+            },
+            HEX_COLOR => {
+                let (fg, bg) = take_colors(&mut chars, 6, 6, 16, |digits| {
+                    u32::from_str_radix(digits, 16).ok().map(|rgb| {
+                        Color::Rgb((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8)
+                    })
+                });
+                apply_colors(&mut next_style, fg, bg);
+            },
+            _ => {
+                text.push(c);
+                continue;
+            },
+        }
+        if next_style != style {
+            if !text.is_empty() {
+                spans.push(Span{ style: style, text: text });
+                text = String::new();
+            }
+            style = next_style;
+        }
+    }
+    if !text.is_empty() {
+        spans.push(Span{ style: style, text: text });
+    }
+    spans
+}
+
+/// A color code with no colors after it resets both colors.
+fn apply_colors(style: &mut Style, fg: Option<Color>, bg: Option<Color>) {
+    if fg.is_none() {
+        style.fg = None;
+        style.bg = None;
+    } else {
+        style.fg = fg;
+        if bg.is_some() {
+            style.bg = bg;
+        }
+    }
+}
+
+/// Reads `fg[,bg]` after a color code, where each color is between
+/// `min` and `max` digits in `radix`.  A comma is only part of the
+/// code if a background color follows it, so `"\x034,hello"` is red
+/// `",hello"`.
+fn take_colors<I, F>(chars: &mut Peekable<I>, min: usize, max: usize, radix: u32, parse_color: F) -> (Option<Color>, Option<Color>)
+    where I: Iterator<Item=char> + Clone, F: Fn(&str) -> Option<Color> {
+    let fg_digits = peek_digits(chars, max, radix);
+    if fg_digits.len() < min {
+        return (None, None);
+    }
+    for _ in 0..fg_digits.len() {
+        chars.next();
+    }
+    let fg = parse_color(&fg_digits);
+
+    let mut lookahead = chars.clone();
+    if lookahead.next() != Some(',') {
+        return (fg, None);
+    }
+    let bg_digits = peek_digits(&lookahead, max, radix);
+    if bg_digits.len() < min {
+        return (fg, None);
+    }
+    for _ in 0..bg_digits.len() + 1 {
+        chars.next();
+    }
+    (fg, parse_color(&bg_digits))
+}
+
+fn peek_digits<I>(chars: &Peekable<I>, max: usize, radix: u32) -> String
+    where I: Iterator<Item=char> + Clone {
+    chars.clone().take(max).take_while(|c| c.is_digit(radix)).collect()
+}
+
+/// A regex matching one formatting code, colors included, for
+/// matching against text without stripping it first.
+pub const CODE_PATTERN: &'static str =
+    r"(?:[\x02\x0f\x11\x16\x1d\x1e\x1f]|\x03(?:[0-9]{1,2}(?:,[0-9]{1,2})?)?|\x04(?:[0-9a-fA-F]{6}(?:,[0-9a-fA-F]{6})?)?)";
+
+/// Removes all formatting codes from `s`.
+pub fn strip(s: &str) -> String {
+    parse(s).into_iter().map(|span| span.text).collect()
+}
+
+/// Builds formatted text.  Each styled piece resets formatting after
+/// itself, so pieces don't bleed into each other.
+pub struct Formatted {
+    buf: String,
+}
+
+impl Formatted {
+
+    pub fn new() -> Formatted {
+        Formatted{ buf: String::new() }
+    }
+
+    /// Appends plain text.
+    pub fn text(mut self, s: &str) -> Formatted {
+        self.buf.push_str(s);
+        self
+    }
+
+    pub fn bold(self, s: &str) -> Formatted {
+        self.styled(&Style{ bold: true, ..Style::default() }, s)
+    }
+
+    pub fn italic(self, s: &str) -> Formatted {
+        self.styled(&Style{ italic: true, ..Style::default() }, s)
+    }
+
+    pub fn underline(self, s: &str) -> Formatted {
+        self.styled(&Style{ underline: true, ..Style::default() }, s)
+    }
+
+    pub fn strikethrough(self, s: &str) -> Formatted {
+        self.styled(&Style{ strikethrough: true, ..Style::default() }, s)
+    }
+
+    pub fn reverse(self, s: &str) -> Formatted {
+        self.styled(&Style{ reverse: true, ..Style::default() }, s)
+    }
+
+    pub fn monospace(self, s: &str) -> Formatted {
+        self.styled(&Style{ monospace: true, ..Style::default() }, s)
+    }
+
+    pub fn color(self, fg: Color, bg: Option<Color>, s: &str) -> Formatted {
+        self.styled(&Style{ fg: Some(fg), bg: bg, ..Style::default() }, s)
+    }
+
+    /// Appends `s` in an arbitrary `style`.
+    pub fn styled(mut self, style: &Style, s: &str) -> Formatted {
+        if style.is_plain() || s.is_empty() {
+            return self.text(s);
+        }
+        for &(on, code) in [(style.bold, BOLD), (style.italic, ITALIC),
+                            (style.underline, UNDERLINE), (style.strikethrough, STRIKETHROUGH),
+                            (style.reverse, REVERSE), (style.monospace, MONOSPACE)].iter() {
+            if on {
+                self.buf.push(code);
+            }
+        }
+        if let Some(fg) = style.fg {
+            let fg_only = self.push_colors(fg, style.bg);
+            if fg_only && s.starts_with(',') {
+                // Keep a leading comma from being read as the start of
+                // a background color.
+                self.buf.push(BOLD);
+                self.buf.push(BOLD);
+            }
+        }
+        self.buf.push_str(s);
+        self.buf.push(RESET);
+        self
+    }
+
+    /// Returns whether the last code written was only a foreground
+    /// color, which a comma could still extend.
+    fn push_colors(&mut self, fg: Color, bg: Option<Color>) -> bool {
+        // Both colors go out with the same code, so if they're
+        // different kinds, send them separately.
+        let same_kind = |a: &Color, b: &Color| {
+            match (*a, *b) {
+                (Color::Palette(_), Color::Palette(_)) | (Color::Rgb(..), Color::Rgb(..)) => true,
+                _ => false,
+            }
+        };
+        let code = |c: &Color| if let Color::Rgb(..) = *c { HEX_COLOR } else { COLOR };
+        match bg {
+            Some(ref bg) if same_kind(&fg, bg) => {
+                self.buf.push(code(&fg));
+                self.buf.push_str(&format!("{},{}", fg.format(), bg.format()));
+                false
+            },
+            Some(ref bg) => {
+                // Set the background first, with a throwaway
+                // foreground, then the real foreground.
+                self.buf.push(code(bg));
+                self.buf.push_str(&format!("{},{}", fg_placeholder(bg).format(), bg.format()));
+                self.buf.push(code(&fg));
+                self.buf.push_str(&fg.format());
+                true
+            },
+            None => {
+                self.buf.push(code(&fg));
+                self.buf.push_str(&fg.format());
+                true
+            },
+        }
+    }
+
+    /// Returns the formatted text.
+    pub fn build(self) -> String {
+        self.buf
+    }
+
+}
+
+fn fg_placeholder(bg: &Color) -> Color {
+    match *bg {
+        Color::Palette(_) => Color::Palette(1),
+        Color::Rgb(..) => Color::Rgb(0, 0, 0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{parse, strip, Color, Formatted, Span, Style};
+
+    /// Formats `s` in `style` and parses it back.
+    fn round_trip(style: &Style, s: &str) -> Vec<Span> {
+        parse(&Formatted::new().styled(style, s).build())
+    }
+
+    fn colored(fg: Color, bg: Option<Color>) -> Style {
+        Style{ fg: Some(fg), bg: bg, ..Style::default() }
+    }
+
+    #[test]
+    fn round_trip_colors() {
+        let styles = [
+            colored(Color::Palette(4), None),
+            colored(Color::Palette(4), Some(Color::Palette(12))),
+            colored(Color::Rgb(1, 2, 3), None),
+            colored(Color::Rgb(1, 2, 3), Some(Color::Rgb(0xAB, 0xCD, 0xEF))),
+            colored(Color::Palette(4), Some(Color::Rgb(0xAB, 0xCD, 0xEF))),
+            colored(Color::Rgb(1, 2, 3), Some(Color::Palette(12))),
+            Style{ bold: true, underline: true, ..colored(Color::Palette(98), Some(Color::Rgb(0, 0, 0))) },
+            ];
+        for style in styles.iter() {
+            for text in [",hello", ",12", "5 apples", "ABCDEF", "plain"].iter() {
+                assert_eq!(round_trip(style, text), vec![Span{ style: style.clone(), text: text.to_string() }],
+                           "{:?} {:?}", style, text);
+            }
+        }
+    }
+
+    #[test]
+    fn plain_text_round_trips() {
+        assert_eq!(round_trip(&Style::default(), ",x"), vec![Span{ style: Style::default(), text: ",x".to_string() }]);
+    }
+
+    fn span(style: Style, text: &str) -> Span {
+        Span{ style: style, text: text.to_string() }
+    }
+
+    #[test]
+    fn bold() {
+        let bold = Style{ bold: true, ..Style::default() };
+        assert_eq!(parse("\x02karma\x02 foo"), vec![span(bold, "karma"), span(Style::default(), " foo")]);
+        assert_eq!(strip("\x02karma\x02 foo"), "karma foo");
+    }
+
+    #[test]
+    fn bare_color_code_resets_colors() {
+        let bold = Style{ bold: true, ..Style::default() };
+        assert_eq!(parse("\x034,12red\x03plain"), vec![
+            span(colored(Color::Palette(4), Some(Color::Palette(12))), "red"),
+            span(Style::default(), "plain"),
+            ]);
+        // Only the colors; bold carries on.
+        assert_eq!(parse("\x02\x0304x\x03y"), vec![
+            span(Style{ bold: true, ..colored(Color::Palette(4), None) }, "x"),
+            span(bold, "y"),
+            ]);
+        assert_eq!(strip("\x034,12red\x03plain"), "redplain");
+    }
+
+    #[test]
+    fn reset() {
+        let style = Style{ bold: true, underline: true, ..colored(Color::Palette(3), None) };
+        assert_eq!(parse("\x02\x1f\x033both\x0fnone"), vec![span(style, "both"), span(Style::default(), "none")]);
+        assert_eq!(strip("\x02\x1f\x033both\x0fnone\x0f"), "bothnone");
+    }
+
+    #[test]
+    fn hex_colors() {
+        assert_eq!(parse("\x04FF0000,00ff00x\x04y"), vec![
+            span(colored(Color::Rgb(255, 0, 0), Some(Color::Rgb(0, 255, 0))), "x"),
+            span(Style::default(), "y"),
+            ]);
+        // Too few digits isn't a color, so it's a reset.
+        assert_eq!(strip("\x04FF00 short"), "FF00 short");
+    }
+
+    #[test]
+    fn commas_and_digits_after_colors() {
+        assert_eq!(parse("\x034,hello"), vec![span(colored(Color::Palette(4), None), ",hello")]);
+        assert_eq!(parse("\x03123"), vec![span(colored(Color::Palette(12), None), "3")]);
+        assert_eq!(parse("\x0304,051st"), vec![span(colored(Color::Palette(4), Some(Color::Palette(5))), "1st")]);
+        assert_eq!(strip("\x0304,05\x02rust\x02\x0f++"), "rust++");
+    }
+
+}
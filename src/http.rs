@@ -0,0 +1,469 @@
+//! Just enough HTTP for plugins that look things up on the web.
+//!
+//! `HttpClient` is what plugins use, so tests can give them something
+//! else.  `BasicClient` speaks HTTP/1.0 over TCP, or TLS for `https`,
+//! and won't connect to loopback, private or otherwise internal
+//! addresses unless told to, so people on IRC can't use the bot to poke
+//! at the network it runs on.  Redirects are checked the same way.
+//!
+//! Certificates aren't verified, so don't use it for anything that
+//! needs to be trusted.
+//!
+//! # Example:
+//! ```{.ignore .rust}
+//! use irc::http::{BasicClient, HttpClient, Limits, Url};
+//!
+//! let url = Url::parse("https://www.rust-lang.org/").unwrap();
+//! let page = try!(BasicClient::new().get(&url, &Limits::new()));
+//! ```
+
+use openssl::ssl::{SslContext, SslMethod, SslStream};
+use std::fmt;
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::channel;
+use std::thread;
+use time;
+use super::error::{Error, Result};
+
+/// Room for the status line and headers, on top of `Limits::max_bytes`.
+const MAX_HEADER_BYTES: usize = 16 * 1024;
+
+/// An `http` or `https` URL.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Url {
+    pub tls: bool,
+    /// Without brackets, for IPv6 addresses.
+    pub host: String,
+    pub port: u16,
+    /// Everything from the first `/`, including any query.
+    pub path: String,
+}
+
+impl Url {
+
+    /// Parses an absolute URL.  Fragments are dropped, and user info
+    /// isn't allowed.
+    pub fn parse(s: &str) -> Option<Url> {
+        let c = match regex!(r"(?i)^(https?)://(\[[0-9a-fA-F:.]+\]|[^/?#:@\[\]]+)(?::(\d{1,5}))?([/?][^#]*)?(?:#.*)?$").captures(s) {
+            Some(c) => c,
+            None => return None,
+        };
+        let tls = c.at(1).expect("Bad match group").to_lowercase() == "https";
+        let host = c.at(2).expect("Bad match group").trim_left_matches('[').trim_right_matches(']').to_lowercase();
+        let port = match c.at(3) {
+            Some(port) => match port.parse() {
+                Ok(port) => port,
+                Err(_) => return None,
+            },
+            None => if tls { 443 } else { 80 },
+        };
+        let path = match c.at(4) {
+            Some(path) if path.starts_with('/') => path.to_string(),
+            Some(query) => format!("/{}", query),
+            None => "/".to_string(),
+        };
+        Some(Url{
+            tls: tls,
+            host: host,
+            port: port,
+            path: path,
+        })
+    }
+
+    /// Resolves `location`, from a redirect, against this URL.
+    pub fn join(&self, location: &str) -> Option<Url> {
+        if regex!(r"(?i)^https?://").is_match(location) {
+            Url::parse(location)
+        } else if location.starts_with("//") {
+            Url::parse(&format!("{}:{}", if self.tls { "https" } else { "http" }, location))
+        } else if location.starts_with('/') {
+            Some(Url{ path: location.to_string(), ..self.clone() })
+        } else {
+            let dir = match self.path.rfind('/') {
+                Some(i) => &self.path[..i + 1],
+                None => "/",
+            };
+            Some(Url{ path: format!("{}{}", dir, location), ..self.clone() })
+        }
+    }
+
+    fn host_header(&self) -> String {
+        let host = if self.host.contains(':') { format!("[{}]", self.host) } else { self.host.clone() };
+        if self.port == if self.tls { 443 } else { 80 } {
+            host
+        } else {
+            format!("{}:{}", host, self.port)
+        }
+    }
+
+}
+
+impl fmt::Display for Url {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}://{}{}", if self.tls { "https" } else { "http" }, self.host_header(), self.path)
+    }
+
+}
+
+/// How much a request may fetch, and for how long.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Limits {
+    /// The most of a body to read.  Anything after is dropped.
+    pub max_bytes: usize,
+    /// How long to wait for responses, across all redirects.
+    pub timeout_ms: u32,
+    pub max_redirects: u32,
+}
+
+impl Limits {
+
+    /// 64 KiB in 5 seconds, with up to 3 redirects.
+    pub fn new() -> Limits {
+        Limits{
+            max_bytes: 64 * 1024,
+            timeout_ms: 5000,
+            max_redirects: 3,
+        }
+    }
+
+}
+
+/// A successful response.
+#[derive(Clone, Debug)]
+pub struct Page {
+    /// Where it ended up, after redirects.
+    pub url: Url,
+    pub content_type: Option<String>,
+    /// Up to `Limits::max_bytes` of it.
+    pub body: Vec<u8>,
+}
+
+/// Fetches pages.
+pub trait HttpClient: Send + Sync {
+
+    /// GETs `url`, following redirects.  Statuses other than 2xx are
+    /// errors.
+    fn get(&self, url: &Url, limits: &Limits) -> Result<Page>;
+
+}
+
+/// Whether `ip` is on the public internet.
+pub fn is_public_v4(ip: &Ipv4Addr) -> bool {
+    let o = ip.octets();
+    match (o[0], o[1], o[2]) {
+        (0, _, _) | (10, _, _) | (127, _, _) => false,
+        (100, b, _) if b >= 64 && b < 128 => false,
+        (169, 254, _) => false,
+        (172, b, _) if b >= 16 && b < 32 => false,
+        (192, 0, 0) | (192, 0, 2) | (192, 168, _) => false,
+        (198, 18, _) | (198, 19, _) | (198, 51, 100) | (203, 0, 113) => false,
+        // Multicast and reserved, including broadcast.
+        (a, _, _) if a >= 224 => false,
+        _ => true,
+    }
+}
+
+/// Whether `ip` is on the public internet.  IPv4 addresses mapped into
+/// IPv6 are judged as IPv4.
+pub fn is_public_v6(ip: &Ipv6Addr) -> bool {
+    let s = ip.segments();
+    let embedded = Ipv4Addr::new((s[6] >> 8) as u8, s[6] as u8, (s[7] >> 8) as u8, s[7] as u8);
+    match (s[0], s[1], s[2], s[3], s[4], s[5]) {
+        // Unspecified and loopback.
+        (0, 0, 0, 0, 0, 0) if s[6] == 0 && s[7] <= 1 => false,
+        // Mapped, and translated by NAT64.
+        (0, 0, 0, 0, 0, 0xffff) | (0x64, 0xff9b, 0, 0, 0, 0) => is_public_v4(&embedded),
+        // Unique local, link local and multicast.
+        (a, _, _, _, _, _) if a & 0xfe00 == 0xfc00 || a & 0xffc0 == 0xfe80 || a & 0xff00 == 0xff00 => false,
+        // Documentation.
+        (0x2001, 0xdb8, _, _, _, _) => false,
+        _ => true,
+    }
+}
+
+/// Whether `addr` is on the public internet.
+pub fn is_public(addr: &SocketAddr) -> bool {
+    match *addr {
+        SocketAddr::V4(ref a) => is_public_v4(a.ip()),
+        SocketAddr::V6(ref a) => is_public_v6(a.ip()),
+    }
+}
+
+/// Shuts a socket down if it's still open after a deadline, which makes
+/// any blocked reads or writes on it fail.
+struct Watchdog {
+    done: Arc<AtomicBool>,
+}
+
+impl Watchdog {
+
+    fn start(socket: &TcpStream, timeout_ms: u32) -> Result<Watchdog> {
+        let done = Arc::new(AtomicBool::new(false));
+        let socket = try!(socket.try_clone());
+        let watching = done.clone();
+        thread::spawn(move || {
+            thread::sleep_ms(timeout_ms);
+            if !watching.load(Ordering::SeqCst) {
+                let _ = socket.shutdown(Shutdown::Both);
+            }
+        });
+        Ok(Watchdog{
+            done: done,
+        })
+    }
+
+}
+
+impl Drop for Watchdog {
+
+    fn drop(&mut self) {
+        self.done.store(true, Ordering::SeqCst);
+    }
+
+}
+
+/// Connects to `addr`, giving up after `timeout_ms` milliseconds.
+/// Connecting can't be timed out directly, so it happens on another
+/// thread, which is left to finish on its own if it's too slow.
+fn connect(addr: SocketAddr, timeout_ms: u32) -> Result<TcpStream> {
+    let (connected_tx, connected) = channel();
+    let timer_tx = connected_tx.clone();
+    thread::spawn(move || {
+        let _ = connected_tx.send(Some(TcpStream::connect(&addr)));
+    });
+    thread::spawn(move || {
+        thread::sleep_ms(timeout_ms);
+        let _ = timer_tx.send(None);
+    });
+    match connected.recv() {
+        Ok(Some(result)) => Ok(try!(result)),
+        _ => Err(Error::Timeout(format!("connecting to {}", addr))),
+    }
+}
+
+/// A response's status and the headers we care about.
+struct Head {
+    status: u32,
+    location: Option<String>,
+    content_type: Option<String>,
+}
+
+fn parse_head(head: &str) -> Result<Head> {
+    let mut lines = head.split("\r\n");
+    let status = match lines.next().and_then(|line| regex!(r"^HTTP/\d\.\d\s+(\d{3})").captures(line)) {
+        Some(c) => c.at(1).expect("Bad match group").parse().unwrap_or(0),
+        None => return Err(Error::Http("bad status line".to_string())),
+    };
+    let mut parsed = Head{
+        status: status,
+        location: None,
+        content_type: None,
+    };
+    for line in lines {
+        let mut parts = line.splitn(2, ':');
+        let name = parts.next().unwrap_or("").trim().to_lowercase();
+        let value = parts.next().unwrap_or("").trim().to_string();
+        match &name[..] {
+            "location" => parsed.location = Some(value),
+            "content-type" => parsed.content_type = Some(value),
+            _ => (),
+        }
+    }
+    Ok(parsed)
+}
+
+/// Reads a response, stopping once there's `max_bytes` of body.
+fn read_response<S: Read>(stream: S, max_bytes: usize) -> Result<(Head, Vec<u8>)> {
+    let mut response = Vec::new();
+    try!(stream.take((MAX_HEADER_BYTES + max_bytes) as u64).read_to_end(&mut response));
+    let end = match response.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(end) => end,
+        None => return Err(Error::Http("no end to the headers".to_string())),
+    };
+    let head = try!(parse_head(&String::from_utf8_lossy(&response[..end])));
+    let mut body = response[end + 4..].to_vec();
+    body.truncate(max_bytes);
+    Ok((head, body))
+}
+
+/// Fetches pages over plain TCP or TLS.
+pub struct BasicClient {
+    allow_private: bool,
+}
+
+impl BasicClient {
+
+    /// A client that only connects to public addresses.
+    pub fn new() -> BasicClient {
+        BasicClient{
+            allow_private: false,
+        }
+    }
+
+    /// A client that connects anywhere, for talking to servers on the
+    /// same machine or network.
+    pub fn allowing_private() -> BasicClient {
+        BasicClient{
+            allow_private: true,
+        }
+    }
+
+    /// Where `url`'s host is, if we're allowed to go there.
+    fn address(&self, url: &Url) -> Result<SocketAddr> {
+        let addrs: Vec<SocketAddr> = try!((&url.host[..], url.port).to_socket_addrs()).collect();
+        if addrs.is_empty() {
+            return Err(Error::Http(format!("{} has no addresses", url.host)));
+        }
+        // Refuse if any are internal, so a name can't hide one behind
+        // another.
+        if !self.allow_private && addrs.iter().any(|a| !is_public(a)) {
+            return Err(Error::Http(format!("{} isn't a public address", url.host)));
+        }
+        Ok(addrs[0])
+    }
+
+    /// One request, without following redirects.
+    fn request(&self, url: &Url, limits: &Limits, timeout_ms: u32) -> Result<(Head, Vec<u8>)> {
+        let started_ms = time::precise_time_ns() / 1000000;
+        let addr = try!(self.address(url));
+        // Connect to the address we checked, rather than letting the
+        // name be looked up again.
+        let socket = try!(connect(addr, timeout_ms));
+        let elapsed_ms = time::precise_time_ns() / 1000000 - started_ms;
+        let _watchdog = try!(Watchdog::start(&socket, timeout_ms.saturating_sub(elapsed_ms as u32)));
+        let request = format!("GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: hiphopabotamus\r\nAccept: text/html, */*;q=0.5\r\nConnection: close\r\n\r\n",
+                              url.path, url.host_header());
+        if url.tls {
+            let ctx = try!(SslContext::new(SslMethod::Sslv23).map_err(|e| Error::Tls(e.to_string())));
+            let mut tls = try!(SslStream::new(&ctx, socket).map_err(|e| Error::Tls(e.to_string())));
+            try!(tls.write_all(request.as_bytes()));
+            read_response(tls, limits.max_bytes)
+        } else {
+            let mut socket = socket;
+            try!(socket.write_all(request.as_bytes()));
+            read_response(socket, limits.max_bytes)
+        }
+    }
+
+}
+
+impl HttpClient for BasicClient {
+
+    fn get(&self, url: &Url, limits: &Limits) -> Result<Page> {
+        let mut url = url.clone();
+        let mut redirects = 0;
+        let started_ms = time::precise_time_ns() / 1000000;
+        loop {
+            let elapsed_ms = time::precise_time_ns() / 1000000 - started_ms;
+            if elapsed_ms >= limits.timeout_ms as u64 {
+                return Err(Error::Timeout(format!("{}", url)));
+            }
+            let (head, body) = try!(self.request(&url, limits, limits.timeout_ms - elapsed_ms as u32));
+            match (head.status, head.location) {
+                (200...299, _) => {
+                    return Ok(Page{
+                        url: url,
+                        content_type: head.content_type,
+                        body: body,
+                    });
+                },
+                (300...399, Some(ref location)) if redirects < limits.max_redirects => {
+                    url = match url.join(location) {
+                        Some(next) => next,
+                        None => return Err(Error::Http(format!("bad redirect to {}", location))),
+                    };
+                    redirects += 1;
+                },
+                (status, _) => return Err(Error::Http(format!("{} returned {}", url, status))),
+            }
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::{Ipv4Addr, Ipv6Addr, TcpListener};
+    use std::thread;
+    use super::{is_public_v4, is_public_v6, BasicClient, HttpClient, Limits, Url};
+    use super::super::error::Error;
+
+    #[test]
+    fn parse_urls() {
+        let url = Url::parse("HTTPS://Example.com:8443/a/b?c=d#e").unwrap();
+        assert_eq!(url, Url{ tls: true, host: "example.com".to_string(), port: 8443, path: "/a/b?c=d".to_string() });
+        assert_eq!(url.to_string(), "https://example.com:8443/a/b?c=d");
+        assert_eq!(Url::parse("http://[::1]/").unwrap().host, "::1");
+        assert_eq!(Url::parse("http://example.com").unwrap().path, "/");
+        assert_eq!(Url::parse("http://user@example.com/"), None);
+        assert_eq!(Url::parse("ftp://example.com/"), None);
+        assert_eq!(url.join("/x").unwrap().to_string(), "https://example.com:8443/x");
+        assert_eq!(url.join("x").unwrap().to_string(), "https://example.com:8443/a/x");
+        assert_eq!(url.join("//other.org/").unwrap().to_string(), "https://other.org/");
+    }
+
+    #[test]
+    fn public_addresses() {
+        for &(ip, public) in [([8, 8, 8, 8], true), ([127, 0, 0, 1], false), ([10, 1, 2, 3], false),
+                              ([172, 20, 0, 1], false), ([172, 32, 0, 1], true), ([192, 168, 1, 1], false),
+                              ([169, 254, 169, 254], false), ([100, 64, 0, 1], false), ([0, 0, 0, 0], false),
+                              ([224, 0, 0, 1], false)].iter() {
+            assert_eq!(is_public_v4(&Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3])), public);
+        }
+        assert!(!is_public_v6(&Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)));
+        assert!(!is_public_v6(&Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1)));
+        assert!(!is_public_v6(&Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1)));
+        assert!(!is_public_v6(&Ipv6Addr::new(0, 0, 0, 0, 0, 0xffff, 0x7f00, 1)));
+        assert!(is_public_v6(&Ipv6Addr::new(0x2a00, 0x1450, 0, 0, 0, 0, 0, 1)));
+    }
+
+    /// Serves `responses` in order on a local port, returning its URL.
+    fn serve(responses: Vec<String>) -> Url {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            for response in responses.into_iter() {
+                let mut conn = listener.accept().unwrap().0;
+                let mut reader = BufReader::new(conn.try_clone().unwrap());
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 0 && line != "\r\n" {
+                    line.clear();
+                }
+                conn.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        Url::parse(&format!("http://127.0.0.1:{}/start", port)).unwrap()
+    }
+
+    #[test]
+    fn fetch_with_redirect_and_limit() {
+        let url = serve(vec![
+            "HTTP/1.0 302 Found\r\nLocation: /page\r\n\r\n".to_string(),
+            "HTTP/1.0 200 OK\r\nContent-Type: text/html\r\n\r\n<title>Hello</title>and then some".to_string(),
+            ]);
+        let limits = Limits{ max_bytes: 20, ..Limits::new() };
+        let page = BasicClient::allowing_private().get(&url, &limits).unwrap();
+        assert_eq!(page.url.path, "/page");
+        assert_eq!(page.content_type, Some("text/html".to_string()));
+        assert_eq!(page.body, b"<title>Hello</title>".to_vec());
+    }
+
+    #[test]
+    fn refuse_private_addresses() {
+        let url = serve(vec!["HTTP/1.0 200 OK\r\n\r\nhello".to_string()]);
+        match BasicClient::new().get(&url, &Limits::new()) {
+            Err(Error::Http(ref msg)) => assert_eq!(msg, "127.0.0.1 isn't a public address"),
+            Err(e) => panic!("Wrong error: {}", e),
+            Ok(_) => panic!("Fetched a private address"),
+        }
+        // It was there to be fetched.
+        assert_eq!(BasicClient::allowing_private().get(&url, &Limits::new()).unwrap().body, b"hello".to_vec());
+    }
+
+}
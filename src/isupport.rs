@@ -0,0 +1,232 @@
+//! What the server told us about itself in RPL_ISUPPORT (005).
+
+use std::ascii::AsciiExt;
+use std::collections::HashMap;
+use super::protocol::Message;
+
+/// How the server folds case in nicks and channel names.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Casemapping {
+    /// Only `A-Z` fold to `a-z`.
+    Ascii,
+    /// Like `Rfc1459`, but `~` and `^` are unrelated.
+    StrictRfc1459,
+    /// `A-Z[]\~` fold to `a-z{}|^`.  The default.
+    Rfc1459,
+}
+
+impl Casemapping {
+
+    /// Folds `s` to lower case, so two names are the same if their
+    /// normalized forms are equal.
+    pub fn normalize(&self, s: &str) -> String {
+        s.chars().map(|c| {
+            match (*self, c) {
+                (_, 'A'...'Z') => c.to_ascii_lowercase(),
+                (Casemapping::Ascii, _) => c,
+                (_, '[') => '{',
+                (_, ']') => '}',
+                (_, '\\') => '|',
+                (Casemapping::Rfc1459, '~') => '^',
+                _ => c,
+            }
+        }).collect()
+    }
+
+    /// Whether `a` and `b` are the same name.
+    pub fn same_name(&self, a: &str, b: &str) -> bool {
+        self.normalize(a) == self.normalize(b)
+    }
+
+}
+
+/// The four kinds of channel modes from the `CHANMODES` token.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChanModes {
+    /// Type A: list modes like `b`, which always take a parameter
+    /// (except when listing).
+    pub list: String,
+    /// Type B: modes like `k`, which always take a parameter.
+    pub always: String,
+    /// Type C: modes like `l`, which take a parameter only when set.
+    pub when_set: String,
+    /// Type D: flags like `m`, which never take a parameter.
+    pub never: String,
+}
+
+/// The server's RPL_ISUPPORT tokens, with the RFC defaults for
+/// anything it didn't send.
+#[derive(Clone, Debug)]
+pub struct ISupport {
+    tokens: HashMap<String, Option<String>>,
+}
+
+impl ISupport {
+
+    pub fn new() -> ISupport {
+        ISupport{ tokens: HashMap::new() }
+    }
+
+    /// Updates from an RPL_ISUPPORT line.  Ignores other lines.
+    pub fn update(&mut self, msg: &Message) {
+        if msg.command != "005" || msg.params.len() < 2 {
+            return;
+        }
+        // The first parameter is our nick and the last is
+        // "are supported by this server".
+        for token in msg.params[1..msg.params.len() - 1].iter() {
+            if token.starts_with('-') {
+                self.tokens.remove(&token[1..]);
+            } else {
+                let mut parts = token.splitn(2, '=');
+                let key = parts.next().unwrap_or("").to_string();
+                let value = parts.next().map(|v| v.to_string());
+                self.tokens.insert(key, value);
+            }
+        }
+    }
+
+    /// The raw value of a token: `None` if it wasn't sent,
+    /// `Some(None)` if it was sent without a value.
+    pub fn get(&self, key: &str) -> Option<Option<&str>> {
+        self.tokens.get(key).map(|v| v.as_ref().map(|s| &s[..]))
+    }
+
+    pub fn casemapping(&self) -> Casemapping {
+        match self.get("CASEMAPPING") {
+            Some(Some("ascii")) => Casemapping::Ascii,
+            Some(Some("strict-rfc1459")) => Casemapping::StrictRfc1459,
+            _ => Casemapping::Rfc1459,
+        }
+    }
+
+    pub fn chanmodes(&self) -> ChanModes {
+        let value = match self.get("CHANMODES") {
+            Some(Some(v)) => v,
+            _ => "beI,k,l,imnpst",
+        };
+        let mut kinds = value.split(',').map(|s| s.to_string());
+        ChanModes{
+            list: kinds.next().unwrap_or(String::new()),
+            always: kinds.next().unwrap_or(String::new()),
+            when_set: kinds.next().unwrap_or(String::new()),
+            never: kinds.next().unwrap_or(String::new()),
+        }
+    }
+
+    /// Channel membership modes and their nick prefixes, highest
+    /// rank first, e.g. `[('o', '@'), ('v', '+')]`.
+    pub fn prefix(&self) -> Vec<(char, char)> {
+        let value = match self.get("PREFIX") {
+            Some(Some(v)) => v,
+            Some(None) => "",
+            None => "(ov)@+",
+        };
+        match (value.find('('), value.find(')')) {
+            (Some(0), Some(close)) => {
+                value[1..close].chars().zip(value[close + 1..].chars()).collect()
+            },
+            _ => Vec::new(),
+        }
+    }
+
+    /// The characters channel names may start with.
+    pub fn chantypes(&self) -> String {
+        match self.get("CHANTYPES") {
+            Some(Some(v)) => v.to_string(),
+            Some(None) => String::new(),
+            None => "#&".to_string(),
+        }
+    }
+
+    /// Whether `name` is a channel rather than a nick.
+    pub fn is_channel(&self, name: &str) -> bool {
+        name.chars().next().map_or(false, |c| self.chantypes().contains(c))
+    }
+
+    /// How many parameterized mode changes may go in one MODE
+    /// command, or `None` if there's no limit.
+    pub fn modes(&self) -> Option<usize> {
+        match self.get("MODES") {
+            Some(Some(v)) => v.parse().ok(),
+            Some(None) => None,
+            None => Some(3),
+        }
+    }
+
+    /// How many targets `command` accepts at once, or `None` if
+    /// there's no limit.
+    pub fn targmax(&self, command: &str) -> Option<usize> {
+        let value = match self.get("TARGMAX") {
+            Some(Some(v)) => v,
+            _ => return None,
+        };
+        value.split(',').filter_map(|pair| {
+            let mut parts = pair.splitn(2, ':');
+            match (parts.next(), parts.next()) {
+                (Some(c), Some(n)) if c.eq_ignore_ascii_case(command) => Some(n.parse().ok()),
+                _ => None,
+            }
+        }).next().unwrap_or(None)
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{Casemapping, ChanModes, ISupport};
+    use super::super::protocol::Message;
+
+    fn isupport(tokens: &str) -> ISupport {
+        let mut isupport = ISupport::new();
+        let line = format!(":irc.example.net 005 bot {} :are supported by this server", tokens);
+        isupport.update(&Message::parse(&line).unwrap());
+        isupport
+    }
+
+    #[test]
+    fn chanmodes() {
+        assert_eq!(isupport("CHANMODES=beIq,k,fjl,CMNOimnpst").chanmodes(), ChanModes{
+            list: "beIq".to_string(),
+            always: "k".to_string(),
+            when_set: "fjl".to_string(),
+            never: "CMNOimnpst".to_string(),
+        });
+        assert_eq!(ISupport::new().chanmodes().list, "beI");
+    }
+
+    #[test]
+    fn prefix() {
+        assert_eq!(isupport("PREFIX=(qaohv)~&@%+").prefix(),
+                   vec![('q', '~'), ('a', '&'), ('o', '@'), ('h', '%'), ('v', '+')]);
+        assert_eq!(ISupport::new().prefix(), vec![('o', '@'), ('v', '+')]);
+        assert!(isupport("PREFIX").prefix().is_empty());
+    }
+
+    #[test]
+    fn casemapping() {
+        assert_eq!(isupport("CASEMAPPING=ascii").casemapping(), Casemapping::Ascii);
+        assert_eq!(isupport("CASEMAPPING=strict-rfc1459").casemapping(), Casemapping::StrictRfc1459);
+        assert_eq!(isupport("CASEMAPPING=rfc1459").casemapping(), Casemapping::Rfc1459);
+        assert_eq!(ISupport::new().casemapping(), Casemapping::Rfc1459);
+    }
+
+    #[test]
+    fn removed_tokens() {
+        let mut isupport = isupport("CASEMAPPING=ascii MODES=4");
+        isupport.update(&Message::parse(":irc.example.net 005 bot -CASEMAPPING :are supported by this server").unwrap());
+        assert_eq!(isupport.casemapping(), Casemapping::Rfc1459);
+        assert_eq!(isupport.modes(), Some(4));
+    }
+
+    #[test]
+    fn rfc1459_folding() {
+        assert_eq!(Casemapping::Rfc1459.normalize("Nick[Away]\\~"), "nick{away}|^");
+        assert_eq!(Casemapping::StrictRfc1459.normalize("Nick[Away]\\~"), "nick{away}|~");
+        assert_eq!(Casemapping::Ascii.normalize("Nick[Away]\\~"), "nick[away]\\~");
+        assert!(Casemapping::Rfc1459.same_name("[bot]~", "{BOT}^"));
+        assert!(!Casemapping::Ascii.same_name("[bot]", "{bot}"));
+    }
+
+}
@@ -0,0 +1,486 @@
+//! Joining channels and finding out whether it worked.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::result;
+use std::thread;
+use time;
+use super::command::Command;
+use super::error::{Error, Result};
+use super::event_stream::{Action, EventStream, HandlerAction, HandlerOptions, Response, INTERNAL_PRIORITY};
+use super::isupport::ISupport;
+use super::protocol::Message;
+use super::scheduler::Schedule;
+
+/// How long to wait for the server to answer a round of JOINs or PARTs.
+const REPLY_TIMEOUT_MS: u32 = 30 * 1000;
+/// The longest line we'll build, leaving room for the line ending.
+const MAX_LINE_LEN: usize = 510;
+
+/// Why we couldn't join a channel.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum JoinError {
+    /// ERR_BANNEDFROMCHAN (474).
+    Banned,
+    /// ERR_INVITEONLYCHAN (473).
+    InviteOnly,
+    /// ERR_CHANNELISFULL (471).
+    Full,
+    /// ERR_BADCHANNELKEY (475).
+    BadKey,
+    /// ERR_NOSUCHCHANNEL (403) or ERR_BADCHANMASK (476).
+    NoSuchChannel,
+    /// ERR_TOOMANYCHANNELS (405).
+    TooManyChannels,
+    /// Some other refusal.  Contains the server's reply.
+    Other(String),
+    /// The server never answered.
+    TimedOut,
+}
+
+impl JoinError {
+
+    /// Whether trying again later might work.
+    pub fn is_transient(&self) -> bool {
+        match *self {
+            JoinError::BadKey | JoinError::NoSuchChannel => false,
+            _ => true,
+        }
+    }
+
+}
+
+impl fmt::Display for JoinError {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            JoinError::Banned => write!(f, "banned"),
+            JoinError::InviteOnly => write!(f, "invite only"),
+            JoinError::Full => write!(f, "channel is full"),
+            JoinError::BadKey => write!(f, "wrong key"),
+            JoinError::NoSuchChannel => write!(f, "no such channel"),
+            JoinError::TooManyChannels => write!(f, "joined too many channels"),
+            JoinError::Other(ref line) => write!(f, "refused: {}", line),
+            JoinError::TimedOut => write!(f, "timed out"),
+        }
+    }
+
+}
+
+/// How one channel's join went.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct JoinOutcome {
+    pub channel: String,
+    pub result: result::Result<(), JoinError>,
+}
+
+/// What to do when a join fails.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct JoinPolicy {
+    /// How many more times to try channels whose failure might be
+    /// temporary.
+    pub retries: u32,
+    /// How long to wait before retrying.
+    pub retry_delay_ms: u32,
+    /// For invite-only channels, keep watching for an INVITE and join
+    /// when one arrives, in the background.
+    pub wait_for_invite: bool,
+    /// How long to wait before rejoining a channel we were kicked
+    /// from, or `None` to stay out.
+    pub rejoin_delay_ms: Option<u32>,
+}
+
+impl JoinPolicy {
+
+    /// Try once, don't wait for invites, don't rejoin after kicks.
+    pub fn new() -> JoinPolicy {
+        JoinPolicy{
+            retries: 0,
+            retry_delay_ms: 60 * 1000,
+            wait_for_invite: false,
+            rejoin_delay_ms: None,
+        }
+    }
+
+}
+
+/// Why we couldn't leave a channel.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PartError {
+    /// ERR_NOTONCHANNEL (442).
+    NotOnChannel,
+    /// ERR_NOSUCHCHANNEL (403).
+    NoSuchChannel,
+    /// Some other refusal.  Contains the server's reply.
+    Other(String),
+    /// The server never answered.
+    TimedOut,
+}
+
+impl fmt::Display for PartError {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PartError::NotOnChannel => write!(f, "not on channel"),
+            PartError::NoSuchChannel => write!(f, "no such channel"),
+            PartError::Other(ref line) => write!(f, "refused: {}", line),
+            PartError::TimedOut => write!(f, "timed out"),
+        }
+    }
+
+}
+
+/// How leaving one channel went.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PartOutcome {
+    pub channel: String,
+    pub result: result::Result<(), PartError>,
+}
+
+/// Builds JOIN commands for `channels`, with no more channels per
+/// line than the server's `TARGMAX` allows and no line too long to
+/// send.  Keyed channels go first in each line, since keys apply to
+/// channels in order.
+pub fn join_commands(channels: &[(String, Option<String>)], isupport: &ISupport) -> Vec<Command> {
+    let keyed = channels.iter().filter(|c| c.1.is_some());
+    let unkeyed = channels.iter().filter(|c| c.1.is_none());
+    let ordered: Vec<(&str, Option<&str>)> = keyed.chain(unkeyed)
+        .map(|&(ref chan, ref key)| (&chan[..], key.as_ref().map(|k| &k[..])))
+        .collect();
+    batch(&ordered, isupport.targmax("JOIN"), |chans, keys| {
+        Command::Join(chans.iter().map(|c| c.to_string()).collect(), keys.iter().map(|k| k.to_string()).collect())
+    })
+}
+
+/// Builds PART commands for `channels`, the same way as
+/// `join_commands`.
+pub fn part_commands(channels: &[String], reason: Option<&str>, isupport: &ISupport) -> Vec<Command> {
+    let targets: Vec<(&str, Option<&str>)> = channels.iter().map(|chan| (&chan[..], None)).collect();
+    batch(&targets, isupport.targmax("PART"), |chans, _| {
+        Command::Part(chans.iter().map(|c| c.to_string()).collect(), reason.map(|r| r.to_string()))
+    })
+}
+
+/// Groups channels and their keys into as few commands as `format`
+/// can make of them without going over `max_targets` or the line
+/// length.
+fn batch<F>(channels: &[(&str, Option<&str>)], max_targets: Option<usize>, format: F) -> Vec<Command>
+    where F: Fn(&[&str], &[&str]) -> Command {
+    let mut commands = Vec::new();
+    let mut chans: Vec<&str> = Vec::new();
+    let mut keys: Vec<&str> = Vec::new();
+    for &(chan, key) in channels.iter() {
+        let mut next_chans = chans.clone();
+        next_chans.push(chan);
+        let mut next_keys = keys.clone();
+        next_keys.extend(key);
+        let full = max_targets.map_or(false, |max| next_chans.len() > max);
+        if !chans.is_empty() && (full || format(&next_chans, &next_keys).to_string().len() > MAX_LINE_LEN) {
+            commands.push(format(&chans, &keys));
+            next_chans = vec![chan];
+            next_keys = key.into_iter().collect();
+        }
+        chans = next_chans;
+        keys = next_keys;
+    }
+    if !chans.is_empty() {
+        commands.push(format(&chans, &keys));
+    }
+    commands
+}
+
+fn join_error(msg: &Message, line: &str) -> JoinError {
+    match msg.command {
+        "471" => JoinError::Full,
+        "473" => JoinError::InviteOnly,
+        "474" => JoinError::Banned,
+        "475" => JoinError::BadKey,
+        "403" | "476" => JoinError::NoSuchChannel,
+        "405" => JoinError::TooManyChannels,
+        _ => JoinError::Other(line.to_string()),
+    }
+}
+
+/// Joins `channels`, each with an optional key, as `nick`, and waits
+/// for the server to accept or refuse each one.  Transient failures
+/// are retried in the background according to `policy`; the outcomes
+/// returned are from the first try.
+///
+/// Only fails if we lose the connection; refusals are reported per
+/// channel.
+pub fn join(stream: &mut EventStream, nick: &str, channels: &[(String, Option<String>)],
+            isupport: &ISupport, policy: &JoinPolicy) -> Result<Vec<JoinOutcome>> {
+    let casemapping = isupport.casemapping();
+    let mut outcomes: HashMap<String, JoinOutcome> = HashMap::new();
+    for (chan, result) in try!(join_once(stream, nick, channels, isupport)).into_iter() {
+        outcomes.insert(casemapping.normalize(&chan), JoinOutcome{ channel: chan, result: result });
+    }
+
+    let retry = channels.iter().filter(|&&(ref chan, _)| {
+        match outcomes.get(&casemapping.normalize(chan)) {
+            Some(&JoinOutcome{ result: Err(ref e), .. }) => should_retry(e, policy),
+            _ => false,
+        }
+    }).cloned().collect::<Vec<_>>();
+    if !retry.is_empty() && policy.retries > 0 {
+        schedule_retry(stream, nick, retry, isupport, policy, 1);
+    }
+
+    if policy.wait_for_invite {
+        for outcome in outcomes.values().filter(|o| o.result == Err(JoinError::InviteOnly)) {
+            try!(wait_for_invite(stream, nick, &outcome.channel, isupport));
+        }
+    }
+
+    Ok(channels.iter().map(|&(ref chan, _)| {
+        outcomes.remove(&casemapping.normalize(chan)).unwrap_or(JoinOutcome{
+            channel: chan.clone(),
+            result: Err(JoinError::TimedOut),
+        })
+    }).collect())
+}
+
+/// Whether a channel that failed with `e` is worth another try.
+fn should_retry(e: &JoinError, policy: &JoinPolicy) -> bool {
+    e.is_transient() && !(policy.wait_for_invite && *e == JoinError::InviteOnly)
+}
+
+/// Tries `channels` again after `policy.retry_delay_ms`, and again
+/// after that until `attempt` reaches `policy.retries`.  The JOINs go
+/// out from a thread of their own, since waiting for the replies on
+/// the event loop would stop them arriving.
+fn schedule_retry(stream: &mut EventStream, nick: &str, channels: Vec<(String, Option<String>)>,
+                  isupport: &ISupport, policy: &JoinPolicy, attempt: u32) {
+    info!("Retrying {} channels in {}ms...", channels.len(), policy.retry_delay_ms);
+    let nick = nick.to_string();
+    let isupport = isupport.clone();
+    let policy = policy.clone();
+    let mut channels = Some(channels);
+    stream.schedule("retry joins", Schedule::After(policy.retry_delay_ms), box move |stream: &mut EventStream| {
+        let channels = match channels.take() {
+            Some(channels) => channels,
+            None => return,
+        };
+        let mut stream = stream.clone();
+        let nick = nick.clone();
+        let isupport = isupport.clone();
+        let policy = policy.clone();
+        thread::spawn(move || {
+            let results = match join_once(&mut stream, &nick, &channels, &isupport) {
+                Ok(results) => results,
+                Err(e) => {
+                    error!("Error retrying joins: {}", e);
+                    return;
+                },
+            };
+            let retry = results.into_iter().filter_map(|(chan, result)| {
+                match result {
+                    Err(ref e) if should_retry(e, &policy) => {
+                        channels.iter().find(|c| c.0 == chan).cloned()
+                    },
+                    _ => None,
+                }
+            }).collect::<Vec<_>>();
+            if !retry.is_empty() && attempt < policy.retries {
+                schedule_retry(&mut stream, &nick, retry, &isupport, &policy, attempt + 1);
+            }
+        });
+    });
+}
+
+/// Sends one round of JOINs and collects the answers.
+fn join_once(stream: &mut EventStream, nick: &str, channels: &[(String, Option<String>)],
+             isupport: &ISupport) -> Result<Vec<(String, result::Result<(), JoinError>)>> {
+    let names: Vec<String> = channels.iter().map(|&(ref chan, _)| chan.clone()).collect();
+    let commands = join_commands(channels, isupport);
+    let results = try!(collect_replies(stream, nick, isupport, "JOIN", &names, &commands,
+                                       &["403", "405", "437", "470", "471", "472", "473", "474", "475", "476", "477", "478", "479"],
+                                       JoinError::TimedOut, join_error));
+    for &(ref chan, ref result) in results.iter() {
+        match *result {
+            Ok(()) => info!("Joined channel {}!", chan),
+            Err(ref e) => warn!("Couldn't join {}: {}", chan, e),
+        }
+    }
+    Ok(results)
+}
+
+/// Leaves `channels` as `nick`, saying `reason` if given, and waits
+/// for the server to confirm or refuse each one.
+///
+/// Only fails if we lose the connection; refusals are reported per
+/// channel.
+pub fn part(stream: &mut EventStream, nick: &str, channels: &[String], reason: Option<&str>,
+            isupport: &ISupport) -> Result<Vec<PartOutcome>> {
+    let commands = part_commands(channels, reason, isupport);
+    let results = try!(collect_replies(stream, nick, isupport, "PART", channels, &commands,
+                                       &["403", "442"], PartError::TimedOut, |msg, line| {
+        match msg.command {
+            "403" => PartError::NoSuchChannel,
+            "442" => PartError::NotOnChannel,
+            _ => PartError::Other(line.to_string()),
+        }
+    }));
+    Ok(results.into_iter().map(|(chan, result)| {
+        match result {
+            Ok(()) => info!("Left channel {}!", chan),
+            Err(ref e) => warn!("Couldn't leave {}: {}", chan, e),
+        }
+        PartOutcome{ channel: chan, result: result }
+    }).collect())
+}
+
+/// Sends `commands`, then waits for `command` to be echoed back from
+/// `nick` for each of `channels`, or for an error reply in `errors`, which `classify` turns into an `E`.  Channels the server
+/// doesn't answer for in time get `timed_out`.
+fn collect_replies<E, F>(stream: &mut EventStream, nick: &str, isupport: &ISupport,
+                         command: &str, channels: &[String], commands: &[Command],
+                         errors: &[&str], timed_out: E, classify: F) -> Result<Vec<(String, result::Result<(), E>)>>
+    where E: Clone, F: Fn(&Message, &str) -> E {
+    let casemapping = isupport.casemapping();
+    let mut waiting: HashMap<String, String> = channels.iter()
+        .map(|chan| (casemapping.normalize(chan), chan.clone()))
+        .collect();
+    let mut results = Vec::new();
+
+    let mut expected = vec![command];
+    expected.extend(errors.iter().cloned());
+    let mut watch = try!(stream.watch_commands(&expected));
+    for c in commands.iter() {
+        info!("Sending: {}", c);
+        try!(stream.send(c));
+    }
+
+    let deadline = time::precise_time_ns() + REPLY_TIMEOUT_MS as u64 * 1000000;
+    while !waiting.is_empty() {
+        let now = time::precise_time_ns();
+        if now >= deadline {
+            break;
+        }
+        let line = match watch.next(((deadline - now) / 1000000) as u32) {
+            Ok(line) => line,
+            Err(Error::Timeout(_)) => break,
+            Err(e) => return Err(e),
+        };
+        let msg = match Message::parse(&line) {
+            Some(msg) => msg,
+            None => continue,
+        };
+        let (chan, result) = if msg.command == command {
+            if !msg.source_nick().map_or(false, |n| casemapping.same_name(n, nick)) {
+                continue;
+            }
+            match msg.params.get(0) {
+                Some(chan) => (*chan, Ok(())),
+                None => continue,
+            }
+        } else {
+            match msg.params.get(1) {
+                Some(chan) => (*chan, Err(classify(&msg, &line))),
+                None => continue,
+            }
+        };
+        if let Some(original) = waiting.remove(&casemapping.normalize(chan)) {
+            results.push((original, result));
+        }
+    }
+
+    for (_, chan) in waiting.into_iter() {
+        results.push((chan, Err(timed_out.clone())));
+    }
+    Ok(results)
+}
+
+/// Installs a handler that joins `chan` the next time someone invites
+/// us to it.
+fn wait_for_invite(stream: &mut EventStream, nick: &str, chan: &str, isupport: &ISupport) -> Result<()> {
+    info!("Waiting for an invite to {}...", chan);
+    let casemapping = isupport.casemapping();
+    let nick = nick.to_string();
+    let chan = chan.to_string();
+    let options = HandlerOptions::new(&format!("invite to {}", chan)).priority(INTERNAL_PRIORITY);
+    try!(stream.add_handler_with(options, box move |line| {
+        match Message::parse(line) {
+            Some(ref msg) if msg.command == "INVITE" && msg.params.len() >= 2
+                && casemapping.same_name(msg.params[0], &nick)
+                && casemapping.same_name(msg.params[1], &chan) => {
+                info!("Invited to {}, joining...", chan);
+                Response(Some(Command::join(&chan, None)), HandlerAction::Remove, Action::Continue)
+            },
+            _ => Response::nothing(),
+        }
+    }));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{join_commands, part_commands, MAX_LINE_LEN};
+    use super::super::command::Command;
+    use super::super::isupport::ISupport;
+    use super::super::protocol::Message;
+
+    fn isupport(tokens: &str) -> ISupport {
+        let mut isupport = ISupport::new();
+        let line = format!(":irc.example.net 005 bot {} :are supported by this server", tokens);
+        isupport.update(&Message::parse(&line).unwrap());
+        isupport
+    }
+
+    fn channels(names: &[&str]) -> Vec<(String, Option<String>)> {
+        names.iter().map(|name| (name.to_string(), None)).collect()
+    }
+
+    fn lines(commands: &[Command]) -> Vec<String> {
+        commands.iter().map(|c| c.to_string()).collect()
+    }
+
+    #[test]
+    fn one_line_without_limits() {
+        let commands = join_commands(&channels(&["#a", "#b", "#c"]), &ISupport::new());
+        assert_eq!(lines(&commands), vec!["JOIN #a,#b,#c"]);
+    }
+
+    #[test]
+    fn targmax() {
+        let isupport = isupport("TARGMAX=PRIVMSG:4,JOIN:2,PART:");
+        let commands = join_commands(&channels(&["#a", "#b", "#c", "#d", "#e"]), &isupport);
+        assert_eq!(lines(&commands), vec!["JOIN #a,#b", "JOIN #c,#d", "JOIN #e"]);
+        let parts = part_commands(&["#a".to_string(), "#b".to_string(), "#c".to_string()], Some("bye"), &isupport);
+        assert_eq!(lines(&parts), vec!["PART #a,#b,#c :bye"]);
+    }
+
+    #[test]
+    fn line_length() {
+        let names: Vec<String> = (0..60).map(|i| format!("#channel-{:02}", i)).collect();
+        let names: Vec<&str> = names.iter().map(|n| &n[..]).collect();
+        let commands = join_commands(&channels(&names), &ISupport::new());
+        assert!(commands.len() > 1);
+        for line in lines(&commands).iter() {
+            assert!(line.len() <= MAX_LINE_LEN, "{} bytes: {}", line.len(), line);
+        }
+        let joined: Vec<String> = commands.into_iter().flat_map(|c| {
+            match c {
+                Command::Join(chans, _) => chans.into_iter(),
+                _ => panic!("Expected JOIN"),
+            }
+        }).collect();
+        assert_eq!(joined, names);
+    }
+
+    #[test]
+    fn keyed_channels_first() {
+        let chans = vec![
+            ("#open".to_string(), None),
+            ("#secret".to_string(), Some("swordfish".to_string())),
+            ("#also-open".to_string(), None),
+            ("#vault".to_string(), Some("hunter2".to_string())),
+        ];
+        let commands = join_commands(&chans, &ISupport::new());
+        assert_eq!(lines(&commands), vec!["JOIN #secret,#vault,#open,#also-open swordfish,hunter2"]);
+        let commands = join_commands(&chans, &isupport("TARGMAX=JOIN:3"));
+        assert_eq!(lines(&commands), vec!["JOIN #secret,#vault,#open swordfish,hunter2", "JOIN #also-open"]);
+    }
+
+}
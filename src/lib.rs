@@ -1,11 +1,12 @@
-#![feature(box_syntax,plugin,std_misc,unboxed_closures)]
-#![plugin(regex_macros)]
+#![feature(box_syntax,catch_panic,dynamic_lib,plugin,std_misc,unboxed_closures)]
+#![plugin(postgres_macros,regex_macros)]
 
 //! Provides some basic functionality for connecting to IRC servers
 //! and performing robotic tasks.
 //!
 //! # Example:
 //! ```{.ignore .rust}
+//! use irc::command::Command;
 //! use irc::event_stream::Response;
 //! use irc::protocol;
 //!
@@ -17,7 +18,7 @@
 //!     if let Some(pm) = protocol::Privmsg::parse(line).and_then(|pm| pm.targeted_msg(nick)) {
 //!         if pm.msg == "foo" {
 //!             if let Some(reply_to) = pm.reply_target(nick) {
-//!                 return Response::respond(protocol::Privmsg::new(reply_to, "bar").format());
+//!                 return Response::respond(Command::privmsg(&reply_to.format(), "bar"));
 //!             }
 //!         }
 //!     }
@@ -28,28 +29,86 @@
 //!     &addr, nick, &channels, "rustbot", "rust irc robot")
 //!     .ok().expect(&format!("Error connecting to {:?}.", addr));
 //!
-//! client.add_handler(Box::new(echo_handler));
-//! join_handle.join().ok().unwrap();
+//! client.add_handler(Box::new(echo_handler)).ok().expect("Event loop died.");
+//! if let Ok(Err(e)) = join_handle.join() {
+//!     println!("Disconnected: {}", e);
+//! }
 //! ```
 
-use event_stream::{Handler, EventStream, Response};
+use command::Command;
+use encoding::{Encoding, Encodings};
+use event_stream::{Handler, HandlerId, HandlerInfo, HandlerOptions, EventStream, IoThreads, SentHooks};
+use isupport::ISupport;
+use join::{JoinOutcome, PartOutcome};
+use membership::Membership;
+use modes::UserModes;
+use scheduler::{Schedule, Scheduler, Task, TaskHandle, TaskId, TaskInfo};
+use state::{ServerState, SharedState};
+use std::collections::BTreeSet;
 use std::fmt;
-use std::io;
 use std::net;
+use std::net::ToSocketAddrs;
 use std::thread;
 
+pub use builder::ClientBuilder;
+pub use error::{Error, Result};
+
+mod builder;
 mod channels;
+pub mod command;
+pub mod config;
+pub mod encoding;
+pub mod error;
 pub mod event_stream;
+pub mod formatting;
+pub mod http;
+pub mod isupport;
+pub mod join;
+pub mod membership;
+pub mod modes;
+pub mod plugin;
+pub mod plugins;
+pub mod pool;
 pub mod protocol;
+pub mod scheduler;
+pub mod state;
+pub mod storage;
 
 #[macro_use]
 extern crate log;
+extern crate openssl;
+extern crate postgres;
+extern crate rand;
+extern crate rusqlite;
 extern crate regex;
+extern crate rustc_serialize;
+extern crate time;
+extern crate toml;
+
+/// How long dropping a `Client` waits for its QUIT to be written.
+const DROP_QUIT_TIMEOUT_MS: u32 = 5 * 1000;
 
 /// The top-level IRC client.
 pub struct Client {
     stream: EventStream,
-    server: String,
+    state: SharedState,
+    membership: Membership,
+    joins: Vec<JoinOutcome>,
+    socket: Option<net::TcpStream>,
+    threads: Option<IoThreads>,
+}
+
+/// How quitting went.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QuitSummary {
+    /// Whether the event loop finished, because the server said
+    /// goodbye or closed the connection, before we gave up waiting.
+    pub server_closed: bool,
+    /// Whether the event loop had finished by the time we returned.
+    /// If not, a handler is probably stuck.
+    pub event_loop_finished: bool,
+    /// How long quitting took.
+    pub elapsed_ms: u64,
 }
 
 impl Client {
@@ -58,7 +117,14 @@ impl Client {
     ///
     /// Returns a `Client` object to which you can add handlers, and a
     /// `thread::JoinHandle` which will join when the thread handling
-    /// IRC events finishes.
+    /// IRC events finishes, yielding the reason it stopped.
+    ///
+    /// Fails if we can't connect or the server refuses to register
+    /// us.  Channels we couldn't join are reported by
+    /// `Client::join_outcomes`.
+    ///
+    /// Asks to be invisible (`+i`); use `Client::connect_mode` for
+    /// other user modes.
     ///
     /// # Example:
     /// ```{.ignore .rust}
@@ -71,81 +137,280 @@ impl Client {
     /// ```
     pub fn connect<A: net::ToSocketAddrs + fmt::Debug>(addr: &A,
                                                        nick: &str, channels: &[&str],
-                                                       user: &str, realname: &str) -> io::Result<(Client, thread::JoinHandle<()>)> {
-        Client::connect_mode(addr, nick, channels, user, realname, false, true)
+                                                       user: &str, realname: &str) -> Result<(Client, thread::JoinHandle<Result<()>>)> {
+        Client::connect_mode(addr, nick, channels, user, realname, UserModes::new().invisible(true))
     }
 
-    /// Connects to an IRC server with additional options `invisible` and `wallops`.
+    /// Connects to an IRC server and asks for `modes` once registered.
     ///
-    /// See `Client::connect` and the IRC protocol RFC for details.
+    /// See `Client::connect` and `modes::UserModes` for details, and
+    /// `ClientBuilder` for more options.
     pub fn connect_mode<A: net::ToSocketAddrs + fmt::Debug>(addr: &A,
                                                             nick: &str, channels: &[&str],
                                                             user: &str, realname: &str,
-                                                            invisible: bool, wallops: bool) -> io::Result<(Client, thread::JoinHandle<()>)> {
-        let default_handlers: Vec<Handler> = vec![
-            box protocol::pong_handler,
-            box protocol::timeout_handler,
-            ];
-
-        debug!("Connecting to {:?}...", addr);
-        let conn = try!(net::TcpStream::connect(addr));
-        let conn_copy = try!(conn.try_clone());
-
-        let (mut stream, join_handle) = try!(EventStream::new(conn, conn_copy, default_handlers));
-        info!("Connected to {:?}!", addr);
-
-        let server = try!(protocol::login(&mut stream, nick, user, realname, invisible, wallops));
-        info!("Logged in at \"{}\"!", server);
-
-        try!(protocol::join(&mut stream, &server, channels));
-
-        let client = Client{
-            stream: stream,
-            server: server,
-        };
-        Ok((client, join_handle))
+                                                            modes: UserModes) -> Result<(Client, thread::JoinHandle<Result<()>>)> {
+        let mut builder = ClientBuilder::new(nick)
+            .user(user)
+            .realname(realname)
+            .user_modes(modes);
+        for sock_addr in try!(addr.to_socket_addrs()) {
+            builder = builder.server(&sock_addr.ip().to_string(), sock_addr.port());
+        }
+        for chan in channels.iter() {
+            builder = builder.channel(chan);
+        }
+        builder.connect()
     }
 
     /// Adds a new handler to the event loop.
     ///
+    /// Fails with `Error::Disconnected` if the event loop has died.
+    ///
     /// # Example:
     /// ```{.ignore .rust}
+    /// use irc::command::Command;
     /// use irc::event_stream::Response;
     ///
     /// let (mut client, _) = irc::Client::connect(
     ///     &("irc.freenode.net", 6667), "rustbot", &[], "rustbot", "rustbot").ok().unwrap();
     /// client.add_handler(Box::new(move |line: &str| {
     ///     // A literal echo server like this would really confuse a real IRC server...
-    ///     Response::respond(line.to_string())
-    /// }));
+    ///     Response::respond(Command::Raw(line.to_string()))
+    /// })).ok().unwrap();
+    /// ```
+    pub fn add_handler(&mut self, handler: Handler) -> Result<HandlerId> {
+        self.stream.add_handler(handler)
+    }
+
+    /// Adds a new handler with a name and priority.  See
+    /// `event_stream::HandlerOptions`.
+    pub fn add_handler_with(&mut self, options: HandlerOptions, handler: Handler) -> Result<HandlerId> {
+        self.stream.add_handler_with(options, handler)
+    }
+
+    /// Removes the handler `id`.  Returns whether it was still
+    /// installed.
+    pub fn remove_handler(&mut self, id: HandlerId) -> Result<bool> {
+        self.stream.remove_handler(id)
+    }
+
+    /// Replaces the handler `id`, keeping its name, priority and place.
+    /// Returns whether it was still installed.
+    pub fn replace_handler(&mut self, id: HandlerId, handler: Handler) -> Result<bool> {
+        self.stream.replace_handler(id, handler)
+    }
+
+    /// Removes some handlers and adds others atomically.  See
+    /// `EventStream::swap_handlers`.
+    pub fn swap_handlers(&mut self, remove: &[HandlerId], add: Vec<(HandlerOptions, Handler)>) -> Result<Vec<HandlerId>> {
+        self.stream.swap_handlers(remove, add)
+    }
+
+    /// The installed handlers, in the order they see each line.
+    pub fn handlers(&self) -> Result<Vec<HandlerInfo>> {
+        self.stream.handlers()
+    }
+
+    /// Runs `task` on the event loop according to `schedule`.  See
+    /// `EventStream::schedule`.
+    pub fn schedule(&mut self, name: &str, schedule: Schedule, task: Task) -> TaskHandle {
+        self.stream.schedule(name, schedule, task)
+    }
+
+    /// Cancels the task `id`.  Returns whether it was still scheduled.
+    pub fn cancel_task(&mut self, id: TaskId) -> bool {
+        self.stream.cancel_task(id)
+    }
+
+    /// The scheduled tasks, soonest first.
+    pub fn tasks(&self) -> Vec<TaskInfo> {
+        self.stream.tasks()
+    }
+
+    /// A handle for scheduling tasks from handlers.  See
+    /// `EventStream::scheduler`.
+    pub fn scheduler(&self) -> Scheduler {
+        self.stream.scheduler()
+    }
+
+    /// A handle for seeing the lines we send.  See
+    /// `EventStream::sent_hooks`.
+    pub fn sent_hooks(&self) -> SentHooks {
+        self.stream.sent_hooks()
+    }
+
+    /// Sends `command` to the server.
+    pub fn send(&mut self, command: &Command) -> Result<()> {
+        self.stream.send(command)
+    }
+
+    /// Says goodbye with `reason` and shuts down.
+    ///
+    /// Sends QUIT after anything still waiting to be sent, waits up to
+    /// `timeout_ms` milliseconds for the server to close the
+    /// connection, closes it ourselves if it hasn't, and waits for the
+    /// reader, writer and event loop threads to finish.  Once this
+    /// returns, the event loop's `thread::JoinHandle` is ready to join.
+    ///
+    /// # Example:
+    /// ```{.ignore .rust}
+    /// let summary = client.quit("Going home", 10000).ok().unwrap();
+    /// if !summary.server_closed {
+    ///     println!("The server never answered.");
+    /// }
     /// ```
-    pub fn add_handler(&mut self, handler: Handler) {
-        let server = self.server.clone();
-        let mut handler_mut = handler;
-        self.stream.add_handler(box move |line| {
-            let Response(msg, ha, a) = handler_mut(line);
-            if let Some(s) = msg {
-                Response(Some(format!("{} {}\r\n", &server, s)), ha, a)
-            } else {
-                Response(msg, ha, a)
+    pub fn quit(mut self, reason: &str, timeout_ms: u32) -> Result<QuitSummary> {
+        let start = time::precise_time_ns();
+        let mut threads = match self.threads.take() {
+            Some(threads) => threads,
+            None => return Err(Error::Disconnected),
+        };
+
+        info!("Quitting from server...");
+        if let Err(e) = self.stream.send(&Command::Quit(Some(reason.to_string()))) {
+            warn!("Error sending QUIT: {}", e);
+        }
+        if let Err(e) = self.stream.close() {
+            warn!("Error closing writer: {}", e);
+        }
+        // The writer can block forever on a server that stopped
+        // reading, so it only gets part of the timeout.  Shutting the
+        // socket down for writing unsticks it.
+        if !threads.join_writer_timeout(timeout_ms) {
+            warn!("Timed out writing QUIT.");
+            if let Some(ref socket) = self.socket {
+                if let Err(e) = socket.shutdown(net::Shutdown::Write) {
+                    debug!("Error shutting down socket for writing: {}", e);
+                }
+            }
+        }
+        let elapsed_ms = (time::precise_time_ns() - start) / 1000000;
+        let server_closed = threads.wait_for_event_loop(timeout_ms.saturating_sub(elapsed_ms as u32));
+
+        if let Some(socket) = self.socket.take() {
+            if let Err(e) = socket.shutdown(net::Shutdown::Both) {
+                debug!("Error shutting down socket: {}", e);
             }
-        });
+        }
+        threads.join_reader();
+        let event_loop_finished = threads.wait_for_event_loop(timeout_ms);
+
+        let summary = QuitSummary{
+            server_closed: server_closed,
+            event_loop_finished: event_loop_finished,
+            elapsed_ms: (time::precise_time_ns() - start) / 1000000,
+        };
+        info!("Quit: {:?}", summary);
+        Ok(summary)
+    }
+
+    /// How joining each channel went while connecting, in the order
+    /// the channels were given.
+    pub fn join_outcomes(&self) -> &[JoinOutcome] {
+        &self.joins
+    }
+
+    /// Joins `channels`, each with an optional key, and waits for the
+    /// server to let us in or refuse.  Failed channels are retried in
+    /// the background according to the client's `JoinPolicy`.
+    ///
+    /// Only fails if we've been disconnected.
+    pub fn join(&mut self, channels: &[(String, Option<String>)]) -> Result<Vec<JoinOutcome>> {
+        self.membership.join(channels)
+    }
+
+    /// Leaves `channels`, saying `reason` if given.
+    pub fn part(&mut self, channels: &[String], reason: Option<&str>) -> Result<Vec<PartOutcome>> {
+        self.membership.part(channels, reason)
+    }
+
+    /// Leaves and rejoins `channels`.
+    pub fn cycle(&mut self, channels: &[String], reason: Option<&str>) -> Result<Vec<JoinOutcome>> {
+        self.membership.cycle(channels, reason)
+    }
+
+    /// The channels we're in.
+    pub fn channels(&self) -> Vec<String> {
+        self.membership.channels()
+    }
+
+    /// The channels we want to be in: those we've joined through the
+    /// client, minus those we've parted, whether or not we got in.
+    pub fn desired_channels(&self) -> Vec<String> {
+        self.membership.desired_channels()
+    }
+
+    /// A handle for joining and leaving channels from handlers.  See
+    /// `membership::Membership`.
+    pub fn membership(&self) -> Membership {
+        self.membership.clone()
+    }
+
+    /// A snapshot of what we know about the server and ourselves.
+    pub fn state(&self) -> ServerState {
+        state::snapshot(&self.state)
+    }
+
+    /// Our current nick.
+    pub fn nick(&self) -> String {
+        self.state().nick
+    }
+
+    /// What the server told us about itself in RPL_ISUPPORT.
+    pub fn isupport(&self) -> ISupport {
+        self.state().isupport
+    }
+
+    /// Our user modes, as last confirmed by the server.  These may
+    /// differ from what was asked for if the server doesn't support a
+    /// mode or hasn't confirmed it yet.
+    pub fn user_modes(&self) -> BTreeSet<char> {
+        self.state().user_modes
+    }
+
+    /// The encodings currently used to talk to the server.
+    pub fn encodings(&self) -> Encodings {
+        self.stream.encodings()
+    }
+
+    /// Replaces the encodings used to talk to the server.
+    ///
+    /// Lines are strict UTF-8 by default; for a server full of
+    /// legacy clients, something like
+    /// `Encodings::with(Encoding::Utf8Latin1Fallback, Encoding::Utf8)`
+    /// is friendlier.
+    pub fn set_encodings(&mut self, encodings: Encodings) {
+        self.stream.set_encodings(encodings)
+    }
+
+    /// Uses `encoding` for lines to and from `chan`.
+    pub fn set_channel_encoding(&mut self, chan: &str, encoding: Encoding) {
+        self.stream.set_channel_encoding(chan, encoding)
     }
 
 }
 
 impl Drop for Client {
 
-    /// Sends a QUIT message before dropping.
+    /// Sends a QUIT message and waits up to `DROP_QUIT_TIMEOUT_MS` for
+    /// it to be written, unless `Client::quit` was already called.
     fn drop(&mut self) {
-        use std::io::Write;
-
-        info!("Quitting from server...");
-        write!(&mut self.stream, "{} QUIT: adios\r\n", &self.server)
-            .err().and_then(|e| -> Option<()> {
-                error!("Error quitting: {:?}", e);
-                None
-            });
+        if let Some(mut threads) = self.threads.take() {
+            info!("Quitting from server...");
+            self.stream.send(&Command::Quit(Some("adios".to_string())))
+                .err().and_then(|e| -> Option<()> {
+                    error!("Error quitting: {:?}", e);
+                    None
+                });
+            if self.stream.close().is_ok() && !threads.join_writer_timeout(DROP_QUIT_TIMEOUT_MS) {
+                warn!("Timed out writing QUIT.");
+                if let Some(ref socket) = self.socket {
+                    if let Err(e) = socket.shutdown(net::Shutdown::Write) {
+                        debug!("Error shutting down socket for writing: {}", e);
+                    }
+                }
+            }
+        }
     }
 
 }
@@ -0,0 +1,141 @@
+//! Joining and leaving channels while connected, and keeping track of
+//! where we want to be.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use super::error::Result;
+use super::event_stream::{EventStream, Handler, Response};
+use super::join;
+use super::join::{JoinOutcome, JoinPolicy, PartOutcome};
+use super::protocol::Message;
+use super::state;
+use super::state::SharedState;
+
+/// The channels we want to be in, by normalized name, with their
+/// names as given and their keys.
+type Desired = BTreeMap<String, (String, Option<String>)>;
+
+/// A handle for joining and leaving channels.
+///
+/// It's cheap to clone, and clones share what they know, so one can be
+/// moved into a handler.  Its methods wait for the server to answer,
+/// though, so a handler should call them from another thread, not
+/// while it's handling a line.
+#[derive(Clone)]
+pub struct Membership {
+    stream: EventStream,
+    state: SharedState,
+    desired: Arc<Mutex<Desired>>,
+    policy: JoinPolicy,
+}
+
+impl Membership {
+
+    pub fn new(stream: EventStream, state: SharedState, policy: JoinPolicy) -> Membership {
+        Membership{
+            stream: stream,
+            state: state,
+            desired: Arc::new(Mutex::new(BTreeMap::new())),
+            policy: policy,
+        }
+    }
+
+    fn lock_desired(&self) -> MutexGuard<Desired> {
+        match self.desired.lock() {
+            Ok(desired) => desired,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    /// Joins `channels`, each with an optional key, and remembers that
+    /// we want to be in them even if we couldn't get in.
+    pub fn join(&mut self, channels: &[(String, Option<String>)]) -> Result<Vec<JoinOutcome>> {
+        let state = state::snapshot(&self.state);
+        let casemapping = state.isupport.casemapping();
+        {
+            let mut desired = self.lock_desired();
+            for &(ref chan, ref key) in channels.iter() {
+                desired.insert(casemapping.normalize(chan), (chan.clone(), key.clone()));
+            }
+        }
+        join::join(&mut self.stream, &state.nick, channels, &state.isupport, &self.policy)
+    }
+
+    /// Leaves `channels`, saying `reason` if given, and forgets that we
+    /// wanted to be in them.
+    pub fn part(&mut self, channels: &[String], reason: Option<&str>) -> Result<Vec<PartOutcome>> {
+        let state = state::snapshot(&self.state);
+        let casemapping = state.isupport.casemapping();
+        {
+            let mut desired = self.lock_desired();
+            for chan in channels.iter() {
+                desired.remove(&casemapping.normalize(chan));
+            }
+        }
+        join::part(&mut self.stream, &state.nick, channels, reason, &state.isupport)
+    }
+
+    /// Leaves and rejoins `channels`, using the keys we joined them
+    /// with.
+    pub fn cycle(&mut self, channels: &[String], reason: Option<&str>) -> Result<Vec<JoinOutcome>> {
+        let casemapping = state::snapshot(&self.state).isupport.casemapping();
+        let rejoin: Vec<(String, Option<String>)> = {
+            let desired = self.lock_desired();
+            channels.iter().map(|chan| {
+                match desired.get(&casemapping.normalize(chan)) {
+                    Some(&(_, ref key)) => (chan.clone(), key.clone()),
+                    None => (chan.clone(), None),
+                }
+            }).collect()
+        };
+        try!(self.part(channels, reason));
+        self.join(&rejoin)
+    }
+
+    /// The channels we're in, as the server last confirmed.
+    pub fn channels(&self) -> Vec<String> {
+        state::snapshot(&self.state).channels.into_iter().collect()
+    }
+
+    /// The channels we've asked to be in, whether or not we are.
+    pub fn desired_channels(&self) -> Vec<String> {
+        self.lock_desired().values().map(|&(ref chan, _)| chan.clone()).collect()
+    }
+
+    /// A handler that rejoins channels we're kicked from, if the
+    /// `JoinPolicy` says to.
+    pub fn rejoin_handler(&self) -> Handler {
+        let membership = self.clone();
+        box move |line: &str| {
+            let delay_ms = match membership.policy.rejoin_delay_ms {
+                Some(delay_ms) => delay_ms,
+                None => return Response::nothing(),
+            };
+            let msg = match Message::parse(line) {
+                Some(msg) => msg,
+                None => return Response::nothing(),
+            };
+            if msg.command != "KICK" || msg.params.len() < 2 {
+                return Response::nothing();
+            }
+            let state = state::snapshot(&membership.state);
+            if !state.is_me(msg.params[1]) {
+                return Response::nothing();
+            }
+            let wanted = membership.lock_desired().get(&state.isupport.casemapping().normalize(msg.params[0])).cloned();
+            if let Some(chan) = wanted {
+                info!("Kicked from {}, rejoining in {}ms...", chan.0, delay_ms);
+                let mut membership = membership.clone();
+                thread::spawn(move || {
+                    thread::sleep_ms(delay_ms);
+                    if let Err(e) = membership.join(&[chan]) {
+                        error!("Error rejoining: {}", e);
+                    }
+                });
+            }
+            Response::nothing()
+        }
+    }
+
+}
@@ -0,0 +1,364 @@
+//! Parsing and formatting MODE changes.
+//!
+//! # Example:
+//! ```{.ignore .rust}
+//! use irc::isupport::ISupport;
+//! use irc::modes;
+//!
+//! let changes = modes::parse_channel_modes("+ov-b", &["nick1", "nick2", "*!*@x"], &ISupport::new()).unwrap();
+//! assert_eq!(changes[2], modes::ModeChange::remove('b', Some("*!*@x")));
+//! ```
+
+use std::collections::BTreeSet;
+use super::error::{Error, Result};
+use super::isupport::ISupport;
+
+/// The longest line we'll build, leaving room for the line ending.
+const MAX_LINE_LEN: usize = 510;
+
+/// USER command mode bits, from RFC 2812 section 3.1.3.
+const USER_BIT_WALLOPS: u16 = 4;
+const USER_BIT_INVISIBLE: u16 = 8;
+
+/// A single mode being set or unset.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ModeChange {
+    pub adding: bool,
+    pub mode: char,
+    pub param: Option<String>,
+}
+
+impl ModeChange {
+
+    pub fn add(mode: char, param: Option<&str>) -> ModeChange {
+        ModeChange{
+            adding: true,
+            mode: mode,
+            param: param.map(|p| p.to_string()),
+        }
+    }
+
+    pub fn remove(mode: char, param: Option<&str>) -> ModeChange {
+        ModeChange{
+            adding: false,
+            mode: mode,
+            param: param.map(|p| p.to_string()),
+        }
+    }
+
+}
+
+/// The user modes we want for ourselves.  Invisible and wallops can
+/// be requested in the USER command at registration; everything is
+/// also requested with a MODE command once we're registered, since
+/// not every server honors the USER bits.
+///
+/// # Example:
+/// ```{.ignore .rust}
+/// let modes = irc::modes::UserModes::new().invisible(true).bot(true);
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct UserModes {
+    invisible: bool,
+    wallops: bool,
+    cloak: bool,
+    bot: bool,
+    registered_only: bool,
+}
+
+impl UserModes {
+
+    /// No modes at all.
+    pub fn new() -> UserModes {
+        UserModes::default()
+    }
+
+    /// `+i`: hide us from WHO and NAMES for users not sharing a
+    /// channel with us.
+    pub fn invisible(mut self, on: bool) -> UserModes {
+        self.invisible = on;
+        self
+    }
+
+    /// `+w`: receive WALLOPS.
+    pub fn wallops(mut self, on: bool) -> UserModes {
+        self.wallops = on;
+        self
+    }
+
+    /// `+x`: cloak our hostname, on servers that support it.
+    pub fn cloak(mut self, on: bool) -> UserModes {
+        self.cloak = on;
+        self
+    }
+
+    /// `+B`: mark ourselves as a bot, on servers that support it.
+    pub fn bot(mut self, on: bool) -> UserModes {
+        self.bot = on;
+        self
+    }
+
+    /// `+R`: only accept private messages from registered users, on
+    /// servers that support it.
+    pub fn registered_only(mut self, on: bool) -> UserModes {
+        self.registered_only = on;
+        self
+    }
+
+    /// The mode bitmask for the USER command.
+    pub fn user_bitmask(&self) -> u16 {
+        (if self.wallops { USER_BIT_WALLOPS } else { 0 }) + (if self.invisible { USER_BIT_INVISIBLE } else { 0 })
+    }
+
+    /// The mode characters we want set.
+    pub fn chars(&self) -> BTreeSet<char> {
+        [(self.invisible, 'i'), (self.wallops, 'w'), (self.cloak, 'x'),
+         (self.bot, 'B'), (self.registered_only, 'R')].iter()
+            .filter(|&&(on, _)| on)
+            .map(|&(_, c)| c)
+            .collect()
+    }
+
+    /// The changes to request once registered.
+    pub fn changes(&self) -> Vec<ModeChange> {
+        self.chars().into_iter().map(|c| ModeChange::add(c, None)).collect()
+    }
+
+}
+
+/// What kind of parameter a channel mode takes.
+enum ParamKind {
+    /// Type A list modes: a parameter, except when listing.
+    List,
+    /// Type B modes and membership prefixes: always a parameter.
+    Always,
+    /// Type C modes: a parameter only when set.
+    WhenSet,
+    /// Type D modes: never a parameter.
+    Never,
+}
+
+fn param_kind(mode: char, isupport: &ISupport) -> Option<ParamKind> {
+    let chanmodes = isupport.chanmodes();
+    if chanmodes.list.contains(mode) {
+        Some(ParamKind::List)
+    } else if chanmodes.always.contains(mode) || isupport.prefix().iter().any(|&(m, _)| m == mode) {
+        Some(ParamKind::Always)
+    } else if chanmodes.when_set.contains(mode) {
+        Some(ParamKind::WhenSet)
+    } else if chanmodes.never.contains(mode) {
+        Some(ParamKind::Never)
+    } else {
+        None
+    }
+}
+
+/// Parses a channel mode string like `+ov-b` and its parameters,
+/// using the server's `CHANMODES` and `PREFIX` to decide which modes
+/// consume a parameter.
+///
+/// Modes the server didn't tell us about are assumed to take no
+/// parameter.  Fails if a mode that requires a parameter is missing
+/// one.
+pub fn parse_channel_modes(modes: &str, params: &[&str], isupport: &ISupport) -> Result<Vec<ModeChange>> {
+    let mut params = params.iter();
+    let mut adding = true;
+    let mut changes = Vec::new();
+    for mode in modes.chars() {
+        match mode {
+            '+' => { adding = true; continue; },
+            '-' => { adding = false; continue; },
+            _ => (),
+        }
+        let param = match param_kind(mode, isupport) {
+            Some(ParamKind::List) => params.next().map(|p| p.to_string()),
+            Some(ParamKind::Always) => Some(try!(params.next().ok_or(missing_param(mode))).to_string()),
+            Some(ParamKind::WhenSet) if adding => Some(try!(params.next().ok_or(missing_param(mode))).to_string()),
+            Some(ParamKind::WhenSet) | Some(ParamKind::Never) => None,
+            None => {
+                warn!("Unknown channel mode {}, assuming it takes no parameter.", mode);
+                None
+            },
+        };
+        changes.push(ModeChange{
+            adding: adding,
+            mode: mode,
+            param: param,
+        });
+    }
+    Ok(changes)
+}
+
+fn missing_param(mode: char) -> Error {
+    Error::Protocol(format!("mode {} is missing its parameter", mode))
+}
+
+/// Parses a user mode string like `+iw-x`.  User modes never take
+/// parameters.
+pub fn parse_user_modes(modes: &str) -> Vec<ModeChange> {
+    let mut adding = true;
+    modes.chars().filter_map(|mode| {
+        match mode {
+            '+' => { adding = true; None },
+            '-' => { adding = false; None },
+            _ => Some(ModeChange{
+                adding: adding,
+                mode: mode,
+                param: None,
+            }),
+        }
+    }).collect()
+}
+
+/// Applies user mode changes to a set of modes.
+pub fn apply_user_modes(current: &mut BTreeSet<char>, changes: &[ModeChange]) {
+    for change in changes.iter() {
+        if change.adding {
+            current.insert(change.mode);
+        } else {
+            current.remove(&change.mode);
+        }
+    }
+}
+
+/// Formats `changes` to `target` as MODE commands (without line
+/// endings), splitting them so no line has more parameters than the
+/// server's `MODES` limit or is too long to send.
+pub fn format_modes(target: &str, changes: &[ModeChange], isupport: &ISupport) -> Vec<String> {
+    let max_params = isupport.modes();
+    let mut lines = Vec::new();
+    let mut modes = String::new();
+    let mut params: Vec<&str> = Vec::new();
+    let mut sign = None;
+    for change in changes.iter() {
+        let param = change.param.as_ref().map(|p| &p[..]);
+        let too_many = param.is_some() && max_params.map_or(false, |max| params.len() >= max);
+        // Leave room for a sign, the mode, and a colon on the parameter.
+        let too_long = line_len(target, &modes, &params) + 3 + param.map_or(0, |p| p.len() + 1) > MAX_LINE_LEN;
+        if !modes.is_empty() && (too_many || too_long) {
+            lines.push(format_line(target, &modes, &params));
+            modes = String::new();
+            params = Vec::new();
+            sign = None;
+        }
+        if sign != Some(change.adding) {
+            modes.push(if change.adding { '+' } else { '-' });
+            sign = Some(change.adding);
+        }
+        modes.push(change.mode);
+        if let Some(p) = param {
+            params.push(p);
+        }
+    }
+    if !modes.is_empty() {
+        lines.push(format_line(target, &modes, &params));
+    }
+    lines
+}
+
+fn line_len(target: &str, modes: &str, params: &[&str]) -> usize {
+    "MODE ".len() + target.len() + 1 + modes.len()
+        + params.iter().map(|p| p.len() + 1).fold(0, |a, b| a + b)
+}
+
+fn format_line(target: &str, modes: &str, params: &[&str]) -> String {
+    let mut line = format!("MODE {} {}", target, modes);
+    for (i, param) in params.iter().enumerate() {
+        // The last parameter needs a colon if it would otherwise be
+        // misread.
+        if i == params.len() - 1 && (param.is_empty() || param.starts_with(':') || param.contains(' ')) {
+            line.push_str(" :");
+        } else {
+            line.push(' ');
+        }
+        line.push_str(param);
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{format_modes, parse_channel_modes, ModeChange, UserModes, MAX_LINE_LEN};
+    use super::super::isupport::ISupport;
+    use super::super::protocol::Message;
+
+    fn isupport(tokens: &str) -> ISupport {
+        let mut isupport = ISupport::new();
+        let line = format!(":irc.example.net 005 bot {} :are supported by this server", tokens);
+        isupport.update(&Message::parse(&line).unwrap());
+        isupport
+    }
+
+    #[test]
+    fn channel_modes() {
+        let isupport = isupport("CHANMODES=beIq,k,fl,imnpst PREFIX=(yqaohv)!~&@%+");
+        let changes = parse_channel_modes("+ov-b", &["nick1", "nick2", "*!*@x"], &isupport).unwrap();
+        assert_eq!(changes, vec![
+            ModeChange::add('o', Some("nick1")),
+            ModeChange::add('v', Some("nick2")),
+            ModeChange::remove('b', Some("*!*@x")),
+            ]);
+        // y is a prefix mode, q a list mode, and f takes a parameter
+        // only when set.
+        let changes = parse_channel_modes("+yqf-fm", &["alice", "*!*@spam", "3:5"], &isupport).unwrap();
+        assert_eq!(changes, vec![
+            ModeChange::add('y', Some("alice")),
+            ModeChange::add('q', Some("*!*@spam")),
+            ModeChange::add('f', Some("3:5")),
+            ModeChange::remove('f', None),
+            ModeChange::remove('m', None),
+            ]);
+    }
+
+    #[test]
+    fn channel_mode_parameters() {
+        let isupport = ISupport::new();
+        // Listing bans.
+        assert_eq!(parse_channel_modes("+b", &[], &isupport).unwrap(), vec![ModeChange::add('b', None)]);
+        assert!(parse_channel_modes("+k", &[], &isupport).is_err());
+        assert!(parse_channel_modes("+ov", &["alice"], &isupport).is_err());
+        assert_eq!(parse_channel_modes("-l", &[], &isupport).unwrap(), vec![ModeChange::remove('l', None)]);
+    }
+
+    #[test]
+    fn modes_limit() {
+        let changes = vec![
+            ModeChange::add('o', Some("a")),
+            ModeChange::add('o', Some("b")),
+            ModeChange::add('o', Some("c")),
+            ModeChange::add('m', None),
+            ModeChange::remove('v', Some("d")),
+            ];
+        assert_eq!(format_modes("#x", &changes, &isupport("MODES=2")),
+                   vec!["MODE #x +oo a b", "MODE #x +om-v c d"]);
+        assert_eq!(format_modes("#x", &changes, &ISupport::new()),
+                   vec!["MODE #x +ooom a b c", "MODE #x -v d"]);
+        assert_eq!(format_modes("#x", &changes, &isupport("MODES")),
+                   vec!["MODE #x +ooom-v a b c d"]);
+    }
+
+    #[test]
+    fn line_length() {
+        let masks: Vec<String> = (0..40).map(|i| format!("*!*@spammer-{:02}.example.net", i)).collect();
+        let changes: Vec<ModeChange> = masks.iter().map(|m| ModeChange::add('b', Some(&m[..]))).collect();
+        let lines = format_modes("#x", &changes, &isupport("MODES"));
+        assert!(lines.len() > 1);
+        for line in lines.iter() {
+            assert!(line.len() <= MAX_LINE_LEN, "{} bytes: {}", line.len(), line);
+        }
+        let sent: Vec<&str> = lines.iter().flat_map(|line| line.split(' ').skip(3)).collect();
+        assert_eq!(sent, masks);
+    }
+
+    #[test]
+    fn user_bitmask() {
+        assert_eq!(UserModes::new().user_bitmask(), 0);
+        assert_eq!(UserModes::new().invisible(true).user_bitmask(), 8);
+        assert_eq!(UserModes::new().wallops(true).user_bitmask(), 4);
+        assert_eq!(UserModes::new().invisible(true).wallops(true).user_bitmask(), 12);
+        // Modes the USER command can't carry don't change it.
+        assert_eq!(UserModes::new().invisible(true).bot(true).cloak(true).user_bitmask(), 8);
+    }
+
+}
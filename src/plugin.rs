@@ -0,0 +1,620 @@
+//! Bundles of handlers that can be configured, loaded and unloaded,
+//! and turned on and off per channel.  Plugins can be built in or
+//! loaded from shared libraries, which can be reloaded without
+//! reconnecting.
+//!
+//! A plugin's settings come from its `[plugins.<name>]` table.  Two
+//! keys there are handled by the `Registry` rather than the plugin:
+//!
+//! ```{.ignore .toml}
+//! [plugins.karma]
+//! # Don't load it at all.  Defaults to true.
+//! enabled = false
+//! # Only run it in these channels.  Defaults to all of them.
+//! channels = ["#rustbot_test"]
+//! ```
+
+use std::collections::BTreeMap;
+use std::dynamic_lib::DynamicLibrary;
+use std::env;
+use std::fs;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use time;
+use toml;
+use super::Client;
+use super::config::Section;
+use super::error::{Error, Result};
+use super::event_stream::{Handler, HandlerId, HandlerOptions, Response, SentHook, SentHookId};
+use super::isupport::Casemapping;
+use super::membership::Membership;
+use super::protocol::{Dest, Message, Privmsg, Source};
+use super::scheduler::Scheduler;
+use super::storage::Storage;
+
+/// How many times a plugin's handler may panic before it's disabled,
+/// unless it says otherwise.
+pub const MAX_FAILURES: u32 = 10;
+
+/// The function a plugin library exports to make its plugin:
+///
+/// ```ignore
+/// #[no_mangle]
+/// pub extern fn irc_plugin() -> Box<Plugin> {
+///     box MyPlugin
+/// }
+/// ```
+pub type PluginConstructor = extern fn() -> Box<Plugin>;
+
+/// The name of a plugin library's `PluginConstructor`.
+pub const PLUGIN_SYMBOL: &'static str = "irc_plugin";
+
+/// A message addressed to us that starts with a command's name, like
+/// `rustbot: join #rust`.
+pub struct Invocation<'a> {
+    /// Who sent it.
+    pub sender: &'a str,
+    /// Where to send a reply: the channel, or the sender if it was a
+    /// private message.
+    pub reply_to: &'a str,
+    /// Everything after the command's name, trimmed.
+    pub args: &'a str,
+}
+
+/// Runs a command.  It's called from a handler, so the same rules
+/// apply.
+pub type CommandHandler = Box<FnMut(&Invocation) -> Response + Send>;
+
+/// The first words of the commands each loaded plugin handles, so
+/// plugins that act on anything said to us, like factoids, can leave
+/// commands alone.  Clones share the same words.
+#[derive(Clone)]
+pub struct CommandWords {
+    /// By plugin name.
+    words: Arc<Mutex<BTreeMap<String, Vec<String>>>>,
+}
+
+impl CommandWords {
+
+    pub fn new() -> CommandWords {
+        CommandWords{
+            words: Arc::new(Mutex::new(BTreeMap::new())),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<BTreeMap<String, Vec<String>>> {
+        match self.words.lock() {
+            Ok(words) => words,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    /// Replaces the words `plugin` handles.
+    pub fn set(&self, plugin: &str, words: Vec<String>) {
+        let words = words.into_iter().map(|w| w.to_lowercase()).collect();
+        self.lock().insert(plugin.to_string(), words);
+    }
+
+    /// Forgets the words `plugin` handled.
+    pub fn remove(&self, plugin: &str) {
+        self.lock().remove(plugin);
+    }
+
+    /// Whether some plugin handles messages starting with `word`,
+    /// ignoring case.
+    pub fn contains(&self, word: &str) -> bool {
+        let word = word.to_lowercase();
+        self.lock().values().any(|words| words.contains(&word))
+    }
+
+}
+
+/// What a plugin has to work with.
+pub struct Context<'a> {
+    /// Our nick when the plugin was loaded.
+    pub nick: String,
+    /// The plugin's settings, without `enabled` and `channels`.
+    pub config: Section<'a>,
+    pub membership: Membership,
+    /// How the server folds case, for comparing nicks and channels.
+    pub casemapping: Casemapping,
+    /// For running things later.  Tasks outlive the plugin unless it
+    /// cancels them in `shutdown`.
+    pub scheduler: Scheduler,
+    /// The network's name, if the server said it in ISUPPORT.
+    pub network: Option<String>,
+    /// What every loaded plugin's commands start with, as it changes.
+    pub commands: CommandWords,
+    storage: Option<Arc<Storage>>,
+}
+
+impl<'a> Context<'a> {
+
+    /// Fails if there's no `[storage]` configured.  Plugins without
+    /// tables of their own can keep things with `Storage::set_value`,
+    /// under their name.
+    pub fn storage(&self) -> Result<Arc<Storage>> {
+        self.storage.clone().ok_or(Error::Plugin(format!("{} needs [storage] to be configured", self.config.path)))
+    }
+
+}
+
+/// Collects a plugin's handlers, commands and sent hooks as it
+/// registers them.
+pub struct Registrar {
+    handlers: Vec<(HandlerOptions, Handler)>,
+    commands: Vec<(String, CommandHandler)>,
+    sent_hooks: Vec<SentHook>,
+    words: Vec<String>,
+}
+
+impl Registrar {
+
+    /// Adds a handler.  Its name is prefixed with the plugin's.
+    pub fn handler(&mut self, options: HandlerOptions, handler: Handler) {
+        self.handlers.push((options, handler));
+    }
+
+    /// Adds a command, run when someone says `<nick>: <name> ...`.
+    pub fn command(&mut self, name: &str, command: CommandHandler) {
+        self.words.push(name.to_string());
+        self.commands.push((name.to_string(), command));
+    }
+
+    /// Notes that one of the plugin's handlers takes messages to us
+    /// starting with `word` as a command.  See `CommandWords`.
+    /// Commands added with `command` are noted already.
+    pub fn command_word(&mut self, word: &str) {
+        self.words.push(word.to_string());
+    }
+
+    /// Adds a hook that sees the lines we send.  Like handlers, it
+    /// doesn't see lines to channels the plugin is off in.
+    pub fn sent_hook(&mut self, hook: SentHook) {
+        self.sent_hooks.push(hook);
+    }
+
+}
+
+/// A feature that can be loaded into a `Client`.
+pub trait Plugin: Send {
+
+    /// The name used in the configuration and in handler names.
+    fn name(&self) -> &str;
+
+    /// Reads settings and sets up storage.  A plugin that fails to
+    /// initialize isn't loaded.
+    fn init(&mut self, _ctx: &Context) -> Result<()> {
+        Ok(())
+    }
+
+    /// Registers the plugin's handlers and commands.
+    fn register(&mut self, ctx: &Context, registrar: &mut Registrar);
+
+    /// Whether the plugin only runs in channels it's been turned on in,
+    /// by `channels` or `enable`, rather than everywhere by default.
+    fn opt_in(&self) -> bool {
+        false
+    }
+
+    /// Called after the plugin's handlers and sent hooks have been
+    /// removed.
+    fn shutdown(&mut self) {
+    }
+
+}
+
+/// The channels a plugin runs in.
+struct Channels {
+    /// Whether it runs in channels not in `channels`.
+    default: bool,
+    channels: BTreeMap<String, bool>,
+}
+
+/// Shared between a plugin's handlers and the `Registry`.
+#[derive(Clone)]
+struct Switch {
+    channels: Arc<Mutex<Channels>>,
+    casemapping: Casemapping,
+}
+
+impl Switch {
+
+    fn lock(&self) -> MutexGuard<Channels> {
+        match self.channels.lock() {
+            Ok(channels) => channels,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    fn set(&self, chan: Option<&str>, enabled: bool) {
+        let mut channels = self.lock();
+        match chan {
+            Some(chan) => {
+                channels.channels.insert(self.casemapping.normalize(chan), enabled);
+            },
+            None => {
+                channels.default = enabled;
+                channels.channels.clear();
+            },
+        }
+    }
+
+    fn enabled_in(&self, chan: &str) -> bool {
+        let channels = self.lock();
+        *channels.channels.get(&self.casemapping.normalize(chan)).unwrap_or(&channels.default)
+    }
+
+    /// Whether the plugin should see `line`.  Lines that aren't about
+    /// a channel, like private messages, are always seen.
+    fn allows(&self, line: &str) -> bool {
+        let msg = match Message::parse(line) {
+            Some(msg) => msg,
+            None => return true,
+        };
+        let target = match msg.command {
+            "PRIVMSG" | "NOTICE" | "JOIN" | "PART" | "KICK" | "TOPIC" | "MODE" => msg.params.first(),
+            // RPL_NAMREPLY: <me> <type> <channel> :<names>
+            "353" => msg.params.get(2),
+            _ => return true,
+        };
+        match target.and_then(|target| Dest::parse(target)) {
+            Some(Dest::Chan(chan)) => self.enabled_in(chan),
+            _ => true,
+        }
+    }
+
+}
+
+/// Runs `commands` on messages addressed to `nick`.
+fn command_handler(nick: String, mut commands: Vec<(String, CommandHandler)>) -> Handler {
+    box move |line: &str| {
+        let pm = match Privmsg::parse(line).and_then(|pm| pm.targeted_msg(&nick)) {
+            Some(pm) => pm,
+            None => return Response::nothing(),
+        };
+        let msg = pm.plain_msg();
+        let mut words = msg.trim().splitn(2, char::is_whitespace);
+        let name = words.next().unwrap_or("");
+        let args = words.next().unwrap_or("").trim();
+        let sender = match pm.src {
+            Some(Source::User(ref user_info)) => user_info.nick,
+            _ => return Response::nothing(),
+        };
+        let reply_to = match pm.reply_target(&nick) {
+            Some(reply_to) => reply_to.format(),
+            None => return Response::nothing(),
+        };
+        match commands.iter_mut().find(|&&mut (ref n, _)| n == name) {
+            Some(&mut (_, ref mut command)) => command(&Invocation{
+                sender: sender,
+                reply_to: &reply_to,
+                args: args,
+            }),
+            None => Response::nothing(),
+        }
+    }
+}
+
+/// A loaded plugin's name and where it runs.
+#[derive(Clone, Debug)]
+pub struct PluginInfo {
+    pub name: String,
+    /// The shared library it was loaded from, if it isn't built in.
+    pub library: Option<PathBuf>,
+    /// Whether it runs in channels not listed in `channels`.
+    pub enabled_by_default: bool,
+    /// Channels where it's been turned on or off, by normalized name.
+    pub channels: BTreeMap<String, bool>,
+}
+
+struct Loaded {
+    plugin: Box<Plugin>,
+    /// The shared library it came from, if any, so it can be
+    /// reloaded.
+    path: Option<PathBuf>,
+    switch: Switch,
+    handlers: Vec<HandlerId>,
+    sent_hooks: Vec<SentHookId>,
+}
+
+/// The plugins loaded into a `Client`.
+pub struct Registry {
+    settings: BTreeMap<String, toml::Table>,
+    storage: Option<Arc<Storage>>,
+    commands: CommandWords,
+    loaded: Vec<Loaded>,
+    /// Every library we've opened.  They're never closed, since a
+    /// worker or task may still be running code from an unloaded
+    /// plugin.
+    libraries: Vec<DynamicLibrary>,
+}
+
+/// Opens a copy of the library at `path`, so a rebuilt library is
+/// really loaded again rather than the one already open, and makes a
+/// plugin with its `PLUGIN_SYMBOL`.
+fn open_library(path: &Path) -> Result<(DynamicLibrary, Box<Plugin>)> {
+    let file_name = match path.file_name() {
+        Some(file_name) => file_name.to_string_lossy().into_owned(),
+        None => return Err(Error::Plugin(format!("{} isn't a file", path.display()))),
+    };
+    let copy = env::temp_dir().join(format!("{}-{}", time::precise_time_ns(), file_name));
+    try!(fs::copy(path, &copy));
+    let opened = DynamicLibrary::open(Some(&copy));
+    // Once it's open, the copy is no longer needed.
+    let _ = fs::remove_file(&copy);
+    let library = try!(opened.map_err(|e| Error::Plugin(format!("{}: {}", path.display(), e))));
+    let plugin = unsafe {
+        let symbol = try!(library.symbol::<u8>(PLUGIN_SYMBOL)
+                          .map_err(|e| Error::Plugin(format!("{}: {}", path.display(), e))));
+        let constructor: PluginConstructor = mem::transmute(symbol);
+        constructor()
+    };
+    Ok((library, plugin))
+}
+
+impl Registry {
+
+    /// `settings` are the `[plugins.<name>]` tables, as in
+    /// `Config::plugins`.
+    pub fn new(settings: BTreeMap<String, toml::Table>, storage: Option<Arc<Storage>>) -> Registry {
+        Registry{
+            settings: settings,
+            storage: storage,
+            commands: CommandWords::new(),
+            loaded: Vec::new(),
+            libraries: Vec::new(),
+        }
+    }
+
+    fn position(&self, name: &str) -> Option<usize> {
+        self.loaded.iter().position(|l| l.plugin.name() == name)
+    }
+
+    /// The plugin's settings, without the ones the registry handles,
+    /// and the channels it's limited to, or `None` if it's disabled.
+    fn settings(&self, name: &str) -> Result<Option<(toml::Table, Vec<String>)>> {
+        let mut table = self.settings.get(name).cloned().unwrap_or(BTreeMap::new());
+        let (enabled, channels) = {
+            let section = Section{ table: &table, path: format!("plugins.{}", name) };
+            (try!(section.bool("enabled")).unwrap_or(true), try!(section.strings("channels")))
+        };
+        if !enabled {
+            return Ok(None);
+        }
+        table.remove("enabled");
+        table.remove("channels");
+        Ok(Some((table, channels)))
+    }
+
+    /// Initializes `plugin` and collects its handlers and sent hooks,
+    /// wrapped so they only run where `switch` says, and its command
+    /// words.
+    fn start(&self, client: &Client, plugin: &mut Box<Plugin>, table: &toml::Table, switch: &Switch) -> Result<(Vec<(HandlerOptions, Handler)>, Vec<SentHook>, Vec<String>)> {
+        let name = plugin.name().to_string();
+        let ctx = Context{
+            nick: client.nick(),
+            config: Section{ table: table, path: format!("plugins.{}", name) },
+            membership: client.membership(),
+            casemapping: client.isupport().casemapping(),
+            scheduler: client.scheduler(),
+            network: client.isupport().get("NETWORK").and_then(|n| n).map(|n| n.to_string()),
+            commands: self.commands.clone(),
+            storage: self.storage.clone(),
+        };
+        try!(plugin.init(&ctx));
+        let mut registrar = Registrar{
+            handlers: Vec::new(),
+            commands: Vec::new(),
+            sent_hooks: Vec::new(),
+            words: Vec::new(),
+        };
+        plugin.register(&ctx, &mut registrar);
+        if !registrar.commands.is_empty() {
+            let commands = command_handler(ctx.nick.clone(), registrar.commands);
+            registrar.handlers.push((HandlerOptions::new("commands"), commands));
+        }
+        let handlers = registrar.handlers.into_iter().map(|(mut options, mut handler)| {
+            options.name = format!("{}: {}", name, options.name);
+            options.max_failures = options.max_failures.or(Some(MAX_FAILURES));
+            let handler_switch = switch.clone();
+            let wrapped: Handler = box move |line: &str| {
+                if handler_switch.allows(line) {
+                    handler(line)
+                } else {
+                    Response::nothing()
+                }
+            };
+            (options, wrapped)
+        }).collect();
+        let sent_hooks = registrar.sent_hooks.into_iter().map(|hook| {
+            let hook_switch = switch.clone();
+            let wrapped: SentHook = box move |line: &str| {
+                if hook_switch.allows(line) {
+                    hook(line);
+                }
+            };
+            wrapped
+        }).collect();
+        Ok((handlers, sent_hooks, registrar.words))
+    }
+
+    fn add_sent_hooks(client: &Client, hooks: Vec<SentHook>) -> Vec<SentHookId> {
+        let sent_hooks = client.sent_hooks();
+        hooks.into_iter().map(|hook| sent_hooks.add(hook)).collect()
+    }
+
+    fn remove_sent_hooks(client: &Client, ids: &[SentHookId]) {
+        let sent_hooks = client.sent_hooks();
+        for &id in ids.iter() {
+            sent_hooks.remove(id);
+        }
+    }
+
+    /// Initializes `plugin` and installs its handlers in `client`.
+    /// Returns false without doing anything if it's disabled in its
+    /// settings.
+    pub fn load(&mut self, client: &mut Client, plugin: Box<Plugin>) -> Result<bool> {
+        self.install(client, plugin, None)
+    }
+
+    /// Loads a plugin from the shared library at `path`, which must
+    /// export a `PluginConstructor` named `PLUGIN_SYMBOL`, built with
+    /// the same compiler and version of this crate as we were.
+    pub fn load_library(&mut self, client: &mut Client, path: &Path) -> Result<bool> {
+        let (library, plugin) = try!(open_library(path));
+        self.libraries.push(library);
+        self.install(client, plugin, Some(path.to_path_buf()))
+    }
+
+    fn install(&mut self, client: &mut Client, mut plugin: Box<Plugin>, path: Option<PathBuf>) -> Result<bool> {
+        let name = plugin.name().to_string();
+        if self.position(&name).is_some() {
+            return Err(Error::Plugin(format!("{} is already loaded", name)));
+        }
+        let (table, channels) = match try!(self.settings(&name)) {
+            Some(settings) => settings,
+            None => {
+                info!("Plugin {} is disabled.", name);
+                return Ok(false);
+            },
+        };
+
+        let switch = Switch{
+            channels: Arc::new(Mutex::new(Channels{
+                default: channels.is_empty() && !plugin.opt_in(),
+                channels: BTreeMap::new(),
+            })),
+            casemapping: client.isupport().casemapping(),
+        };
+        for chan in channels.iter() {
+            switch.set(Some(chan), true);
+        }
+
+        let (handlers, sent_hooks, words) = try!(self.start(client, &mut plugin, &table, &switch));
+        let handlers = match client.swap_handlers(&[], handlers) {
+            Ok(handlers) => handlers,
+            Err(e) => {
+                plugin.shutdown();
+                return Err(e);
+            },
+        };
+        let sent_hooks = Registry::add_sent_hooks(client, sent_hooks);
+        self.commands.set(&name, words);
+        info!("Loaded plugin {}.", name);
+        self.loaded.push(Loaded{
+            plugin: plugin,
+            path: path,
+            switch: switch,
+            handlers: handlers,
+            sent_hooks: sent_hooks,
+        });
+        Ok(true)
+    }
+
+    /// Loads the plugin `name` again from its shared library, and swaps
+    /// its new handlers in for the old ones in one go.  It stays on in
+    /// the same channels.  If the new one fails to load, the old one
+    /// keeps running.
+    pub fn reload(&mut self, client: &mut Client, name: &str) -> Result<()> {
+        let i = match self.position(name) {
+            Some(i) => i,
+            None => return Err(Error::Plugin(format!("{} isn't loaded", name))),
+        };
+        let path = match self.loaded[i].path.clone() {
+            Some(path) => path,
+            None => return Err(Error::Plugin(format!("{} is built in", name))),
+        };
+        let (library, mut plugin) = try!(open_library(&path));
+        self.libraries.push(library);
+        if plugin.name() != name {
+            return Err(Error::Plugin(format!("{} now contains {}", path.display(), plugin.name())));
+        }
+        let (table, _) = match try!(self.settings(name)) {
+            Some(settings) => settings,
+            None => return Err(Error::Plugin(format!("{} has been disabled", name))),
+        };
+        let switch = self.loaded[i].switch.clone();
+        let (handlers, sent_hooks, words) = try!(self.start(client, &mut plugin, &table, &switch));
+        let handlers = match client.swap_handlers(&self.loaded[i].handlers, handlers) {
+            Ok(handlers) => handlers,
+            Err(e) => {
+                plugin.shutdown();
+                return Err(e);
+            },
+        };
+        Registry::remove_sent_hooks(client, &self.loaded[i].sent_hooks);
+        let sent_hooks = Registry::add_sent_hooks(client, sent_hooks);
+        self.commands.set(name, words);
+        let loaded = &mut self.loaded[i];
+        loaded.plugin.shutdown();
+        loaded.plugin = plugin;
+        loaded.handlers = handlers;
+        loaded.sent_hooks = sent_hooks;
+        info!("Reloaded plugin {} from {}.", name, path.display());
+        Ok(())
+    }
+
+    /// Removes the plugin `name`'s handlers from `client` and shuts it
+    /// down.  Returns whether it was loaded.
+    pub fn unload(&mut self, client: &mut Client, name: &str) -> Result<bool> {
+        let i = match self.position(name) {
+            Some(i) => i,
+            None => return Ok(false),
+        };
+        try!(client.swap_handlers(&self.loaded[i].handlers, Vec::new()));
+        Registry::remove_sent_hooks(client, &self.loaded[i].sent_hooks);
+        self.commands.remove(name);
+        let mut loaded = self.loaded.remove(i);
+        loaded.plugin.shutdown();
+        info!("Unloaded plugin {}.", name);
+        Ok(true)
+    }
+
+    /// Unloads every plugin, most recently loaded first.
+    pub fn unload_all(&mut self, client: &mut Client) -> Result<()> {
+        let names: Vec<String> = self.loaded.iter().rev().map(|l| l.plugin.name().to_string()).collect();
+        for name in names.iter() {
+            try!(self.unload(client, name));
+        }
+        Ok(())
+    }
+
+    /// Turns the plugin `name` on in `chan`, or everywhere if `chan` is
+    /// `None`.  Returns whether it's loaded.
+    pub fn enable(&self, name: &str, chan: Option<&str>) -> bool {
+        self.set(name, chan, true)
+    }
+
+    /// Turns the plugin `name` off in `chan`, or everywhere if `chan`
+    /// is `None`.  Its handlers stay installed but don't see lines from
+    /// the channels it's off in.  Returns whether it's loaded.
+    pub fn disable(&self, name: &str, chan: Option<&str>) -> bool {
+        self.set(name, chan, false)
+    }
+
+    fn set(&self, name: &str, chan: Option<&str>, enabled: bool) -> bool {
+        match self.position(name) {
+            Some(i) => {
+                self.loaded[i].switch.set(chan, enabled);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// The loaded plugins, in the order they were loaded.
+    pub fn plugins(&self) -> Vec<PluginInfo> {
+        self.loaded.iter().map(|l| {
+            let channels = l.switch.lock();
+            PluginInfo{
+                name: l.plugin.name().to_string(),
+                library: l.path.clone(),
+                enabled_by_default: channels.default,
+                channels: channels.channels.clone(),
+            }
+        }).collect()
+    }
+
+}
@@ -0,0 +1,194 @@
+//! Lets the bot's owners manage plugins from IRC:
+//!
+//! ```{.ignore .text}
+//! rustbot: plugin load karma2        loads <dir>/libkarma2.so
+//! rustbot: plugin reload karma2
+//! rustbot: plugin unload karma2
+//! rustbot: plugin disable karma #rust
+//! rustbot: plugin enable karma       everywhere
+//! rustbot: plugin list
+//! ```
+//!
+//! Only people matching one of the `masks` may use it, and libraries
+//! are only loaded from `dir`:
+//!
+//! ```{.ignore .toml}
+//! [plugins.admin]
+//! masks = ["leif!*@*.example.com"]
+//! dir = "/usr/local/lib/hiphopabotamus"
+//! ```
+//!
+//! The plugins are owned by whoever holds the `Registry`, so requests
+//! go to them over a channel, to be passed to `handle`.
+
+use regex;
+use regex::Regex;
+use std::env::consts;
+use std::path::PathBuf;
+use std::sync::mpsc::Sender;
+use super::super::Client;
+use super::super::command::Command;
+use super::super::error::{Error, Result};
+use super::super::event_stream::{Action, HandlerAction, HandlerOptions, Response};
+use super::super::plugin::{Context, Plugin, Registrar, Registry};
+use super::super::protocol;
+
+/// Something an owner asked for.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Request {
+    Load(PathBuf),
+    Reload(String),
+    Unload(String),
+    /// Turns a plugin on in a channel, or everywhere.
+    Enable(String, Option<String>),
+    /// Turns a plugin off in a channel, or everywhere.
+    Disable(String, Option<String>),
+    List,
+}
+
+/// A `Request`, and where to send the result.
+pub struct Envelope {
+    pub request: Request,
+    pub reply_to: String,
+}
+
+pub struct Admin {
+    requests: Sender<Envelope>,
+    masks: Vec<Regex>,
+    dir: Option<PathBuf>,
+}
+
+impl Admin {
+
+    pub fn new(requests: Sender<Envelope>) -> Admin {
+        Admin{
+            requests: requests,
+            masks: Vec::new(),
+            dir: None,
+        }
+    }
+
+}
+
+/// Turns a mask like `nick!*@host` into a regex.
+pub fn mask_regex(mask: &str) -> Option<Regex> {
+    let pattern = regex::quote(mask).replace(r"\*", ".*").replace(r"\?", ".");
+    Regex::new(&format!("(?i)^{}$", pattern)).ok()
+}
+
+/// Whether `name` is safe to use as part of a file name.
+fn is_plain_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+}
+
+impl Plugin for Admin {
+
+    fn name(&self) -> &str {
+        "admin"
+    }
+
+    fn init(&mut self, ctx: &Context) -> Result<()> {
+        try!(ctx.config.allow_keys(&["masks", "dir"]));
+        let masks = try!(ctx.config.strings("masks"));
+        self.masks = try!(masks.iter().map(|mask| {
+            mask_regex(mask).ok_or(Error::Config(ctx.config.error("masks", &format!("bad mask {}", mask))))
+        }).collect());
+        self.dir = try!(ctx.config.string("dir")).map(PathBuf::from);
+        if self.masks.is_empty() {
+            warn!("No admin masks configured, so nobody can manage plugins.");
+        }
+        Ok(())
+    }
+
+    fn register(&mut self, ctx: &Context, registrar: &mut Registrar) {
+        let nick = ctx.nick.clone();
+        let requests = self.requests.clone();
+        let masks = self.masks.clone();
+        let dir = self.dir.clone();
+        registrar.command_word("plugin");
+        registrar.handler(HandlerOptions::new("plugin"), box move |line: &str| {
+            let source = match protocol::Message::parse(line).and_then(|msg| msg.prefix) {
+                Some(source) => source.to_string(),
+                None => return Response::nothing(),
+            };
+            let pm = match protocol::Privmsg::parse(line).and_then(|pm| pm.targeted_msg(&nick)) {
+                Some(pm) => pm,
+                None => return Response::nothing(),
+            };
+            let msg = pm.plain_msg();
+            let c = match regex!(r"^plugin\s+(\w+)(?:\s+([^\s]+))?(?:\s+([^\s]+))?\s*$").captures(&msg) {
+                Some(c) => c,
+                None => return Response::nothing(),
+            };
+            let reply_to = match pm.reply_target(&nick) {
+                Some(reply_to) => reply_to.format(),
+                None => return Response::nothing(),
+            };
+            if !masks.iter().any(|re| re.is_match(&source)) {
+                warn!("{} tried to manage plugins.", source);
+                return Response(None, HandlerAction::Keep, Action::Skip);
+            }
+            let name = c.at(2).map(|name| name.to_string());
+            let chan = c.at(3).map(|chan| chan.to_string());
+            let request = match (c.at(1).expect("Bad match group"), name) {
+                ("list", None) => Ok(Request::List),
+                ("load", Some(ref name)) if is_plain_name(name) => match dir {
+                    Some(ref dir) => Ok(Request::Load(dir.join(format!("{}{}{}", consts::DLL_PREFIX, name, consts::DLL_SUFFIX)))),
+                    None => Err("no plugin dir is configured"),
+                },
+                ("reload", Some(name)) => Ok(Request::Reload(name)),
+                ("unload", Some(name)) => Ok(Request::Unload(name)),
+                ("enable", Some(name)) => Ok(Request::Enable(name, chan)),
+                ("disable", Some(name)) => Ok(Request::Disable(name, chan)),
+                _ => Err("usage: plugin list|load|reload|unload|enable|disable [name] [channel]"),
+            };
+            match request {
+                Ok(request) => {
+                    let envelope = Envelope{ request: request, reply_to: reply_to };
+                    if requests.send(envelope).is_err() {
+                        error!("Nobody is listening for plugin requests.");
+                    }
+                    Response(None, HandlerAction::Keep, Action::Skip)
+                },
+                Err(usage) => Response(Some(Command::privmsg(&reply_to, usage)), HandlerAction::Keep, Action::Skip),
+            }
+        });
+    }
+
+}
+
+/// Carries out `request`, returning what to tell whoever asked.
+pub fn handle(registry: &mut Registry, client: &mut Client, request: Request) -> String {
+    let result = match request {
+        Request::Load(path) => registry.load_library(client, &path).map(|loaded| {
+            if loaded {
+                format!("loaded {}", path.display())
+            } else {
+                format!("{} is disabled in the configuration", path.display())
+            }
+        }),
+        Request::Reload(name) => registry.reload(client, &name).map(|_| format!("reloaded {}", name)),
+        Request::Unload(ref name) if name == "admin" => Ok("I'd rather not".to_string()),
+        Request::Unload(name) => registry.unload(client, &name).map(|unloaded| {
+            if unloaded { format!("unloaded {}", name) } else { format!("{} isn't loaded", name) }
+        }),
+        Request::Enable(name, chan) => Ok(switched(registry.enable(&name, chan.as_ref().map(|c| &c[..])), &name, "on", chan)),
+        Request::Disable(name, chan) => Ok(switched(registry.disable(&name, chan.as_ref().map(|c| &c[..])), &name, "off", chan)),
+        Request::List => Ok(registry.plugins().iter().map(|p| p.name.clone()).collect::<Vec<_>>().join(", ")),
+    };
+    match result {
+        Ok(reply) => reply,
+        Err(e) => {
+            error!("Plugin request failed: {}", e);
+            format!("failed: {}", e)
+        },
+    }
+}
+
+fn switched(loaded: bool, name: &str, state: &str, chan: Option<String>) -> String {
+    match (loaded, chan) {
+        (false, _) => format!("{} isn't loaded", name),
+        (true, Some(chan)) => format!("{} is {} in {}", name, state, chan),
+        (true, None) => format!("{} is {} everywhere", name, state),
+    }
+}
@@ -0,0 +1,37 @@
+//! Picks one of several things: `rustbot: tea or coffee`.
+
+use rand::{thread_rng, Rng};
+use super::super::event_stream::{HandlerOptions, Response};
+use super::super::plugin::{Context, Plugin, Registrar};
+use super::super::protocol;
+
+pub struct Choice;
+
+impl Plugin for Choice {
+
+    fn name(&self) -> &str {
+        "choice"
+    }
+
+    fn register(&mut self, ctx: &Context, registrar: &mut Registrar) {
+        let nick = ctx.nick.clone();
+        registrar.handler(HandlerOptions::new("choose"), box move |line: &str| {
+            if let Some(pm) = protocol::Privmsg::parse(line).and_then(|pm| pm.targeted_msg(&nick)) {
+                let msg = pm.plain_msg();
+                let choices: Vec<_> = regex!(r"\s+or\s+")
+                    .split(&msg)
+                    .map(|s| s.trim())
+                    .filter(|s| !s.is_empty())
+                    .collect();
+                if choices.len() > 1 {
+                    let mut rng = thread_rng();
+                    if let Some(reply_to) = pm.reply_target(&nick) {
+                        return Response::respond(protocol::Privmsg::new(reply_to, rng.choose(&choices).unwrap()).command());
+                    }
+                }
+            }
+            Response::nothing()
+        });
+    }
+
+}
@@ -0,0 +1,34 @@
+//! Says hi back, and leaves when told to go away.
+
+use super::super::event_stream::{Action, HandlerAction, HandlerOptions, Response};
+use super::super::plugin::{Context, Plugin, Registrar};
+use super::super::protocol;
+
+pub struct Echo;
+
+impl Plugin for Echo {
+
+    fn name(&self) -> &str {
+        "echo"
+    }
+
+    fn register(&mut self, ctx: &Context, registrar: &mut Registrar) {
+        let nick = ctx.nick.clone();
+        registrar.handler(HandlerOptions::new("echo"), box move |line: &str| {
+            if let Some(pm) = protocol::Privmsg::parse(line).and_then(|pm| pm.targeted_msg(&nick)) {
+                let msg = pm.plain_msg();
+                if regex!(r"^[Hh]i$").is_match(&msg) {
+                    if let Some(reply_to) = pm.reply_target(&nick) {
+                        if let Some(protocol::Source::User(ref user_info)) = pm.src {
+                            return Response::respond(protocol::Privmsg::new(reply_to, &format!("Hi, {}!", user_info.nick)).command());
+                        }
+                    }
+                } else if regex!(r"^go away$").is_match(&msg) {
+                    return Response(None, HandlerAction::Keep, Action::Stop);
+                }
+            }
+            Response::nothing()
+        });
+    }
+
+}
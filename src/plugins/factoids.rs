@@ -0,0 +1,418 @@
+//! Remembers what things are, and tells people when they ask.
+//!
+//! Said to the bot:
+//!
+//! ```{.ignore .text}
+//! rustbot: rust is a language         learns it, unless rust is already something
+//! rustbot: rust is also <reply>fast   adds another value
+//! rustbot: no, rust is $who's friend  replaces every value
+//! rustbot: forget rust
+//! rustbot: literal rust               the values as written, and who set them
+//! rustbot: lock rust                  admins only; also unlock
+//! rustbot: rust?                      or just "rust?" in a channel
+//! ```
+//!
+//! A value starting with `<reply>` is said as is, and one starting with
+//! `<action>` is done as a CTCP ACTION; otherwise the bot says "rust is
+//! ...".  `$who` is replaced with whoever asked, `$nick` with the bot's
+//! nick and `$chan` with the channel.  Keys are case-insensitive.
+//! Messages starting with another plugin's command, like "rustbot: tell
+//! alice the build is fixed", are left to that plugin.
+//!
+//! ```{.ignore .toml}
+//! [plugins.factoids]
+//! admins = ["leif!*@*.example.com"]
+//! ```
+
+use rand::{thread_rng, Rng};
+use regex::{Captures, Regex};
+use std::sync::Arc;
+use time;
+use time::Timespec;
+use super::super::command::Command;
+use super::super::error::{Error, Result};
+use super::super::event_stream::{HandlerOptions, Response};
+use super::super::plugin::{CommandWords, Context, Plugin, Registrar};
+use super::super::protocol;
+use super::super::storage::{Factoid, Storage};
+use super::admin;
+
+pub struct Factoids {
+    admins: Vec<Regex>,
+}
+
+impl Factoids {
+
+    pub fn new() -> Factoids {
+        Factoids{
+            admins: Vec::new(),
+        }
+    }
+
+}
+
+/// How keys are compared.
+fn normalize(key: &str) -> String {
+    key.trim().to_lowercase()
+}
+
+/// Fills in `$who`, `$nick` and `$chan`.
+fn substitute(value: &str, who: &str, nick: &str, chan: &str) -> String {
+    value.replace("$who", who).replace("$nick", nick).replace("$chan", chan)
+}
+
+/// What to send for one of `name`'s values, after substitution.
+fn render(reply_to: &str, name: &str, value: &str) -> Command {
+    if let Some(c) = regex!(r"^\s*<reply>\s*(.*)$").captures(value) {
+        Command::privmsg(reply_to, c.at(1).expect("Bad match group"))
+    } else if let Some(c) = regex!(r"^\s*<action>\s*(.*)$").captures(value) {
+        Command::privmsg(reply_to, &protocol::ctcp_action(c.at(1).expect("Bad match group")))
+    } else {
+        Command::privmsg(reply_to, &format!("{} is {}", name, value))
+    }
+}
+
+/// The values of a key as they were written, with who set the latest.
+fn literal(factoids: &[Factoid]) -> String {
+    let values: Vec<&str> = factoids.iter().map(|f| &f.value[..]).collect();
+    let latest = factoids.iter().max_by(|f| f.set_at).expect("No factoids");
+    let mut text = format!("{} =is= {}", latest.name, values.join(" =or= "));
+    if !latest.set_by.is_empty() {
+        text.push_str(&format!(" (last set by {} at {})", latest.set_by, time::at_utc(Timespec::new(latest.set_at, 0)).rfc3339()));
+    }
+    text
+}
+
+/// What someone said to us, or asked in a channel.
+#[derive(Debug, PartialEq, Eq)]
+enum Request<'a> {
+    Forget(&'a str),
+    Literal(&'a str),
+    Lock(&'a str, bool),
+    Replace(&'a str, &'a str),
+    Also(&'a str, &'a str),
+    Learn(&'a str, &'a str),
+    Recall(&'a str),
+}
+
+fn at<'t>(c: &Captures<'t>, i: usize) -> &'t str {
+    c.at(i).expect("Bad match group").trim()
+}
+
+fn parse_targeted<'a>(msg: &'a str, commands: &CommandWords) -> Option<Request<'a>> {
+    let msg = msg.trim();
+    if commands.contains(msg.split(char::is_whitespace).next().unwrap_or("")) {
+        None
+    } else if let Some(c) = regex!(r"^forget\s+(.+)$").captures(msg) {
+        Some(Request::Forget(at(&c, 1)))
+    } else if let Some(c) = regex!(r"^literal\s+(.+)$").captures(msg) {
+        Some(Request::Literal(at(&c, 1)))
+    } else if let Some(c) = regex!(r"^(lock|unlock)\s+(.+)$").captures(msg) {
+        Some(Request::Lock(at(&c, 2), at(&c, 1) == "lock"))
+    } else if let Some(c) = regex!(r"(?i)^no,?\s+(.+?)\s+is\s+(.+)$").captures(msg) {
+        Some(Request::Replace(at(&c, 1), at(&c, 2)))
+    } else if let Some(c) = regex!(r"^(.+?)\s+is\s+also\s+(.+)$").captures(msg) {
+        Some(Request::Also(at(&c, 1), at(&c, 2)))
+    } else if let Some(c) = regex!(r"^(.+?)\s+is\s+(.+)$").captures(msg) {
+        Some(Request::Learn(at(&c, 1), at(&c, 2)))
+    } else {
+        let key = msg.trim_right_matches(|c| c == '?' || c == '!').trim();
+        if key.is_empty() { None } else { Some(Request::Recall(key)) }
+    }
+}
+
+/// Lines said in a channel without addressing us only count if they're
+/// questions.
+fn parse_untargeted(msg: &str) -> Option<Request> {
+    let msg = msg.trim();
+    if msg.ends_with('?') {
+        let key = msg.trim_right_matches('?').trim();
+        if !key.is_empty() {
+            return Some(Request::Recall(key));
+        }
+    }
+    None
+}
+
+struct Asker<'a> {
+    who: &'a str,
+    source: &'a str,
+    reply_to: &'a str,
+}
+
+fn run(storage: &Arc<Storage>, admins: &[Regex], nick: &str, asker: &Asker, request: Request) -> Result<Option<Command>> {
+    let say = |text: String| Ok(Some(Command::privmsg(asker.reply_to, &text)));
+    let now = time::get_time().sec;
+    let factoid = |name: &str, value: &str| Factoid{
+        name: name.to_string(),
+        value: value.to_string(),
+        set_by: asker.who.to_string(),
+        set_at: now,
+    };
+    let locked = |name: &str| storage.factoid_locked(&normalize(name));
+    match request {
+        Request::Recall(name) => {
+            let factoids = try!(storage.factoids(&normalize(name)));
+            match thread_rng().choose(&factoids) {
+                Some(f) => {
+                    let value = substitute(&f.value, asker.who, nick, asker.reply_to);
+                    Ok(Some(render(asker.reply_to, &f.name, &value)))
+                },
+                None => Ok(None),
+            }
+        },
+        Request::Literal(name) => {
+            let factoids = try!(storage.factoids(&normalize(name)));
+            if factoids.is_empty() {
+                say(format!("I don't know anything about {}.", name))
+            } else {
+                say(literal(&factoids))
+            }
+        },
+        Request::Lock(name, lock) => {
+            if !admins.iter().any(|re| re.is_match(asker.source)) {
+                return say(format!("Sorry {}, only admins can do that.", asker.who));
+            }
+            try!(storage.lock_factoid(&normalize(name), lock, asker.who));
+            say(format!("{} is {}.", name, if lock { "locked" } else { "unlocked" }))
+        },
+        Request::Forget(name) => {
+            if try!(locked(name)) {
+                return say(format!("{} is locked.", name));
+            }
+            match try!(storage.forget_factoid(&normalize(name))) {
+                0 => say(format!("I don't know anything about {}.", name)),
+                _ => say(format!("I forgot {}.", name)),
+            }
+        },
+        Request::Replace(name, value) => {
+            if try!(locked(name)) {
+                return say(format!("{} is locked.", name));
+            }
+            try!(storage.replace_factoid(&normalize(name), &factoid(name, value)));
+            say(format!("OK, {}.", asker.who))
+        },
+        Request::Also(name, value) => {
+            if try!(locked(name)) {
+                return say(format!("{} is locked.", name));
+            }
+            if try!(storage.add_factoid(&normalize(name), &factoid(name, value))) {
+                say(format!("OK, {}.", asker.who))
+            } else {
+                say(format!("I already knew that, {}.", asker.who))
+            }
+        },
+        Request::Learn(name, value) => {
+            let existing = try!(storage.factoids(&normalize(name)));
+            if let Some(f) = existing.first() {
+                if f.value == value {
+                    return say(format!("I already knew that, {}.", asker.who));
+                }
+                return say(format!("...but {} is {}", f.name, f.value));
+            }
+            if try!(locked(name)) {
+                return say(format!("{} is locked.", name));
+            }
+            try!(storage.add_factoid(&normalize(name), &factoid(name, value)));
+            say(format!("OK, {}.", asker.who))
+        },
+    }
+}
+
+impl Plugin for Factoids {
+
+    fn name(&self) -> &str {
+        "factoids"
+    }
+
+    fn init(&mut self, ctx: &Context) -> Result<()> {
+        try!(ctx.config.allow_keys(&["admins"]));
+        self.admins = try!(try!(ctx.config.strings("admins")).iter().map(|mask| {
+            admin::mask_regex(mask).ok_or(Error::Config(ctx.config.error("admins", &format!("bad mask {}", mask))))
+        }).collect());
+        try!(ctx.storage());
+        Ok(())
+    }
+
+    fn register(&mut self, ctx: &Context, registrar: &mut Registrar) {
+        let nick = ctx.nick.clone();
+        let admins = self.admins.clone();
+        let commands = ctx.commands.clone();
+        let storage = ctx.storage().ok().expect("Checked in init");
+        registrar.handler(HandlerOptions::new("factoids").blocking(), box move |line: &str| {
+            let source = match protocol::Message::parse(line).and_then(|msg| msg.prefix) {
+                Some(source) => source.to_string(),
+                None => return Response::nothing(),
+            };
+            let pm = match protocol::Privmsg::parse(line) {
+                Some(pm) => pm,
+                None => return Response::nothing(),
+            };
+            let who = match pm.src {
+                Some(protocol::Source::User(ref user_info)) => user_info.nick.to_string(),
+                _ => return Response::nothing(),
+            };
+            let reply_to = match pm.reply_target(&nick) {
+                Some(reply_to) => reply_to.format(),
+                None => return Response::nothing(),
+            };
+            let msg = pm.plain_msg();
+            let targeted = match protocol::Privmsg::parse(line).and_then(|pm| pm.targeted_msg(&nick)) {
+                Some(targeted) => Some(targeted.plain_msg()),
+                None => None,
+            };
+            let request = match targeted {
+                Some(ref targeted) => parse_targeted(targeted, &commands),
+                None => parse_untargeted(&msg),
+            };
+            let request = match request {
+                Some(request) => request,
+                None => return Response::nothing(),
+            };
+            let asker = Asker{ who: &who, source: &source, reply_to: &reply_to };
+            match run(&storage, &admins, &nick, &asker, request) {
+                Ok(Some(command)) => Response::respond(command),
+                Ok(None) => Response::nothing(),
+                Err(e) => {
+                    error!("Error handling factoid: {}", e);
+                    Response::nothing()
+                },
+            }
+        });
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use regex::Regex;
+    use std::sync::Arc;
+    use super::{parse_targeted, parse_untargeted, render, run, Asker, Request};
+    use super::super::admin::mask_regex;
+    use super::super::super::command::Command;
+    use super::super::super::plugin::CommandWords;
+    use super::super::super::protocol::ctcp_action;
+    use super::super::super::storage::Storage;
+    use super::super::super::storage::memory::MemoryStorage;
+
+    /// Someone in #rust, addressed to a bot called "bot" with `admins`.
+    struct Channel {
+        storage: Arc<Storage>,
+        admins: Vec<Regex>,
+    }
+
+    impl Channel {
+
+        fn new(admins: &[&str]) -> Channel {
+            Channel{
+                storage: Arc::new(MemoryStorage::new()),
+                admins: admins.iter().map(|mask| mask_regex(mask).unwrap()).collect(),
+            }
+        }
+
+        /// What the bot does when `source` says `msg` to it.
+        fn say(&self, source: &str, msg: &str) -> Option<Command> {
+            let who = source.split('!').next().unwrap();
+            let asker = Asker{ who: who, source: source, reply_to: "#rust" };
+            let request = parse_targeted(msg, &CommandWords::new()).unwrap();
+            run(&self.storage, &self.admins, "bot", &asker, request).unwrap()
+        }
+
+        /// The text of the bot's reply.
+        fn reply(&self, source: &str, msg: &str) -> String {
+            match self.say(source, msg) {
+                Some(Command::Privmsg(ref target, ref text)) if target == "#rust" => text.clone(),
+                other => panic!("Expected a reply to {:?}, got {:?}", msg, other),
+            }
+        }
+
+    }
+
+    const ALICE: &'static str = "alice!a@home.example.net";
+    const BOB: &'static str = "bob!b@work.example.net";
+    const LEIF: &'static str = "leif!l@desk.example.com";
+
+    #[test]
+    fn requests() {
+        let commands = CommandWords::new();
+        assert_eq!(parse_targeted("no, Rust is fun", &commands), Some(Request::Replace("Rust", "fun")));
+        assert_eq!(parse_targeted("No rust is fun", &commands), Some(Request::Replace("rust", "fun")));
+        assert_eq!(parse_targeted("rust is also fast", &commands), Some(Request::Also("rust", "fast")));
+        assert_eq!(parse_targeted("rust is a language", &commands), Some(Request::Learn("rust", "a language")));
+        assert_eq!(parse_targeted("forget rust ", &commands), Some(Request::Forget("rust")));
+        assert_eq!(parse_targeted("literal rust", &commands), Some(Request::Literal("rust")));
+        assert_eq!(parse_targeted("lock rust", &commands), Some(Request::Lock("rust", true)));
+        assert_eq!(parse_targeted("unlock rust", &commands), Some(Request::Lock("rust", false)));
+        assert_eq!(parse_targeted("rust?!", &commands), Some(Request::Recall("rust")));
+        assert_eq!(parse_untargeted("rust?"), Some(Request::Recall("rust")));
+        assert_eq!(parse_untargeted("rust"), None);
+        assert_eq!(parse_untargeted("?"), None);
+    }
+
+    #[test]
+    fn learning_and_forgetting() {
+        let chan = Channel::new(&[]);
+        assert_eq!(chan.say(BOB, "rust?"), None);
+        assert_eq!(chan.reply(ALICE, "rust is a language"), "OK, alice.");
+        assert_eq!(chan.reply(BOB, "Rust is a language"), "I already knew that, bob.");
+        assert_eq!(chan.reply(BOB, "Rust is fast"), "...but rust is a language");
+        assert_eq!(chan.reply(ALICE, "rust is also fast"), "OK, alice.");
+        assert_eq!(chan.reply(BOB, "RUST is also fast"), "I already knew that, bob.");
+        assert!(chan.reply(BOB, "literal rust").starts_with("rust =is= a language =or= fast (last set by alice at "));
+
+        assert_eq!(chan.reply(BOB, "no, rust is $who's friend"), "OK, bob.");
+        assert_eq!(chan.reply(ALICE, "rust?"), "rust is alice's friend");
+        assert!(chan.reply(ALICE, "literal rust").starts_with("rust =is= $who's friend (last set by bob at "));
+
+        assert_eq!(chan.reply(ALICE, "forget Rust"), "I forgot Rust.");
+        assert_eq!(chan.reply(ALICE, "forget rust"), "I don't know anything about rust.");
+        assert_eq!(chan.reply(ALICE, "literal rust"), "I don't know anything about rust.");
+        assert_eq!(chan.say(ALICE, "rust?"), None);
+    }
+
+    #[test]
+    fn locking() {
+        let chan = Channel::new(&["leif!*@*.example.com"]);
+        assert_eq!(chan.reply(ALICE, "rust is a language"), "OK, alice.");
+        assert_eq!(chan.reply(BOB, "lock rust"), "Sorry bob, only admins can do that.");
+        assert_eq!(chan.reply(BOB, "forget rust"), "I forgot rust.");
+        assert_eq!(chan.reply(ALICE, "rust is a language"), "OK, alice.");
+
+        assert_eq!(chan.reply(LEIF, "lock rust"), "rust is locked.");
+        assert_eq!(chan.reply(BOB, "no, rust is bad"), "rust is locked.");
+        assert_eq!(chan.reply(BOB, "rust is also bad"), "rust is locked.");
+        assert_eq!(chan.reply(BOB, "forget RUST"), "RUST is locked.");
+        assert_eq!(chan.reply(BOB, "unlock rust"), "Sorry bob, only admins can do that.");
+        assert_eq!(chan.reply(BOB, "rust?"), "rust is a language");
+
+        assert_eq!(chan.reply(LEIF, "unlock Rust"), "Rust is unlocked.");
+        assert_eq!(chan.reply(BOB, "no, rust is bad"), "OK, bob.");
+    }
+
+    #[test]
+    fn rendering() {
+        let chan = Channel::new(&[]);
+        assert_eq!(chan.reply(ALICE, "hello is <reply>Hi $who, I'm $nick in $chan!"), "OK, alice.");
+        assert_eq!(chan.reply(BOB, "hello?"), "Hi bob, I'm bot in #rust!");
+        assert_eq!(chan.reply(ALICE, "wave is <action> waves at $who"), "OK, alice.");
+        assert_eq!(chan.reply(BOB, "wave?"), ctcp_action("waves at bob"));
+        assert_eq!(render("#rust", "Rust", "<reply>"), Command::privmsg("#rust", ""));
+        assert_eq!(render("#rust", "Rust", "safe"), Command::privmsg("#rust", "Rust is safe"));
+    }
+
+    #[test]
+    fn other_plugins_commands_arent_learned() {
+        let commands = CommandWords::new();
+        commands.set("tell", vec!["tell".to_string()]);
+        commands.set("reminders", vec!["remind".to_string(), "reminders".to_string(), "cancel".to_string()]);
+        assert_eq!(parse_targeted("tell alice the build is fixed", &commands), None);
+        assert_eq!(parse_targeted("Remind me in 5m the oven is hot", &commands), None);
+        assert_eq!(parse_targeted("the build is fixed", &commands), Some(Request::Learn("the build", "fixed")));
+        assert_eq!(parse_targeted("telling is fun", &commands), Some(Request::Learn("telling", "fun")));
+
+        commands.remove("tell");
+        assert_eq!(parse_targeted("tell alice the build is fixed", &commands), Some(Request::Learn("tell alice the build", "fixed")));
+    }
+
+}
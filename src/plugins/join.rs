@@ -0,0 +1,46 @@
+//! Joins and leaves channels when asked: `rustbot: join #rust`, and
+//! likewise `part` and `cycle`.
+
+use std::thread;
+use super::super::event_stream::{Action, HandlerAction, Response};
+use super::super::membership::Membership;
+use super::super::plugin::{CommandHandler, Context, Invocation, Plugin, Registrar};
+
+pub struct Join;
+
+fn command(membership: &Membership, action: &'static str) -> CommandHandler {
+    let membership = membership.clone();
+    box move |invocation: &Invocation| {
+        let chan = match regex!(r"^(#[^\s]+)").captures(invocation.args).and_then(|c| c.at(1)) {
+            Some(chan) => chan.to_string(),
+            None => return Response::nothing(),
+        };
+        let mut membership = membership.clone();
+        // Membership waits for the server, so don't hold up the event loop.
+        thread::spawn(move || {
+            let result = match action {
+                "join" => membership.join(&[(chan, None)]).map(|_| ()),
+                "part" => membership.part(&[chan], None).map(|_| ()),
+                _ => membership.cycle(&[chan], None).map(|_| ()),
+            };
+            if let Err(e) = result {
+                error!("Error trying to {}: {}", action, e);
+            }
+        });
+        Response(None, HandlerAction::Keep, Action::Skip)
+    }
+}
+
+impl Plugin for Join {
+
+    fn name(&self) -> &str {
+        "join"
+    }
+
+    fn register(&mut self, ctx: &Context, registrar: &mut Registrar) {
+        for action in ["join", "part", "cycle"].iter() {
+            registrar.command(action, command(&ctx.membership, *action));
+        }
+    }
+
+}
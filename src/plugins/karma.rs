@@ -0,0 +1,333 @@
+//! Keeps score.
+//!
+//! ```{.ignore .text}
+//! rust++                     one point for rust
+//! java-- # checked exceptions  takes one away, and remembers why
+//! (borrow checker)++         for names with spaces
+//! karma rust                 the score, and the latest reasons
+//! karma top 5                the best scores; also karma bottom
+//! ```
+//!
+//! Names are compared the way the server compares nicks.  Nobody can
+//! change their own karma, changes only count in channels, and each
+//! person gets `max_changes` changes every `window_secs` seconds.  With
+//! `per_channel`, every channel keeps its own scores.
+//!
+//! ```{.ignore .toml}
+//! [plugins.karma]
+//! per_channel = false
+//! max_changes = 5
+//! window_secs = 600
+//! ```
+
+use regex::Captures;
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use time;
+use super::super::error::Result;
+use super::super::event_stream::{HandlerOptions, Response};
+use super::super::isupport::Casemapping;
+use super::super::plugin::{Context, Plugin, Registrar};
+use super::super::protocol;
+use super::super::storage::{KarmaChange, Storage};
+
+/// How many reasons `karma X` shows.
+const REASONS: usize = 3;
+/// How many names a leaderboard shows unless asked for more.
+const DEFAULT_RANKING: usize = 5;
+/// The longest leaderboard anyone can ask for.
+const MAX_RANKING: usize = 10;
+
+pub struct Karma {
+    per_channel: bool,
+    max_changes: usize,
+    window_secs: i64,
+}
+
+impl Karma {
+
+    pub fn new() -> Karma {
+        Karma{
+            per_channel: false,
+            max_changes: 5,
+            window_secs: 600,
+        }
+    }
+
+}
+
+/// What someone said.
+#[derive(Debug, PartialEq, Eq)]
+enum Request<'a> {
+    Change(&'a str, i32, &'a str),
+    Show(&'a str),
+    Ranking(bool, usize),
+}
+
+fn at<'t>(c: &Captures<'t>, i: usize) -> &'t str {
+    c.at(i).unwrap_or("").trim()
+}
+
+fn change(c: &Captures) -> i32 {
+    if at(c, 2) == "++" { 1 } else { -1 }
+}
+
+fn parse(msg: &str) -> Option<Request> {
+    let msg = msg.trim();
+    if let Some(c) = regex!(r"^\((.+?)\)(\+\+|--)(?:\s*#\s*(.*))?$").captures(msg) {
+        Some(Request::Change(at(&c, 1), change(&c), at(&c, 3)))
+    } else if let Some(c) = regex!(r"^([^-+\s]+)(\+\+|--)(?:\s*#\s*(.*))?$").captures(msg) {
+        Some(Request::Change(at(&c, 1), change(&c), at(&c, 3)))
+    } else if let Some(c) = regex!(r"^karma\s+(top|bottom)(?:\s+(\d+))?$").captures(msg) {
+        let limit = at(&c, 2).parse().unwrap_or(DEFAULT_RANKING);
+        Some(Request::Ranking(at(&c, 1) == "bottom", cmp::min(cmp::max(limit, 1), MAX_RANKING)))
+    } else if let Some(c) = regex!(r"^karma\s+(.+)$").captures(msg) {
+        let name = at(&c, 1);
+        let name = if name.starts_with('(') && name.ends_with(')') { &name[1..name.len() - 1] } else { name };
+        Some(Request::Show(name.trim()))
+    } else {
+        None
+    }
+}
+
+/// When each person last changed karma, to hold them to their limit.
+struct RateLimit {
+    max_changes: usize,
+    window_secs: i64,
+    recent: HashMap<String, VecDeque<i64>>,
+}
+
+impl RateLimit {
+
+    /// Records a change by `who` if they're under their limit.
+    fn allow(&mut self, who: &str, now: i64) -> bool {
+        let window_secs = self.window_secs;
+        let idle: Vec<String> = self.recent.iter()
+            .filter(|&(_, times)| times.back().map_or(true, |&t| now - t >= window_secs))
+            .map(|(who, _)| who.clone())
+            .collect();
+        for who in idle.iter() {
+            self.recent.remove(who);
+        }
+        let times = self.recent.entry(who.to_string()).or_insert(VecDeque::new());
+        while times.front().map_or(false, |&t| now - t >= window_secs) {
+            times.pop_front();
+        }
+        if times.len() >= self.max_changes {
+            return false;
+        }
+        times.push_back(now);
+        true
+    }
+
+}
+
+struct Asker<'a> {
+    who: &'a str,
+    /// The channel, if it was said in one.
+    chan: Option<&'a str>,
+    reply_to: &'a str,
+}
+
+struct Scorer {
+    storage: Arc<Storage>,
+    casemapping: Casemapping,
+    per_channel: bool,
+    limit: RateLimit,
+}
+
+impl Scorer {
+
+    fn scope(&self, asker: &Asker) -> String {
+        match asker.chan {
+            Some(chan) if self.per_channel => self.casemapping.normalize(chan),
+            _ => String::new(),
+        }
+    }
+
+    fn run(&mut self, asker: &Asker, request: Request) -> Result<Option<String>> {
+        let scope = self.scope(asker);
+        match request {
+            Request::Change(name, change, reason) => {
+                let chan = match asker.chan {
+                    Some(chan) => chan,
+                    None => return Ok(Some("Karma only counts in channels.".to_string())),
+                };
+                let subject = self.casemapping.normalize(name);
+                if subject == self.casemapping.normalize(asker.who) {
+                    return Ok(Some(format!("Nice try, {}.", asker.who)));
+                }
+                let now = time::get_time().sec;
+                if !self.limit.allow(&self.casemapping.normalize(asker.who), now) {
+                    return Ok(Some(format!("Slow down, {}.", asker.who)));
+                }
+                let score = try!(self.storage.add_karma(&KarmaChange{
+                    scope: scope,
+                    subject: subject,
+                    name: name.to_string(),
+                    change: change,
+                    reason: reason.to_string(),
+                    set_by: asker.who.to_string(),
+                    set_at: now,
+                }));
+                debug!("{} changed {}'s karma in {} to {}", asker.who, name, chan, score);
+                Ok(None)
+            },
+            Request::Show(name) => {
+                let subject = self.casemapping.normalize(name);
+                let score = try!(self.storage.karma(&scope, &subject));
+                let reasons = try!(self.storage.karma_reasons(&scope, &subject, REASONS));
+                if reasons.is_empty() {
+                    Ok(Some(format!("{}: {}", name, score)))
+                } else {
+                    Ok(Some(format!("{}: {} ({})", name, score, reasons.join("; "))))
+                }
+            },
+            Request::Ranking(ascending, limit) => {
+                let scores = try!(self.storage.karma_ranking(&scope, ascending, limit));
+                if scores.is_empty() {
+                    return Ok(Some("Nobody has any karma yet.".to_string()));
+                }
+                let scores: Vec<String> = scores.iter().map(|k| format!("{}: {}", k.name, k.score)).collect();
+                Ok(Some(scores.join(", ")))
+            },
+        }
+    }
+
+}
+
+impl Plugin for Karma {
+
+    fn name(&self) -> &str {
+        "karma"
+    }
+
+    fn init(&mut self, ctx: &Context) -> Result<()> {
+        try!(ctx.config.allow_keys(&["per_channel", "max_changes", "window_secs"]));
+        self.per_channel = try!(ctx.config.bool("per_channel")).unwrap_or(false);
+        self.max_changes = try!(ctx.config.positive("max_changes")).unwrap_or(5) as usize;
+        self.window_secs = try!(ctx.config.positive("window_secs")).unwrap_or(600) as i64;
+        try!(ctx.storage());
+        Ok(())
+    }
+
+    fn register(&mut self, ctx: &Context, registrar: &mut Registrar) {
+        let nick = ctx.nick.clone();
+        let mut scorer = Scorer{
+            storage: ctx.storage().ok().expect("Checked in init"),
+            casemapping: ctx.casemapping,
+            per_channel: self.per_channel,
+            limit: RateLimit{
+                max_changes: self.max_changes,
+                window_secs: self.window_secs,
+                recent: HashMap::new(),
+            },
+        };
+        registrar.command_word("karma");
+        registrar.handler(HandlerOptions::new("karma").blocking(), box move |line: &str| {
+            let pm = match protocol::Privmsg::parse(line) {
+                Some(pm) => pm,
+                None => return Response::nothing(),
+            };
+            let who = match pm.src {
+                Some(protocol::Source::User(ref user_info)) => user_info.nick.to_string(),
+                _ => return Response::nothing(),
+            };
+            let (reply_to, in_chan) = match pm.reply_target(&nick) {
+                Some(dest @ protocol::Dest::Chan(_)) => (dest.format(), true),
+                Some(dest) => (dest.format(), false),
+                None => return Response::nothing(),
+            };
+            let msg = pm.plain_msg();
+            let request = match parse(&msg) {
+                Some(request) => request,
+                None => return Response::nothing(),
+            };
+            let chan = if in_chan { Some(&reply_to[..]) } else { None };
+            let asker = Asker{ who: &who, chan: chan, reply_to: &reply_to };
+            match scorer.run(&asker, request) {
+                Ok(Some(text)) => Response::respond(protocol::Privmsg::new(asker.reply_to, &text).command()),
+                Ok(None) => Response::nothing(),
+                Err(e) => {
+                    error!("Error handling karma: {}", e);
+                    Response::nothing()
+                },
+            }
+        });
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use super::{parse, Asker, RateLimit, Request, Scorer};
+    use super::super::super::isupport::Casemapping;
+    use super::super::super::storage::memory::MemoryStorage;
+
+    fn scorer() -> Scorer {
+        Scorer{
+            storage: Arc::new(MemoryStorage::new()),
+            casemapping: Casemapping::Rfc1459,
+            per_channel: false,
+            limit: RateLimit{
+                max_changes: 5,
+                window_secs: 600,
+                recent: HashMap::new(),
+            },
+        }
+    }
+
+    #[test]
+    fn changes() {
+        assert_eq!(parse("(multi word)++ # reason"), Some(Request::Change("multi word", 1, "reason")));
+        assert_eq!(parse("foo--"), Some(Request::Change("foo", -1, "")));
+        assert_eq!(parse("  rust++#fearless  "), Some(Request::Change("rust", 1, "fearless")));
+        assert_eq!(parse("c++ is fine"), None);
+        assert_eq!(parse("--"), None);
+    }
+
+    #[test]
+    fn queries() {
+        assert_eq!(parse("karma top 3"), Some(Request::Ranking(false, 3)));
+        assert_eq!(parse("karma bottom"), Some(Request::Ranking(true, 5)));
+        assert_eq!(parse("karma top 0"), Some(Request::Ranking(false, 1)));
+        assert_eq!(parse("karma top 500"), Some(Request::Ranking(false, 10)));
+        assert_eq!(parse("karma (multi word)"), Some(Request::Show("multi word")));
+        assert_eq!(parse("karma rust"), Some(Request::Show("rust")));
+    }
+
+    #[test]
+    fn rate_limit() {
+        let mut limit = RateLimit{ max_changes: 2, window_secs: 60, recent: HashMap::new() };
+        assert!(limit.allow("alice", 0));
+        assert!(limit.allow("alice", 10));
+        assert!(!limit.allow("alice", 20));
+        assert!(limit.allow("bob", 20));
+        // The change at 0 has aged out, but the refused one didn't count.
+        assert!(limit.allow("alice", 60));
+        assert!(!limit.allow("alice", 61));
+        assert!(limit.allow("alice", 70));
+    }
+
+    #[test]
+    fn no_self_karma() {
+        let mut scorer = scorer();
+        let asker = Asker{ who: "[Alice]", chan: Some("#c"), reply_to: "#c" };
+        assert_eq!(scorer.run(&asker, Request::Change("{alice}", 1, "")).unwrap(), Some("Nice try, [Alice].".to_string()));
+        assert_eq!(scorer.run(&asker, Request::Show("{alice}")).unwrap(), Some("{alice}: 0".to_string()));
+        assert_eq!(scorer.run(&asker, Request::Change("bob", 1, "helpful")).unwrap(), None);
+        assert_eq!(scorer.run(&asker, Request::Show("Bob")).unwrap(), Some("Bob: 1 (helpful)".to_string()));
+    }
+
+    #[test]
+    fn only_in_channels() {
+        let mut scorer = scorer();
+        let asker = Asker{ who: "alice", chan: None, reply_to: "alice" };
+        assert_eq!(scorer.run(&asker, Request::Change("bob", 1, "")).unwrap(), Some("Karma only counts in channels.".to_string()));
+    }
+
+}
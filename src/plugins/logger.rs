@@ -0,0 +1,723 @@
+//! Keeps logs of the channels we're in, and of private conversations,
+//! in files under `dir`.
+//!
+//! Each conversation gets its own files, named by `layout` with
+//! `{network}`, `{target}`, `{date}`, `{year}`, `{month}` and `{day}`
+//! filled in, so the logs rotate whenever the name changes: daily, with
+//! the default layout.  `formats` picks irssi-style text (`.log`) and
+//! JSON Lines (`.jsonl`), one object per event.  Times are in UTC, and
+//! come from the server's `server-time` tags when it sends them, so
+//! request that capability for accurate logs.
+//!
+//! What we say is logged as it's sent.  If the server echoes our
+//! messages back with `echo-message`, the copies aren't logged again.
+//! Quits and nick changes are logged in every channel we've seen the
+//! person in.  Nothing is logged without a `dir`.
+//!
+//! ```{.ignore .toml}
+//! [plugins.logger]
+//! dir = "/var/log/hiphopabotamus"
+//! layout = "{network}/{target}/{date}"
+//! formats = ["text", "json"]
+//! network = "freenode"
+//! ```
+
+use rustc_serialize::json::Json;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+use time::{self, Timespec};
+use super::super::command::Command;
+use super::super::error::{Error, Result};
+use super::super::event_stream::{EventStream, HandlerOptions, Response, INTERNAL_PRIORITY};
+use super::super::formatting;
+use super::super::isupport::Casemapping;
+use super::super::plugin::{Context, Plugin, Registrar};
+use super::super::protocol::{Dest, Message, Source};
+use super::super::scheduler::Schedule;
+
+/// How long to wait after loading before asking who's in our channels,
+/// so our handler is installed to see the answers.
+const NAMES_DELAY_MS: u32 = 1000;
+
+/// How many log files to keep open at once.  The one used least
+/// recently is closed to make room for another.
+const MAX_OPEN_FILES: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Format {
+    Text,
+    Json,
+}
+
+impl Format {
+
+    fn extension(&self) -> &'static str {
+        match *self {
+            Format::Text => "log",
+            Format::Json => "jsonl",
+        }
+    }
+
+}
+
+/// When something happened, in UTC.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Stamp {
+    sec: i64,
+    ms: u32,
+}
+
+impl Stamp {
+
+    fn now() -> Stamp {
+        let now = time::get_time();
+        Stamp{ sec: now.sec, ms: (now.nsec / 1000000) as u32 }
+    }
+
+    /// Parses a `server-time` tag, like "2015-07-01T12:34:56.789Z".
+    fn parse(s: &str) -> Option<Stamp> {
+        let c = match regex!(r"^(\d{4}-\d\d-\d\dT\d\d:\d\d:\d\d)(?:\.(\d+))?Z$").captures(s) {
+            Some(c) => c,
+            None => return None,
+        };
+        let tm = match time::strptime(c.at(1).expect("Bad match group"), "%Y-%m-%dT%H:%M:%S") {
+            Ok(tm) => tm,
+            Err(_) => return None,
+        };
+        let ms = c.at(2).map_or(0, |fraction| {
+            let digits: String = fraction.chars().chain("00".chars()).take(3).collect();
+            digits.parse().unwrap_or(0)
+        });
+        Some(Stamp{ sec: tm.to_timespec().sec, ms: ms })
+    }
+
+    fn format(&self, format: &str) -> String {
+        time::strftime(format, &time::at_utc(Timespec::new(self.sec, 0))).expect("Bad time format")
+    }
+
+    fn rfc3339(&self) -> String {
+        format!("{}.{:03}Z", self.format("%Y-%m-%dT%H:%M:%S"), self.ms)
+    }
+
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    Message,
+    Action,
+    Notice,
+    Join,
+    Part,
+    Quit,
+    Nick,
+    Kick,
+    Topic,
+    Mode,
+}
+
+impl Kind {
+
+    fn as_str(&self) -> &'static str {
+        match *self {
+            Kind::Message => "message",
+            Kind::Action => "action",
+            Kind::Notice => "notice",
+            Kind::Join => "join",
+            Kind::Part => "part",
+            Kind::Quit => "quit",
+            Kind::Nick => "nick",
+            Kind::Kick => "kick",
+            Kind::Topic => "topic",
+            Kind::Mode => "mode",
+        }
+    }
+
+}
+
+/// Something to log in one conversation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Event {
+    at: Stamp,
+    /// The channel, or the other person in a private conversation.
+    target: String,
+    kind: Kind,
+    /// Who did it: a nick, or a server's name.
+    nick: String,
+    /// Their `user@host`, if we know it.
+    address: Option<String>,
+    /// What was said, or the reason, topic, modes or new nick.
+    text: String,
+    /// Who was kicked.
+    victim: Option<String>,
+    /// Whether we did it.
+    own: bool,
+}
+
+impl Event {
+
+    /// A line like irssi's, with formatting codes removed.
+    fn text_line(&self) -> String {
+        let address = self.address.as_ref().map_or(String::new(), |a| format!(" [{}]", a));
+        let text = formatting::strip(&self.text);
+        let line = match self.kind {
+            Kind::Message => format!("<{}> {}", self.nick, text),
+            Kind::Action => format!(" * {} {}", self.nick, text),
+            Kind::Notice => format!("-{}- {}", self.nick, text),
+            Kind::Join => format!("-!- {}{} has joined {}", self.nick, address, self.target),
+            Kind::Part => format!("-!- {}{} has left {} [{}]", self.nick, address, self.target, text),
+            Kind::Quit => format!("-!- {}{} has quit [{}]", self.nick, address, text),
+            Kind::Nick => format!("-!- {} is now known as {}", self.nick, text),
+            Kind::Kick => format!("-!- {} was kicked from {} by {} [{}]", self.victim.as_ref().map_or("", |v| &v[..]), self.target, self.nick, text),
+            Kind::Topic => format!("-!- {} changed the topic of {} to: {}", self.nick, self.target, text),
+            Kind::Mode => format!("-!- mode/{} [{}] by {}", self.target, text, self.nick),
+        };
+        format!("{} {}", self.at.format("%H:%M:%S"), line)
+    }
+
+    /// A JSON object on one line, with the text as it was sent.
+    fn json_line(&self, network: &str) -> String {
+        let mut obj = BTreeMap::new();
+        obj.insert("time".to_string(), Json::String(self.at.rfc3339()));
+        obj.insert("network".to_string(), Json::String(network.to_string()));
+        obj.insert("target".to_string(), Json::String(self.target.clone()));
+        obj.insert("type".to_string(), Json::String(self.kind.as_str().to_string()));
+        obj.insert("nick".to_string(), Json::String(self.nick.clone()));
+        obj.insert("self".to_string(), Json::Boolean(self.own));
+        if let Some(ref address) = self.address {
+            obj.insert("address".to_string(), Json::String(address.clone()));
+        }
+        if let Some(ref victim) = self.victim {
+            obj.insert("kicked".to_string(), Json::String(victim.clone()));
+        }
+        let text_key = match self.kind {
+            Kind::Join => None,
+            Kind::Nick => Some("new_nick"),
+            Kind::Mode => Some("modes"),
+            _ => Some("text"),
+        };
+        if let Some(key) = text_key {
+            obj.insert(key.to_string(), Json::String(self.text.clone()));
+        }
+        Json::Object(obj).to_string()
+    }
+
+}
+
+fn is_channel(target: &str) -> bool {
+    match Dest::parse(target) {
+        Some(Dest::Chan(_)) => true,
+        _ => false,
+    }
+}
+
+/// Who's in the channels being logged, so quits and nick changes can
+/// be logged where they were seen, and what our nick is.
+struct Tracker {
+    me: String,
+    casemapping: Casemapping,
+    /// Each channel's name and its members, by normalized name.
+    channels: HashMap<String, (String, HashSet<String>)>,
+}
+
+impl Tracker {
+
+    fn new(me: &str, casemapping: Casemapping) -> Tracker {
+        Tracker{
+            me: me.to_string(),
+            casemapping: casemapping,
+            channels: HashMap::new(),
+        }
+    }
+
+    fn is_me(&self, nick: &str) -> bool {
+        self.casemapping.normalize(nick) == self.casemapping.normalize(&self.me)
+    }
+
+    fn add(&mut self, chan: &str, nick: &str) {
+        let (key, nick) = (self.casemapping.normalize(chan), self.casemapping.normalize(nick));
+        self.channels.entry(key)
+            .or_insert_with(|| (chan.to_string(), HashSet::new()))
+            .1.insert(nick);
+    }
+
+    fn remove(&mut self, chan: &str, nick: &str) {
+        let key = self.casemapping.normalize(chan);
+        if self.is_me(nick) {
+            self.channels.remove(&key);
+        } else if let Some(&mut (_, ref mut members)) = self.channels.get_mut(&key) {
+            members.remove(&self.casemapping.normalize(nick));
+        }
+    }
+
+    /// Moves `nick` to `new_nick`, or forgets them if there's no new
+    /// one, and returns the channels they were in.
+    fn rename(&mut self, nick: &str, new_nick: Option<&str>) -> Vec<String> {
+        let nick = self.casemapping.normalize(nick);
+        let new_nick = new_nick.map(|n| self.casemapping.normalize(n));
+        let mut chans = Vec::new();
+        for (_, &mut (ref chan, ref mut members)) in self.channels.iter_mut() {
+            if members.remove(&nick) {
+                if let Some(ref new_nick) = new_nick {
+                    members.insert(new_nick.clone());
+                }
+                chans.push(chan.clone());
+            }
+        }
+        chans.sort();
+        chans
+    }
+
+    /// What `line` says happened.  `sent` is whether we sent it, rather
+    /// than received it, and `received_at` when that was.
+    fn observe(&mut self, line: &str, sent: bool, received_at: Stamp) -> Vec<Event> {
+        let msg = match Message::parse(line) {
+            Some(msg) => msg,
+            None => return Vec::new(),
+        };
+        // The server tells us the results of anything else we send.
+        if sent && msg.command != "PRIVMSG" && msg.command != "NOTICE" {
+            return Vec::new();
+        }
+        let at = msg.tag("time").and_then(|t| Stamp::parse(&t)).unwrap_or(received_at);
+        let (nick, address) = if sent {
+            (self.me.clone(), None)
+        } else {
+            match msg.prefix.and_then(Source::parse) {
+                Some(Source::User(user)) => (user.nick.to_string(), match (user.user, user.host) {
+                    (Some(user), Some(host)) => Some(format!("{}@{}", user, host)),
+                    _ => None,
+                }),
+                Some(Source::Server(server)) => (server.to_string(), None),
+                None => return Vec::new(),
+            }
+        };
+        let own = sent || self.is_me(&nick);
+        let param = |i: usize| msg.params.get(i).map(|p| p.to_string()).unwrap_or(String::new());
+        let event = |target: &str, kind: Kind, text: String| Event{
+            at: at,
+            target: target.to_string(),
+            kind: kind,
+            nick: nick.clone(),
+            address: address.clone(),
+            text: text,
+            victim: None,
+            own: own,
+        };
+        match msg.command {
+            "PRIVMSG" | "NOTICE" if msg.params.len() >= 2 => {
+                // We logged it when we sent it.
+                if own && !sent {
+                    return Vec::new();
+                }
+                let dst = msg.params[0];
+                let target = if sent || is_channel(dst) { dst } else { &nick[..] };
+                let text = msg.params[1];
+                let (kind, text) = if msg.command == "NOTICE" {
+                    (Kind::Notice, text)
+                } else if text.starts_with("\u{1}ACTION ") {
+                    (Kind::Action, text[8..].trim_right_matches('\u{1}'))
+                } else if text.starts_with('\u{1}') {
+                    return Vec::new();
+                } else {
+                    (Kind::Message, text)
+                };
+                vec![event(target, kind, text.to_string())]
+            },
+            "JOIN" => {
+                let chan = param(0);
+                if own {
+                    self.channels.remove(&self.casemapping.normalize(&chan));
+                }
+                self.add(&chan, &nick);
+                vec![event(&chan, Kind::Join, String::new())]
+            },
+            "PART" => {
+                let chan = param(0);
+                self.remove(&chan, &nick);
+                vec![event(&chan, Kind::Part, param(1))]
+            },
+            "KICK" => {
+                let (chan, victim) = (param(0), param(1));
+                self.remove(&chan, &victim);
+                let mut kicked = event(&chan, Kind::Kick, param(2));
+                kicked.victim = Some(victim);
+                vec![kicked]
+            },
+            "QUIT" => {
+                let chans = self.rename(&nick, None);
+                chans.iter().map(|chan| event(chan, Kind::Quit, param(0))).collect()
+            },
+            "NICK" => {
+                let new_nick = param(0);
+                let chans = self.rename(&nick, Some(&new_nick));
+                if own {
+                    self.me = new_nick.clone();
+                }
+                chans.iter().map(|chan| event(chan, Kind::Nick, new_nick.clone())).collect()
+            },
+            "TOPIC" => vec![event(&param(0), Kind::Topic, param(1))],
+            "MODE" if msg.params.len() >= 2 && is_channel(msg.params[0]) => {
+                vec![event(msg.params[0], Kind::Mode, msg.params[1..].join(" "))]
+            },
+            // RPL_NAMREPLY: <me> <type> <channel> :<names>
+            "353" if msg.params.len() >= 4 => {
+                let chan = msg.params[2];
+                for name in msg.params[3].split(' ').filter(|n| !n.is_empty()) {
+                    let name = name.trim_left_matches(|c| "~&@%+".contains(c));
+                    self.add(chan, name.split('!').next().unwrap_or(name));
+                }
+                Vec::new()
+            },
+            _ => Vec::new(),
+        }
+    }
+
+}
+
+/// `name` made safe to use as one part of a path.
+fn safe_name(name: &str) -> String {
+    let safe: String = name.chars().map(|c| {
+        if c == '/' || c == '\\' || c.is_control() { '_' } else { c }
+    }).collect();
+    if safe.chars().all(|c| c == '.') {
+        format!("_{}", safe)
+    } else {
+        safe
+    }
+}
+
+/// Where a conversation is logged at `at`, under `dir`.
+fn log_path(dir: &Path, layout: &str, network: &str, target: &str, at: Stamp, format: Format) -> PathBuf {
+    let name = layout
+        .replace("{network}", &safe_name(network))
+        .replace("{target}", &safe_name(target))
+        .replace("{date}", &at.format("%Y-%m-%d"))
+        .replace("{year}", &at.format("%Y"))
+        .replace("{month}", &at.format("%m"))
+        .replace("{day}", &at.format("%d"));
+    dir.join(format!("{}.{}", name, format.extension()))
+}
+
+/// Writes events to the files they belong in.
+struct Writer {
+    dir: PathBuf,
+    layout: String,
+    network: String,
+    formats: Vec<Format>,
+    casemapping: Casemapping,
+    /// The file each conversation is being logged to, by normalized
+    /// target and format, with when it was last written to in `writes`.
+    files: HashMap<(String, Format), (PathBuf, File, u64)>,
+    /// How many lines have been written, for finding the file used
+    /// least recently.
+    writes: u64,
+}
+
+impl Writer {
+
+    fn write(&mut self, event: &Event) {
+        let target = self.casemapping.normalize(&event.target);
+        for &format in self.formats.clone().iter() {
+            let path = log_path(&self.dir, &self.layout, &self.network, &target, event.at, format);
+            let line = match format {
+                Format::Text => event.text_line(),
+                Format::Json => event.json_line(&self.network),
+            };
+            if let Err(e) = self.append((target.clone(), format), &path, &line, event.at) {
+                warn!("Couldn't log to {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    fn append(&mut self, key: (String, Format), path: &Path, line: &str, at: Stamp) -> io::Result<()> {
+        let open = match self.files.get(&key) {
+            Some(&(ref open_path, _, _)) => &**open_path == path,
+            None => false,
+        };
+        if !open {
+            // Closes the file it was being logged to before, if any.
+            self.files.remove(&key);
+            if self.files.len() >= MAX_OPEN_FILES {
+                let oldest = self.files.iter().min_by(|&(_, &(_, _, used))| used).map(|(key, _)| key.clone());
+                if let Some(oldest) = oldest {
+                    self.files.remove(&oldest);
+                }
+            }
+            if let Some(parent) = path.parent() {
+                try!(fs::create_dir_all(parent));
+            }
+            let mut file = try!(OpenOptions::new().append(true).create(true).open(path));
+            if key.1 == Format::Text {
+                try!(write!(file, "--- Log opened {}\n", at.format("%a %b %d %H:%M:%S %Y")));
+            }
+            self.files.insert(key.clone(), (path.to_path_buf(), file, 0));
+        }
+        self.writes += 1;
+        let open = self.files.get_mut(&key).expect("Log file just opened");
+        open.2 = self.writes;
+        write!(open.1, "{}\n", line)
+    }
+
+}
+
+enum Record {
+    /// A line, whether we sent it, and when we received or sent it.
+    Line(String, bool, Stamp),
+    Stop,
+}
+
+fn writer_loop(mut tracker: Tracker, mut writer: Writer, rx: Receiver<Record>) {
+    for record in rx.iter() {
+        match record {
+            Record::Line(line, sent, at) => {
+                for event in tracker.observe(&line, sent, at).iter() {
+                    writer.write(event);
+                }
+            },
+            Record::Stop => break,
+        }
+    }
+}
+
+pub struct Logger {
+    dir: Option<PathBuf>,
+    layout: String,
+    formats: Vec<Format>,
+    network: Option<String>,
+    /// Where to send lines to be logged, and the thread logging them.
+    thread: Option<(Sender<Record>, thread::JoinHandle<()>)>,
+}
+
+impl Logger {
+
+    pub fn new() -> Logger {
+        Logger{
+            dir: None,
+            layout: "{network}/{target}/{date}".to_string(),
+            formats: vec![Format::Text, Format::Json],
+            network: None,
+            thread: None,
+        }
+    }
+
+}
+
+impl Plugin for Logger {
+
+    fn name(&self) -> &str {
+        "logger"
+    }
+
+    fn init(&mut self, ctx: &Context) -> Result<()> {
+        try!(ctx.config.allow_keys(&["dir", "layout", "formats", "network"]));
+        self.dir = try!(ctx.config.string("dir")).map(PathBuf::from);
+        if let Some(layout) = try!(ctx.config.string("layout")) {
+            if !layout.contains("{target}") {
+                return Err(Error::Config(ctx.config.error("layout", "must include {target}")));
+            }
+            self.layout = layout;
+        }
+        let formats = try!(ctx.config.strings("formats"));
+        if !formats.is_empty() {
+            self.formats = try!(formats.iter().map(|format| {
+                match &format[..] {
+                    "text" => Ok(Format::Text),
+                    "json" => Ok(Format::Json),
+                    _ => Err(Error::Config(ctx.config.error("formats", &format!("unknown format {}", format)))),
+                }
+            }).collect());
+        }
+        self.network = try!(ctx.config.string("network"));
+        Ok(())
+    }
+
+    fn register(&mut self, ctx: &Context, registrar: &mut Registrar) {
+        let dir = match self.dir {
+            Some(ref dir) => dir.clone(),
+            None => {
+                info!("No dir for logs, so not logging.");
+                return;
+            },
+        };
+        let writer = Writer{
+            dir: dir,
+            layout: self.layout.clone(),
+            network: self.network.clone().or(ctx.network.clone()).unwrap_or("irc".to_string()),
+            formats: self.formats.clone(),
+            casemapping: ctx.casemapping,
+            files: HashMap::new(),
+            writes: 0,
+        };
+        let tracker = Tracker::new(&ctx.nick, ctx.casemapping);
+        let (tx, rx) = channel();
+        let handle = thread::spawn(move || writer_loop(tracker, writer, rx));
+
+        let received = tx.clone();
+        registrar.handler(HandlerOptions::new("logger").priority(INTERNAL_PRIORITY), box move |line: &str| {
+            let _ = received.send(Record::Line(line.to_string(), false, Stamp::now()));
+            Response::nothing()
+        });
+        let sent = Mutex::new(tx.clone());
+        registrar.sent_hook(box move |line: &str| {
+            let sent = match sent.lock() {
+                Ok(sent) => sent,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            let _ = sent.send(Record::Line(line.to_string(), true, Stamp::now()));
+        });
+
+        // We joined our channels before being loaded, so ask who's in
+        // them.
+        let chans = ctx.membership.channels();
+        if !chans.is_empty() {
+            ctx.scheduler.schedule("logger names", Schedule::After(NAMES_DELAY_MS), box move |stream: &mut EventStream| {
+                if let Err(e) = stream.send(&Command::Raw(format!("NAMES {}", chans.join(",")))) {
+                    error!("Error asking for names: {}", e);
+                }
+            });
+        }
+        self.thread = Some((tx, handle));
+    }
+
+    fn shutdown(&mut self) {
+        if let Some((tx, handle)) = self.thread.take() {
+            let _ = tx.send(Record::Stop);
+            let _ = handle.join();
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use rustc_serialize::json::Json;
+    use std::collections::HashMap;
+    use std::env;
+    use std::fs;
+    use std::io::Read;
+    use std::path::{Path, PathBuf};
+    use time;
+    use super::super::super::isupport::Casemapping;
+    use super::{log_path, Event, Format, Kind, Stamp, Tracker, Writer, MAX_OPEN_FILES};
+
+    fn at() -> Stamp {
+        Stamp{ sec: 1435754096, ms: 789 }
+    }
+
+    #[test]
+    fn parse_server_time() {
+        assert_eq!(Stamp::parse("2015-07-01T12:34:56.789Z"), Some(at()));
+        assert_eq!(Stamp::parse("2015-07-01T12:34:56.7Z"), Some(Stamp{ sec: 1435754096, ms: 700 }));
+        assert_eq!(Stamp::parse("2015-07-01T12:34:56Z"), Some(Stamp{ sec: 1435754096, ms: 0 }));
+        assert_eq!(Stamp::parse("yesterday"), None);
+        assert_eq!(at().rfc3339(), "2015-07-01T12:34:56.789Z");
+    }
+
+    #[test]
+    fn format_events() {
+        let mut event = Event{
+            at: at(),
+            target: "#rust".to_string(),
+            kind: Kind::Message,
+            nick: "alice".to_string(),
+            address: Some("al@example.com".to_string()),
+            text: "\u{2}hi\u{2} there".to_string(),
+            victim: None,
+            own: false,
+        };
+        assert_eq!(event.text_line(), "12:34:56 <alice> hi there");
+        let json = Json::from_str(&event.json_line("freenode")).unwrap();
+        assert_eq!(json.find("time").and_then(|j| j.as_string()), Some("2015-07-01T12:34:56.789Z"));
+        assert_eq!(json.find("network").and_then(|j| j.as_string()), Some("freenode"));
+        assert_eq!(json.find("text").and_then(|j| j.as_string()), Some("\u{2}hi\u{2} there"));
+        assert_eq!(json.find("self").and_then(|j| j.as_boolean()), Some(false));
+        event.kind = Kind::Join;
+        assert_eq!(event.text_line(), "12:34:56 -!- alice [al@example.com] has joined #rust");
+        event.kind = Kind::Kick;
+        event.victim = Some("bob".to_string());
+        event.text = "bye".to_string();
+        assert_eq!(event.text_line(), "12:34:56 -!- bob was kicked from #rust by alice [bye]");
+    }
+
+    #[test]
+    fn track_conversations() {
+        let mut tracker = Tracker::new("bot", Casemapping::Rfc1459);
+        let now = Stamp{ sec: 0, ms: 0 };
+        let observe = |tracker: &mut Tracker, line: &str, sent: bool| -> Vec<(String, Kind, String, bool)> {
+            tracker.observe(line, sent, now).into_iter().map(|e| (e.target, e.kind, e.nick, e.own)).collect()
+        };
+        observe(&mut tracker, ":bot!b@h JOIN #a", false);
+        observe(&mut tracker, ":bot!b@h JOIN #b", false);
+        observe(&mut tracker, ":server 353 bot = #a :bot @Alice +carol", false);
+        observe(&mut tracker, ":server 353 bot = #b :bot alice!al@h", false);
+        assert_eq!(observe(&mut tracker, ":alice!al@h NICK alicia", false), vec![
+            ("#a".to_string(), Kind::Nick, "alice".to_string(), false),
+            ("#b".to_string(), Kind::Nick, "alice".to_string(), false),
+            ]);
+        assert_eq!(observe(&mut tracker, ":carol!c@h QUIT :gone", false), vec![
+            ("#a".to_string(), Kind::Quit, "carol".to_string(), false),
+            ]);
+        assert_eq!(observe(&mut tracker, ":alicia!al@h PRIVMSG bot :\u{1}ACTION waves\u{1}", false), vec![
+            ("alicia".to_string(), Kind::Action, "alicia".to_string(), false),
+            ]);
+        assert_eq!(observe(&mut tracker, "PRIVMSG alicia :hi", true), vec![
+            ("alicia".to_string(), Kind::Message, "bot".to_string(), true),
+            ]);
+        assert!(observe(&mut tracker, ":bot!b@h PRIVMSG #a :hi", false).is_empty());
+        assert!(observe(&mut tracker, "JOIN #c", true).is_empty());
+        let events = tracker.observe("@time=2015-07-01T12:34:56.789Z :alicia!al@h PART #a", false, now);
+        assert_eq!(events[0].at, at());
+        assert_eq!(observe(&mut tracker, ":alicia!al@h QUIT", false), vec![
+            ("#b".to_string(), Kind::Quit, "alicia".to_string(), false),
+            ]);
+    }
+
+    #[test]
+    fn layout_paths() {
+        let dir = Path::new("/logs");
+        assert_eq!(log_path(dir, "{network}/{target}/{date}", "freenode", "#rust", at(), Format::Text),
+                   PathBuf::from("/logs/freenode/#rust/2015-07-01.log"));
+        assert_eq!(log_path(dir, "{network}/{year}/{month}/{target}-{day}", "..", "#a/b", at(), Format::Json),
+                   PathBuf::from("/logs/_../2015/07/#a_b-01.jsonl"));
+    }
+
+    #[test]
+    fn least_recently_used_files_are_closed() {
+        let dir = env::temp_dir().join(format!("logger-{}", time::precise_time_ns()));
+        let mut writer = Writer{
+            dir: dir.clone(),
+            layout: "{target}".to_string(),
+            network: "irc".to_string(),
+            formats: vec![Format::Json],
+            casemapping: Casemapping::Rfc1459,
+            files: HashMap::new(),
+            writes: 0,
+        };
+        let key = |n: usize| (format!("#{}", n), Format::Json);
+        let path = |n: usize| dir.join(format!("{}.log", n));
+        for n in 0..MAX_OPEN_FILES {
+            writer.append(key(n), &path(n), "first", at()).unwrap();
+        }
+        writer.append(key(0), &path(0), "second", at()).unwrap();
+        writer.append(key(MAX_OPEN_FILES), &path(MAX_OPEN_FILES), "first", at()).unwrap();
+        assert_eq!(writer.files.len(), MAX_OPEN_FILES);
+        assert!(writer.files.contains_key(&key(0)));
+        assert!(!writer.files.contains_key(&key(1)));
+
+        // Reopened, not truncated.
+        writer.append(key(1), &path(1), "second", at()).unwrap();
+        drop(writer);
+        let mut logged = String::new();
+        fs::File::open(&path(1)).unwrap().read_to_string(&mut logged).unwrap();
+        assert_eq!(logged, "first\nsecond\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+}
@@ -0,0 +1,48 @@
+//! The plugins hiphopabotamus comes with.
+
+use std::cmp;
+use super::plugin::Plugin;
+
+pub mod admin;
+pub mod choice;
+pub mod echo;
+pub mod factoids;
+pub mod join;
+pub mod karma;
+pub mod logger;
+pub mod reminders;
+pub mod seen;
+pub mod tell;
+pub mod titles;
+
+/// One of each built-in plugin, in the order their handlers should
+/// run.  `admin` isn't included, since it needs somewhere to send
+/// requests.
+pub fn builtins() -> Vec<Box<Plugin>> {
+    vec![
+        box logger::Logger::new(),
+        box choice::Choice,
+        box karma::Karma::new(),
+        box factoids::Factoids::new(),
+        box seen::Seen,
+        box tell::Tell::new(),
+        box reminders::Reminders::new(),
+        box titles::Titles::new(),
+        box join::Join,
+        box echo::Echo,
+        ]
+}
+
+fn plural(n: i64, unit: &str) -> String {
+    if n == 1 { format!("1 {}", unit) } else { format!("{} {}s", n, unit) }
+}
+
+/// How long `secs` is, roughly, like "3 hours".
+fn ago(secs: i64) -> String {
+    match secs {
+        s if s < 60 => plural(cmp::max(s, 0), "second"),
+        s if s < 60 * 60 => plural(s / 60, "minute"),
+        s if s < 24 * 60 * 60 => plural(s / (60 * 60), "hour"),
+        s => plural(s / (24 * 60 * 60), "day"),
+    }
+}
@@ -0,0 +1,432 @@
+//! Says things later.
+//!
+//! ```{.ignore .text}
+//! rustbot: remind me in 2h to deploy
+//! rustbot: remind me in 1 day 30 minutes to review #42
+//! rustbot: remind #rust at 17:00 UTC standup   asked in #rust
+//! rustbot: remind me tomorrow at 9am to water the plants
+//! rustbot: reminders                  what you've asked for, with ids
+//! rustbot: cancel reminder 3
+//! ```
+//!
+//! Times of day are in UTC.  Reminders can only be for whoever asks, or
+//! for the channel they ask in, so the bot can't be used to pester
+//! anyone else.  A reminder for "me" is said in the channel it was
+//! asked for in, addressed to the asker, or to them in private if it
+//! was asked for in private.  Each person can have `max_pending`
+//! reminders waiting.  Reminders are kept in storage, so they survive
+//! restarts; any that came due while the bot was gone are said as soon
+//! as it's back.
+//!
+//! ```{.ignore .toml}
+//! [plugins.reminders]
+//! max_pending = 10
+//! ```
+
+use regex::Captures;
+use std::cmp;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::u32;
+use time;
+use super::super::error::Result;
+use super::super::event_stream::{EventStream, HandlerOptions, Response};
+use super::super::isupport::Casemapping;
+use super::super::plugin::{Context, Plugin, Registrar};
+use super::super::protocol::{self, Dest};
+use super::super::scheduler::{Schedule, Scheduler, TaskHandle};
+use super::super::storage::{Reminder, Storage};
+use super::ago;
+
+/// How far ahead reminders can be set, in days.
+const MAX_DAYS: i64 = 30;
+
+const DAY_SECS: i64 = 24 * 60 * 60;
+
+/// The reminders waiting to be said, by id.
+type Scheduled = Arc<Mutex<BTreeMap<i64, (Reminder, TaskHandle)>>>;
+
+fn lock(scheduled: &Scheduled) -> MutexGuard<BTreeMap<i64, (Reminder, TaskHandle)>> {
+    match scheduled.lock() {
+        Ok(scheduled) => scheduled,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+pub struct Reminders {
+    scheduled: Scheduled,
+    max_pending: usize,
+}
+
+impl Reminders {
+
+    pub fn new() -> Reminders {
+        Reminders{
+            scheduled: Arc::new(Mutex::new(BTreeMap::new())),
+            max_pending: 10,
+        }
+    }
+
+}
+
+fn at<'t>(c: &Captures<'t>, i: usize) -> &'t str {
+    c.at(i).unwrap_or("")
+}
+
+/// The text after a time, without a leading "to".
+fn reminder_text(text: &str) -> Option<&str> {
+    let text = text.trim();
+    let text = match regex!(r"(?i)^to\s+(.*)$").captures(text) {
+        Some(c) => at(&c, 1).trim(),
+        None => text,
+    };
+    if text.is_empty() { None } else { Some(text) }
+}
+
+/// Seconds in one of a duration's units.
+fn unit_secs(unit: &str) -> i64 {
+    match unit.chars().next() {
+        Some('w') => 7 * DAY_SECS,
+        Some('d') => DAY_SECS,
+        Some('h') => 60 * 60,
+        Some('m') => 60,
+        _ => 1,
+    }
+}
+
+/// Splits "in 2h to deploy" or "at 17:00 UTC standup" into when it's
+/// due, in seconds since the epoch, and what to say.
+fn parse_when(when: &str, now: i64) -> Option<(i64, &str)> {
+    let when = when.trim();
+    if let Some(c) = regex!(r"(?i)^in\s+(.*)$").captures(when) {
+        let mut rest = at(&c, 1);
+        let mut secs = 0;
+        while let Some(c) = regex!(r"(?i)^(\d{1,6})\s*(weeks?|w|days?|d|hours?|hrs?|h|minutes?|mins?|m|seconds?|secs?|s)\b\s*(?:,\s*|and\s+)?").captures(rest) {
+            secs += at(&c, 1).parse::<i64>().unwrap_or(0) * unit_secs(&at(&c, 2).to_lowercase());
+            rest = &rest[c.pos(0).map_or(0, |(_, end)| end)..];
+        }
+        if secs == 0 {
+            return None;
+        }
+        reminder_text(rest).map(|text| (now + secs, text))
+    } else if let Some(c) = regex!(r"(?i)^(?:(tomorrow)\s+)?at\s+(\d{1,2})(?::(\d{2}))?\s*(am|pm)?(?:\s+utc)?(?:\s+(.*))?$").captures(when) {
+        let mut hour: i64 = at(&c, 2).parse().unwrap_or(24);
+        let minute: i64 = if at(&c, 3).is_empty() { 0 } else { at(&c, 3).parse().unwrap_or(60) };
+        match &at(&c, 4).to_lowercase()[..] {
+            "" => (),
+            _ if hour < 1 || hour > 12 => return None,
+            "am" => hour %= 12,
+            _ => hour = hour % 12 + 12,
+        }
+        if hour > 23 || minute > 59 {
+            return None;
+        }
+        let mut due = now - now % DAY_SECS + hour * 60 * 60 + minute * 60;
+        if !at(&c, 1).is_empty() {
+            due += DAY_SECS;
+        } else if due <= now {
+            due += DAY_SECS;
+        }
+        reminder_text(at(&c, 5)).map(|text| (due, text))
+    } else {
+        None
+    }
+}
+
+/// What to say when `reminder` is due.
+fn message(casemapping: Casemapping, reminder: &Reminder) -> String {
+    let mut text = if reminder.nick.is_empty() {
+        format!("Reminder: {}", reminder.text)
+    } else {
+        format!("{}: reminder: {}", reminder.nick, reminder.text)
+    };
+    let recipient = if reminder.nick.is_empty() { &reminder.target } else { &reminder.nick };
+    if !casemapping.same_name(recipient, &reminder.owner) {
+        text.push_str(&format!(" (from {})", reminder.owner));
+    }
+    text
+}
+
+/// Says reminders when they're due.
+struct Clock {
+    storage: Arc<Storage>,
+    scheduler: Scheduler,
+    scheduled: Scheduled,
+    casemapping: Casemapping,
+    max_pending: usize,
+}
+
+impl Clock {
+
+    /// Schedules `reminder` to be said when it's due, or right away if
+    /// it's overdue.
+    fn arm(&self, reminder: Reminder, now: i64) {
+        let id = reminder.id;
+        let delay_ms = cmp::min(cmp::max(reminder.due_at - now, 0) * 1000, u32::MAX as i64) as u32;
+        let storage = self.storage.clone();
+        let scheduled = self.scheduled.clone();
+        let casemapping = self.casemapping;
+        // Held while scheduling so the task can't run before it's
+        // recorded.
+        let mut pending = lock(&self.scheduled);
+        let handle = self.scheduler.schedule(&format!("reminder {}", id), Schedule::After(delay_ms), box move |stream: &mut EventStream| {
+            let reminder = match lock(&scheduled).remove(&id) {
+                Some((reminder, _)) => reminder,
+                None => return,
+            };
+            let text = message(casemapping, &reminder);
+            if let Some(dest) = Dest::parse(&reminder.target) {
+                if let Err(e) = stream.send(&protocol::Privmsg::new(dest, &text).command()) {
+                    error!("Error sending reminder {}: {}", id, e);
+                }
+            }
+            // Tasks run on the event loop, which storage shouldn't hold up.
+            let storage = storage.clone();
+            thread::spawn(move || {
+                if let Err(e) = storage.delete_reminder(id) {
+                    error!("Error deleting reminder {}: {}", id, e);
+                }
+            });
+        });
+        pending.insert(id, (reminder, handle));
+    }
+
+    /// Sets a reminder for `who`, which is "me", the owner's nick or
+    /// the channel they're in, returning what to tell the owner.
+    fn set(&self, owner: &str, chan: Option<&str>, who: &str, when: &str, now: i64) -> Result<String> {
+        let who = if who == "me" { owner } else { who };
+        let (target, nick) = match (Dest::parse(who), chan) {
+            (Some(Dest::Chan(_)), Some(chan)) if self.casemapping.same_name(who, chan) => (chan, ""),
+            (_, chan) if self.casemapping.same_name(who, owner) => match chan {
+                Some(chan) => (chan, owner),
+                None => (owner, ""),
+            },
+            _ => return Ok(format!("Sorry {}, I can only remind you, or the channel you ask in.", owner)),
+        };
+        let owner_key = self.casemapping.normalize(owner);
+        let pending = lock(&self.scheduled).values().filter(|&&(ref r, _)| r.owner_key == owner_key).count();
+        if pending >= self.max_pending {
+            return Ok(format!("Sorry {}, you have too many reminders waiting.", owner));
+        }
+        let (due_at, text) = match parse_when(when, now) {
+            Some(parsed) => parsed,
+            None => return Ok(format!("Sorry {}, I didn't understand when.", owner)),
+        };
+        if due_at - now > MAX_DAYS * DAY_SECS {
+            return Ok(format!("Sorry {}, I can only remember things for {} days.", owner, MAX_DAYS));
+        }
+        let mut reminder = Reminder{
+            id: 0,
+            owner: owner.to_string(),
+            owner_key: owner_key,
+            target: target.to_string(),
+            nick: nick.to_string(),
+            text: text.to_string(),
+            due_at: due_at,
+            set_at: now,
+        };
+        reminder.id = try!(self.storage.add_reminder(&reminder));
+        let id = reminder.id;
+        self.arm(reminder, now);
+        Ok(format!("OK, {}, in {} (reminder {}).", owner, ago(due_at - now), id))
+    }
+
+    /// The reminders `owner` has set.
+    fn list(&self, owner: &str, now: i64) -> String {
+        let owner_key = self.casemapping.normalize(owner);
+        let mut mine: Vec<Reminder> = lock(&self.scheduled).values()
+            .filter(|&&(ref r, _)| r.owner_key == owner_key)
+            .map(|&(ref r, _)| r.clone())
+            .collect();
+        if mine.is_empty() {
+            return format!("You have no reminders, {}.", owner);
+        }
+        mine.sort_by(|a, b| a.due_at.cmp(&b.due_at));
+        let listed: Vec<String> = mine.iter().map(|r| {
+            format!("{}: in {} to {}: {}", r.id, ago(r.due_at - now), r.target, r.text)
+        }).collect();
+        listed.join("; ")
+    }
+
+    /// Cancels one of `owner`'s reminders.
+    fn cancel(&self, owner: &str, id: i64) -> Result<String> {
+        let owner_key = self.casemapping.normalize(owner);
+        let cancelled = {
+            let mut scheduled = lock(&self.scheduled);
+            match scheduled.get(&id) {
+                Some(&(ref r, _)) if r.owner_key == owner_key => (),
+                _ => return Ok(format!("You have no reminder {}, {}.", id, owner)),
+            }
+            scheduled.remove(&id)
+        };
+        if let Some((_, handle)) = cancelled {
+            handle.cancel();
+        }
+        try!(self.storage.delete_reminder(id));
+        Ok(format!("OK, {}, reminder {} is cancelled.", owner, id))
+    }
+
+}
+
+impl Plugin for Reminders {
+
+    fn name(&self) -> &str {
+        "reminders"
+    }
+
+    fn init(&mut self, ctx: &Context) -> Result<()> {
+        try!(ctx.config.allow_keys(&["max_pending"]));
+        self.max_pending = try!(ctx.config.positive("max_pending")).unwrap_or(10) as usize;
+        try!(ctx.storage());
+        Ok(())
+    }
+
+    fn register(&mut self, ctx: &Context, registrar: &mut Registrar) {
+        let nick = ctx.nick.clone();
+        let clock = Clock{
+            storage: ctx.storage().ok().expect("Checked in init"),
+            scheduler: ctx.scheduler.clone(),
+            scheduled: self.scheduled.clone(),
+            casemapping: ctx.casemapping,
+            max_pending: self.max_pending,
+        };
+        let now = time::get_time().sec;
+        match clock.storage.reminders() {
+            Ok(reminders) => {
+                for reminder in reminders.into_iter() {
+                    clock.arm(reminder, now);
+                }
+            },
+            Err(e) => { error!("Error loading reminders: {}", e); },
+        }
+        for word in ["remind", "reminders", "cancel"].iter() {
+            registrar.command_word(word);
+        }
+        registrar.handler(HandlerOptions::new("reminders").blocking(), box move |line: &str| {
+            let pm = match protocol::Privmsg::parse(line) {
+                Some(pm) => pm,
+                None => return Response::nothing(),
+            };
+            let owner = match pm.src {
+                Some(protocol::Source::User(ref user_info)) => user_info.nick.to_string(),
+                _ => return Response::nothing(),
+            };
+            let chan = match pm.dst {
+                Dest::Chan(ref chan) => Some(chan.to_string()),
+                Dest::Nick(_) => None,
+            };
+            let reply_to = match pm.reply_target(&nick) {
+                Some(reply_to) => reply_to,
+                None => return Response::nothing(),
+            };
+            let msg = match protocol::Privmsg::parse(line).and_then(|pm| pm.targeted_msg(&nick)) {
+                Some(targeted) => targeted.plain_msg(),
+                None => return Response::nothing(),
+            };
+            let msg = msg.trim();
+            let now = time::get_time().sec;
+            let result = if let Some(c) = regex!(r"^remind\s+([^\s]+)\s+(.+)$").captures(msg) {
+                clock.set(&owner, chan.as_ref().map(|chan| &chan[..]), at(&c, 1), at(&c, 2), now)
+            } else if regex!(r"^reminders\s*$").is_match(msg) {
+                Ok(clock.list(&owner, now))
+            } else if let Some(c) = regex!(r"^cancel\s+reminder\s+(\d+)\s*$").captures(msg) {
+                clock.cancel(&owner, at(&c, 1).parse().unwrap_or(0))
+            } else {
+                return Response::nothing();
+            };
+            match result {
+                Ok(text) => Response::respond(protocol::Privmsg::new(reply_to, &text).command()),
+                Err(e) => {
+                    error!("Error handling reminder: {}", e);
+                    Response::nothing()
+                },
+            }
+        });
+    }
+
+    fn shutdown(&mut self) {
+        // They'll be loaded again from storage next time.
+        let mut scheduled = lock(&self.scheduled);
+        for (_, &(_, ref handle)) in scheduled.iter() {
+            handle.cancel();
+        }
+        scheduled.clear();
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex};
+    use super::{lock, parse_when, Clock};
+    use super::super::super::isupport::Casemapping;
+    use super::super::super::scheduler::Scheduler;
+    use super::super::super::storage::memory::MemoryStorage;
+
+    fn clock(max_pending: usize) -> Clock {
+        Clock{
+            storage: Arc::new(MemoryStorage::new()),
+            scheduler: Scheduler::new(),
+            scheduled: Arc::new(Mutex::new(BTreeMap::new())),
+            casemapping: Casemapping::Rfc1459,
+            max_pending: max_pending,
+        }
+    }
+
+    /// 2015-06-01 12:00:00 UTC.
+    const NOON: i64 = 1433160000;
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_when("in 2h to deploy", NOON), Some((NOON + 2 * 60 * 60, "deploy")));
+        assert_eq!(parse_when("in 1 day, 30 minutes review it", NOON), Some((NOON + 24 * 60 * 60 + 30 * 60, "review it")));
+        assert_eq!(parse_when("in 1h and 5m to eat", NOON), Some((NOON + 65 * 60, "eat")));
+        assert_eq!(parse_when("in a while to eat", NOON), None);
+        assert_eq!(parse_when("in 5m", NOON), None);
+    }
+
+    #[test]
+    fn times_of_day() {
+        assert_eq!(parse_when("at 17:00 UTC standup", NOON), Some((NOON + 5 * 60 * 60, "standup")));
+        assert_eq!(parse_when("at 9am to water the plants", NOON), Some((NOON + 21 * 60 * 60, "water the plants")));
+        assert_eq!(parse_when("tomorrow at 12:30pm lunch", NOON), Some((NOON + 24 * 60 * 60 + 30 * 60, "lunch")));
+        assert_eq!(parse_when("at 12am midnight", NOON), Some((NOON + 12 * 60 * 60, "midnight")));
+        assert_eq!(parse_when("at 25:00 never", NOON), None);
+        assert_eq!(parse_when("at 13pm never", NOON), None);
+    }
+
+    #[test]
+    fn only_the_owner_and_their_channel_can_be_reminded() {
+        let clock = clock(10);
+        assert!(clock.set("alice", Some("#rust"), "me", "in 1h to eat", NOON).unwrap().starts_with("OK"));
+        assert!(clock.set("alice", Some("#rust"), "Alice", "in 1h to eat", NOON).unwrap().starts_with("OK"));
+        assert!(clock.set("alice", Some("#rust"), "#Rust", "in 1h standup", NOON).unwrap().starts_with("OK"));
+        assert!(clock.set("alice", None, "me", "in 1h to eat", NOON).unwrap().starts_with("OK"));
+        assert!(clock.set("alice", Some("#rust"), "bob", "in 1h to eat", NOON).unwrap().starts_with("Sorry"));
+        assert!(clock.set("alice", Some("#rust"), "#spam", "in 1h spam", NOON).unwrap().starts_with("Sorry"));
+        assert!(clock.set("alice", None, "#rust", "in 1h spam", NOON).unwrap().starts_with("Sorry"));
+
+        let targets: Vec<(String, String)> = lock(&clock.scheduled).values()
+            .map(|&(ref r, _)| (r.target.clone(), r.nick.clone()))
+            .collect();
+        assert_eq!(targets, vec![
+            ("#rust".to_string(), "alice".to_string()),
+            ("#rust".to_string(), "alice".to_string()),
+            ("#rust".to_string(), "".to_string()),
+            ("alice".to_string(), "".to_string()),
+            ]);
+    }
+
+    #[test]
+    fn owners_can_only_have_so_many() {
+        let clock = clock(2);
+        assert!(clock.set("alice", None, "me", "in 1h to eat", NOON).unwrap().starts_with("OK"));
+        assert!(clock.set("alice", None, "me", "in 2h to eat", NOON).unwrap().starts_with("OK"));
+        assert!(clock.set("ALICE", None, "me", "in 3h to eat", NOON).unwrap().starts_with("Sorry"));
+        assert!(clock.set("bob", None, "me", "in 3h to eat", NOON).unwrap().starts_with("OK"));
+    }
+
+}
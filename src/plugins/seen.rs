@@ -0,0 +1,245 @@
+//! Remembers when people were last around: `seen alice` says what alice
+//! last did and how long ago, and when alice last spoke if that was
+//! something else.  After a nick change, it goes on to say what the new
+//! nick has been up to since.
+//!
+//! Messages, joins, parts, quits and nick changes are all remembered,
+//! the latest of each person's in every channel.
+
+use std::sync::Arc;
+use time;
+use super::super::error::Result;
+use super::super::event_stream::{HandlerOptions, Response};
+use super::super::isupport::Casemapping;
+use super::super::plugin::{Context, Plugin, Registrar};
+use super::super::protocol::{self, Dest, Message};
+use super::super::storage::{Activity, Sighting, Storage};
+use super::ago;
+
+/// How many nick changes `seen` follows.
+const MAX_HOPS: usize = 5;
+
+pub struct Seen;
+
+/// What a line says someone did, if anything.
+fn sighting(line: &str, nick: &str, now: i64) -> Option<Sighting> {
+    let msg = match Message::parse(line) {
+        Some(msg) => msg,
+        None => return None,
+    };
+    let who = match msg.source_nick() {
+        Some(who) => who,
+        None => return None,
+    };
+    let param = |i: usize| msg.params.get(i).map(|p| p.to_string()).unwrap_or(String::new());
+    let (chan, activity, text) = match msg.command {
+        "PRIVMSG" => match protocol::Privmsg::parse(line) {
+            Some(pm) => match pm.reply_target(nick) {
+                Some(Dest::Chan(chan)) => (chan.to_string(), Activity::Message, pm.plain_msg()),
+                _ => return None,
+            },
+            None => return None,
+        },
+        "JOIN" => (param(0), Activity::Join, String::new()),
+        "PART" => (param(0), Activity::Part, param(1)),
+        "QUIT" => (String::new(), Activity::Quit, param(0)),
+        "NICK" => (String::new(), Activity::Nick, param(0)),
+        _ => return None,
+    };
+    Some(Sighting{
+        nick: who.to_string(),
+        chan: chan,
+        activity: activity,
+        text: text,
+        seen_at: now,
+    })
+}
+
+/// What someone was doing, to follow "was last seen ...,".
+fn describe(sighting: &Sighting) -> String {
+    let reason = |verb: String| {
+        if sighting.text.is_empty() { verb } else { format!("{} ({})", verb, sighting.text) }
+    };
+    match sighting.activity {
+        Activity::Message => format!("saying \"{}\" in {}", sighting.text, sighting.chan),
+        Activity::Join => format!("joining {}", sighting.chan),
+        Activity::Part => reason(format!("leaving {}", sighting.chan)),
+        Activity::Quit => reason("quitting".to_string()),
+        Activity::Nick => format!("changing nick to {}", sighting.text),
+    }
+}
+
+fn report(storage: &Arc<Storage>, casemapping: Casemapping, name: &str, now: i64) -> Result<String> {
+    let mut key = casemapping.normalize(name);
+    let mut visited = vec![key.clone()];
+    let mut text = String::new();
+    let mut since = 0;
+    loop {
+        let sightings: Vec<Sighting> = try!(storage.sightings(&key)).into_iter().filter(|s| s.seen_at >= since).collect();
+        let latest = match sightings.first() {
+            Some(latest) => latest.clone(),
+            None if text.is_empty() => return Ok(format!("I haven't seen {}.", name)),
+            None => return Ok(text),
+        };
+        if !text.is_empty() {
+            text.push(' ');
+        }
+        text.push_str(&format!("{} was last seen {} ago, {}.", latest.nick, ago(now - latest.seen_at), describe(&latest)));
+        if latest.activity != Activity::Message {
+            if let Some(said) = sightings.iter().find(|s| s.activity == Activity::Message) {
+                text.push_str(&format!(" Before that, {} ago, {}.", ago(now - said.seen_at), describe(said)));
+            }
+        }
+        if latest.activity != Activity::Nick || visited.len() > MAX_HOPS {
+            return Ok(text);
+        }
+        key = casemapping.normalize(&latest.text);
+        if visited.contains(&key) {
+            return Ok(text);
+        }
+        visited.push(key.clone());
+        since = latest.seen_at;
+    }
+}
+
+impl Plugin for Seen {
+
+    fn name(&self) -> &str {
+        "seen"
+    }
+
+    fn init(&mut self, ctx: &Context) -> Result<()> {
+        try!(ctx.storage());
+        Ok(())
+    }
+
+    fn register(&mut self, ctx: &Context, registrar: &mut Registrar) {
+        let nick = ctx.nick.clone();
+        let casemapping = ctx.casemapping;
+        let storage = ctx.storage().ok().expect("Checked in init");
+        registrar.command_word("seen");
+        registrar.handler(HandlerOptions::new("seen").blocking(), box move |line: &str| {
+            let now = time::get_time().sec;
+            if let Some(sighting) = sighting(line, &nick, now) {
+                if let Err(e) = storage.record_sighting(&casemapping.normalize(&sighting.nick), &sighting) {
+                    error!("Error recording {}: {}", sighting.nick, e);
+                }
+            }
+            let pm = match protocol::Privmsg::parse(line) {
+                Some(pm) => pm,
+                None => return Response::nothing(),
+            };
+            let msg = pm.plain_msg();
+            let name = match regex!(r"^seen\s+([^\s?]+)\s*\??$").captures(msg.trim()).and_then(|c| c.at(1)) {
+                Some(name) => name.to_string(),
+                None => return Response::nothing(),
+            };
+            let reply_to = match pm.reply_target(&nick) {
+                Some(reply_to) => reply_to,
+                None => return Response::nothing(),
+            };
+            let asker = match pm.src {
+                Some(protocol::Source::User(ref user_info)) => user_info.nick,
+                _ => return Response::nothing(),
+            };
+            let text = if casemapping.same_name(&name, &nick) {
+                "I'm right here.".to_string()
+            } else if casemapping.same_name(&name, asker) {
+                format!("You're right here, {}.", name)
+            } else {
+                match report(&storage, casemapping, &name, now) {
+                    Ok(text) => text,
+                    Err(e) => {
+                        error!("Error looking up {}: {}", name, e);
+                        return Response::nothing();
+                    },
+                }
+            };
+            Response::respond(protocol::Privmsg::new(reply_to, &text).command())
+        });
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::sync::Arc;
+    use super::{report, sighting};
+    use super::super::super::isupport::Casemapping;
+    use super::super::super::storage::Storage;
+    use super::super::super::storage::memory::MemoryStorage;
+
+    /// Remembers what the `lines` say people did, each at its time.
+    fn watched(lines: &[(i64, &str)]) -> Arc<Storage> {
+        let storage: Arc<Storage> = Arc::new(MemoryStorage::new());
+        for &(now, line) in lines.iter() {
+            if let Some(s) = sighting(line, "bot", now) {
+                storage.record_sighting(&Casemapping::Rfc1459.normalize(&s.nick), &s).unwrap();
+            }
+        }
+        storage
+    }
+
+    fn seen(storage: &Arc<Storage>, name: &str, now: i64) -> String {
+        report(storage, Casemapping::Rfc1459, name, now).unwrap()
+    }
+
+    #[test]
+    fn follows_nick_changes() {
+        let storage = watched(&[
+            (100, ":Alice!a@home PRIVMSG #rust :hello"),
+            (200, ":Alice!a@home NICK :Alice_away"),
+            (300, ":Alice_away!a@home PRIVMSG #rust :brb"),
+            ]);
+        assert_eq!(seen(&storage, "alice", 400),
+                   "Alice was last seen 3 minutes ago, changing nick to Alice_away. \
+                    Before that, 5 minutes ago, saying \"hello\" in #rust. \
+                    Alice_away was last seen 1 minute ago, saying \"brb\" in #rust.");
+        assert_eq!(seen(&storage, "alice_away", 400), "Alice_away was last seen 1 minute ago, saying \"brb\" in #rust.");
+    }
+
+    #[test]
+    fn nick_changes_back_and_forth() {
+        let storage = watched(&[
+            (100, ":carol!c@home NICK :carol_"),
+            (200, ":carol_!c@home NICK :carol"),
+            ]);
+        assert_eq!(seen(&storage, "carol", 300),
+                   "carol was last seen 3 minutes ago, changing nick to carol_. \
+                    carol_ was last seen 1 minute ago, changing nick to carol.");
+    }
+
+    #[test]
+    fn quits_and_parts() {
+        let storage = watched(&[
+            (50, ":bob!b@home PRIVMSG #rust :night all"),
+            (100, ":bob!b@home QUIT :Ping timeout: 240 seconds"),
+            (100, ":eve!e@home PART #rust :bye"),
+            (100, ":mallory!m@home QUIT"),
+            ]);
+        assert_eq!(seen(&storage, "bob", 160),
+                   "bob was last seen 1 minute ago, quitting (Ping timeout: 240 seconds). \
+                    Before that, 1 minute ago, saying \"night all\" in #rust.");
+        assert_eq!(seen(&storage, "eve", 130), "eve was last seen 30 seconds ago, leaving #rust (bye).");
+        assert_eq!(seen(&storage, "mallory", 101), "mallory was last seen 1 second ago, quitting.");
+    }
+
+    #[test]
+    fn casemapped_names() {
+        let storage = watched(&[
+            (100, "@time=2015-06-01T12:00:00.000Z :[Dan]!d@home JOIN #rust"),
+            ]);
+        assert_eq!(seen(&storage, "{DAN}", 110), "[Dan] was last seen 10 seconds ago, joining #rust.");
+        assert_eq!(seen(&storage, "[dan]", 110), "[Dan] was last seen 10 seconds ago, joining #rust.");
+        assert_eq!(seen(&storage, "dan", 110), "I haven't seen dan.");
+    }
+
+    #[test]
+    fn private_messages_arent_sightings() {
+        assert!(sighting(":a!b@c PRIVMSG bot :secret", "bot", 100).is_none());
+        assert!(sighting(":irc.example.net 001 bot :Welcome", "bot", 100).is_none());
+        assert!(sighting(":a!b@c PRIVMSG #rust :hi", "bot", 100).is_some());
+    }
+
+}
@@ -0,0 +1,239 @@
+//! Passes on messages: `rustbot: tell alice the build is fixed` keeps
+//! the message until alice next speaks or joins a channel, then says it
+//! there.  Messages left in private are delivered in private, and so is
+//! everything with `deliver_by_pm`.
+//!
+//! A message for a nick is also delivered to whoever is logged in to an
+//! account by that name, if the server sends `account` message tags.
+//! Each person can have `max_pending` messages waiting, and messages
+//! nobody collects are forgotten after `expire_days`.
+//!
+//! ```{.ignore .toml}
+//! [plugins.tell]
+//! max_pending = 5
+//! expire_days = 30
+//! deliver_by_pm = false
+//! ```
+
+use std::sync::Arc;
+use time;
+use super::super::command::Command;
+use super::super::error::Result;
+use super::super::event_stream::{HandlerOptions, Response};
+use super::super::isupport::Casemapping;
+use super::super::plugin::{Context, Plugin, Registrar};
+use super::super::protocol::{self, Dest, Message};
+use super::super::storage::{Memo, Storage};
+use super::ago;
+
+pub struct Tell {
+    max_pending: usize,
+    expire_secs: i64,
+    deliver_by_pm: bool,
+}
+
+impl Tell {
+
+    pub fn new() -> Tell {
+        Tell{
+            max_pending: 5,
+            expire_secs: 30 * 24 * 60 * 60,
+            deliver_by_pm: false,
+        }
+    }
+
+}
+
+struct Messenger {
+    nick: String,
+    storage: Arc<Storage>,
+    casemapping: Casemapping,
+    max_pending: usize,
+    expire_secs: i64,
+    deliver_by_pm: bool,
+}
+
+/// Who said something, and where.
+struct Speaker<'a> {
+    nick: &'a str,
+    /// From the `account` tag, if they're logged in.
+    account: Option<String>,
+    /// The channel, or `None` in private.
+    chan: Option<&'a str>,
+}
+
+impl Messenger {
+
+    /// Leaves `text` for `recipient`, returning what to tell the sender.
+    fn leave(&self, speaker: &Speaker, recipient: &str, text: &str, now: i64) -> Result<String> {
+        if self.casemapping.same_name(recipient, &self.nick) {
+            return Ok(format!("I'm right here, {}.", speaker.nick));
+        }
+        if self.casemapping.same_name(recipient, speaker.nick) {
+            return Ok(format!("That's you, {}.", speaker.nick));
+        }
+        try!(self.storage.expire_memos(now - self.expire_secs));
+        let sender_key = self.casemapping.normalize(speaker.nick);
+        if try!(self.storage.memos_from(&sender_key)) >= self.max_pending {
+            return Ok(format!("Sorry {}, you have too many messages waiting to be delivered.", speaker.nick));
+        }
+        try!(self.storage.add_memo(&Memo{
+            id: 0,
+            recipient: self.casemapping.normalize(recipient),
+            sender: speaker.nick.to_string(),
+            sender_key: sender_key,
+            chan: speaker.chan.unwrap_or("").to_string(),
+            text: text.to_string(),
+            private: speaker.chan.is_none(),
+            sent_at: now,
+        }));
+        Ok(format!("OK, {}, I'll tell {} when I see them.", speaker.nick, recipient))
+    }
+
+    /// The oldest message waiting for the speaker, if any, which is
+    /// then forgotten.  The rest wait until they speak again.
+    fn deliver(&self, speaker: &Speaker, now: i64) -> Result<Option<Command>> {
+        let mut recipients = vec![self.casemapping.normalize(speaker.nick)];
+        if let Some(ref account) = speaker.account {
+            recipients.push(self.casemapping.normalize(account));
+        }
+        let memos: Vec<Memo> = try!(self.storage.memos_for(&recipients)).into_iter()
+            .filter(|m| m.sent_at >= now - self.expire_secs)
+            .collect();
+        let memo = match memos.first() {
+            Some(memo) => memo,
+            None => return Ok(None),
+        };
+        if !try!(self.storage.delete_memo(memo.id)) {
+            return Ok(None);
+        }
+        let mut text = format!("{} left you a message {} ago: {}", memo.sender, ago(now - memo.sent_at), memo.text);
+        if memos.len() > 1 {
+            text.push_str(&format!(" ({} more waiting)", memos.len() - 1));
+        }
+        match speaker.chan {
+            Some(chan) if !memo.private && !self.deliver_by_pm => {
+                Ok(Some(Command::privmsg(chan, &format!("{}: {}", speaker.nick, text))))
+            },
+            _ => Ok(Some(Command::privmsg(speaker.nick, &text))),
+        }
+    }
+
+    fn handle(&self, line: &str) -> Result<Option<Command>> {
+        let msg = match Message::parse(line) {
+            Some(msg) => msg,
+            None => return Ok(None),
+        };
+        let nick = match msg.source_nick() {
+            Some(nick) => nick,
+            None => return Ok(None),
+        };
+        let now = time::get_time().sec;
+        let mut speaker = Speaker{
+            nick: nick,
+            account: msg.tag("account").and_then(|a| if a.is_empty() || a == "*" { None } else { Some(a) }),
+            chan: None,
+        };
+        match msg.command {
+            "PRIVMSG" => {
+                speaker.chan = match msg.params.first().and_then(|target| Dest::parse(target)) {
+                    Some(Dest::Chan(chan)) => Some(chan),
+                    Some(Dest::Nick(_)) => None,
+                    None => return Ok(None),
+                };
+                let targeted = protocol::Privmsg::parse(line).and_then(|pm| pm.targeted_msg(&self.nick)).map(|pm| pm.plain_msg());
+                if let Some(c) = targeted.as_ref().and_then(|t| regex!(r"^tell\s+([^\s]+)\s+(.+)$").captures(t.trim())) {
+                    let reply = try!(self.leave(&speaker, c.at(1).expect("Bad match group"), c.at(2).expect("Bad match group").trim(), now));
+                    let reply_to = speaker.chan.unwrap_or(speaker.nick);
+                    return Ok(Some(Command::privmsg(reply_to, &reply)));
+                }
+            },
+            "JOIN" => {
+                if self.casemapping.same_name(nick, &self.nick) {
+                    return Ok(None);
+                }
+                speaker.chan = msg.params.first().map(|chan| *chan);
+            },
+            _ => return Ok(None),
+        }
+        self.deliver(&speaker, now)
+    }
+
+}
+
+impl Plugin for Tell {
+
+    fn name(&self) -> &str {
+        "tell"
+    }
+
+    fn init(&mut self, ctx: &Context) -> Result<()> {
+        try!(ctx.config.allow_keys(&["max_pending", "expire_days", "deliver_by_pm"]));
+        self.max_pending = try!(ctx.config.positive("max_pending")).unwrap_or(5) as usize;
+        self.expire_secs = try!(ctx.config.positive("expire_days")).unwrap_or(30) as i64 * 24 * 60 * 60;
+        self.deliver_by_pm = try!(ctx.config.bool("deliver_by_pm")).unwrap_or(false);
+        try!(ctx.storage());
+        Ok(())
+    }
+
+    fn register(&mut self, ctx: &Context, registrar: &mut Registrar) {
+        let messenger = Messenger{
+            nick: ctx.nick.clone(),
+            storage: ctx.storage().ok().expect("Checked in init"),
+            casemapping: ctx.casemapping,
+            max_pending: self.max_pending,
+            expire_secs: self.expire_secs,
+            deliver_by_pm: self.deliver_by_pm,
+        };
+        registrar.command_word("tell");
+        registrar.handler(HandlerOptions::new("tell").blocking(), box move |line: &str| {
+            match messenger.handle(line) {
+                Ok(Some(command)) => Response::respond(command),
+                Ok(None) => Response::nothing(),
+                Err(e) => {
+                    error!("Error passing on messages: {}", e);
+                    Response::nothing()
+                },
+            }
+        });
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::sync::Arc;
+    use super::Messenger;
+    use super::super::super::command::Command;
+    use super::super::super::isupport::Casemapping;
+    use super::super::super::storage::memory::MemoryStorage;
+
+    fn messenger() -> Messenger {
+        Messenger{
+            nick: "bot".to_string(),
+            storage: Arc::new(MemoryStorage::new()),
+            casemapping: Casemapping::Rfc1459,
+            max_pending: 5,
+            expire_secs: 30 * 24 * 60 * 60,
+            deliver_by_pm: false,
+        }
+    }
+
+    #[test]
+    fn tagged_lines() {
+        let messenger = messenger();
+        let left = messenger.handle("@account=alice;time=2015-06-01T12:00:00.000Z :a!b@c PRIVMSG #x :bot: tell bob hi").unwrap();
+        assert_eq!(left, Some(Command::privmsg("#x", "OK, a, I'll tell bob when I see them.")));
+        match messenger.handle("@account=bob :Bob!d@e PRIVMSG #x :morning").unwrap() {
+            Some(Command::Privmsg(target, text)) => {
+                assert_eq!(target, "#x");
+                assert!(text.starts_with("Bob: a left you a message"));
+                assert!(text.ends_with(": hi"));
+            },
+            other => panic!("Expected the message, got {:?}", other),
+        }
+        assert_eq!(messenger.handle(":Bob!d@e PRIVMSG #x :again").unwrap(), None);
+    }
+
+}
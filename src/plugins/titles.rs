@@ -0,0 +1,290 @@
+//! Says the titles of pages linked in channels.
+//!
+//! It only runs in channels it's turned on in, with `channels` or the
+//! admin plugin's `enable`.  Links to internal addresses are never
+//! fetched, nor those to `ignore_domains` or their subdomains, and a
+//! link already announced in a channel isn't announced again for
+//! `repeat_secs`.
+//!
+//! ```{.ignore .toml}
+//! [plugins.titles]
+//! channels = ["#rust"]
+//! ignore_domains = ["example.com"]
+//! max_bytes = 65536
+//! timeout_secs = 5
+//! repeat_secs = 600
+//! ```
+
+use regex::Captures;
+use std::collections::HashMap;
+use std::sync::Arc;
+use time;
+use super::super::error::Result;
+use super::super::event_stream::{HandlerOptions, Response};
+use super::super::http::{BasicClient, HttpClient, Limits, Url};
+use super::super::isupport::Casemapping;
+use super::super::plugin::{Context, Plugin, Registrar};
+use super::super::protocol::{self, Dest};
+
+/// How many links in one message are looked at.
+const MAX_LINKS: usize = 3;
+/// How much of a title is said, in characters.
+const MAX_TITLE: usize = 200;
+
+pub struct Titles {
+    client: Arc<HttpClient>,
+    ignore_domains: Vec<String>,
+    limits: Limits,
+    repeat_secs: i64,
+}
+
+impl Titles {
+
+    pub fn new() -> Titles {
+        Titles::with_client(Arc::new(BasicClient::new()))
+    }
+
+    /// Fetches pages with `client` rather than over the network.
+    pub fn with_client(client: Arc<HttpClient>) -> Titles {
+        Titles{
+            client: client,
+            ignore_domains: Vec::new(),
+            limits: Limits::new(),
+            repeat_secs: 600,
+        }
+    }
+
+}
+
+/// The links in a message, without trailing punctuation.
+fn links(msg: &str) -> Vec<Url> {
+    regex!(r"(?i)\bhttps?://[^\s<>]+").captures_iter(msg).filter_map(|c| {
+        let link = c.at(0).expect("Bad match group").trim_right_matches(|c| ".,;:!?'\")".contains(c));
+        Url::parse(link)
+    }).take(MAX_LINKS).collect()
+}
+
+fn decode_entities(text: &str) -> String {
+    regex!(r"&(#[0-9]+|#[xX][0-9a-fA-F]+|[a-zA-Z]+);").replace_all(text, |c: &Captures| {
+        let entity = c.at(1).expect("Bad match group");
+        let decoded = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some(' '),
+            _ if entity.starts_with("#x") || entity.starts_with("#X") => u32::from_str_radix(&entity[2..], 16).ok().and_then(::std::char::from_u32),
+            _ if entity.starts_with('#') => entity[1..].parse().ok().and_then(::std::char::from_u32),
+            _ => None,
+        };
+        match decoded {
+            Some(decoded) => decoded.to_string(),
+            None => c.at(0).expect("Bad match group").to_string(),
+        }
+    })
+}
+
+/// The title of an HTML page, tidied up for IRC.
+fn title(body: &[u8]) -> Option<String> {
+    let html = String::from_utf8_lossy(body);
+    let raw = match regex!(r"(?is)<title[^>]*>(.*?)</title>").captures(&html).and_then(|c| c.at(1)) {
+        Some(raw) => raw.to_string(),
+        None => return None,
+    };
+    let title = regex!(r"\s+").replace_all(&decode_entities(&raw), " ").trim().to_string();
+    if title.is_empty() {
+        return None;
+    }
+    if title.chars().count() > MAX_TITLE {
+        let short: String = title.chars().take(MAX_TITLE).collect();
+        Some(format!("{}...", short.trim_right()))
+    } else {
+        Some(title)
+    }
+}
+
+/// Whether `host` is `domain` or under it.
+fn in_domain(host: &str, domain: &str) -> bool {
+    host == domain || host.ends_with(&format!(".{}", domain))
+}
+
+struct Announcer {
+    client: Arc<HttpClient>,
+    ignore_domains: Vec<String>,
+    limits: Limits,
+    repeat_secs: i64,
+    casemapping: Casemapping,
+    /// When each link was last announced, by channel and link.
+    announced: HashMap<(String, String), i64>,
+}
+
+impl Announcer {
+
+    /// What to say about `link`, posted in `chan`, if anything.
+    fn announce(&mut self, chan: &str, link: &Url, now: i64) -> Option<String> {
+        if self.ignore_domains.iter().any(|d| in_domain(&link.host, d)) {
+            return None;
+        }
+        let expired: Vec<(String, String)> = self.announced.iter()
+            .filter(|&(_, &at)| now - at >= self.repeat_secs)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in expired.iter() {
+            self.announced.remove(key);
+        }
+        let key = (self.casemapping.normalize(chan), link.to_string());
+        if self.announced.contains_key(&key) {
+            return None;
+        }
+        self.announced.insert(key, now);
+        let page = match self.client.get(link, &self.limits) {
+            Ok(page) => page,
+            Err(e) => {
+                debug!("Not announcing {}: {}", link, e);
+                return None;
+            },
+        };
+        if !page.content_type.as_ref().map_or(false, |t| t.to_lowercase().contains("html")) {
+            return None;
+        }
+        if self.ignore_domains.iter().any(|d| in_domain(&page.url.host, d)) {
+            return None;
+        }
+        title(&page.body).map(|title| format!("Title: {} ({})", title, page.url.host))
+    }
+
+}
+
+impl Plugin for Titles {
+
+    fn name(&self) -> &str {
+        "titles"
+    }
+
+    fn init(&mut self, ctx: &Context) -> Result<()> {
+        try!(ctx.config.allow_keys(&["ignore_domains", "max_bytes", "timeout_secs", "repeat_secs"]));
+        self.ignore_domains = try!(ctx.config.strings("ignore_domains")).iter()
+            .map(|d| d.trim_left_matches('.').to_lowercase())
+            .collect();
+        if let Some(max_bytes) = try!(ctx.config.positive("max_bytes")) {
+            self.limits.max_bytes = max_bytes as usize;
+        }
+        if let Some(timeout_secs) = try!(ctx.config.positive("timeout_secs")) {
+            self.limits.timeout_ms = timeout_secs.saturating_mul(1000);
+        }
+        if let Some(repeat_secs) = try!(ctx.config.positive("repeat_secs")) {
+            self.repeat_secs = repeat_secs as i64;
+        }
+        Ok(())
+    }
+
+    fn register(&mut self, ctx: &Context, registrar: &mut Registrar) {
+        let nick = ctx.nick.clone();
+        let mut announcer = Announcer{
+            client: self.client.clone(),
+            ignore_domains: self.ignore_domains.clone(),
+            limits: self.limits.clone(),
+            repeat_secs: self.repeat_secs,
+            casemapping: ctx.casemapping,
+            announced: HashMap::new(),
+        };
+        registrar.handler(HandlerOptions::new("titles").blocking(), box move |line: &str| {
+            let pm = match protocol::Privmsg::parse(line) {
+                Some(pm) => pm,
+                None => return Response::nothing(),
+            };
+            let reply_to = match pm.reply_target(&nick) {
+                Some(reply_to @ Dest::Chan(_)) => reply_to,
+                _ => return Response::nothing(),
+            };
+            let chan = reply_to.format();
+            let now = time::get_time().sec;
+            let said: Vec<String> = links(&pm.plain_msg()).iter()
+                .filter_map(|link| announcer.announce(&chan, link, now))
+                .collect();
+            if said.is_empty() {
+                Response::nothing()
+            } else {
+                Response::respond(protocol::Privmsg::new(reply_to, &said.join(" | ")).command())
+            }
+        });
+    }
+
+    fn opt_in(&self) -> bool {
+        true
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use super::super::super::error::{Error, Result};
+    use super::super::super::http::{HttpClient, Limits, Page, Url};
+    use super::super::super::isupport::Casemapping;
+    use super::{links, title, Announcer};
+
+    /// Serves one page for every URL, and counts requests.
+    struct FakeClient {
+        body: &'static str,
+        requests: Mutex<Vec<String>>,
+    }
+
+    impl HttpClient for FakeClient {
+
+        fn get(&self, url: &Url, _limits: &Limits) -> Result<Page> {
+            self.requests.lock().unwrap().push(url.to_string());
+            if url.host == "broken.example" {
+                return Err(Error::Http("broken".to_string()));
+            }
+            Ok(Page{
+                url: url.clone(),
+                content_type: Some("text/html; charset=utf-8".to_string()),
+                body: self.body.as_bytes().to_vec(),
+            })
+        }
+
+    }
+
+    fn announcer(client: Arc<FakeClient>) -> Announcer {
+        Announcer{
+            client: client,
+            ignore_domains: vec!["ignored.org".to_string()],
+            limits: Limits::new(),
+            repeat_secs: 600,
+            casemapping: Casemapping::Rfc1459,
+            announced: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn find_links() {
+        let found: Vec<String> = links("see https://a.org/x, (http://b.org/y) and ftp://c.org").iter().map(|u| u.to_string()).collect();
+        assert_eq!(found, vec!["https://a.org/x".to_string(), "http://b.org/y".to_string()]);
+    }
+
+    #[test]
+    fn tidy_titles() {
+        assert_eq!(title(b"<html><TITLE lang=en>\n  Rust &amp; you &#8212; &#x41;\n</TITLE>"), Some("Rust & you \u{2014} A".to_string()));
+        assert_eq!(title(b"<title>  </title>"), None);
+        assert_eq!(title(b"no title"), None);
+    }
+
+    #[test]
+    fn announce_once_per_channel() {
+        let client = Arc::new(FakeClient{ body: "<title>Hi</title>", requests: Mutex::new(Vec::new()) });
+        let mut announcer = announcer(client.clone());
+        let url = Url::parse("http://rust-lang.org/").unwrap();
+        assert_eq!(announcer.announce("#rust", &url, 0), Some("Title: Hi (rust-lang.org)".to_string()));
+        assert_eq!(announcer.announce("#RUST", &url, 10), None);
+        assert_eq!(announcer.announce("#other", &url, 10), Some("Title: Hi (rust-lang.org)".to_string()));
+        assert_eq!(announcer.announce("#rust", &url, 700), Some("Title: Hi (rust-lang.org)".to_string()));
+        assert_eq!(announcer.announce("#rust", &Url::parse("http://www.ignored.org/").unwrap(), 0), None);
+        assert_eq!(announcer.announce("#rust", &Url::parse("http://broken.example/").unwrap(), 0), None);
+        assert_eq!(client.requests.lock().unwrap().len(), 4);
+    }
+
+}
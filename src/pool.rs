@@ -0,0 +1,59 @@
+//! A fixed set of threads for running slow work off the event loop.
+
+use std::cmp;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+use super::error::Result;
+
+/// Work for a `Pool`.  It's called once.
+pub type Job = Box<FnMut() + Send>;
+
+/// Worker threads that take `Job`s in the order they're submitted.
+/// Clones submit to the same workers, which exit once every clone is
+/// gone and the queue is empty.
+///
+/// A job that panics kills its worker, so jobs should catch their own
+/// panics.
+#[derive(Clone)]
+pub struct Pool {
+    jobs: Sender<Job>,
+}
+
+impl Pool {
+
+    /// Starts `size` workers, or one if `size` is 0, since jobs would
+    /// never run otherwise.
+    pub fn new(size: usize) -> Pool {
+        let (tx, rx) = channel::<Job>();
+        let rx = Arc::new(Mutex::new(rx));
+        for n in 0..cmp::max(size, 1) {
+            let rx = rx.clone();
+            thread::spawn(move || {
+                loop {
+                    let job = match rx.lock() {
+                        Ok(rx) => rx.recv(),
+                        Err(_) => break,
+                    };
+                    match job {
+                        Ok(mut job) => job(),
+                        Err(_) => break,
+                    }
+                }
+                debug!("Worker {} exiting.", n);
+            });
+        }
+        Pool{
+            jobs: tx,
+        }
+    }
+
+    /// Queues `job` for the next free worker.
+    ///
+    /// Fails with `Error::Disconnected` if every worker has died.
+    pub fn submit(&self, job: Job) -> Result<()> {
+        try!(self.jobs.send(job));
+        Ok(())
+    }
+
+}
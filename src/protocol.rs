@@ -1,25 +1,32 @@
+use regex;
 use regex::Regex;
+use rustc_serialize::base64;
+use rustc_serialize::base64::ToBase64;
 use std::char;
-use std::io;
 use std::io::prelude::*;
+use super::command::Command;
+use super::error::{Error, Result};
+use super::formatting;
+use super::isupport::ISupport;
+use super::modes;
+use super::modes::UserModes;
 use super::event_stream::{Action, HandlerAction, Response, EventStream};
 
-static MODE_WALLOPS: u16 = 4;
-static MODE_INVISIBLE: u16 = 8;
-
-fn mode_for(invisible: bool, wallops: bool) -> u16 {
-    (if invisible { MODE_WALLOPS } else { 0 }) + (if wallops { MODE_INVISIBLE } else { 0 })
-}
+/// How long to wait for the server to accept our registration.
+const LOGIN_TIMEOUT_MS: u32 = 60 * 1000;
 
 pub fn pong_handler(line: &str) -> Response {
     //debug!("pong_handler considering message \"{}\"", s);
-    if line.starts_with("PING") {
-        let resp = format!("PONG {}\r\n", line.split(' ').skip(1).next().or(Some("")).unwrap());
-        //debug!("pong_handler responding \"{}\"", resp);
-        Response::respond(resp.to_string())
-    } else {
-        //debug!("pong_handler returning Continue");
-        Response::nothing()
+    match Message::parse(line) {
+        Some(ref msg) if msg.command == "PING" => {
+            let token = msg.params.get(0).map(|t| t.to_string()).unwrap_or(String::new());
+            //debug!("pong_handler responding to \"{}\"", token);
+            Response::respond(Command::Pong(token))
+        },
+        _ => {
+            //debug!("pong_handler returning Continue");
+            Response::nothing()
+        },
     }
 }
 
@@ -31,40 +38,295 @@ pub fn timeout_handler(line: &str) -> Response {
     }
 }
 
-pub fn login(stream: &mut EventStream, nick: &str,
-             user: &str, realname: &str,
-             invisible: bool, wallops: bool) -> io::Result<String> {
-    let re = regex!(r"^([^\s]+)\s+001");
-    let server_responses = stream.await_lines(vec![re.clone()]);
-    let login_responses = stream.await_lines(vec![Regex::new(&format!(r"MODE {} :\+[iswx]+$", nick))
-                                                  .ok().expect("Regex compilation failure: bad username?")]);
+/// Everything we tell the server when registering.
+#[derive(Clone, Debug)]
+pub struct Registration {
+    /// Nicks to try, in order of preference.
+    pub nicks: Vec<String>,
+    pub user: String,
+    pub realname: String,
+    pub modes: UserModes,
+    /// IRCv3 capabilities to request, if the server offers them.
+    pub capabilities: Vec<String>,
+    /// Account and password for SASL PLAIN authentication.
+    pub sasl: Option<(String, String)>,
+}
+
+impl Registration {
+
+    /// Registers as `nick`, with the same user name and real name.
+    pub fn new(nick: &str) -> Registration {
+        Registration{
+            nicks: vec![nick.to_string()],
+            user: nick.to_string(),
+            realname: nick.to_string(),
+            modes: UserModes::new(),
+            capabilities: Vec::new(),
+            sasl: None,
+        }
+    }
 
-    try!(write!(stream, "NICK {}\r\n", nick));
-    try!(write!(stream, "USER {} {} unused {}\r\n", user, mode_for(invisible, wallops), realname));
+}
 
-    login_responses.into_iter().map(|mut f| f.get()).next().expect("Didn't get login response.");
+/// SASL payloads are sent in chunks of at most this many bytes.
+const SASL_CHUNK_LEN: usize = 400;
+
+/// Registers with the server, negotiating capabilities and
+/// authenticating with SASL first if asked to.  Returns the server's
+/// name once it has finished its MOTD, by which time it has sent its
+/// ISUPPORT tokens.
+///
+/// Fails with `Error::Registration` if the server refuses every nick
+/// in `registration`, SASL authentication fails, or the server
+/// refuses us for any other reason.
+pub fn login(stream: &mut EventStream, registration: &Registration) -> Result<String> {
+    // RPL_WELCOME, nick errors (431-433, 436), ERR_UNKNOWNCOMMAND for
+    // servers that don't know CAP, ERR_NEEDMOREPARAMS,
+    // ERR_ALREADYREGISTRED, ERR_NOPERMFORHOST, ERR_PASSWDMISMATCH,
+    // ERR_YOUREBANNEDCREEP, SASL results, capability negotiation, the
+    // end of the MOTD (376) or its absence (422), or the server just
+    // hanging up on us.
+    let mut watch = try!(stream.watch_commands(&[
+        "001", "376", "422", "431", "432", "433", "436", "421", "461", "462", "463", "464", "465",
+        "902", "903", "904", "905", "906", "907", "908", "ERROR", "CAP", "AUTHENTICATE",
+    ]));
+
+    let mut nicks = registration.nicks.iter();
+    let first_nick = try!(nicks.next().ok_or(Error::Registration("no nicks to try".to_string())));
+    let mut wanted_caps = registration.capabilities.clone();
+    if registration.sasl.is_some() && !wanted_caps.iter().any(|c| c == "sasl") {
+        wanted_caps.push("sasl".to_string());
+    }
+    let mut offered_caps = Vec::new();
+    let mut server_name = None;
 
-    Ok(server_responses.into_iter().map(|mut f| {
-        re.captures(&f.get())
-            .expect("Regex match was bad")
-            .at(1).expect("Regex didn't capture").to_string()
-    }).next().expect("Didn't get server response"))
+    if !wanted_caps.is_empty() {
+        try!(write!(stream, "CAP LS 302\r\n"));
+    }
+    try!(write!(stream, "NICK {}\r\n", first_nick));
+    try!(write!(stream, "USER {} {} unused :{}\r\n", registration.user, registration.modes.user_bitmask(), registration.realname));
+
+    loop {
+        let line = match watch.next(LOGIN_TIMEOUT_MS) {
+            Ok(line) => line,
+            Err(Error::Timeout(e)) => match server_name.take() {
+                // Registered, but the server never finished its MOTD.
+                Some(server) => return Ok(server),
+                None => return Err(Error::Timeout(e)),
+            },
+            Err(e) => return Err(e),
+        };
+        let msg = match Message::parse(&line) {
+            Some(msg) => msg,
+            None => continue,
+        };
+        match msg.command {
+            "001" => {
+                server_name = Some(try!(msg.prefix.map(|server| server.to_string())
+                    .ok_or(Error::Protocol(format!("bad welcome \"{}\"", line)))));
+            },
+            "376" | "422" => {
+                if let Some(server) = server_name.take() {
+                    return Ok(server);
+                }
+            },
+            "432" | "433" | "436" => {
+                match nicks.next() {
+                    Some(nick) => {
+                        info!("Nick refused, trying {}...", nick);
+                        try!(write!(stream, "NICK {}\r\n", nick));
+                    },
+                    None => return Err(Error::Registration(line.clone())),
+                }
+            },
+            // The server doesn't do capability negotiation, so it will
+            // just carry on registering us.
+            "421" => (),
+            "CAP" if msg.params.len() >= 3 => {
+                match msg.params[1] {
+                    "LS" => {
+                        // Multi-line replies have a "*" before the last parameter.
+                        let more = msg.params.len() >= 4 && msg.params[2] == "*";
+                        let caps = msg.params[msg.params.len() - 1];
+                        offered_caps.extend(caps.split(' ').filter(|c| !c.is_empty()).map(|c| {
+                            c.splitn(2, '=').next().unwrap_or(c).to_string()
+                        }));
+                        if !more {
+                            let requested: Vec<&str> = wanted_caps.iter()
+                                .filter(|c| offered_caps.contains(*c))
+                                .map(|c| &c[..])
+                                .collect();
+                            if requested.is_empty() {
+                                try!(write!(stream, "CAP END\r\n"));
+                            } else {
+                                try!(write!(stream, "CAP REQ :{}\r\n", requested.join(" ")));
+                            }
+                        }
+                    },
+                    "ACK" => {
+                        let acked = msg.params[msg.params.len() - 1];
+                        if registration.sasl.is_some() && acked.split(' ').any(|c| c == "sasl") {
+                            try!(write!(stream, "AUTHENTICATE PLAIN\r\n"));
+                        } else {
+                            try!(write!(stream, "CAP END\r\n"));
+                        }
+                    },
+                    "NAK" => {
+                        warn!("Server refused capabilities \"{}\".", msg.params[msg.params.len() - 1]);
+                        try!(write!(stream, "CAP END\r\n"));
+                    },
+                    _ => (),
+                }
+            },
+            "AUTHENTICATE" if msg.params.get(0) == Some(&"+") => {
+                if let Some((ref account, ref password)) = registration.sasl {
+                    try!(send_sasl_plain(stream, account, password));
+                }
+            },
+            // RPL_SASLSUCCESS
+            "903" => {
+                info!("Authenticated with SASL.");
+                try!(write!(stream, "CAP END\r\n"));
+            },
+            // RPL_LOGGEDIN and RPL_LOGGEDOUT are informational.
+            "900" | "901" => (),
+            "431" | "461" | "462" | "463" | "464" | "465" | "ERROR"
+                | "902" | "904" | "905" | "906" | "907" | "908" => {
+                return Err(Error::Registration(line.clone()));
+            },
+            _ => (),
+        }
+    }
 }
 
-pub fn join(stream: &mut EventStream, server: &str, channels: &[&str]) -> io::Result<()> {
-    let regexes = channels.iter().map(|chan| Regex::new(&format!(r"JOIN\s+:?{}", chan)).unwrap()).collect();
-    let responses = stream.await_lines(regexes);
+fn send_sasl_plain(stream: &mut EventStream, account: &str, password: &str) -> Result<()> {
+    let payload = format!("{}\0{}\0{}", account, account, password)
+        .as_bytes().to_base64(base64::STANDARD);
+    let mut rest = &payload[..];
+    loop {
+        let chunk_len = ::std::cmp::min(rest.len(), SASL_CHUNK_LEN);
+        let (chunk, after) = rest.split_at(chunk_len);
+        try!(write!(stream, "AUTHENTICATE {}\r\n", if chunk.is_empty() { "+" } else { chunk }));
+        // A full-length chunk means more is coming, so a payload that's
+        // an exact multiple of the chunk length ends with an empty one.
+        if chunk.len() < SASL_CHUNK_LEN {
+            return Ok(());
+        }
+        rest = after;
+    }
+}
 
-    for chan in channels.iter() {
-        info!("Joining channel {}...", chan);
-        try!(write!(stream, "{} JOIN {}\r\n", server, chan));
+/// Asks for `user_modes` once we're registered, within the limits in
+/// the server's `isupport`.  The server's confirmation is picked up by
+/// `state::tracker`.
+pub fn request_user_modes(stream: &mut EventStream, nick: &str, user_modes: &UserModes,
+                          isupport: &ISupport) -> Result<()> {
+    for line in modes::format_modes(nick, &user_modes.changes(), isupport).iter() {
+        try!(write!(stream, "{}\r\n", line));
     }
-    responses.into_iter().map(|f| {
-        info!("Joined channel {}!", regex!(r"JOIN\s+:?([^\s]+)").captures(&f.into_inner()).unwrap().at(1).unwrap());
-    }).count();
     Ok(())
 }
 
+/// A generic IRC message: `[@tags] [:prefix] COMMAND [params...] [:trailing]`.
+///
+/// The trailing parameter, if any, is the last element of `params`.
+pub struct Message<'a> {
+    pub tags: Option<&'a str>,
+    pub prefix: Option<&'a str>,
+    pub command: &'a str,
+    pub params: Vec<&'a str>,
+}
+
+impl<'a> Message<'a> {
+
+    pub fn parse(line: &'a str) -> Option<Message<'a>> {
+        let mut rest = line.trim_left();
+        let mut tags = None;
+        let mut prefix = None;
+        if rest.starts_with('@') {
+            let (word, after) = split_word(&rest[1..]);
+            tags = Some(word);
+            rest = after;
+        }
+        if rest.starts_with(':') {
+            let (word, after) = split_word(&rest[1..]);
+            prefix = Some(word);
+            rest = after;
+        }
+        let (command, mut rest) = split_word(rest);
+        if command.is_empty() {
+            return None;
+        }
+        let mut params = Vec::new();
+        while !rest.is_empty() {
+            if rest.starts_with(':') {
+                params.push(&rest[1..]);
+                break;
+            }
+            let (param, after) = split_word(rest);
+            params.push(param);
+            rest = after;
+        }
+        Some(Message{
+            tags: tags,
+            prefix: prefix,
+            command: command,
+            params: params,
+        })
+    }
+
+    /// The unescaped value of an IRCv3 message tag.  Tags without a
+    /// value are returned as the empty string.
+    pub fn tag(&self, key: &str) -> Option<String> {
+        self.tags.and_then(|tags| {
+            tags.split(';').filter_map(|tag| {
+                let mut parts = tag.splitn(2, '=');
+                match parts.next() {
+                    Some(k) if k == key => Some(unescape_tag_value(parts.next().unwrap_or(""))),
+                    _ => None,
+                }
+            }).next()
+        })
+    }
+
+    /// The nick that sent this message, if it came from a user.
+    pub fn source_nick(&self) -> Option<&'a str> {
+        match self.prefix.and_then(Source::parse) {
+            Some(Source::User(user_info)) => Some(user_info.nick),
+            _ => None,
+        }
+    }
+
+}
+
+/// Splits off the first space-separated word, skipping extra spaces.
+fn split_word(s: &str) -> (&str, &str) {
+    match s.find(' ') {
+        Some(i) => (&s[..i], s[i..].trim_left_matches(' ')),
+        None => (s, ""),
+    }
+}
+
+fn unescape_tag_value(value: &str) -> String {
+    let mut unescaped = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some(':') => unescaped.push(';'),
+            Some('s') => unescaped.push(' '),
+            Some('r') => unescaped.push('\r'),
+            Some('n') => unescaped.push('\n'),
+            Some(other) => unescaped.push(other),
+            None => (),
+        }
+    }
+    unescaped
+}
+
 pub fn ctcp_action(msg: &str) -> String {
     let one_byte = char::from_u32(1).unwrap();
     return format!("{}ACTION {}{}", one_byte, msg, one_byte);
@@ -138,17 +400,19 @@ pub struct Privmsg<'a> {
 impl<'a> Privmsg<'a> {
 
     pub fn parse(line: &'a str) -> Option<Privmsg<'a>> {
-        regex!(r"^:([^\s]+)\s+PRIVMSG\s+([^\s]+)\s+:?(.*)$")
-            .captures(line).map(
-                |c| Source::parse(c.at(1).expect("Bad match group")).map(
-                    |src| Dest::parse(c.at(2).expect("Bad match group")).map(
-                        |dst| Privmsg{
-                            src: Some(src),
-                            dst: dst,
-                            msg: c.at(3).expect("Bad match group"),
-                        })
-                        .unwrap())
-                    .unwrap())
+        Message::parse(line).and_then(|msg| {
+            if msg.command != "PRIVMSG" || msg.params.len() < 2 {
+                return None;
+            }
+            match (msg.prefix.and_then(Source::parse), Dest::parse(msg.params[0])) {
+                (Some(src), Some(dst)) => Some(Privmsg{
+                    src: Some(src),
+                    dst: dst,
+                    msg: msg.params[1],
+                }),
+                _ => None,
+            }
+        })
     }
 
     pub fn new(dst: Dest<'a>, msg: &'a str) -> Privmsg<'a> {
@@ -163,6 +427,17 @@ impl<'a> Privmsg<'a> {
         format!("PRIVMSG {} :{}", self.dst.format(), self.msg)
     }
 
+    /// This message as a command to send.
+    pub fn command(&self) -> Command {
+        Command::privmsg(&self.dst.format(), self.msg)
+    }
+
+    /// The message with any bold, color, etc. codes removed, for
+    /// matching commands against.
+    pub fn plain_msg(&self) -> String {
+        formatting::strip(self.msg)
+    }
+
     pub fn reply_target(&'a self, nick: &str) -> Option<Dest<'a>> {
         match self.dst {
             Dest::Nick(ref n) if *n == nick => {
@@ -183,17 +458,88 @@ impl<'a> Privmsg<'a> {
 
     pub fn targeted_msg(self, nick: &str) -> Option<Privmsg<'a>> {
         if let Dest::Nick(n) = self.dst {
-            assert_eq!(n, nick);
-            Some(self)
-        } else if let Some(c) = Regex::new(&format!(r"^{}[:,]?\s+(.*)\s*$", nick)).ok().expect("bad regex").captures(self.msg) {
+            if n == nick {
+                Some(self)
+            } else {
+                None
+            }
+        } else {
+            // Nicks may contain regex metacharacters like `[` and `|`,
+            // and people may embolden or color the nick they address.
+            let codes = format!("{}*", formatting::CODE_PATTERN);
+            let addressed = nick.chars().map(|c| format!("{}{}", regex::quote(&c.to_string()), codes)).collect::<Vec<_>>().concat();
+            let re = match Regex::new(&format!(r"^{}{}[:,]?{}\s+(.*)\s*$", codes, addressed, codes)) {
+                Ok(re) => re,
+                Err(_) => return None,
+            };
+            let msg = match re.captures(self.msg).and_then(|c| c.at(1)) {
+                Some(msg) => msg,
+                None => return None,
+            };
             Some(Privmsg{
                 src: self.src,
                 dst: self.dst,
-                msg: c.at(1).expect("Bad regex group"),
+                msg: msg,
             })
-        } else {
-            None
         }
     }
 
 }
+
+#[cfg(test)]
+mod tests {
+
+    use super::{pong_handler, Dest, Privmsg, Source};
+    use super::super::command::Command;
+    use super::super::event_stream::Response;
+
+    fn pong(line: &str) -> Option<String> {
+        match pong_handler(line) {
+            Response(Some(Command::Pong(token)), _, _) => Some(token),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn pings_are_answered_with_or_without_tags() {
+        assert_eq!(pong("PING :irc.example.net"), Some("irc.example.net".to_string()));
+        assert_eq!(pong(":irc.example.net PING :abc"), Some("abc".to_string()));
+        assert_eq!(pong("@time=2015-06-01T12:00:00.000Z PING :abc"), Some("abc".to_string()));
+        assert_eq!(pong(":a!b@c PRIVMSG #x :PING"), None);
+    }
+
+    #[test]
+    fn privmsgs_with_tags() {
+        let line = "@account=alice;time=2015-06-01T12:00:00.000Z :a!b@c PRIVMSG #x :bot: tell bob hi";
+        let pm = Privmsg::parse(line).unwrap();
+        match pm.src {
+            Some(Source::User(ref user)) => assert_eq!(user.nick, "a"),
+            _ => panic!("Expected a user"),
+        }
+        match pm.dst {
+            Dest::Chan(chan) => assert_eq!(chan, "#x"),
+            _ => panic!("Expected a channel"),
+        }
+        assert_eq!(pm.msg, "bot: tell bob hi");
+        assert_eq!(pm.targeted_msg("bot").unwrap().msg, "tell bob hi");
+    }
+
+    #[test]
+    fn not_privmsgs() {
+        assert!(Privmsg::parse("@time=2015-06-01T12:00:00.000Z :a!b@c NOTICE #x :hi").is_none());
+        assert!(Privmsg::parse("PRIVMSG #x :no prefix").is_none());
+        assert!(Privmsg::parse(":a!b@c PRIVMSG #x").is_none());
+    }
+
+    #[test]
+    fn formatted_addressing() {
+        let targeted = |line: &'static str| Privmsg::parse(line).and_then(|pm| pm.targeted_msg("bot[1]")).map(|pm| pm.plain_msg());
+        assert_eq!(targeted(":a!b@c PRIVMSG #x :bot[1]: karma rust"), Some("karma rust".to_string()));
+        assert_eq!(targeted(":a!b@c PRIVMSG #x :\x02bot[1]\x02: karma rust"), Some("karma rust".to_string()));
+        assert_eq!(targeted(":a!b@c PRIVMSG #x :\x0304,12bot\x02[1]:\x0f karma \x1frust"), Some("karma rust".to_string()));
+        assert_eq!(targeted(":a!b@c PRIVMSG #x :\x04FF0000bot[1]\x04, karma rust"), Some("karma rust".to_string()));
+        assert_eq!(targeted(":a!b@c PRIVMSG #x :bot1: karma rust"), None);
+        assert_eq!(targeted(":a!b@c PRIVMSG #x :\x02karma\x02 rust"), None);
+    }
+
+}
@@ -0,0 +1,422 @@
+//! Running things later, on the event loop.
+//!
+//! Tasks run between lines, on the event loop's thread, with an
+//! `EventStream` they can use to send commands and add or remove
+//! handlers.  Like handlers, they shouldn't block.
+
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use time;
+use time::Timespec;
+use super::error::{Error, Result};
+use super::event_stream::EventStream;
+
+/// How often the event loop checks for tasks that are due, in
+/// milliseconds.
+pub const TICK_MS: u32 = 100;
+
+/// Something to do later.
+pub type Task = Box<FnMut(&mut EventStream) + Send>;
+
+/// When to run a task.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Schedule {
+    /// Once, this many milliseconds from now.
+    After(u32),
+    /// Every this many milliseconds, starting this many milliseconds
+    /// from now.
+    Every(u32),
+    /// Whenever the time matches, in UTC.
+    Cron(Cron),
+}
+
+/// A cron-style schedule: minute, hour, day of month, month and day
+/// of week, each of which may be `*`, a number, a range like `1-5`, a
+/// step like `*/15` or `0-30/10`, or a comma-separated list of those.
+/// Days of the week run from 0 (Sunday) to 6, and 7 is Sunday too.
+///
+/// As in cron, if both the day of month and the day of week are
+/// restricted, a day matching either will do.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+/// Parses one field into a bitmask of the values it allows.
+fn parse_field(field: &str, min: u32, max: u32) -> Option<u64> {
+    let mut mask = 0u64;
+    for part in field.split(',') {
+        let mut pieces = part.splitn(2, '/');
+        let range = pieces.next().unwrap_or("");
+        let step = match pieces.next() {
+            Some(step) => match step.parse::<u32>() {
+                Ok(step) if step > 0 => step,
+                _ => return None,
+            },
+            None => 1,
+        };
+        let (lo, hi) = if range == "*" {
+            (min, max)
+        } else {
+            let mut bounds = range.splitn(2, '-');
+            let lo = match bounds.next().and_then(|lo| lo.parse::<u32>().ok()) {
+                Some(lo) => lo,
+                None => return None,
+            };
+            match bounds.next() {
+                Some(hi) => match hi.parse::<u32>() {
+                    Ok(hi) => (lo, hi),
+                    Err(_) => return None,
+                },
+                // `5/10` means from 5 onwards.
+                None if step > 1 => (lo, max),
+                None => (lo, lo),
+            }
+        };
+        if lo < min || hi > max || lo > hi {
+            return None;
+        }
+        let mut value = lo;
+        while value <= hi {
+            mask |= 1 << value;
+            value += step;
+        }
+    }
+    Some(mask)
+}
+
+impl Cron {
+
+    /// Parses a five-field cron expression, like `"0 9 * * 1-5"` for
+    /// 09:00 on weekdays.
+    pub fn parse(spec: &str) -> Result<Cron> {
+        let invalid = || Error::Schedule(format!("cron expression {:?}", spec));
+        let fields: Vec<&str> = spec.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(invalid());
+        }
+        let minutes = try!(parse_field(fields[0], 0, 59).ok_or_else(&invalid));
+        let hours = try!(parse_field(fields[1], 0, 23).ok_or_else(&invalid));
+        let days = try!(parse_field(fields[2], 1, 31).ok_or_else(&invalid));
+        let months = try!(parse_field(fields[3], 1, 12).ok_or_else(&invalid));
+        let mut weekdays = try!(parse_field(fields[4], 0, 7).ok_or_else(&invalid));
+        if weekdays & (1 << 7) != 0 {
+            weekdays |= 1;
+        }
+        Ok(Cron{
+            minutes: minutes,
+            hours: hours,
+            days: days,
+            months: months,
+            weekdays: weekdays,
+            any_day: fields[2] == "*",
+            any_weekday: fields[4] == "*",
+        })
+    }
+
+    fn matches_day(&self, tm: &time::Tm) -> bool {
+        if self.months & (1 << (tm.tm_mon + 1)) == 0 {
+            return false;
+        }
+        let day = self.days & (1 << tm.tm_mday) != 0;
+        let weekday = self.weekdays & (1 << tm.tm_wday) != 0;
+        match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        }
+    }
+
+    /// The first matching minute after `after`, in seconds since the
+    /// epoch, or `None` if there isn't one in the next few years (say,
+    /// for the 31st of February).
+    pub fn next_after(&self, after: i64) -> Option<i64> {
+        let mut t = (after / 60 + 1) * 60;
+        // Days, then hours, then minutes, over four years or so.
+        for _ in 0..(5 * 366 + 24 + 60) * 2 {
+            let tm = time::at_utc(Timespec::new(t, 0));
+            let into_day = tm.tm_hour as i64 * 3600 + tm.tm_min as i64 * 60;
+            if !self.matches_day(&tm) {
+                t += 86400 - into_day;
+            } else if self.hours & (1 << tm.tm_hour) == 0 {
+                t += 3600 - tm.tm_min as i64 * 60;
+            } else if self.minutes & (1 << tm.tm_min) == 0 {
+                t += 60;
+            } else {
+                return Some(t);
+            }
+        }
+        None
+    }
+
+}
+
+/// Identifies a scheduled task.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TaskId(u64);
+
+/// A scheduled task's name, schedule and when it's next due.
+#[derive(Clone, Debug)]
+pub struct TaskInfo {
+    pub id: TaskId,
+    pub name: String,
+    pub schedule: Schedule,
+    /// Milliseconds until it next runs.  Zero if it's overdue or
+    /// running.
+    pub due_in_ms: u64,
+}
+
+struct Entry {
+    id: TaskId,
+    name: String,
+    schedule: Schedule,
+    /// By `now_ms`.
+    due_ms: u64,
+    /// Taken out while it runs.
+    task: Option<Task>,
+}
+
+struct Tasks {
+    entries: Vec<Entry>,
+    next_id: u64,
+}
+
+fn now_ms() -> u64 {
+    time::precise_time_ns() / 1000000
+}
+
+/// When `schedule` is next due, by `now_ms`, or `None` if it isn't.
+fn next_due(schedule: &Schedule, now: u64, first: bool) -> Option<u64> {
+    match *schedule {
+        Schedule::After(ms) => if first { Some(now + ms as u64) } else { None },
+        Schedule::Every(ms) => Some(now + ms as u64),
+        Schedule::Cron(ref cron) => {
+            let wall = time::get_time();
+            let wall_ms = wall.sec * 1000 + wall.nsec as i64 / 1000000;
+            cron.next_after(wall.sec).map(|next| now + (next * 1000 - wall_ms) as u64)
+        },
+    }
+}
+
+/// The tasks waiting to run on an event loop.  Clones share the same
+/// tasks.
+#[derive(Clone)]
+pub struct Scheduler {
+    tasks: Arc<Mutex<Tasks>>,
+}
+
+impl Scheduler {
+
+    pub fn new() -> Scheduler {
+        Scheduler{
+            tasks: Arc::new(Mutex::new(Tasks{
+                entries: Vec::new(),
+                next_id: 0,
+            })),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<Tasks> {
+        // Tasks run outside the lock, so it can't be poisoned by one
+        // panicking.
+        match self.tasks.lock() {
+            Ok(tasks) => tasks,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    /// Runs `task` according to `schedule`.  A cron schedule that never
+    /// matches is never run.
+    pub fn schedule(&self, name: &str, schedule: Schedule, task: Task) -> TaskHandle {
+        let mut tasks = self.lock();
+        let id = TaskId(tasks.next_id);
+        tasks.next_id += 1;
+        match next_due(&schedule, now_ms(), true) {
+            Some(due_ms) => {
+                tasks.entries.push(Entry{
+                    id: id,
+                    name: name.to_string(),
+                    schedule: schedule,
+                    due_ms: due_ms,
+                    task: Some(task),
+                });
+            },
+            None => {
+                warn!("Task {} will never run.", name);
+            },
+        }
+        TaskHandle{
+            id: id,
+            scheduler: self.clone(),
+        }
+    }
+
+    /// Cancels the task `id`.  Returns whether it was still scheduled.
+    /// If it's running, it finishes but doesn't run again.
+    pub fn cancel(&self, id: TaskId) -> bool {
+        let mut tasks = self.lock();
+        match tasks.entries.iter().position(|e| e.id == id) {
+            Some(i) => {
+                tasks.entries.remove(i);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// The scheduled tasks, soonest first.
+    pub fn tasks(&self) -> Vec<TaskInfo> {
+        let now = now_ms();
+        let tasks = self.lock();
+        let mut info: Vec<TaskInfo> = tasks.entries.iter().map(|e| TaskInfo{
+            id: e.id,
+            name: e.name.clone(),
+            schedule: e.schedule.clone(),
+            due_in_ms: if e.due_ms > now { e.due_ms - now } else { 0 },
+        }).collect();
+        info.sort_by(|a, b| a.due_in_ms.cmp(&b.due_in_ms));
+        info
+    }
+
+    /// Runs the tasks that are due, soonest first.  The event loop
+    /// calls this every `TICK_MS` milliseconds.
+    ///
+    /// A task that panics is dropped.
+    pub fn run_due(&self, stream: &mut EventStream) {
+        let now = now_ms();
+        let mut due: Vec<(u64, TaskId)> = self.lock().entries.iter()
+            .filter(|e| e.task.is_some() && e.due_ms <= now)
+            .map(|e| (e.due_ms, e.id))
+            .collect();
+        due.sort();
+        for (_, id) in due.into_iter() {
+            // Taken out so the task can schedule and cancel tasks
+            // while it runs.
+            let taken = {
+                let mut tasks = self.lock();
+                tasks.entries.iter_mut().find(|e| e.id == id).and_then(|e| e.task.take())
+            };
+            let mut task = match taken {
+                Some(task) => task,
+                None => continue,
+            };
+            let mut task_stream = stream.clone();
+            let result = thread::catch_panic(move || {
+                task(&mut task_stream);
+                task
+            });
+            let mut tasks = self.lock();
+            let i = match tasks.entries.iter().position(|e| e.id == id) {
+                Some(i) => i,
+                // Cancelled while it ran.
+                None => continue,
+            };
+            let next = next_due(&tasks.entries[i].schedule, now_ms(), false);
+            match (result, next) {
+                (Ok(task), Some(due_ms)) => {
+                    tasks.entries[i].task = Some(task);
+                    tasks.entries[i].due_ms = due_ms;
+                },
+                (Ok(_), None) => {
+                    tasks.entries.remove(i);
+                },
+                (Err(_), _) => {
+                    error!("Task {} panicked, dropping it.", tasks.entries[i].name);
+                    tasks.entries.remove(i);
+                },
+            }
+        }
+    }
+
+}
+
+/// Refers to a scheduled task, so it can be cancelled.  Dropping the
+/// handle leaves the task scheduled.
+#[derive(Clone)]
+pub struct TaskHandle {
+    id: TaskId,
+    scheduler: Scheduler,
+}
+
+impl TaskHandle {
+
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    /// Cancels the task.  Returns whether it was still scheduled.
+    pub fn cancel(&self) -> bool {
+        self.scheduler.cancel(self.id)
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use time;
+    use super::Cron;
+
+    /// Seconds since the epoch for a UTC time.
+    fn at(year: i32, mon: i32, mday: i32, hour: i32, min: i32) -> i64 {
+        let tm = time::Tm{
+            tm_sec: 0,
+            tm_min: min,
+            tm_hour: hour,
+            tm_mday: mday,
+            tm_mon: mon - 1,
+            tm_year: year - 1900,
+            tm_wday: 0,
+            tm_yday: 0,
+            tm_isdst: 0,
+            tm_utcoff: 0,
+            tm_nsec: 0,
+        };
+        tm.to_timespec().sec
+    }
+
+    #[test]
+    fn rejects_bad_expressions() {
+        for spec in ["", "* * * *", "60 * * * *", "* 24 * * *", "5-1 * * * *", "*/0 * * * *", "a * * * *"].iter() {
+            assert!(Cron::parse(spec).is_err(), "{:?} should be rejected", spec);
+        }
+    }
+
+    #[test]
+    fn finds_the_next_minute() {
+        let cron = Cron::parse("* * * * *").unwrap();
+        assert_eq!(cron.next_after(at(2015, 6, 1, 12, 0) + 30), Some(at(2015, 6, 1, 12, 1)));
+        assert_eq!(cron.next_after(at(2015, 6, 1, 12, 0)), Some(at(2015, 6, 1, 12, 1)));
+    }
+
+    #[test]
+    fn handles_steps_lists_and_ranges() {
+        let cron = Cron::parse("*/15 9-10,17 * * *").unwrap();
+        assert_eq!(cron.next_after(at(2015, 6, 1, 9, 50)), Some(at(2015, 6, 1, 10, 0)));
+        assert_eq!(cron.next_after(at(2015, 6, 1, 10, 45)), Some(at(2015, 6, 1, 17, 0)));
+        assert_eq!(cron.next_after(at(2015, 6, 1, 17, 45)), Some(at(2015, 6, 2, 9, 0)));
+    }
+
+    #[test]
+    fn matches_weekdays_and_days_of_month() {
+        // 2015-06-01 was a Monday.
+        let weekdays = Cron::parse("0 9 * * 1-5").unwrap();
+        assert_eq!(weekdays.next_after(at(2015, 6, 5, 9, 0)), Some(at(2015, 6, 8, 9, 0)));
+        let sundays = Cron::parse("0 0 * * 7").unwrap();
+        assert_eq!(sundays.next_after(at(2015, 6, 1, 0, 0)), Some(at(2015, 6, 7, 0, 0)));
+        // Either the 10th or a Sunday.
+        let either = Cron::parse("0 0 10 * 0").unwrap();
+        assert_eq!(either.next_after(at(2015, 6, 1, 0, 0)), Some(at(2015, 6, 7, 0, 0)));
+        assert_eq!(either.next_after(at(2015, 6, 7, 0, 0)), Some(at(2015, 6, 10, 0, 0)));
+    }
+
+    #[test]
+    fn gives_up_on_impossible_dates() {
+        let cron = Cron::parse("0 0 31 2 *").unwrap();
+        assert_eq!(cron.next_after(at(2015, 1, 1, 0, 0)), None);
+    }
+}
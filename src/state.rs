@@ -0,0 +1,142 @@
+//! What we know about our connection to the server, kept up to date
+//! by a handler watching the lines the server sends.
+
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex};
+use super::event_stream::{Handler, Response};
+use super::isupport::ISupport;
+use super::modes;
+use super::protocol::Message;
+
+/// Our view of the server and of ourselves on it.
+#[derive(Clone, Debug)]
+pub struct ServerState {
+    /// Our current nick.
+    pub nick: String,
+    /// What the server told us about itself.
+    pub isupport: ISupport,
+    /// Our user modes, as confirmed by the server.
+    pub user_modes: BTreeSet<char>,
+    /// The IRCv3 capabilities the server has enabled for us.
+    pub capabilities: BTreeSet<String>,
+    /// The channels we're in, as the server last confirmed.
+    pub channels: BTreeSet<String>,
+}
+
+/// `ServerState` shared between the event loop and the `Client`.
+pub type SharedState = Arc<Mutex<ServerState>>;
+
+impl ServerState {
+
+    pub fn new(nick: &str) -> ServerState {
+        ServerState{
+            nick: nick.to_string(),
+            isupport: ISupport::new(),
+            user_modes: BTreeSet::new(),
+            capabilities: BTreeSet::new(),
+            channels: BTreeSet::new(),
+        }
+    }
+
+    /// Whether `nick` is us.
+    pub fn is_me(&self, nick: &str) -> bool {
+        self.isupport.casemapping().same_name(nick, &self.nick)
+    }
+
+    /// Whether we're in `chan`.
+    pub fn in_channel(&self, chan: &str) -> bool {
+        let casemapping = self.isupport.casemapping();
+        self.channels.iter().any(|c| casemapping.same_name(c, chan))
+    }
+
+    fn leave_channel(&mut self, chan: &str) {
+        let casemapping = self.isupport.casemapping();
+        self.channels = self.channels.iter().filter(|c| !casemapping.same_name(c, chan)).cloned().collect();
+    }
+
+    /// Updates our view from a line sent by the server.
+    pub fn observe(&mut self, msg: &Message) {
+        match msg.command {
+            // RPL_WELCOME tells us the nick we actually got.
+            "001" => {
+                if let Some(nick) = msg.params.get(0) {
+                    self.nick = nick.to_string();
+                }
+            },
+            "005" => {
+                self.isupport.update(msg);
+            },
+            // RPL_UMODEIS is the complete set of our modes.
+            "221" => {
+                if let Some(modestring) = msg.params.get(1) {
+                    self.user_modes.clear();
+                    modes::apply_user_modes(&mut self.user_modes, &modes::parse_user_modes(modestring));
+                }
+            },
+            "MODE" if msg.params.len() >= 2 && self.is_me(msg.params[0]) => {
+                modes::apply_user_modes(&mut self.user_modes, &modes::parse_user_modes(msg.params[1]));
+                debug!("User modes are now {:?}.", self.user_modes);
+            },
+            "CAP" if msg.params.len() >= 3 => {
+                let caps = msg.params[msg.params.len() - 1].split(' ').filter(|c| !c.is_empty());
+                match msg.params[1] {
+                    "ACK" => {
+                        for cap in caps {
+                            if cap.starts_with('-') {
+                                self.capabilities.remove(&cap[1..]);
+                            } else {
+                                self.capabilities.insert(cap.to_string());
+                            }
+                        }
+                    },
+                    "DEL" => {
+                        for cap in caps {
+                            self.capabilities.remove(cap);
+                        }
+                    },
+                    _ => (),
+                }
+            },
+            "JOIN" if msg.params.len() >= 1 && msg.source_nick().map_or(false, |n| self.is_me(n)) => {
+                if !self.in_channel(msg.params[0]) {
+                    self.channels.insert(msg.params[0].to_string());
+                }
+            },
+            "PART" if msg.params.len() >= 1 && msg.source_nick().map_or(false, |n| self.is_me(n)) => {
+                for chan in msg.params[0].split(',') {
+                    self.leave_channel(chan);
+                }
+            },
+            "KICK" if msg.params.len() >= 2 && self.is_me(msg.params[1]) => {
+                self.leave_channel(msg.params[0]);
+            },
+            "NICK" if msg.params.len() >= 1 && msg.source_nick().map_or(false, |n| self.is_me(n)) => {
+                self.nick = msg.params[0].to_string();
+            },
+            _ => (),
+        }
+    }
+
+}
+
+/// A copy of `state`, even if a thread panicked while holding it.
+pub fn snapshot(state: &SharedState) -> ServerState {
+    match state.lock() {
+        Ok(state) => state.clone(),
+        Err(poisoned) => poisoned.into_inner().clone(),
+    }
+}
+
+/// A handler that keeps `state` up to date.  It never responds or
+/// skips other handlers.
+pub fn tracker(state: SharedState) -> Handler {
+    box move |line: &str| {
+        if let Some(msg) = Message::parse(line) {
+            match state.lock() {
+                Ok(mut state) => state.observe(&msg),
+                Err(poisoned) => poisoned.into_inner().observe(&msg),
+            }
+        }
+        Response::nothing()
+    }
+}
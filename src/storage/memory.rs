@@ -0,0 +1,212 @@
+//! Storage that lives and dies with the process.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Mutex, MutexGuard};
+use super::{Factoid, KarmaChange, KarmaScore, Memo, Reminder, Sighting, Storage};
+use super::super::error::Result;
+
+struct Data {
+    factoids: BTreeMap<String, Vec<Factoid>>,
+    locked: BTreeSet<String>,
+    /// By scope and subject.
+    karma: BTreeMap<(String, String), KarmaScore>,
+    karma_changes: Vec<KarmaChange>,
+    /// By key and channel.
+    seen: BTreeMap<(String, String), Sighting>,
+    /// By id, so oldest first.
+    memos: BTreeMap<i64, Memo>,
+    next_memo: i64,
+    reminders: BTreeMap<i64, Reminder>,
+    next_reminder: i64,
+    /// By namespace and key.
+    values: BTreeMap<(String, String), String>,
+}
+
+pub struct MemoryStorage {
+    data: Mutex<Data>,
+}
+
+impl MemoryStorage {
+
+    pub fn new() -> MemoryStorage {
+        MemoryStorage{
+            data: Mutex::new(Data{
+                factoids: BTreeMap::new(),
+                locked: BTreeSet::new(),
+                karma: BTreeMap::new(),
+                karma_changes: Vec::new(),
+                seen: BTreeMap::new(),
+                memos: BTreeMap::new(),
+                next_memo: 1,
+                reminders: BTreeMap::new(),
+                next_reminder: 1,
+                values: BTreeMap::new(),
+            }),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<Data> {
+        match self.data.lock() {
+            Ok(data) => data,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+}
+
+impl Storage for MemoryStorage {
+
+    fn factoids(&self, key: &str) -> Result<Vec<Factoid>> {
+        Ok(self.lock().factoids.get(key).cloned().unwrap_or(Vec::new()))
+    }
+
+    fn add_factoid(&self, key: &str, factoid: &Factoid) -> Result<bool> {
+        let mut data = self.lock();
+        let factoids = data.factoids.entry(key.to_string()).or_insert(Vec::new());
+        if factoids.iter().any(|f| f.value == factoid.value) {
+            return Ok(false);
+        }
+        factoids.push(factoid.clone());
+        Ok(true)
+    }
+
+    fn replace_factoid(&self, key: &str, factoid: &Factoid) -> Result<()> {
+        self.lock().factoids.insert(key.to_string(), vec![factoid.clone()]);
+        Ok(())
+    }
+
+    fn forget_factoid(&self, key: &str) -> Result<usize> {
+        Ok(self.lock().factoids.remove(key).map(|f| f.len()).unwrap_or(0))
+    }
+
+    fn factoid_locked(&self, key: &str) -> Result<bool> {
+        Ok(self.lock().locked.contains(key))
+    }
+
+    fn lock_factoid(&self, key: &str, locked: bool, _by: &str) -> Result<()> {
+        let mut data = self.lock();
+        if locked {
+            data.locked.insert(key.to_string());
+        } else {
+            data.locked.remove(key);
+        }
+        Ok(())
+    }
+
+    fn add_karma(&self, change: &KarmaChange) -> Result<i32> {
+        let mut data = self.lock();
+        data.karma_changes.push(change.clone());
+        let score = data.karma.entry((change.scope.clone(), change.subject.clone())).or_insert(KarmaScore{
+            name: change.name.clone(),
+            score: 0,
+        });
+        score.score += change.change;
+        Ok(score.score)
+    }
+
+    fn karma(&self, scope: &str, subject: &str) -> Result<i32> {
+        Ok(self.lock().karma.get(&(scope.to_string(), subject.to_string())).map(|k| k.score).unwrap_or(0))
+    }
+
+    fn karma_reasons(&self, scope: &str, subject: &str, limit: usize) -> Result<Vec<String>> {
+        Ok(self.lock().karma_changes.iter().rev()
+           .filter(|c| c.scope == scope && c.subject == subject && !c.reason.is_empty())
+           .take(limit)
+           .map(|c| c.reason.clone())
+           .collect())
+    }
+
+    fn karma_ranking(&self, scope: &str, ascending: bool, limit: usize) -> Result<Vec<KarmaScore>> {
+        let data = self.lock();
+        let mut scores: Vec<KarmaScore> = data.karma.iter()
+            .filter(|&(&(ref s, _), _)| s == scope)
+            .map(|(_, k)| k.clone())
+            .collect();
+        scores.sort_by(|a, b| if ascending { a.score.cmp(&b.score) } else { b.score.cmp(&a.score) });
+        scores.truncate(limit);
+        Ok(scores)
+    }
+
+    fn record_sighting(&self, key: &str, sighting: &Sighting) -> Result<()> {
+        self.lock().seen.insert((key.to_string(), sighting.chan.clone()), sighting.clone());
+        Ok(())
+    }
+
+    fn sightings(&self, key: &str) -> Result<Vec<Sighting>> {
+        let data = self.lock();
+        let mut sightings: Vec<Sighting> = data.seen.iter()
+            .filter(|&(&(ref k, _), _)| k == key)
+            .map(|(_, s)| s.clone())
+            .collect();
+        sightings.sort_by(|a, b| b.seen_at.cmp(&a.seen_at));
+        Ok(sightings)
+    }
+
+    fn add_memo(&self, memo: &Memo) -> Result<()> {
+        let mut data = self.lock();
+        let id = data.next_memo;
+        data.next_memo += 1;
+        data.memos.insert(id, Memo{ id: id, ..memo.clone() });
+        Ok(())
+    }
+
+    fn memos_from(&self, sender_key: &str) -> Result<usize> {
+        Ok(self.lock().memos.values().filter(|m| m.sender_key == sender_key).count())
+    }
+
+    fn memos_for(&self, recipients: &[String]) -> Result<Vec<Memo>> {
+        Ok(self.lock().memos.values().filter(|m| recipients.contains(&m.recipient)).cloned().collect())
+    }
+
+    fn delete_memo(&self, id: i64) -> Result<bool> {
+        Ok(self.lock().memos.remove(&id).is_some())
+    }
+
+    fn expire_memos(&self, sent_at: i64) -> Result<usize> {
+        let mut data = self.lock();
+        let expired: Vec<i64> = data.memos.values().filter(|m| m.sent_at < sent_at).map(|m| m.id).collect();
+        for id in expired.iter() {
+            data.memos.remove(id);
+        }
+        Ok(expired.len())
+    }
+
+    fn add_reminder(&self, reminder: &Reminder) -> Result<i64> {
+        let mut data = self.lock();
+        let id = data.next_reminder;
+        data.next_reminder += 1;
+        data.reminders.insert(id, Reminder{ id: id, ..reminder.clone() });
+        Ok(id)
+    }
+
+    fn reminders(&self) -> Result<Vec<Reminder>> {
+        let mut reminders: Vec<Reminder> = self.lock().reminders.values().cloned().collect();
+        reminders.sort_by(|a, b| a.due_at.cmp(&b.due_at));
+        Ok(reminders)
+    }
+
+    fn delete_reminder(&self, id: i64) -> Result<bool> {
+        Ok(self.lock().reminders.remove(&id).is_some())
+    }
+
+    fn value(&self, namespace: &str, key: &str) -> Result<Option<String>> {
+        Ok(self.lock().values.get(&(namespace.to_string(), key.to_string())).cloned())
+    }
+
+    fn set_value(&self, namespace: &str, key: &str, value: &str) -> Result<()> {
+        self.lock().values.insert((namespace.to_string(), key.to_string()), value.to_string());
+        Ok(())
+    }
+
+    fn delete_value(&self, namespace: &str, key: &str) -> Result<bool> {
+        Ok(self.lock().values.remove(&(namespace.to_string(), key.to_string())).is_some())
+    }
+
+    fn values(&self, namespace: &str, prefix: &str) -> Result<Vec<(String, String)>> {
+        Ok(self.lock().values.iter()
+           .filter(|&(&(ref n, ref k), _)| n == namespace && k.starts_with(prefix))
+           .map(|(&(_, ref k), v)| (k.clone(), v.clone()))
+           .collect())
+    }
+
+}
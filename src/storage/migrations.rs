@@ -0,0 +1,223 @@
+//! The schema, as a list of changes applied in order.  Each database
+//! records which have been applied in `schema_migrations`.
+//!
+//! Never edit a migration once it's been released; add another.
+
+/// One change to the schema, written for each SQL backend.
+pub struct Migration {
+    pub version: u32,
+    pub description: &'static str,
+    pub postgres: &'static str,
+    pub sqlite: &'static str,
+}
+
+/// Creates the table that records applied migrations.  The same SQL
+/// works for every backend.
+pub const CREATE_SCHEMA_MIGRATIONS: &'static str =
+    "CREATE TABLE IF NOT EXISTS schema_migrations (
+       version INTEGER PRIMARY KEY,
+       description VARCHAR NOT NULL
+     )";
+
+// The first two adopt the tables older versions created on startup.
+pub const MIGRATIONS: &'static [Migration] = &[
+    Migration{
+        version: 1,
+        description: "create knowledge",
+        postgres: "CREATE TABLE IF NOT EXISTS knowledge (
+                     key VARCHAR,
+                     val VARCHAR,
+                     PRIMARY KEY(key, val)
+                   )",
+        sqlite: "CREATE TABLE IF NOT EXISTS knowledge (
+                   key TEXT,
+                   val TEXT,
+                   PRIMARY KEY(key, val)
+                 )",
+    },
+    Migration{
+        version: 2,
+        description: "create karma",
+        postgres: "CREATE TABLE IF NOT EXISTS karma (
+                     nick VARCHAR PRIMARY KEY,
+                     karma INTEGER NOT NULL DEFAULT 0
+                   )",
+        sqlite: "CREATE TABLE IF NOT EXISTS karma (
+                   nick TEXT PRIMARY KEY,
+                   karma INTEGER NOT NULL DEFAULT 0
+                 )",
+    },
+    Migration{
+        version: 3,
+        description: "replace knowledge with factoids",
+        postgres: "CREATE TABLE factoids (
+                     key VARCHAR NOT NULL,
+                     name VARCHAR NOT NULL,
+                     val VARCHAR NOT NULL,
+                     set_by VARCHAR NOT NULL DEFAULT '',
+                     set_at BIGINT NOT NULL DEFAULT 0,
+                     PRIMARY KEY(key, val)
+                   );
+                   INSERT INTO factoids (key, name, val)
+                     SELECT DISTINCT ON (lower(key), val) lower(key), key, val FROM knowledge;
+                   CREATE TABLE factoid_locks (
+                     key VARCHAR PRIMARY KEY,
+                     locked_by VARCHAR NOT NULL,
+                     locked_at BIGINT NOT NULL
+                   );
+                   DROP TABLE knowledge;",
+        sqlite: "CREATE TABLE factoids (
+                   key TEXT NOT NULL,
+                   name TEXT NOT NULL,
+                   val TEXT NOT NULL,
+                   set_by TEXT NOT NULL DEFAULT '',
+                   set_at INTEGER NOT NULL DEFAULT 0,
+                   PRIMARY KEY(key, val)
+                 );
+                 INSERT OR IGNORE INTO factoids (key, name, val)
+                   SELECT lower(key), key, val FROM knowledge;
+                 CREATE TABLE factoid_locks (
+                   key TEXT PRIMARY KEY,
+                   locked_by TEXT NOT NULL,
+                   locked_at INTEGER NOT NULL
+                 );
+                 DROP TABLE knowledge;",
+    },
+    Migration{
+        version: 4,
+        description: "scope karma and keep reasons",
+        postgres: "CREATE TABLE karma_scores (
+                     scope VARCHAR NOT NULL,
+                     subject VARCHAR NOT NULL,
+                     name VARCHAR NOT NULL,
+                     score INTEGER NOT NULL DEFAULT 0,
+                     PRIMARY KEY(scope, subject)
+                   );
+                   INSERT INTO karma_scores (scope, subject, name, score)
+                     SELECT '', lower(nick), min(nick), sum(karma)::integer FROM karma GROUP BY lower(nick);
+                   CREATE TABLE karma_reasons (
+                     scope VARCHAR NOT NULL,
+                     subject VARCHAR NOT NULL,
+                     change INTEGER NOT NULL,
+                     reason VARCHAR NOT NULL,
+                     set_by VARCHAR NOT NULL,
+                     set_at BIGINT NOT NULL
+                   );
+                   CREATE INDEX karma_reasons_subject ON karma_reasons (scope, subject, set_at);
+                   DROP TABLE karma;",
+        sqlite: "CREATE TABLE karma_scores (
+                   scope TEXT NOT NULL,
+                   subject TEXT NOT NULL,
+                   name TEXT NOT NULL,
+                   score INTEGER NOT NULL DEFAULT 0,
+                   PRIMARY KEY(scope, subject)
+                 );
+                 INSERT INTO karma_scores (scope, subject, name, score)
+                   SELECT '', lower(nick), min(nick), sum(karma) FROM karma GROUP BY lower(nick);
+                 CREATE TABLE karma_reasons (
+                   scope TEXT NOT NULL,
+                   subject TEXT NOT NULL,
+                   change INTEGER NOT NULL,
+                   reason TEXT NOT NULL,
+                   set_by TEXT NOT NULL,
+                   set_at INTEGER NOT NULL
+                 );
+                 CREATE INDEX karma_reasons_subject ON karma_reasons (scope, subject, set_at);
+                 DROP TABLE karma;",
+    },
+    Migration{
+        version: 5,
+        description: "create seen",
+        postgres: "CREATE TABLE seen (
+                     key VARCHAR NOT NULL,
+                     chan VARCHAR NOT NULL,
+                     nick VARCHAR NOT NULL,
+                     activity VARCHAR NOT NULL,
+                     text VARCHAR NOT NULL,
+                     seen_at BIGINT NOT NULL,
+                     PRIMARY KEY(key, chan)
+                   )",
+        sqlite: "CREATE TABLE seen (
+                   key TEXT NOT NULL,
+                   chan TEXT NOT NULL,
+                   nick TEXT NOT NULL,
+                   activity TEXT NOT NULL,
+                   text TEXT NOT NULL,
+                   seen_at INTEGER NOT NULL,
+                   PRIMARY KEY(key, chan)
+                 )",
+    },
+    Migration{
+        version: 6,
+        description: "create memos",
+        postgres: "CREATE TABLE memos (
+                     id BIGSERIAL PRIMARY KEY,
+                     recipient VARCHAR NOT NULL,
+                     sender VARCHAR NOT NULL,
+                     sender_key VARCHAR NOT NULL,
+                     chan VARCHAR NOT NULL,
+                     text VARCHAR NOT NULL,
+                     private BOOLEAN NOT NULL,
+                     sent_at BIGINT NOT NULL
+                   );
+                   CREATE INDEX memos_recipient ON memos (recipient);
+                   CREATE INDEX memos_sender_key ON memos (sender_key);",
+        sqlite: "CREATE TABLE memos (
+                   id INTEGER PRIMARY KEY AUTOINCREMENT,
+                   recipient TEXT NOT NULL,
+                   sender TEXT NOT NULL,
+                   sender_key TEXT NOT NULL,
+                   chan TEXT NOT NULL,
+                   text TEXT NOT NULL,
+                   private INTEGER NOT NULL,
+                   sent_at INTEGER NOT NULL
+                 );
+                 CREATE INDEX memos_recipient ON memos (recipient);
+                 CREATE INDEX memos_sender_key ON memos (sender_key);",
+    },
+    Migration{
+        version: 7,
+        description: "create reminders",
+        postgres: "CREATE TABLE reminders (
+                     id BIGSERIAL PRIMARY KEY,
+                     owner VARCHAR NOT NULL,
+                     owner_key VARCHAR NOT NULL,
+                     target VARCHAR NOT NULL,
+                     nick VARCHAR NOT NULL,
+                     text VARCHAR NOT NULL,
+                     due_at BIGINT NOT NULL,
+                     set_at BIGINT NOT NULL
+                   )",
+        sqlite: "CREATE TABLE reminders (
+                   id INTEGER PRIMARY KEY AUTOINCREMENT,
+                   owner TEXT NOT NULL,
+                   owner_key TEXT NOT NULL,
+                   target TEXT NOT NULL,
+                   nick TEXT NOT NULL,
+                   text TEXT NOT NULL,
+                   due_at INTEGER NOT NULL,
+                   set_at INTEGER NOT NULL
+                 )",
+    },
+    Migration{
+        version: 8,
+        description: "create plugin_values",
+        postgres: "CREATE TABLE plugin_values (
+                     namespace VARCHAR NOT NULL,
+                     key VARCHAR NOT NULL,
+                     value VARCHAR NOT NULL,
+                     PRIMARY KEY(namespace, key)
+                   )",
+        sqlite: "CREATE TABLE plugin_values (
+                   namespace TEXT NOT NULL,
+                   key TEXT NOT NULL,
+                   value TEXT NOT NULL,
+                   PRIMARY KEY(namespace, key)
+                 )",
+    },
+];
+
+/// The migrations newer than `version`.
+pub fn pending(version: u32) -> Vec<&'static Migration> {
+    MIGRATIONS.iter().filter(|m| m.version > version).collect()
+}
@@ -0,0 +1,256 @@
+//! Where the bot keeps what it learns.
+//!
+//! `open` picks a backend from a URL:
+//!
+//! * `postgres://user@host/db` for PostgreSQL,
+//! * `sqlite:path/to/file.db` for SQLite,
+//! * `memory:` for a store that's forgotten on exit, handy for trying
+//!   things out.
+//!
+//! The SQL backends keep a pool of connections and bring the schema up
+//! to date with `migrations` when opened.
+
+use std::cmp;
+use std::sync::Arc;
+use super::error::{Error, Result};
+
+pub mod memory;
+pub mod migrations;
+pub mod pool;
+pub mod postgresql;
+pub mod sqlite;
+
+/// Connections opened by the SQL backends, unless told otherwise.
+pub const DEFAULT_POOL_SIZE: usize = 4;
+
+/// One of the things a key is, and who said so.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Factoid {
+    /// The key as it was first written.
+    pub name: String,
+    pub value: String,
+    pub set_by: String,
+    /// Seconds since the epoch.
+    pub set_at: i64,
+}
+
+/// Someone's `++` or `--`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KarmaChange {
+    /// Where scores are kept apart, like a channel.  Empty for the
+    /// global scores.
+    pub scope: String,
+    /// What's being scored, normalized.
+    pub subject: String,
+    /// The subject as it was written.
+    pub name: String,
+    pub change: i32,
+    /// Empty if none was given.
+    pub reason: String,
+    pub set_by: String,
+    /// Seconds since the epoch.
+    pub set_at: i64,
+}
+
+/// A subject and its score.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KarmaScore {
+    pub name: String,
+    pub score: i32,
+}
+
+/// What someone was last seen doing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Activity {
+    Message,
+    Join,
+    Part,
+    Quit,
+    /// Changed their nick to the sighting's text.
+    Nick,
+}
+
+impl Activity {
+
+    /// How it's stored.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Activity::Message => "message",
+            Activity::Join => "join",
+            Activity::Part => "part",
+            Activity::Quit => "quit",
+            Activity::Nick => "nick",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Activity> {
+        match s {
+            "message" => Some(Activity::Message),
+            "join" => Some(Activity::Join),
+            "part" => Some(Activity::Part),
+            "quit" => Some(Activity::Quit),
+            "nick" => Some(Activity::Nick),
+            _ => None,
+        }
+    }
+
+}
+
+/// The last time someone did something in a channel.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Sighting {
+    /// The nick as it was written.
+    pub nick: String,
+    /// Empty for quits and nick changes, which aren't in any one
+    /// channel.
+    pub chan: String,
+    pub activity: Activity,
+    /// What they said, their part or quit reason, or their new nick.
+    pub text: String,
+    /// Seconds since the epoch.
+    pub seen_at: i64,
+}
+
+/// A message left for someone who isn't around.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Memo {
+    /// Assigned when it's stored.
+    pub id: i64,
+    /// Who it's for, normalized: a nick, or an account name.
+    pub recipient: String,
+    /// Who left it, as written.
+    pub sender: String,
+    /// Who left it, normalized, for counting what they've left.
+    pub sender_key: String,
+    /// Where it was left, or empty if it was left in private.
+    pub chan: String,
+    pub text: String,
+    /// Whether to deliver it in private.
+    pub private: bool,
+    /// Seconds since the epoch.
+    pub sent_at: i64,
+}
+
+/// Something to say at a certain time.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Reminder {
+    /// Assigned when it's stored.
+    pub id: i64,
+    /// Who asked for it, as written.
+    pub owner: String,
+    /// Who asked for it, normalized.
+    pub owner_key: String,
+    /// The channel or nick to say it to.
+    pub target: String,
+    /// Who to address it to in `target`, or empty for nobody.
+    pub nick: String,
+    pub text: String,
+    /// When to say it, in seconds since the epoch.
+    pub due_at: i64,
+    pub set_at: i64,
+}
+
+/// What plugins can store.  Implementations are shared between
+/// threads, and each call stands alone.
+pub trait Storage: Send + Sync {
+
+    /// Everything `key` is, oldest first.  Keys are compared exactly,
+    /// so callers should normalize them.
+    fn factoids(&self, key: &str) -> Result<Vec<Factoid>>;
+
+    /// Adds another value for `key`.  Returns false if it already had
+    /// that value.
+    fn add_factoid(&self, key: &str, factoid: &Factoid) -> Result<bool>;
+
+    /// Replaces all of `key`'s values with one.
+    fn replace_factoid(&self, key: &str, factoid: &Factoid) -> Result<()>;
+
+    /// Forgets everything `key` is, returning how many values it had.
+    fn forget_factoid(&self, key: &str) -> Result<usize>;
+
+    /// Whether `key` is locked against changes.
+    fn factoid_locked(&self, key: &str) -> Result<bool>;
+
+    /// Locks or unlocks `key`.
+    fn lock_factoid(&self, key: &str, locked: bool, by: &str) -> Result<()>;
+
+    /// Records a change to a subject's karma, returning its new score.
+    fn add_karma(&self, change: &KarmaChange) -> Result<i32>;
+
+    /// A subject's score in `scope`, which starts at zero.
+    fn karma(&self, scope: &str, subject: &str) -> Result<i32>;
+
+    /// The most recent reasons given for changing a subject's karma,
+    /// newest first.
+    fn karma_reasons(&self, scope: &str, subject: &str, limit: usize) -> Result<Vec<String>>;
+
+    /// The highest scores in `scope`, or the lowest if `ascending`.
+    fn karma_ranking(&self, scope: &str, ascending: bool, limit: usize) -> Result<Vec<KarmaScore>>;
+
+    /// Records what `key` was seen doing, replacing what they were last
+    /// seen doing in the same channel.  Keys are compared exactly, so
+    /// callers should normalize them.
+    fn record_sighting(&self, key: &str, sighting: &Sighting) -> Result<()>;
+
+    /// What `key` was last seen doing in each channel, newest first.
+    fn sightings(&self, key: &str) -> Result<Vec<Sighting>>;
+
+    /// Stores a memo, ignoring its `id`.
+    fn add_memo(&self, memo: &Memo) -> Result<()>;
+
+    /// How many memos someone has left that haven't been delivered.
+    fn memos_from(&self, sender_key: &str) -> Result<usize>;
+
+    /// The memos waiting for any of `recipients`, oldest first.
+    fn memos_for(&self, recipients: &[String]) -> Result<Vec<Memo>>;
+
+    /// Forgets a delivered memo.  Returns false if it was already gone.
+    fn delete_memo(&self, id: i64) -> Result<bool>;
+
+    /// Forgets memos sent before `sent_at`, returning how many there
+    /// were.
+    fn expire_memos(&self, sent_at: i64) -> Result<usize>;
+
+    /// Stores a reminder, ignoring its `id`, and returns the id it's
+    /// given.
+    fn add_reminder(&self, reminder: &Reminder) -> Result<i64>;
+
+    /// Every reminder that hasn't been deleted, soonest first.
+    fn reminders(&self) -> Result<Vec<Reminder>>;
+
+    /// Forgets a reminder.  Returns false if it was already gone.
+    fn delete_reminder(&self, id: i64) -> Result<bool>;
+
+    /// The value of `key` in `namespace`, if it has one.
+    ///
+    /// These are for plugins with no storage of their own, which
+    /// should use their name as the namespace.  Keys are compared
+    /// exactly.
+    fn value(&self, namespace: &str, key: &str) -> Result<Option<String>>;
+
+    /// Sets `key` in `namespace`, replacing any value it had.
+    fn set_value(&self, namespace: &str, key: &str, value: &str) -> Result<()>;
+
+    /// Forgets `key` in `namespace`.  Returns false if it had no value.
+    fn delete_value(&self, namespace: &str, key: &str) -> Result<bool>;
+
+    /// The keys in `namespace` starting with `prefix`, and their
+    /// values, in order of key.
+    fn values(&self, namespace: &str, prefix: &str) -> Result<Vec<(String, String)>>;
+
+}
+
+/// Opens the storage at `url`, with `pool_size` connections, or one
+/// if it's zero.
+pub fn open(url: &str, pool_size: usize) -> Result<Arc<Storage>> {
+    let pool_size = cmp::max(pool_size, 1);
+    if url.starts_with("postgres://") || url.starts_with("postgresql://") {
+        Ok(Arc::new(try!(postgresql::PostgresStorage::open(url, pool_size))))
+    } else if url.starts_with("sqlite:") {
+        Ok(Arc::new(try!(sqlite::SqliteStorage::open(&url["sqlite:".len()..], pool_size))))
+    } else if url == "memory:" {
+        Ok(Arc::new(memory::MemoryStorage::new()))
+    } else {
+        Err(Error::Storage(format!("unsupported storage URL {}", url)))
+    }
+}
@@ -0,0 +1,123 @@
+//! A fixed set of database connections shared between threads.
+
+use std::ops::{Deref, DerefMut};
+use std::sync::{Condvar, Mutex, MutexGuard};
+use super::super::error::Result;
+
+/// Connections opened up front, handed out one thread at a time.
+/// Connections that have died, say because the server restarted, are
+/// replaced as they're handed out.
+pub struct ConnectionPool<C> {
+    idle: Mutex<Vec<C>>,
+    returned: Condvar,
+    connect: Box<Fn() -> Result<C> + Send + Sync>,
+    alive: Box<Fn(&C) -> bool + Send + Sync>,
+}
+
+impl<C: Send> ConnectionPool<C> {
+
+    /// Opens `size` connections with `connect`.  Each is checked with
+    /// `alive` before it's handed out.
+    pub fn new<F, A>(size: usize, connect: F, alive: A) -> Result<ConnectionPool<C>>
+        where F: Fn() -> Result<C> + Send + Sync + 'static, A: Fn(&C) -> bool + Send + Sync + 'static {
+        let mut idle = Vec::with_capacity(size);
+        for _ in 0..size {
+            idle.push(try!(connect()));
+        }
+        Ok(ConnectionPool{
+            idle: Mutex::new(idle),
+            returned: Condvar::new(),
+            connect: box connect,
+            alive: box alive,
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<Vec<C>> {
+        // Nothing panics while holding the lock.
+        match self.idle.lock() {
+            Ok(idle) => idle,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    /// Waits for a connection, reconnecting it if it's died.  It goes
+    /// back in the pool when the returned guard is dropped.
+    ///
+    /// Fails if the connection had died and reconnecting failed.  The
+    /// dead connection goes back in the pool, to be tried again next
+    /// time.
+    pub fn get(&self) -> Result<PooledConnection<C>> {
+        let conn = self.take();
+        if (self.alive)(&conn) {
+            return Ok(PooledConnection{
+                pool: self,
+                conn: Some(conn),
+            });
+        }
+        warn!("Database connection died, reconnecting.");
+        match (self.connect)() {
+            Ok(fresh) => Ok(PooledConnection{
+                pool: self,
+                conn: Some(fresh),
+            }),
+            Err(e) => {
+                self.put(conn);
+                Err(e)
+            },
+        }
+    }
+
+    /// Waits for an idle connection.
+    fn take(&self) -> C {
+        let mut idle = self.lock();
+        loop {
+            if let Some(conn) = idle.pop() {
+                return conn;
+            }
+            idle = match self.returned.wait(idle) {
+                Ok(idle) => idle,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+        }
+    }
+
+    fn put(&self, conn: C) {
+        self.lock().push(conn);
+        self.returned.notify_one();
+    }
+
+}
+
+/// A connection borrowed from a `ConnectionPool`.
+pub struct PooledConnection<'a, C: 'a + Send> {
+    pool: &'a ConnectionPool<C>,
+    conn: Option<C>,
+}
+
+impl<'a, C: Send> Deref for PooledConnection<'a, C> {
+
+    type Target = C;
+
+    fn deref(&self) -> &C {
+        self.conn.as_ref().expect("Connection already returned")
+    }
+
+}
+
+impl<'a, C: Send> DerefMut for PooledConnection<'a, C> {
+
+    fn deref_mut(&mut self) -> &mut C {
+        self.conn.as_mut().expect("Connection already returned")
+    }
+
+}
+
+impl<'a, C: Send> Drop for PooledConnection<'a, C> {
+
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            self.pool.put(conn);
+        }
+    }
+
+}
@@ -0,0 +1,295 @@
+//! Storage in PostgreSQL.
+
+use postgres::{Connection, SslMode};
+use time;
+use super::{migrations, Activity, Factoid, KarmaChange, KarmaScore, Memo, Reminder, Sighting, Storage};
+use super::pool::ConnectionPool;
+use super::super::error::{Error, Result};
+
+pub struct PostgresStorage {
+    pool: ConnectionPool<Connection>,
+}
+
+/// Applies any migrations `conn` hasn't seen.
+fn migrate(conn: &Connection) -> Result<()> {
+    try!(conn.batch_execute(migrations::CREATE_SCHEMA_MIGRATIONS));
+    let version: i32 = {
+        let stmt = try!(conn.prepare("SELECT coalesce(max(version), 0) FROM schema_migrations"));
+        let rows = try!(stmt.query(&[]));
+        rows.iter().next().map(|r| r.get(0)).unwrap_or(0)
+    };
+    for migration in migrations::pending(version as u32).into_iter() {
+        info!("Migrating to version {}: {}", migration.version, migration.description);
+        let trans = try!(conn.transaction());
+        try!(trans.batch_execute(migration.postgres));
+        try!(trans.execute("INSERT INTO schema_migrations (version, description) VALUES ($1, $2)",
+                           &[&(migration.version as i32), &migration.description]));
+        try!(trans.commit());
+    }
+    Ok(())
+}
+
+impl PostgresStorage {
+
+    /// Connects to `url` and brings the schema up to date.
+    pub fn open(url: &str, pool_size: usize) -> Result<PostgresStorage> {
+        let url = url.to_string();
+        let pool = try!(ConnectionPool::new(pool_size, move || {
+            Ok(try!(Connection::connect(&url[..], &SslMode::None)))
+        }, |conn| conn.batch_execute("SELECT 1").is_ok()));
+        try!(migrate(&try!(pool.get())));
+        Ok(PostgresStorage{
+            pool: pool,
+        })
+    }
+
+}
+
+impl Storage for PostgresStorage {
+
+    fn factoids(&self, key: &str) -> Result<Vec<Factoid>> {
+        let conn = try!(self.pool.get());
+        let stmt = try!(conn.prepare(sql!("SELECT name, val, set_by, set_at FROM factoids WHERE key = $1 ORDER BY set_at")));
+        let rows = try!(stmt.query(&[&key]));
+        Ok(rows.iter().map(|r| Factoid{
+            name: r.get(0),
+            value: r.get(1),
+            set_by: r.get(2),
+            set_at: r.get(3),
+        }).collect())
+    }
+
+    fn add_factoid(&self, key: &str, factoid: &Factoid) -> Result<bool> {
+        let conn = try!(self.pool.get());
+        let added = try!(conn.execute(sql!("INSERT INTO factoids (key, name, val, set_by, set_at)
+                                            SELECT $1, $2, $3, $4, $5 WHERE NOT EXISTS
+                                              (SELECT 1 FROM factoids WHERE key = $1 AND val = $3)"),
+                                      &[&key, &factoid.name, &factoid.value, &factoid.set_by, &factoid.set_at]));
+        Ok(added > 0)
+    }
+
+    fn replace_factoid(&self, key: &str, factoid: &Factoid) -> Result<()> {
+        let conn = try!(self.pool.get());
+        let trans = try!(conn.transaction());
+        try!(trans.execute(sql!("DELETE FROM factoids WHERE key = $1"), &[&key]));
+        try!(trans.execute(sql!("INSERT INTO factoids (key, name, val, set_by, set_at) VALUES ($1, $2, $3, $4, $5)"),
+                           &[&key, &factoid.name, &factoid.value, &factoid.set_by, &factoid.set_at]));
+        try!(trans.commit());
+        Ok(())
+    }
+
+    fn forget_factoid(&self, key: &str) -> Result<usize> {
+        let conn = try!(self.pool.get());
+        Ok(try!(conn.execute(sql!("DELETE FROM factoids WHERE key = $1"), &[&key])) as usize)
+    }
+
+    fn factoid_locked(&self, key: &str) -> Result<bool> {
+        let conn = try!(self.pool.get());
+        let stmt = try!(conn.prepare(sql!("SELECT 1 FROM factoid_locks WHERE key = $1")));
+        Ok(try!(stmt.query(&[&key])).iter().next().is_some())
+    }
+
+    fn lock_factoid(&self, key: &str, locked: bool, by: &str) -> Result<()> {
+        let conn = try!(self.pool.get());
+        let trans = try!(conn.transaction());
+        try!(trans.execute(sql!("DELETE FROM factoid_locks WHERE key = $1"), &[&key]));
+        if locked {
+            try!(trans.execute(sql!("INSERT INTO factoid_locks (key, locked_by, locked_at) VALUES ($1, $2, $3)"),
+                               &[&key, &by, &time::get_time().sec]));
+        }
+        try!(trans.commit());
+        Ok(())
+    }
+
+    fn add_karma(&self, change: &KarmaChange) -> Result<i32> {
+        let conn = try!(self.pool.get());
+        let trans = try!(conn.transaction());
+        let updated = try!(trans.execute(sql!("UPDATE karma_scores SET score = score + $3, name = $4 WHERE scope = $1 AND subject = $2"),
+                                         &[&change.scope, &change.subject, &change.change, &change.name]));
+        if updated == 0 {
+            try!(trans.execute(sql!("INSERT INTO karma_scores (scope, subject, name, score) VALUES ($1, $2, $3, $4)"),
+                               &[&change.scope, &change.subject, &change.name, &change.change]));
+        }
+        try!(trans.execute(sql!("INSERT INTO karma_reasons (scope, subject, change, reason, set_by, set_at) VALUES ($1, $2, $3, $4, $5, $6)"),
+                           &[&change.scope, &change.subject, &change.change, &change.reason, &change.set_by, &change.set_at]));
+        let score = {
+            let stmt = try!(trans.prepare(sql!("SELECT score FROM karma_scores WHERE scope = $1 AND subject = $2")));
+            let rows = try!(stmt.query(&[&change.scope, &change.subject]));
+            rows.iter().next().map(|r| r.get(0)).unwrap_or(change.change)
+        };
+        try!(trans.commit());
+        Ok(score)
+    }
+
+    fn karma(&self, scope: &str, subject: &str) -> Result<i32> {
+        let conn = try!(self.pool.get());
+        let stmt = try!(conn.prepare(sql!("SELECT score FROM karma_scores WHERE scope = $1 AND subject = $2")));
+        let rows = try!(stmt.query(&[&scope, &subject]));
+        Ok(rows.iter().next().map(|r| r.get(0)).unwrap_or(0))
+    }
+
+    fn karma_reasons(&self, scope: &str, subject: &str, limit: usize) -> Result<Vec<String>> {
+        let conn = try!(self.pool.get());
+        let stmt = try!(conn.prepare(sql!("SELECT reason FROM karma_reasons
+                                           WHERE scope = $1 AND subject = $2 AND reason <> ''
+                                           ORDER BY set_at DESC LIMIT $3")));
+        let rows = try!(stmt.query(&[&scope, &subject, &(limit as i64)]));
+        Ok(rows.iter().map(|r| r.get(0)).collect())
+    }
+
+    fn karma_ranking(&self, scope: &str, ascending: bool, limit: usize) -> Result<Vec<KarmaScore>> {
+        let conn = try!(self.pool.get());
+        let stmt = if ascending {
+            try!(conn.prepare(sql!("SELECT name, score FROM karma_scores WHERE scope = $1 ORDER BY score ASC LIMIT $2")))
+        } else {
+            try!(conn.prepare(sql!("SELECT name, score FROM karma_scores WHERE scope = $1 ORDER BY score DESC LIMIT $2")))
+        };
+        let rows = try!(stmt.query(&[&scope, &(limit as i64)]));
+        Ok(rows.iter().map(|r| KarmaScore{
+            name: r.get(0),
+            score: r.get(1),
+        }).collect())
+    }
+
+    fn record_sighting(&self, key: &str, sighting: &Sighting) -> Result<()> {
+        let conn = try!(self.pool.get());
+        let trans = try!(conn.transaction());
+        let updated = try!(trans.execute(sql!("UPDATE seen SET nick = $3, activity = $4, text = $5, seen_at = $6 WHERE key = $1 AND chan = $2"),
+                                         &[&key, &sighting.chan, &sighting.nick, &sighting.activity.as_str(), &sighting.text, &sighting.seen_at]));
+        if updated == 0 {
+            try!(trans.execute(sql!("INSERT INTO seen (key, chan, nick, activity, text, seen_at) VALUES ($1, $2, $3, $4, $5, $6)"),
+                               &[&key, &sighting.chan, &sighting.nick, &sighting.activity.as_str(), &sighting.text, &sighting.seen_at]));
+        }
+        try!(trans.commit());
+        Ok(())
+    }
+
+    fn sightings(&self, key: &str) -> Result<Vec<Sighting>> {
+        let conn = try!(self.pool.get());
+        let stmt = try!(conn.prepare(sql!("SELECT nick, chan, activity, text, seen_at FROM seen WHERE key = $1 ORDER BY seen_at DESC")));
+        let rows = try!(stmt.query(&[&key]));
+        rows.iter().map(|r| {
+            let activity: String = r.get(2);
+            Ok(Sighting{
+                nick: r.get(0),
+                chan: r.get(1),
+                activity: try!(Activity::parse(&activity).ok_or(Error::Storage(format!("unknown activity {}", activity)))),
+                text: r.get(3),
+                seen_at: r.get(4),
+            })
+        }).collect()
+    }
+
+    fn add_memo(&self, memo: &Memo) -> Result<()> {
+        let conn = try!(self.pool.get());
+        try!(conn.execute(sql!("INSERT INTO memos (recipient, sender, sender_key, chan, text, private, sent_at) VALUES ($1, $2, $3, $4, $5, $6, $7)"),
+                          &[&memo.recipient, &memo.sender, &memo.sender_key, &memo.chan, &memo.text, &memo.private, &memo.sent_at]));
+        Ok(())
+    }
+
+    fn memos_from(&self, sender_key: &str) -> Result<usize> {
+        let conn = try!(self.pool.get());
+        let stmt = try!(conn.prepare(sql!("SELECT count(*) FROM memos WHERE sender_key = $1")));
+        let rows = try!(stmt.query(&[&sender_key]));
+        let count: i64 = rows.iter().next().map(|r| r.get(0)).unwrap_or(0);
+        Ok(count as usize)
+    }
+
+    fn memos_for(&self, recipients: &[String]) -> Result<Vec<Memo>> {
+        let conn = try!(self.pool.get());
+        let stmt = try!(conn.prepare(sql!("SELECT id, recipient, sender, sender_key, chan, text, private, sent_at FROM memos
+                                           WHERE recipient = $1 ORDER BY id")));
+        let mut memos = Vec::new();
+        for recipient in recipients.iter() {
+            let rows = try!(stmt.query(&[recipient]));
+            memos.extend(rows.iter().map(|r| Memo{
+                id: r.get(0),
+                recipient: r.get(1),
+                sender: r.get(2),
+                sender_key: r.get(3),
+                chan: r.get(4),
+                text: r.get(5),
+                private: r.get(6),
+                sent_at: r.get(7),
+            }));
+        }
+        memos.sort_by(|a, b| a.id.cmp(&b.id));
+        memos.dedup();
+        Ok(memos)
+    }
+
+    fn delete_memo(&self, id: i64) -> Result<bool> {
+        let conn = try!(self.pool.get());
+        Ok(try!(conn.execute(sql!("DELETE FROM memos WHERE id = $1"), &[&id])) > 0)
+    }
+
+    fn expire_memos(&self, sent_at: i64) -> Result<usize> {
+        let conn = try!(self.pool.get());
+        Ok(try!(conn.execute(sql!("DELETE FROM memos WHERE sent_at < $1"), &[&sent_at])) as usize)
+    }
+
+    fn add_reminder(&self, reminder: &Reminder) -> Result<i64> {
+        let conn = try!(self.pool.get());
+        let stmt = try!(conn.prepare(sql!("INSERT INTO reminders (owner, owner_key, target, nick, text, due_at, set_at)
+                                           VALUES ($1, $2, $3, $4, $5, $6, $7) RETURNING id")));
+        let rows = try!(stmt.query(&[&reminder.owner, &reminder.owner_key, &reminder.target, &reminder.nick,
+                                     &reminder.text, &reminder.due_at, &reminder.set_at]));
+        rows.iter().next().map(|r| r.get(0)).ok_or(Error::Storage("no id for new reminder".to_string()))
+    }
+
+    fn reminders(&self) -> Result<Vec<Reminder>> {
+        let conn = try!(self.pool.get());
+        let stmt = try!(conn.prepare(sql!("SELECT id, owner, owner_key, target, nick, text, due_at, set_at FROM reminders ORDER BY due_at")));
+        let rows = try!(stmt.query(&[]));
+        Ok(rows.iter().map(|r| Reminder{
+            id: r.get(0),
+            owner: r.get(1),
+            owner_key: r.get(2),
+            target: r.get(3),
+            nick: r.get(4),
+            text: r.get(5),
+            due_at: r.get(6),
+            set_at: r.get(7),
+        }).collect())
+    }
+
+    fn delete_reminder(&self, id: i64) -> Result<bool> {
+        let conn = try!(self.pool.get());
+        Ok(try!(conn.execute(sql!("DELETE FROM reminders WHERE id = $1"), &[&id])) > 0)
+    }
+
+    fn value(&self, namespace: &str, key: &str) -> Result<Option<String>> {
+        let conn = try!(self.pool.get());
+        let stmt = try!(conn.prepare(sql!("SELECT value FROM plugin_values WHERE namespace = $1 AND key = $2")));
+        let rows = try!(stmt.query(&[&namespace, &key]));
+        Ok(rows.iter().next().map(|r| r.get(0)))
+    }
+
+    fn set_value(&self, namespace: &str, key: &str, value: &str) -> Result<()> {
+        let conn = try!(self.pool.get());
+        let trans = try!(conn.transaction());
+        let updated = try!(trans.execute(sql!("UPDATE plugin_values SET value = $3 WHERE namespace = $1 AND key = $2"),
+                                         &[&namespace, &key, &value]));
+        if updated == 0 {
+            try!(trans.execute(sql!("INSERT INTO plugin_values (namespace, key, value) VALUES ($1, $2, $3)"),
+                               &[&namespace, &key, &value]));
+        }
+        try!(trans.commit());
+        Ok(())
+    }
+
+    fn delete_value(&self, namespace: &str, key: &str) -> Result<bool> {
+        let conn = try!(self.pool.get());
+        Ok(try!(conn.execute(sql!("DELETE FROM plugin_values WHERE namespace = $1 AND key = $2"), &[&namespace, &key])) > 0)
+    }
+
+    fn values(&self, namespace: &str, prefix: &str) -> Result<Vec<(String, String)>> {
+        let conn = try!(self.pool.get());
+        let stmt = try!(conn.prepare(sql!("SELECT key, value FROM plugin_values
+                                           WHERE namespace = $1 AND substr(key, 1, char_length($2)) = $2
+                                           ORDER BY key")));
+        let rows = try!(stmt.query(&[&namespace, &prefix]));
+        Ok(rows.iter().map(|r| (r.get(0), r.get(1))).collect())
+    }
+
+}
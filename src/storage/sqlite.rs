@@ -0,0 +1,365 @@
+//! Storage in an SQLite file.
+
+use rusqlite::SqliteConnection;
+use std::path::Path;
+use time;
+use super::{migrations, Activity, Factoid, KarmaChange, KarmaScore, Memo, Reminder, Sighting, Storage};
+use super::pool::ConnectionPool;
+use super::super::error::{Error, Result};
+
+/// An `SqliteConnection`, which the pool only ever lends to one thread
+/// at a time.
+struct Handle(SqliteConnection);
+
+// SQLite connections may move between threads as long as they're only
+// used by one at a time, which the pool makes sure of.
+unsafe impl Send for Handle {}
+
+pub struct SqliteStorage {
+    pool: ConnectionPool<Handle>,
+}
+
+/// Applies any migrations `conn` hasn't seen.
+fn migrate(conn: &SqliteConnection) -> Result<()> {
+    try!(conn.execute_batch(migrations::CREATE_SCHEMA_MIGRATIONS));
+    let version: i64 = try!(conn.query_row("SELECT coalesce(max(version), 0) FROM schema_migrations", &[], |r| r.get(0)));
+    for migration in migrations::pending(version as u32).into_iter() {
+        info!("Migrating to version {}: {}", migration.version, migration.description);
+        let trans = try!(conn.transaction());
+        try!(conn.execute_batch(migration.sqlite));
+        try!(conn.execute("INSERT INTO schema_migrations (version, description) VALUES (?1, ?2)",
+                          &[&(migration.version as i64), &migration.description]));
+        try!(trans.commit());
+    }
+    Ok(())
+}
+
+impl SqliteStorage {
+
+    /// Opens, or creates, the database at `path` and brings the schema
+    /// up to date.  An in-memory database only gets one connection,
+    /// whatever `pool_size` says.
+    pub fn open(path: &str, pool_size: usize) -> Result<SqliteStorage> {
+        // Each connection to ":memory:" (or "", a temporary file) gets
+        // a database of its own, so a pool of them would lose writes.
+        let pool_size = if path == ":memory:" || path.is_empty() {
+            if pool_size > 1 {
+                warn!("In-memory SQLite databases can't be shared, using one connection instead of {}.", pool_size);
+            }
+            1
+        } else {
+            pool_size
+        };
+        let path = path.to_string();
+        let pool = try!(ConnectionPool::new(pool_size, move || {
+            let conn = try!(SqliteConnection::open(&Path::new(&path)));
+            // Wait for other connections' writes rather than failing.
+            try!(conn.execute_batch("PRAGMA busy_timeout = 5000"));
+            Ok(Handle(conn))
+        }, |conn| conn.0.execute_batch("SELECT 1").is_ok()));
+        try!(migrate(&try!(pool.get()).0));
+        Ok(SqliteStorage{
+            pool: pool,
+        })
+    }
+
+}
+
+impl Storage for SqliteStorage {
+
+    fn factoids(&self, key: &str) -> Result<Vec<Factoid>> {
+        let conn = &try!(self.pool.get()).0;
+        let mut stmt = try!(conn.prepare("SELECT name, val, set_by, set_at FROM factoids WHERE key = ?1 ORDER BY set_at"));
+        let mut factoids = Vec::new();
+        for row in try!(stmt.query(&[&key])) {
+            let row = try!(row);
+            factoids.push(Factoid{
+                name: row.get(0),
+                value: row.get(1),
+                set_by: row.get(2),
+                set_at: row.get(3),
+            });
+        }
+        Ok(factoids)
+    }
+
+    fn add_factoid(&self, key: &str, factoid: &Factoid) -> Result<bool> {
+        let conn = &try!(self.pool.get()).0;
+        let added = try!(conn.execute("INSERT OR IGNORE INTO factoids (key, name, val, set_by, set_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+                                      &[&key, &factoid.name, &factoid.value, &factoid.set_by, &factoid.set_at]));
+        Ok(added > 0)
+    }
+
+    fn replace_factoid(&self, key: &str, factoid: &Factoid) -> Result<()> {
+        let conn = &try!(self.pool.get()).0;
+        let trans = try!(conn.transaction());
+        try!(conn.execute("DELETE FROM factoids WHERE key = ?1", &[&key]));
+        try!(conn.execute("INSERT INTO factoids (key, name, val, set_by, set_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+                          &[&key, &factoid.name, &factoid.value, &factoid.set_by, &factoid.set_at]));
+        try!(trans.commit());
+        Ok(())
+    }
+
+    fn forget_factoid(&self, key: &str) -> Result<usize> {
+        let conn = &try!(self.pool.get()).0;
+        Ok(try!(conn.execute("DELETE FROM factoids WHERE key = ?1", &[&key])) as usize)
+    }
+
+    fn factoid_locked(&self, key: &str) -> Result<bool> {
+        let conn = &try!(self.pool.get()).0;
+        let mut stmt = try!(conn.prepare("SELECT 1 FROM factoid_locks WHERE key = ?1"));
+        for row in try!(stmt.query(&[&key])) {
+            try!(row);
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    fn lock_factoid(&self, key: &str, locked: bool, by: &str) -> Result<()> {
+        let conn = &try!(self.pool.get()).0;
+        if locked {
+            try!(conn.execute("INSERT OR REPLACE INTO factoid_locks (key, locked_by, locked_at) VALUES (?1, ?2, ?3)",
+                              &[&key, &by, &time::get_time().sec]));
+        } else {
+            try!(conn.execute("DELETE FROM factoid_locks WHERE key = ?1", &[&key]));
+        }
+        Ok(())
+    }
+
+    fn add_karma(&self, change: &KarmaChange) -> Result<i32> {
+        let conn = &try!(self.pool.get()).0;
+        let trans = try!(conn.transaction());
+        try!(conn.execute("INSERT OR IGNORE INTO karma_scores (scope, subject, name, score) VALUES (?1, ?2, ?3, 0)",
+                          &[&change.scope, &change.subject, &change.name]));
+        try!(conn.execute("UPDATE karma_scores SET score = score + ?3, name = ?4 WHERE scope = ?1 AND subject = ?2",
+                          &[&change.scope, &change.subject, &change.change, &change.name]));
+        try!(conn.execute("INSERT INTO karma_reasons (scope, subject, change, reason, set_by, set_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                          &[&change.scope, &change.subject, &change.change, &change.reason, &change.set_by, &change.set_at]));
+        let score = try!(conn.query_row("SELECT score FROM karma_scores WHERE scope = ?1 AND subject = ?2",
+                                        &[&change.scope, &change.subject], |r| r.get(0)));
+        try!(trans.commit());
+        Ok(score)
+    }
+
+    fn karma(&self, scope: &str, subject: &str) -> Result<i32> {
+        let conn = &try!(self.pool.get()).0;
+        let mut stmt = try!(conn.prepare("SELECT score FROM karma_scores WHERE scope = ?1 AND subject = ?2"));
+        for row in try!(stmt.query(&[&scope, &subject])) {
+            return Ok(try!(row).get(0));
+        }
+        Ok(0)
+    }
+
+    fn karma_reasons(&self, scope: &str, subject: &str, limit: usize) -> Result<Vec<String>> {
+        let conn = &try!(self.pool.get()).0;
+        let mut stmt = try!(conn.prepare("SELECT reason FROM karma_reasons
+                                          WHERE scope = ?1 AND subject = ?2 AND reason <> ''
+                                          ORDER BY set_at DESC LIMIT ?3"));
+        let mut reasons = Vec::new();
+        for row in try!(stmt.query(&[&scope, &subject, &(limit as i64)])) {
+            reasons.push(try!(row).get(0));
+        }
+        Ok(reasons)
+    }
+
+    fn karma_ranking(&self, scope: &str, ascending: bool, limit: usize) -> Result<Vec<KarmaScore>> {
+        let conn = &try!(self.pool.get()).0;
+        let sql = if ascending {
+            "SELECT name, score FROM karma_scores WHERE scope = ?1 ORDER BY score ASC LIMIT ?2"
+        } else {
+            "SELECT name, score FROM karma_scores WHERE scope = ?1 ORDER BY score DESC LIMIT ?2"
+        };
+        let mut stmt = try!(conn.prepare(sql));
+        let mut scores = Vec::new();
+        for row in try!(stmt.query(&[&scope, &(limit as i64)])) {
+            let row = try!(row);
+            scores.push(KarmaScore{
+                name: row.get(0),
+                score: row.get(1),
+            });
+        }
+        Ok(scores)
+    }
+
+    fn record_sighting(&self, key: &str, sighting: &Sighting) -> Result<()> {
+        let conn = &try!(self.pool.get()).0;
+        try!(conn.execute("INSERT OR REPLACE INTO seen (key, chan, nick, activity, text, seen_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                          &[&key, &sighting.chan, &sighting.nick, &sighting.activity.as_str(), &sighting.text, &sighting.seen_at]));
+        Ok(())
+    }
+
+    fn sightings(&self, key: &str) -> Result<Vec<Sighting>> {
+        let conn = &try!(self.pool.get()).0;
+        let mut stmt = try!(conn.prepare("SELECT nick, chan, activity, text, seen_at FROM seen WHERE key = ?1 ORDER BY seen_at DESC"));
+        let mut sightings = Vec::new();
+        for row in try!(stmt.query(&[&key])) {
+            let row = try!(row);
+            let activity: String = row.get(2);
+            sightings.push(Sighting{
+                nick: row.get(0),
+                chan: row.get(1),
+                activity: try!(Activity::parse(&activity).ok_or(Error::Storage(format!("unknown activity {}", activity)))),
+                text: row.get(3),
+                seen_at: row.get(4),
+            });
+        }
+        Ok(sightings)
+    }
+
+    fn add_memo(&self, memo: &Memo) -> Result<()> {
+        let conn = &try!(self.pool.get()).0;
+        try!(conn.execute("INSERT INTO memos (recipient, sender, sender_key, chan, text, private, sent_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                          &[&memo.recipient, &memo.sender, &memo.sender_key, &memo.chan, &memo.text, &memo.private, &memo.sent_at]));
+        Ok(())
+    }
+
+    fn memos_from(&self, sender_key: &str) -> Result<usize> {
+        let conn = &try!(self.pool.get()).0;
+        let count: i64 = try!(conn.query_row("SELECT count(*) FROM memos WHERE sender_key = ?1", &[&sender_key], |r| r.get(0)));
+        Ok(count as usize)
+    }
+
+    fn memos_for(&self, recipients: &[String]) -> Result<Vec<Memo>> {
+        let conn = &try!(self.pool.get()).0;
+        let mut stmt = try!(conn.prepare("SELECT id, recipient, sender, sender_key, chan, text, private, sent_at FROM memos
+                                          WHERE recipient = ?1 ORDER BY id"));
+        let mut memos = Vec::new();
+        for recipient in recipients.iter() {
+            for row in try!(stmt.query(&[recipient])) {
+                let row = try!(row);
+                memos.push(Memo{
+                    id: row.get(0),
+                    recipient: row.get(1),
+                    sender: row.get(2),
+                    sender_key: row.get(3),
+                    chan: row.get(4),
+                    text: row.get(5),
+                    private: row.get(6),
+                    sent_at: row.get(7),
+                });
+            }
+        }
+        memos.sort_by(|a, b| a.id.cmp(&b.id));
+        memos.dedup();
+        Ok(memos)
+    }
+
+    fn delete_memo(&self, id: i64) -> Result<bool> {
+        let conn = &try!(self.pool.get()).0;
+        Ok(try!(conn.execute("DELETE FROM memos WHERE id = ?1", &[&id])) > 0)
+    }
+
+    fn expire_memos(&self, sent_at: i64) -> Result<usize> {
+        let conn = &try!(self.pool.get()).0;
+        Ok(try!(conn.execute("DELETE FROM memos WHERE sent_at < ?1", &[&sent_at])) as usize)
+    }
+
+    fn add_reminder(&self, reminder: &Reminder) -> Result<i64> {
+        let conn = &try!(self.pool.get()).0;
+        try!(conn.execute("INSERT INTO reminders (owner, owner_key, target, nick, text, due_at, set_at)
+                           VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                          &[&reminder.owner, &reminder.owner_key, &reminder.target, &reminder.nick,
+                            &reminder.text, &reminder.due_at, &reminder.set_at]));
+        Ok(conn.last_insert_rowid())
+    }
+
+    fn reminders(&self) -> Result<Vec<Reminder>> {
+        let conn = &try!(self.pool.get()).0;
+        let mut stmt = try!(conn.prepare("SELECT id, owner, owner_key, target, nick, text, due_at, set_at FROM reminders ORDER BY due_at"));
+        let mut reminders = Vec::new();
+        for row in try!(stmt.query(&[])) {
+            let row = try!(row);
+            reminders.push(Reminder{
+                id: row.get(0),
+                owner: row.get(1),
+                owner_key: row.get(2),
+                target: row.get(3),
+                nick: row.get(4),
+                text: row.get(5),
+                due_at: row.get(6),
+                set_at: row.get(7),
+            });
+        }
+        Ok(reminders)
+    }
+
+    fn delete_reminder(&self, id: i64) -> Result<bool> {
+        let conn = &try!(self.pool.get()).0;
+        Ok(try!(conn.execute("DELETE FROM reminders WHERE id = ?1", &[&id])) > 0)
+    }
+
+    fn value(&self, namespace: &str, key: &str) -> Result<Option<String>> {
+        let conn = &try!(self.pool.get()).0;
+        let mut stmt = try!(conn.prepare("SELECT value FROM plugin_values WHERE namespace = ?1 AND key = ?2"));
+        for row in try!(stmt.query(&[&namespace, &key])) {
+            return Ok(Some(try!(row).get(0)));
+        }
+        Ok(None)
+    }
+
+    fn set_value(&self, namespace: &str, key: &str, value: &str) -> Result<()> {
+        let conn = &try!(self.pool.get()).0;
+        try!(conn.execute("INSERT OR REPLACE INTO plugin_values (namespace, key, value) VALUES (?1, ?2, ?3)",
+                          &[&namespace, &key, &value]));
+        Ok(())
+    }
+
+    fn delete_value(&self, namespace: &str, key: &str) -> Result<bool> {
+        let conn = &try!(self.pool.get()).0;
+        Ok(try!(conn.execute("DELETE FROM plugin_values WHERE namespace = ?1 AND key = ?2", &[&namespace, &key])) > 0)
+    }
+
+    fn values(&self, namespace: &str, prefix: &str) -> Result<Vec<(String, String)>> {
+        let conn = &try!(self.pool.get()).0;
+        let mut stmt = try!(conn.prepare("SELECT key, value FROM plugin_values
+                                          WHERE namespace = ?1 AND substr(key, 1, length(?2)) = ?2
+                                          ORDER BY key"));
+        let mut values = Vec::new();
+        for row in try!(stmt.query(&[&namespace, &prefix])) {
+            let row = try!(row);
+            values.push((row.get(0), row.get(1)));
+        }
+        Ok(values)
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::SqliteStorage;
+    use super::super::{KarmaChange, KarmaScore, Storage};
+
+    fn change(subject: &str, change: i32, reason: &str, set_at: i64) -> KarmaChange {
+        KarmaChange{
+            scope: "#c".to_string(),
+            subject: subject.to_lowercase(),
+            name: subject.to_string(),
+            change: change,
+            reason: reason.to_string(),
+            set_by: "alice".to_string(),
+            set_at: set_at,
+        }
+    }
+
+    #[test]
+    fn karma_round_trip() {
+        // Each connection to :memory: gets its own database, so there's
+        // only one.
+        let storage = SqliteStorage::open(":memory:", 1).unwrap();
+        assert_eq!(storage.karma("#c", "rust").unwrap(), 0);
+        assert_eq!(storage.add_karma(&change("Rust", 1, "for loops", 1)).unwrap(), 1);
+        assert_eq!(storage.add_karma(&change("rust", 1, "", 2)).unwrap(), 2);
+        assert_eq!(storage.add_karma(&change("Rust", -1, "borrowck", 3)).unwrap(), 1);
+        assert_eq!(storage.add_karma(&change("Go", -2, "", 4)).unwrap(), -2);
+
+        assert_eq!(storage.karma("#c", "rust").unwrap(), 1);
+        assert_eq!(storage.karma("", "rust").unwrap(), 0);
+        assert_eq!(storage.karma_reasons("#c", "rust", 5).unwrap(), vec!["borrowck", "for loops"]);
+        assert_eq!(storage.karma_ranking("#c", false, 5).unwrap(), vec![
+            KarmaScore{ name: "Rust".to_string(), score: 1 },
+            KarmaScore{ name: "Go".to_string(), score: -2 },
+            ]);
+    }
+
+}